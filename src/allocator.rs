@@ -1,126 +1,1386 @@
-use crate::{engine::Value, operators::Num};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+};
+
+use crate::{
+    engine::{Arena, Children, Value, ValueMut, ValueRef},
+    op::Op,
+    operators::{self, Num},
+};
+
+mod closure;
+mod compile;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod registry;
+#[cfg(feature = "serde")]
+mod serialize;
+
+pub use compile::Graph;
+use compile::Pruned;
+pub use registry::TooManyAllocators;
 
 pub type BackwardFn<T> = fn(&mut Allocator<T>, T, T, &[ValueId<T>]);
+// Given the gradient node of an op's output and the output itself, builds one gradient
+// node per child (None for constant children) using differentiable ops
+pub type GradFn<T> =
+    fn(&mut Allocator<T>, ValueId<T>, ValueId<T>, &[ValueId<T>]) -> Vec<Option<ValueId<T>>>;
+// Recomputes an op's data from its children, see `Allocator::replay_forward`
+pub type ForwardFn<T> = fn(&Allocator<T>, &[ValueId<T>]) -> T;
+// A re-runnable forward pass for a checkpointed segment, see `Allocator::checkpoint`
+pub type SegmentFn<T> = Box<dyn Fn(&[ValueId<T>]) -> Vec<ValueId<T>>>;
+// Given the gradients of a fused op's outputs, accumulates those of its inputs, see
+// `Allocator::record_fused`
+pub type FusedFn<T> = Box<dyn Fn(&mut Allocator<T>, &[T])>;
+
+// Set on the ids of temporaries, whose other bits hold the position on the tape. Permanent
+// ids are plain slot indices.
+const TEMPORARY: u32 = 1 << 31;
 
 #[derive(Clone, Copy)]
 pub struct ValueId<T: Num> {
-    id: i64,
-    pub allocator: *mut Allocator<T>,
-    _phantom: std::marker::PhantomData<T>,
+    index: u32,
+    // The generation when the value was created, so ids surviving a `clear_temps()` or
+    // `free()` can be told apart from the nodes that reuse their slots
+    generation: u32,
+    // The allocator's slot in the registry, see `ValueId::allocator`
+    slot: u32,
+    _phantom: std::marker::PhantomData<*mut Allocator<T>>,
 }
 
 impl<T: Num> ValueId<T> {
+    fn new(index: u32, generation: u32, slot: u32) -> Self {
+        ValueId {
+            index,
+            generation,
+            slot,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    // The allocator this value lives on, or null for `ValueId::default()` and ids that
+    // outlived their allocator
+    #[inline(always)]
+    pub fn allocator(&self) -> *mut Allocator<T> {
+        registry::lookup(self.slot) as *mut Allocator<T>
+    }
+
     pub fn step(&self, lr: T) {
-        unsafe { (*self.allocator).get_mut(*self).step(lr) }
+        unsafe { (*self.allocator()).get_mut(*self).step(lr) }
+    }
+
+    pub fn zero_grad(&self) {
+        unsafe { (*self.allocator()).get_mut(*self).zero_grad() }
+    }
+
+    pub fn set_requires_grad(&self, requires_grad: bool) {
+        unsafe { *(*self.allocator()).get_mut(*self).requires_grad = requires_grad }
+    }
+
+    // Position on the tape, or None for permanent values
+    pub(crate) fn tape_index(&self) -> Option<usize> {
+        tape_index(self.index)
+    }
+
+    // The tagged index without generation or allocator, as stored in `Children`
+    #[inline(always)]
+    pub(crate) fn raw(&self) -> u32 {
+        self.index
+    }
+}
+
+#[inline(always)]
+fn tape_index(raw: u32) -> Option<usize> {
+    (raw & TEMPORARY != 0).then_some((raw & !TEMPORARY) as usize)
+}
+
+// The id shown to users: the slot of a permanent value, or -(position + 1) on the tape
+fn display_id(raw: u32) -> i64 {
+    match tape_index(raw) {
+        Some(index) => -(index as i64 + 1),
+        None => raw as i64,
+    }
+}
+
+impl<T: Num> std::fmt::Debug for ValueId<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut tuple = f.debug_tuple("ValueId");
+        tuple.field(&display_id(self.index));
+        if let Some(allocator) = unsafe { self.allocator().as_ref() } {
+            if let Some(label) = allocator.labels.get(&self.index) {
+                tuple.field(label);
+            }
+        }
+        tuple.finish()
+    }
+}
+
+impl<T: Num> PartialEq for ValueId<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation && self.slot == other.slot
+    }
+}
+
+impl<T: Num> Eq for ValueId<T> {}
+
+impl<T: Num> std::hash::Hash for ValueId<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+        self.slot.hash(state);
     }
 }
 
 impl<T: Num> Default for ValueId<T> {
     fn default() -> Self {
-        Self {
-            id: 0,
-            allocator: std::ptr::null_mut(),
-            _phantom: std::marker::PhantomData,
-        }
+        Self::new(0, 0, 0)
     }
 }
 
 pub struct Allocator<T: Num> {
-    permanent: Vec<Value<T>>,
-    temporary: Vec<Value<T>>,
+    permanent: Arena<T>,
+    // Bumped whenever a permanent slot is freed, like `generation` for the tape
+    permanent_generations: Vec<u32>,
+    free_slots: Vec<usize>,
+    temporary: Arena<T>,
+    generation: u32,
+    // Number of permanent values with a backward function, created by `alloc_op`. While
+    // there are none, backward can sweep the temporary arena in reverse creation order.
+    permanent_ops: usize,
+    // Debugging labels by raw id, dropped along with the values they name
+    labels: HashMap<u32, String>,
+    // Set when data changes while ops are recorded, since those ops still hold results
+    // computed from the old data. Cleared by `replay_forward()` and `clear_temps()`.
+    stale: Cell<bool>,
+    // Ops left with old results by the last `Graph::run`, which only a backward pass that
+    // reaches them trips over. Cleared along with `stale`.
+    pruned: Option<Pruned>,
+    // Whether `backward()` only runs the backward functions of the root's ancestors
+    skip_unreachable: bool,
+    // Whether `record` leaves results uncomputed, see `set_lazy`
+    lazy: bool,
+    // The forward function of the cast `record` wraps ops in, see `half::cast`
+    pub(crate) autocast: Option<ForwardFn<T>>,
+    // Longest the tape has been since the last `clear_temps()`, updated whenever it shrinks
+    peak_tape_len: usize,
+    // Checkpointed segments and fused ops, keyed by the tape index of their boundary node
+    segments: HashMap<usize, Segment<T>>,
+    // Reused to hand the children of a node to its backward or forward function
+    scratch: Vec<ValueId<T>>,
+    // The newest generation handed out, so that every tape gets generations of its own
+    last_generation: u32,
+    // Tapes swapped out by `switch_tape`, indexed by `TapeId`. The slot of the active tape
+    // holds an empty placeholder, while its contents live in the fields above.
+    tapes: Vec<Tape<T>>,
+    active_tape: usize,
+    // Lets ids find this allocator, see `ValueId::allocator`
+    handle: registry::Handle,
+}
+
+// Identifies one of the allocator's tapes, see `Allocator::new_tape`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TapeId(usize);
+
+// A tape parked by `switch_tape`, with the per-tape state the allocator keeps for the
+// active one
+struct Tape<T: Num> {
+    arena: Arena<T>,
+    generation: u32,
+    labels: HashMap<u32, String>,
+    stale: bool,
+    pruned: Option<Pruned>,
+    peak_len: usize,
+    segments: HashMap<usize, Segment<T>>,
+}
+
+impl<T: Num> Tape<T> {
+    fn new(generation: u32) -> Self {
+        Tape {
+            arena: Arena::new(),
+            generation,
+            labels: HashMap::new(),
+            stale: false,
+            pruned: None,
+            peak_len: 0,
+            segments: HashMap::new(),
+        }
+    }
+}
+
+// A position on the tape returned by `Allocator::mark`
+#[derive(Clone, Copy, Debug)]
+pub struct TempMark {
+    len: usize,
+    generation: u32,
+}
+
+// A snapshot of the allocator's size, see `Allocator::stats`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub permanent: usize,
+    pub temporary: usize,
+    // Nodes in either arena recorded by an op, as opposed to parameters and constants
+    pub ops: usize,
+    // Nodes plus their children, excluding unused capacity
+    pub bytes: usize,
+    pub peak_tape_len: usize,
+}
+
+// Summary of a set of gradients, see `Allocator::grad_stats`. `max` is the largest
+// magnitude, and every field is zero for an empty set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GradStats<T: Num> {
+    pub count: usize,
+    pub norm: T,
+    pub mean: T,
+    pub std: T,
+    pub max: T,
+}
+
+// The data of every permanent slot, and optionally the gradients, captured by
+// `Allocator::snapshot`. Optimizer state is saved by cloning the optimizer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllocatorSnapshot<T: Num> {
+    data: Vec<T>,
+    grads: Option<Vec<T>>,
+    generations: Vec<u32>,
+}
+
+struct Segment<T: Num> {
+    inputs: Vec<ValueId<T>>,
+    outputs: Vec<ValueId<T>>,
+    backward: SegmentBackward<T>,
+}
+
+enum SegmentBackward<T: Num> {
+    // Rerun the forward pass and backpropagate through it, see `Allocator::checkpoint`
+    Recompute(SegmentFn<T>),
+    Fused(FusedFn<T>),
 }
 
 impl<T: Num> Allocator<T> {
     pub fn new() -> Self {
-        Self {
-            permanent: vec![],
-            temporary: vec![],
-        }
+        Self::try_new().expect("too many live allocators")
+    }
+
+    // Like `new`, but returns an error instead of panicking when every registry slot is
+    // taken by a live allocator
+    pub fn try_new() -> Result<Self, TooManyAllocators> {
+        Ok(Self {
+            permanent: Arena::new(),
+            permanent_generations: vec![],
+            free_slots: vec![],
+            temporary: Arena::new(),
+            generation: 0,
+            permanent_ops: 0,
+            labels: HashMap::new(),
+            stale: Cell::new(false),
+            pruned: None,
+            skip_unreachable: false,
+            lazy: false,
+            autocast: None,
+            peak_tape_len: 0,
+            segments: HashMap::new(),
+            scratch: vec![],
+            last_generation: 0,
+            tapes: vec![Tape::new(0)],
+            active_tape: 0,
+            handle: registry::Handle::new()?,
+        })
     }
 
     pub fn alloc(&mut self, data: T) -> ValueId<T> {
-        let id = self.permanent.len();
-        self.permanent.push(Value::from(data));
-        ValueId {
-            id: id as i64,
-            allocator: self,
-            _phantom: std::marker::PhantomData,
+        self.alloc_permanent(Value::from(data))
+    }
+
+    pub fn alloc_named(&mut self, label: impl Into<String>, data: T) -> ValueId<T> {
+        let value = self.alloc(data);
+        self.set_label(value, label);
+        value
+    }
+
+    // Attaches a label shown by `Debug` for `ValueId`, `dump_json` and panic messages
+    pub fn set_label(&mut self, value: ValueId<T>, label: impl Into<String>) {
+        self.get(value);
+        self.labels.insert(value.index, label.into());
+    }
+
+    pub fn label(&self, value: ValueId<T>) -> Option<&str> {
+        self.labels.get(&value.index).map(|label| label.as_str())
+    }
+
+    // "id" or "id (label)", for panic messages
+    fn describe(&self, raw: u32) -> String {
+        let id = display_id(raw);
+        match self.labels.get(&raw) {
+            Some(label) => format!("{id} ({label})"),
+            None => id.to_string(),
         }
     }
 
-    pub fn alloc_t(&mut self, data: T) -> ValueId<T> {
-        let id = self.temporary.len() + 1;
-        self.temporary.push(Value::from(data));
-        ValueId {
-            id: -(id as i64),
-            allocator: self,
-            _phantom: std::marker::PhantomData,
+    // Reuses a slot released by `free` when there is one
+    fn alloc_permanent(&mut self, value: Value<T>) -> ValueId<T> {
+        let id = match self.free_slots.pop() {
+            Some(id) => {
+                self.permanent.set(id, value);
+                id
+            }
+            None => {
+                self.permanent.push(value);
+                self.permanent_generations.push(0);
+                self.permanent.len() - 1
+            }
+        };
+        self.permanent_id(id)
+    }
+
+    // Makes the ids handed out from here on find this allocator at its current address.
+    // Called by everything that creates ids from `&mut self`, so a moved allocator is found
+    // again once it is used.
+    #[inline(always)]
+    fn publish(&mut self) {
+        let allocator = self as *mut Allocator<T> as *mut ();
+        self.handle.publish(allocator);
+    }
+
+    fn permanent_id(&mut self, slot: usize) -> ValueId<T> {
+        assert!(slot < TEMPORARY as usize, "too many permanent values");
+        self.publish();
+        ValueId::new(
+            slot as u32,
+            self.permanent_generations[slot],
+            self.handle.slot(),
+        )
+    }
+
+    // Releases a permanent value so its slot can be reused by a later `alloc`. The slot's
+    // generation is bumped, so (in debug builds) using the freed id afterwards panics.
+    pub fn free(&mut self, value: ValueId<T>) {
+        assert!(
+            value.tape_index().is_none(),
+            "only permanent values can be freed"
+        );
+        let id = value.index as usize;
+        assert!(
+            value.generation == self.permanent_generations[id],
+            "permanent ValueId {id} was already freed"
+        );
+        self.labels.remove(&value.index);
+        if self.permanent.op(id).is_some() {
+            self.permanent_ops -= 1;
         }
+        self.permanent.set(id, Value::from(T::zero()));
+        self.permanent_generations[id] = self.permanent_generations[id].wrapping_add(1);
+        self.free_slots.push(id);
     }
 
+    // Copies the current data of `value` into a new permanent leaf, detached from the tape,
+    // so computed results such as running losses survive `clear_temps()`
+    pub fn persist(&mut self, value: ValueId<T>) -> ValueId<T> {
+        let data = self.get(value).data;
+        self.alloc(data)
+    }
+
+    // A permanent node with a backward function, for results that should outlive
+    // `clear_temps()`. Its children should be permanent too, since temporary children
+    // become invalid once the tape is cleared.
+    pub fn alloc_op(
+        &mut self,
+        data: T,
+        backward: BackwardFn<T>,
+        previous: impl Into<Children>,
+    ) -> ValueId<T> {
+        self.permanent_ops += 1;
+        self.alloc_permanent(Value::new(data, backward, previous))
+    }
+
+    pub fn alloc_t(&mut self, data: T) -> ValueId<T> {
+        self.push_temp(Value::from(data))
+    }
+
+    // A temporary that never receives a gradient, such as a scalar operand. Ops whose
+    // children are all frozen record no backward edges, see `record`.
+    pub fn alloc_const(&mut self, data: T) -> ValueId<T> {
+        let value = self.alloc_t(data);
+        *self.get_mut(value).requires_grad = false;
+        value
+    }
+
+    // An op defined outside the crate, recorded as `Op::Custom(backward)`
     #[inline(always)]
     pub fn alloc_temp(
         &mut self,
         data: T,
         backward: BackwardFn<T>,
-        previous: [ValueId<T>; 2],
+        previous: impl Into<Children>,
     ) -> ValueId<T> {
-        let id = self.temporary.len() + 1;
-        self.temporary.push(Value::new(data, backward, previous));
-        ValueId {
-            id: -(id as i64),
-            allocator: self,
-            _phantom: std::marker::PhantomData,
-        }
+        self.push_temp(Value::new(data, backward, previous))
+    }
+
+    // Like `alloc_temp`, for ops that can also be differentiated by `grad`
+    #[inline(always)]
+    pub fn alloc_temp_with_grad(
+        &mut self,
+        data: T,
+        backward: BackwardFn<T>,
+        grad_fn: GradFn<T>,
+        previous: impl Into<Children>,
+    ) -> ValueId<T> {
+        let mut value = Value::new(data, backward, previous);
+        value.grad_fn = Some(grad_fn);
+        self.push_temp(value)
+    }
+
+    // Records an op defined outside the crate whose data `forward` computes from its
+    // children, so `replay_forward` can recompute the node later. The node is tagged
+    // `Op::Custom(backward)`, see `record_op`.
+    pub fn record(
+        &mut self,
+        forward: ForwardFn<T>,
+        backward: BackwardFn<T>,
+        grad_fn: Option<GradFn<T>>,
+        previous: impl AsRef<[ValueId<T>]>,
+    ) -> ValueId<T> {
+        self.record_op(forward, Op::Custom(backward), grad_fn, previous)
     }
 
+    // `record` for any op. `op` selects the backward pass, and `grad_fn` is as in
+    // `alloc_temp_with_grad`. When no child
+    // requires a gradient the node gets no backward edges and is itself frozen, so
+    // constant-only computations cost nothing in `backward()`. Unfreezing such an input
+    // afterwards only takes effect once the graph is rebuilt.
     #[inline(always)]
-    pub fn get(&self, value: ValueId<T>) -> &Value<T> {
-        if value.id < 0 {
-            &self.temporary[(-value.id - 1) as usize]
+    pub fn record_op(
+        &mut self,
+        forward: ForwardFn<T>,
+        op: Op<T>,
+        grad_fn: Option<GradFn<T>>,
+        previous: impl AsRef<[ValueId<T>]>,
+    ) -> ValueId<T> {
+        if let Some(cast) = self.autocast {
+            return self.record_autocast(cast, forward, op, grad_fn, previous.as_ref());
+        }
+        let data = if self.lazy {
+            T::zero()
         } else {
-            &self.permanent[value.id as usize]
+            forward(self, previous.as_ref())
+        };
+        self.record_computed(data, forward, op, grad_fn, previous)
+    }
+
+    // `record` for a result the caller already computed, e.g. several at once with SIMD.
+    // `forward` is still kept for `replay_forward()`.
+    #[inline(always)]
+    pub(crate) fn record_computed(
+        &mut self,
+        data: T,
+        forward: ForwardFn<T>,
+        op: Op<T>,
+        grad_fn: Option<GradFn<T>>,
+        previous: impl AsRef<[ValueId<T>]>,
+    ) -> ValueId<T> {
+        let previous = previous.as_ref();
+        if let Some(cast) = self.autocast {
+            // The result was computed from uncast parameters
+            return self.record_autocast(cast, forward, op, grad_fn, previous);
         }
+        let frozen =
+            !previous.is_empty() && previous.iter().all(|child| !self.get(*child).requires_grad);
+        self.mark_stale(self.lazy);
+        self.push_temp(Value {
+            data,
+            grad: T::zero(),
+            requires_grad: !frozen,
+            previous: Children::from_slice(previous),
+            op: (!frozen).then_some(op),
+            grad_fn: grad_fn.filter(|_| !frozen),
+            forward: Some(forward),
+        })
+    }
+
+    // Records an op with its permanent children and its result passed through `cast`, so
+    // parameters stay at full precision while the op sees and produces rounded values
+    fn record_autocast(
+        &mut self,
+        cast: ForwardFn<T>,
+        forward: ForwardFn<T>,
+        op: Op<T>,
+        grad_fn: Option<GradFn<T>>,
+        previous: &[ValueId<T>],
+    ) -> ValueId<T> {
+        // The casts themselves are recorded as plain ops
+        self.autocast = None;
+        let children = previous
+            .iter()
+            .map(|child| match child.tape_index() {
+                Some(_) => *child,
+                None => self.record_op(cast, Op::Cast, Some(operators::cast_grad::<T>), [*child]),
+            })
+            .collect::<Vec<_>>();
+        let result = self.record_op(forward, op, grad_fn, children);
+        let result = self.record_op(cast, Op::Cast, Some(operators::cast_grad::<T>), [result]);
+        self.autocast = Some(cast);
+        result
     }
 
     #[inline(always)]
-    pub fn get_mut(&mut self, value: ValueId<T>) -> &mut Value<T> {
-        if value.id < 0 {
-            &mut self.temporary[(-value.id - 1) as usize]
-        } else {
-            &mut self.permanent[value.id as usize]
+    fn push_temp(&mut self, value: Value<T>) -> ValueId<T> {
+        self.temporary.push(value);
+        self.temp_id(self.temporary.len() - 1)
+    }
+
+    // Re-executes the recorded tape in creation order after leaves have changed (new
+    // inputs via `scatter_data`, parameters updated by an optimizer), so a static graph
+    // can be trained without rebuilding it every iteration. Op results are recomputed in
+    // place, and a following `backward()` works as if the graph had been built anew.
+    // Panics if an op on the tape wasn't recorded with `record`.
+    pub fn replay_forward(&mut self) {
+        self.stale.set(false);
+        self.pruned = None;
+        for i in 0..self.temporary.len() {
+            let node = self.temporary.get(i);
+            let Some(forward) = node.forward else {
+                if node.op.is_none() {
+                    continue;
+                }
+                panic!(
+                    "op {} on the tape doesn't support replay",
+                    self.describe(TEMPORARY | i as u32)
+                );
+            };
+            self.rerun(i, forward);
+        }
+    }
+
+    // Recomputes the op at tape position `index` from the current data of its children
+    #[inline(always)]
+    fn rerun(&mut self, index: usize, forward: ForwardFn<T>) {
+        let node = self.temp_id(index);
+        let children = self.take_children(node);
+        self.temporary.data[index] = forward(self, &children);
+        self.scratch = children;
+    }
+
+    #[inline(always)]
+    pub fn get(&self, value: ValueId<T>) -> ValueRef<'_, T> {
+        match value.tape_index() {
+            Some(index) => {
+                self.check_generation(value);
+                self.temporary.get(index)
+            }
+            None => {
+                self.check_slot(value);
+                self.permanent.get(value.index as usize)
+            }
+        }
+    }
+
+    // Changing the data through the view marks the tape stale, like `set_data`
+    #[inline(always)]
+    pub fn get_mut(&mut self, value: ValueId<T>) -> ValueMut<'_, T> {
+        let stale = (!self.temporary.is_empty()).then_some(&self.stale);
+        let mut node = match value.tape_index() {
+            Some(index) => {
+                self.check_generation(value);
+                self.temporary.get_mut(index)
+            }
+            None => {
+                self.check_slot(value);
+                self.permanent.get_mut(value.index as usize)
+            }
+        };
+        node.stale = stale;
+        node
+    }
+
+    #[inline(always)]
+    fn mark_stale(&self, stale: bool) {
+        self.stale.set(self.stale.get() | stale);
+    }
+
+    // Replaces the whole node, e.g. for rewrites by optimization passes
+    pub(crate) fn set_node(&mut self, value: ValueId<T>, node: Value<T>) {
+        self.get(value);
+        match value.tape_index() {
+            Some(index) => self.temporary.set(index, node),
+            None => self.permanent.set(value.index as usize, node),
+        }
+    }
+
+    // Points `value` at other children, given as raw ids
+    pub(crate) fn set_children(&mut self, value: ValueId<T>, children: &[u32]) {
+        self.get(value);
+        match value.tape_index() {
+            Some(index) => self.temporary.set_children(index, children),
+            None => self.permanent.set_children(value.index as usize, children),
+        }
+    }
+
+    pub fn gather_data(&self, values: &[ValueId<T>]) -> Vec<T> {
+        values.iter().map(|v| self.get(*v).data).collect()
+    }
+
+    pub fn gather_grads(&self, values: &[ValueId<T>]) -> Vec<T> {
+        values.iter().map(|v| self.get(*v).grad).collect()
+    }
+
+    // Changes the data of an input or parameter. Ops already on the tape aren't updated,
+    // so until `replay_forward()` or `clear_temps()` backward panics in debug builds.
+    pub fn set_data(&mut self, value: ValueId<T>, data: T) {
+        self.get_mut(value).set_data(data);
+    }
+
+    pub fn scatter_data(&mut self, values: &[ValueId<T>], data: &[T]) {
+        assert_eq!(values.len(), data.len(), "scatter_data length mismatch");
+        for (value, data) in values.iter().zip(data) {
+            self.set_data(*value, *data);
+        }
+    }
+
+    pub fn scatter_grads(&mut self, values: &[ValueId<T>], grads: &[T]) {
+        assert_eq!(values.len(), grads.len(), "scatter_grads length mismatch");
+        for (value, grad) in values.iter().zip(grads) {
+            self.get_mut(*value).set_grad(*grad);
         }
     }
 
     pub fn zero_grads(&mut self) {
-        for value in self.permanent.iter_mut() {
-            value.grad = T::zero();
+        self.permanent.grad.fill(T::zero());
+        self.temporary.grad.fill(T::zero());
+    }
+
+    // Live permanent values in slot order, which is creation order unless `free` made
+    // slots available for reuse. Includes `alloc_op` nodes.
+    pub fn permanents(&mut self) -> impl Iterator<Item = (ValueId<T>, ValueRef<'_, T>)> + '_ {
+        self.publish();
+        let free_slots = self.free_slots.iter().copied().collect::<HashSet<_>>();
+        let (generations, slot) = (&self.permanent_generations, self.handle.slot());
+        self.permanent
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| !free_slots.contains(i))
+            .map(move |(i, value)| (ValueId::new(i as u32, generations[i], slot), value))
+    }
+
+    // Live permanent leaves in slot order, i.e. the parameters of every model built on this
+    // allocator, frozen ones included. The allocator can't tell parameters apart from other
+    // permanent leaves: inputs and targets allocated with `alloc` instead of `alloc_t`, and
+    // values kept by `persist`, are included too. Allocate data as temporaries (or on an
+    // allocator of its own) when this and the bulk operations below should only see the
+    // model's parameters.
+    pub fn params_mut(&mut self) -> impl Iterator<Item = ValueMut<'_, T>> + '_ {
+        let mut leaves = vec![false; self.permanent.len()];
+        for slot in self.leaf_slots() {
+            leaves[slot] = true;
         }
+        let stale = (!self.temporary.is_empty()).then_some(&self.stale);
+        self.permanent
+            .iter_mut()
+            .zip(leaves)
+            .filter(|(_, leaf)| *leaf)
+            .map(move |(mut value, _)| {
+                value.stale = stale;
+                value
+            })
+    }
+
+    // Slots of the values `params_mut` yields
+    fn leaf_slots(&self) -> Vec<usize> {
+        let free_slots = self.free_slots.iter().copied().collect::<HashSet<_>>();
+        (0..self.permanent.len())
+            .filter(|i| !free_slots.contains(i) && self.permanent.op(*i).is_none())
+            .collect()
+    }
 
-        for value in self.temporary.iter_mut() {
-            value.grad = T::zero();
+    // Scales the gradient of every permanent leaf, data included, see `params_mut`
+    pub fn scale_grads(&mut self, factor: T) {
+        for slot in self.leaf_slots() {
+            self.permanent.grad[slot] = self.permanent.grad[slot] * factor;
         }
     }
 
+    // The L2 norm of the gradients of every permanent leaf taken together, data included,
+    // see `params_mut`
+    pub fn total_grad_norm(&self) -> T {
+        self.leaf_slots()
+            .into_iter()
+            .fold(T::zero(), |acc, slot| {
+                let grad = self.permanent.grad[slot];
+                acc + grad * grad
+            })
+            .sqrt()
+    }
+
+    // L2 norm of the gradients of `params`
+    pub fn grad_norm(&self, params: &[ValueId<T>]) -> T {
+        params
+            .iter()
+            .fold(T::zero(), |acc, p| {
+                let grad = self.get(*p).grad;
+                acc + grad * grad
+            })
+            .sqrt()
+    }
+
+    // Gradient statistics of `params` after `backward()`, for spotting vanishing or
+    // exploding gradients
+    pub fn grad_stats(&self, params: &[ValueId<T>]) -> GradStats<T> {
+        let grads = self.gather_grads(params);
+        if grads.is_empty() {
+            return GradStats {
+                count: 0,
+                norm: T::zero(),
+                mean: T::zero(),
+                std: T::zero(),
+                max: T::zero(),
+            };
+        }
+        let n = T::from_usize(grads.len()).unwrap();
+        let mean = grads.iter().fold(T::zero(), |acc, g| acc + *g) / n;
+        let var = grads
+            .iter()
+            .fold(T::zero(), |acc, g| acc + (*g - mean) * (*g - mean))
+            / n;
+        let max = grads.iter().fold(T::zero(), |acc, g| {
+            let magnitude = if *g < T::zero() { -*g } else { *g };
+            if magnitude > acc {
+                magnitude
+            } else {
+                acc
+            }
+        });
+        GradStats {
+            count: grads.len(),
+            norm: self.grad_norm(params),
+            mean,
+            std: var.sqrt(),
+            max,
+        }
+    }
+
+    // Overwrites the data of every permanent leaf, data included, in `params_mut` order.
+    // Panics without changing anything if `data` has the wrong length.
+    pub fn copy_data_from(&mut self, data: &[T]) {
+        let slots = self.leaf_slots();
+        assert!(
+            data.len() == slots.len(),
+            "copy_data_from got {} values for {} parameters",
+            data.len(),
+            slots.len()
+        );
+        for (slot, x) in slots.into_iter().zip(data) {
+            self.permanent.data[slot] = *x;
+        }
+        self.mark_stale(!self.temporary.is_empty());
+    }
+
+    // Captures the permanent data in memory, e.g. to roll back a trial step or to keep the
+    // best weights for early stopping
+    pub fn snapshot(&self) -> AllocatorSnapshot<T> {
+        AllocatorSnapshot {
+            data: self.permanent.data.clone(),
+            grads: None,
+            generations: self.permanent_generations.clone(),
+        }
+    }
+
+    pub fn snapshot_with_grads(&self) -> AllocatorSnapshot<T> {
+        AllocatorSnapshot {
+            grads: Some(self.permanent.grad.clone()),
+            ..self.snapshot()
+        }
+    }
+
+    // Puts back the data (and gradients, if captured) of every slot in `snapshot`. Values
+    // allocated since are left alone, while slots freed since the snapshot make it panic.
+    // Like `set_data`, ops already on the tape aren't updated.
+    pub fn restore(&mut self, snapshot: &AllocatorSnapshot<T>) {
+        let len = snapshot.data.len();
+        assert!(
+            len <= self.permanent.len()
+                && snapshot.generations == self.permanent_generations[..len],
+            "permanent values were freed since the snapshot"
+        );
+        self.permanent.data[..len].copy_from_slice(&snapshot.data);
+        if let Some(grads) = &snapshot.grads {
+            self.permanent.grad[..len].copy_from_slice(grads);
+        }
+        self.mark_stale(!self.temporary.is_empty());
+    }
+
+    // Swaps in the values, tapes and settings of `other`, e.g. an allocator loaded from
+    // disk, and returns the old ones. Ids created on this allocator keep finding it, so they
+    // refer to the new contents, while the ids of `other` are invalidated along with it.
+    pub fn replace(&mut self, mut other: Allocator<T>) -> Allocator<T> {
+        std::mem::swap(self, &mut other);
+        std::mem::swap(&mut self.handle, &mut other.handle);
+        other.handle = registry::Handle::new().expect("too many live allocators");
+        self.publish();
+        other
+    }
+
+    pub fn tape_len(&self) -> usize {
+        self.temporary.len()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let ops = self.permanent.op_count() + self.temporary.op_count();
+        let edges = self.permanent.edge_count() + self.temporary.edge_count();
+        Stats {
+            permanent: self.permanent.len() - self.free_slots.len(),
+            temporary: self.temporary.len(),
+            ops,
+            bytes: (self.permanent.len() + self.temporary.len()) * Arena::<T>::node_size()
+                + edges * std::mem::size_of::<u32>(),
+            peak_tape_len: self.peak_tape_len.max(self.temporary.len()),
+        }
+    }
+
+    pub fn mark(&self) -> TempMark {
+        TempMark {
+            len: self.temporary.len(),
+            generation: self.generation,
+        }
+    }
+
+    // Discards everything recorded since `mark`, leaving the earlier part of the tape (and
+    // a pending backward over it) intact. Ids created after the mark must not be used
+    // afterwards; unlike `clear_temps()` this isn't caught by the generation check.
+    pub fn truncate_to(&mut self, mark: TempMark) {
+        assert!(
+            mark.generation == self.generation,
+            "TempMark was invalidated by clear_temps() or belongs to another tape"
+        );
+        assert!(
+            mark.len <= self.temporary.len(),
+            "the tape was already truncated below this TempMark"
+        );
+        self.truncate_tape(mark.len);
+    }
+
     pub fn clear_temps(&mut self) {
         self.temporary.clear();
+        self.labels.retain(|id, _| tape_index(*id).is_none());
+        self.stale.set(false);
+        self.pruned = None;
+        self.peak_tape_len = 0;
+        self.segments.clear();
+        self.generation = self.next_generation();
+    }
+
+    fn next_generation(&mut self) -> u32 {
+        self.last_generation = self.last_generation.wrapping_add(1);
+        self.last_generation
+    }
+
+    // Adds an empty tape to record on after `switch_tape`. With two or more tapes, the
+    // forward pass of the next batch can be recorded while the previous batch's tape still
+    // waits for its backward pass and optimizer step.
+    pub fn new_tape(&mut self) -> TapeId {
+        let generation = self.next_generation();
+        self.tapes.push(Tape::new(generation));
+        TapeId(self.tapes.len() - 1)
+    }
+
+    pub fn active_tape(&self) -> TapeId {
+        TapeId(self.active_tape)
+    }
+
+    // Makes `tape` the one ops are recorded on and that `backward()`, `clear_temps()` and
+    // the other tape methods act on. Other tapes keep their nodes, but their ids are only
+    // valid again once their tape is switched back in (misuse panics in debug builds).
+    pub fn switch_tape(&mut self, tape: TapeId) {
+        assert!(tape.0 < self.tapes.len(), "TapeId from another allocator");
+        if tape.0 == self.active_tape {
+            return;
+        }
+        let (labels, permanent_labels) = std::mem::take(&mut self.labels)
+            .into_iter()
+            .partition(|(id, _)| tape_index(*id).is_some());
+        let parked = Tape {
+            arena: std::mem::replace(&mut self.temporary, Arena::new()),
+            generation: self.generation,
+            labels,
+            stale: self.stale.get(),
+            pruned: self.pruned.take(),
+            peak_len: self.peak_tape_len,
+            segments: std::mem::take(&mut self.segments),
+        };
+        let next = std::mem::replace(&mut self.tapes[tape.0], Tape::new(0));
+        self.tapes[self.active_tape] = parked;
+
+        self.temporary = next.arena;
+        self.generation = next.generation;
+        self.labels = permanent_labels;
+        self.labels.extend(next.labels);
+        self.stale.set(next.stale);
+        self.pruned = next.pruned;
+        self.peak_tape_len = next.peak_len;
+        self.segments = next.segments;
+        self.active_tape = tape.0;
+    }
+
+    #[inline(always)]
+    fn check_generation(&self, value: ValueId<T>) {
+        debug_assert!(
+            value.generation == self.generation,
+            "temporary ValueId from generation {} used in generation {}; it was invalidated by clear_temps() or belongs to another tape",
+            value.generation,
+            self.generation
+        );
     }
 
+    #[inline(always)]
+    fn check_slot(&self, value: ValueId<T>) {
+        debug_assert!(
+            value.generation == self.permanent_generations[value.index as usize],
+            "permanent ValueId {} was released by free()",
+            value.index
+        );
+    }
+
+    // Every backward pass starts by resetting the gradients of intermediate (op) nodes,
+    // so the same tape can be swept repeatedly, e.g. once per seed. Leaf gradients keep
+    // accumulating across passes unless `zero_grads` is called in between.
     pub fn backward(&mut self) {
         if self.temporary.is_empty() {
             return;
         }
+        if self.skip_unreachable {
+            let root = self.temp_id(self.temporary.len() - 1);
+            self.backward_from(root);
+            return;
+        }
+        self.reset_op_grads();
+        if self.permanent_ops > 0 {
+            let root = self.temp_id(self.temporary.len() - 1);
+            *self.get_mut(root).grad = T::one();
+            self.backward_topological(&[root]);
+            return;
+        }
 
-        self.temporary.last_mut().unwrap().grad = T::one();
+        *self.temporary.grad.last_mut().unwrap() = T::one();
 
         for i in (0..self.temporary.len()).rev() {
-            let data = self.temporary[i].data;
-            let grad = self.temporary[i].grad;
-            let previous = self.temporary[i].previous;
-            if let Some(backward) = self.temporary[i].backward {
-                backward(self, grad, data, &previous);
+            let node = self.temp_id(i);
+            self.run_backward(node);
+        }
+    }
+
+    // With this enabled, `backward()` first finds the ancestors of the last node and skips
+    // everything else on the tape, e.g. metrics computed alongside the loss. It pays off
+    // when a large part of the tape doesn't contribute to the loss.
+    pub fn set_skip_unreachable(&mut self, enabled: bool) {
+        self.skip_unreachable = enabled;
+    }
+
+    // In lazy mode ops only record the graph and their results read as zero, until a
+    // `Graph` compiled from them is run. Ops that compute their result outside of `record`
+    // (e.g. custom ops from `alloc_temp`) still see the uncomputed data of their children.
+    pub fn set_lazy(&mut self, enabled: bool) {
+        self.lazy = enabled;
+    }
+
+    fn reset_op_grads(&mut self) {
+        debug_assert!(
+            !self.stale.get(),
+            "data was changed by set_data() after ops were recorded; call replay_forward() or rebuild the graph"
+        );
+        let mut arenas = vec![&mut self.temporary];
+        if self.permanent_ops > 0 {
+            arenas.push(&mut self.permanent);
+        }
+        for arena in arenas {
+            for i in 0..arena.len() {
+                if arena.op(i).is_some() {
+                    arena.grad[i] = T::zero();
+                }
+            }
+        }
+    }
+
+    pub(crate) fn temp_id(&mut self, index: usize) -> ValueId<T> {
+        assert!(index < TEMPORARY as usize, "the tape is too long");
+        self.publish();
+        ValueId::new(
+            TEMPORARY | index as u32,
+            self.generation,
+            self.handle.slot(),
+        )
+    }
+
+    // Rebuilds full ids from the raw ids stored for a node's children. Temporaries belong
+    // to the current generation, since the tape only references its own nodes.
+    #[inline(always)]
+    fn node_id(&self, raw: u32) -> ValueId<T> {
+        let generation = match tape_index(raw) {
+            Some(_) => self.generation,
+            None => self.permanent_generations[raw as usize],
+        };
+        ValueId::new(raw, generation, self.handle.slot())
+    }
+
+    pub(crate) fn children(&self, value: ValueId<T>) -> Vec<ValueId<T>> {
+        let previous = self.get(value).previous;
+        previous.iter().map(|raw| self.node_id(*raw)).collect()
+    }
+
+    // Expands the children of `value` into the scratch buffer, which the caller hands back
+    // afterwards. It is moved out while a backward or forward function borrows the allocator.
+    #[inline(always)]
+    fn take_children(&mut self, value: ValueId<T>) -> Vec<ValueId<T>> {
+        let mut children = std::mem::take(&mut self.scratch);
+        children.clear();
+        let previous = self.get(value).previous;
+        children.extend(previous.iter().map(|raw| self.node_id(*raw)));
+        children
+    }
+
+    #[inline(always)]
+    fn run_backward(&mut self, value: ValueId<T>) {
+        if let Some(index) = value.tape_index() {
+            debug_assert!(
+                !self
+                    .pruned
+                    .as_ref()
+                    .is_some_and(|pruned| pruned.contains(index)),
+                "op {} wasn't rerun by Graph::run; call backward_from() on an output of the graph",
+                self.describe(value.raw())
+            );
+            if !self.segments.is_empty() && self.segments.contains_key(&index) {
+                self.backward_segment(index);
+                return;
+            }
+        }
+        let node = self.get(value);
+        if let Some(op) = node.op {
+            let (grad, data) = (node.grad, node.data);
+            let children = self.take_children(value);
+            op.backward(self, grad, data, &children);
+            self.scratch = children;
+        }
+    }
+
+    // Reverse topological sweep over the ancestors of `root` in both arenas, for graphs
+    // where permanent op nodes break the creation order of the temporary arena
+    fn backward_topological(&mut self, roots: &[ValueId<T>]) {
+        let order = self.topological_order(roots);
+        for node in order.into_iter().rev() {
+            self.run_backward(node);
+        }
+    }
+
+    // The ancestors of `roots`, children before parents
+    fn topological_order(&self, roots: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        let mut order = vec![];
+        let mut visited = HashSet::new();
+        // (node, children pushed) pairs, for an iterative post-order DFS
+        let mut stack = roots.iter().map(|root| (*root, false)).collect::<Vec<_>>();
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                order.push(node);
+                continue;
+            }
+            if !visited.insert(node) {
+                continue;
+            }
+            stack.push((node, true));
+            let value = self.get(node);
+            // Children of frozen ops can't receive gradients through them
+            if value.op.is_none() {
+                continue;
+            }
+            for child in value.previous.iter() {
+                let child = self.node_id(*child);
+                if !visited.contains(&child) {
+                    stack.push((child, false));
+                }
             }
         }
+        order
+    }
+
+    // Returns d root / d x for every x in `wrt` as new nodes on the tape instead of
+    // accumulating raw gradients, so the results can be differentiated again (e.g. for
+    // gradient penalties or Hessian-vector products). The `grad` fields are untouched.
+    // Panics if an ancestor of `root` was built by an op without a `GradFn`.
+    pub fn grad(&mut self, root: ValueId<T>, wrt: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        let order = self.topological_order(&[root]);
+        let mut grads = HashMap::new();
+        let seed = self.alloc_t(T::one());
+        grads.insert(root, seed);
+
+        for node in order.into_iter().rev() {
+            let Some(grad) = grads.get(&node).copied() else {
+                continue;
+            };
+            let value = self.get(node);
+            if value.op.is_none() {
+                continue;
+            }
+            let Some(grad_fn) = value.grad_fn else {
+                panic!(
+                    "op {} on the path to the root doesn't support higher-order gradients",
+                    self.describe(node.index)
+                );
+            };
+            let children = self.children(node);
+
+            let child_grads = grad_fn(self, grad, node, &children);
+            for (child, child_grad) in children.into_iter().zip(child_grads) {
+                if let Some(child_grad) = child_grad {
+                    let total = match grads.get(&child) {
+                        Some(existing) => *existing + child_grad,
+                        None => child_grad,
+                    };
+                    grads.insert(child, total);
+                }
+            }
+        }
+
+        wrt.iter()
+            .map(|x| match grads.get(x) {
+                Some(grad) => *grad,
+                None => self.alloc_t(T::zero()),
+            })
+            .collect()
+    }
+
+    pub fn backward_from(&mut self, root: ValueId<T>) {
+        self.backward_from_with_seed(root, T::one());
+    }
+
+    // Backpropagates `seed` from `root`, running the backward functions of its ancestors
+    // only. Temporaries recorded after `root` can't be ancestors, so the sweep starts there.
+    pub fn backward_from_with_seed(&mut self, root: ValueId<T>, seed: T) {
+        self.backward_with_seeds(&[(root, seed)]);
+    }
+
+    // Seeds several nodes with caller-supplied gradients (cotangents) and backpropagates
+    // all of them in one sweep, accumulating the vector-Jacobian product into the leaves,
+    // e.g. for weighted multi-loss training. A node listed twice gets the sum of its seeds.
+    pub fn backward_with_seeds(&mut self, seeds: &[(ValueId<T>, T)]) {
+        self.reset_op_grads();
+        for (node, _) in seeds {
+            self.get_mut(*node).zero_grad();
+        }
+        for (node, seed) in seeds {
+            let value = self.get_mut(*node);
+            *value.grad = *value.grad + *seed;
+        }
+
+        if self.permanent_ops > 0 {
+            let roots = seeds.iter().map(|(node, _)| *node).collect::<Vec<_>>();
+            self.backward_topological(&roots);
+            return;
+        }
+        // Without permanent ops, permanent values have no recorded backward
+        let Some(last) = seeds.iter().filter_map(|(node, _)| node.tape_index()).max() else {
+            return;
+        };
+        let mut reachable = vec![false; last + 1];
+        for (node, _) in seeds {
+            if let Some(index) = node.tape_index() {
+                reachable[index] = true;
+            }
+        }
+
+        for i in (0..=last).rev() {
+            if !reachable[i] || self.temporary.op(i).is_none() {
+                continue;
+            }
+            for child in self.temporary.children(i).iter() {
+                if let Some(index) = tape_index(*child) {
+                    reachable[index] = true;
+                }
+            }
+
+            let node = self.temp_id(i);
+            self.run_backward(node);
+        }
+    }
+
+    // Multi-task training: backpropagates sum(weight * loss) over several loss heads in
+    // one sweep, without recording the weighted sum on the tape. Returns the unweighted
+    // value of each loss, in order, for logging.
+    pub fn backward_weighted(&mut self, losses: &[(ValueId<T>, T)]) -> Vec<T> {
+        self.backward_with_seeds(losses);
+        losses
+            .iter()
+            .map(|(loss, _)| self.get(*loss).data)
+            .collect()
+    }
+
+    // The Jacobian d outputs / d inputs, one row per output, computed with one backward
+    // pass per output over the retained tape. Every gradient in the allocator is restored
+    // afterwards. Frozen inputs get zero columns, since they don't accumulate gradients.
+    pub fn jacobian(&mut self, outputs: &[ValueId<T>], inputs: &[ValueId<T>]) -> Vec<Vec<T>> {
+        let saved = (self.permanent.grad.clone(), self.temporary.grad.clone());
+
+        let jacobian = outputs
+            .iter()
+            .map(|output| {
+                for input in inputs {
+                    self.get_mut(*input).zero_grad();
+                }
+                self.backward_from(*output);
+                self.gather_grads(inputs)
+            })
+            .collect();
+
+        (self.permanent.grad, self.temporary.grad) = saved;
+        jacobian
+    }
+
+    // Gradient checkpointing: runs `forward` on `inputs` but keeps only its outputs on the
+    // tape, discarding the interior activations. The segment's forward is recomputed from
+    // the inputs when backward reaches it, trading compute for memory on long unrolled
+    // graphs. `forward` must be deterministic (e.g. no noise layers in training mode) and
+    // ids created inside it are invalid once it returns.
+    pub fn checkpoint(
+        &mut self,
+        inputs: &[ValueId<T>],
+        forward: impl Fn(&[ValueId<T>]) -> Vec<ValueId<T>> + 'static,
+    ) -> Vec<ValueId<T>> {
+        let start = self.temporary.len();
+        let outputs = forward(inputs);
+        let data = self.gather_data(&outputs);
+        self.truncate_tape(start);
+        let backward = SegmentBackward::Recompute(Box::new(forward));
+        self.push_segment(Op::Checkpoint, inputs, data, backward)
+    }
+
+    // Records an op with several outputs whose results were computed elsewhere, e.g. a
+    // whole layer on a GPU. When backward reaches it, `backward` gets the gradients of the
+    // outputs, in order, and accumulates the gradients of `inputs` itself.
+    pub fn record_fused(
+        &mut self,
+        inputs: &[ValueId<T>],
+        outputs: impl IntoIterator<Item = T>,
+        backward: impl Fn(&mut Allocator<T>, &[T]) + 'static,
+    ) -> Vec<ValueId<T>> {
+        let backward = SegmentBackward::Fused(Box::new(backward));
+        self.push_segment(Op::Fused, inputs, outputs, backward)
+    }
+
+    fn push_segment(
+        &mut self,
+        op: Op<T>,
+        inputs: &[ValueId<T>],
+        data: impl IntoIterator<Item = T>,
+        backward: SegmentBackward<T>,
+    ) -> Vec<ValueId<T>> {
+        // A boundary node depending on every input, and one node per output depending on
+        // the boundary, so any sweep reaches the boundary after all of the outputs
+        let start = self.temporary.len();
+        let boundary = self.push_temp(Value::with_op(T::zero(), op, inputs));
+        let outputs = data
+            .into_iter()
+            .map(|data| self.push_temp(Value::with_op(data, op, [boundary])))
+            .collect::<Vec<_>>();
+        self.segments.insert(
+            start,
+            Segment {
+                inputs: inputs.to_vec(),
+                outputs: outputs.clone(),
+                backward,
+            },
+        );
+        outputs
+    }
+
+    // Hands the gradients of a fused op's outputs to its backward function, or recomputes
+    // a checkpointed segment on top of the tape, backpropagates the gradients of its
+    // outputs through the recomputed nodes and then drops them again
+    fn backward_segment(&mut self, index: usize) {
+        let segment = self.segments.remove(&index).unwrap();
+        let grads = self.gather_grads(&segment.outputs);
+
+        match &segment.backward {
+            SegmentBackward::Fused(backward) => backward(self, &grads),
+            SegmentBackward::Recompute(forward) => {
+                let start = self.temporary.len();
+                let outputs = forward(&segment.inputs);
+                assert_eq!(
+                    outputs.len(),
+                    segment.outputs.len(),
+                    "checkpointed segment changed its number of outputs"
+                );
+                for (output, grad) in outputs.iter().zip(grads) {
+                    self.get_mut(*output).add_grad(grad);
+                }
+                for i in (start..self.temporary.len()).rev() {
+                    let node = self.temp_id(i);
+                    self.run_backward(node);
+                }
+                self.truncate_tape(start);
+            }
+        }
+        self.segments.insert(index, segment);
+    }
+
+    fn truncate_tape(&mut self, len: usize) {
+        self.peak_tape_len = self.peak_tape_len.max(self.temporary.len());
+        self.temporary.truncate(len);
+        self.labels
+            .retain(|id, _| tape_index(*id).is_none_or(|index| index < len));
+        self.segments.retain(|index, _| *index < len);
+    }
+
+    // Serializes every live node as JSON, permanent values first and then the tape in creation
+    // order, for inspecting a graph offline or diffing it between runs. Op results also
+    // carry the name of their op. Non-finite numbers are written as strings, since JSON has
+    // no NaN or infinity.
+    pub fn dump_json(&self) -> String {
+        let free_slots = self.free_slots.iter().collect::<HashSet<_>>();
+        let permanent = self
+            .permanent
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !free_slots.contains(i))
+            .map(|(i, v)| (i as u32, v));
+        let temporary = self
+            .temporary
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (TEMPORARY | i as u32, v));
+        let nodes = permanent
+            .chain(temporary)
+            .map(|(id, value)| {
+                let kind = match (tape_index(id).is_none(), value.op.is_some()) {
+                    (_, true) => "op",
+                    (true, false) => "parameter",
+                    (false, false) => "constant",
+                };
+                let children = value
+                    .previous
+                    .iter()
+                    .map(|child| display_id(*child).to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let label = match self.labels.get(&id) {
+                    Some(label) => format!(",\"label\":{}", json_string(label)),
+                    None => String::new(),
+                };
+                let op = match value.op {
+                    Some(op) => format!(",\"op\":\"{}\"", op.name()),
+                    None => String::new(),
+                };
+                format!(
+                    "{{\"id\":{}{label},\"kind\":\"{kind}\"{op},\"data\":{},\"grad\":{},\"requires_grad\":{},\"children\":[{children}]}}",
+                    display_id(id),
+                    json_number(value.data),
+                    json_number(value.grad),
+                    value.requires_grad
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"generation\":{},\"nodes\":[{}]}}",
+            self.generation,
+            nodes.join(",")
+        )
     }
 
     pub fn alloc_one_hot(&mut self, index: usize, size: usize, temp: bool) -> Vec<ValueId<T>> {
@@ -144,8 +1404,802 @@ impl<T: Num> Allocator<T> {
     }
 }
 
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_number<T: Num>(x: T) -> String {
+    let s = x.to_string();
+    if s.chars().all(|c| c.is_ascii_digit() || "-.e".contains(c)) {
+        s
+    } else {
+        format!("\"{s}\"")
+    }
+}
+
+// Gradients through a checkpoint or fused op are propagated by `Allocator::backward_segment`
+pub(crate) fn checkpoint_backward<T: Num>(
+    _allocator: &mut Allocator<T>,
+    _base_grad: T,
+    _base_val: T,
+    _children: &[ValueId<T>],
+) {
+}
+
 impl<T: Num> Default for Allocator<T> {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Allocator, Op, ValueId};
+
+    #[test]
+    fn test_generations() {
+        let mut allocator = Allocator::<f64>::new();
+        let param = allocator.alloc(1.0);
+        let temp = allocator.alloc_t(2.0);
+        allocator.clear_temps();
+
+        let fresh = allocator.alloc_t(3.0);
+        assert!(fresh != temp);
+        assert_eq!(allocator.get(fresh).data, 3.0);
+        // Permanent values stay valid across generations
+        assert_eq!(allocator.get(param).data, 1.0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "invalidated by clear_temps()")]
+    fn test_stale_temporary() {
+        let mut allocator = Allocator::<f64>::new();
+        let temp = allocator.alloc_t(2.0);
+        allocator.clear_temps();
+        allocator.alloc_t(3.0);
+        allocator.get(temp);
+    }
+
+    #[test]
+    fn test_backward_from() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(2.0);
+        let b = allocator.alloc(3.0);
+        let first = a * b;
+        let second = a + b;
+        let unrelated = b * b;
+
+        // Only `first` is differentiated, even though `second` and `unrelated` come later
+        allocator.backward_from(first);
+        assert_eq!(allocator.get(a).grad, 3.0);
+        assert_eq!(allocator.get(b).grad, 2.0);
+        assert_eq!(allocator.get(second).grad, 0.0);
+        assert_eq!(allocator.get(unrelated).grad, 0.0);
+
+        allocator.zero_grads();
+        allocator.backward_from_with_seed(second, 0.5);
+        assert_eq!(allocator.get(a).grad, 0.5);
+        assert_eq!(allocator.get(b).grad, 0.5);
+    }
+
+    fn product_backward(
+        allocator: &mut Allocator<f64>,
+        base_grad: f64,
+        _base_val: f64,
+        children: &[ValueId<f64>],
+    ) {
+        let a = allocator.get(children[0]).data;
+        let b = allocator.get(children[1]).data;
+        allocator.get_mut(children[0]).add_grad(base_grad * b);
+        allocator.get_mut(children[1]).add_grad(base_grad * a);
+    }
+
+    #[test]
+    fn test_permanent_ops() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(2.0);
+        let b = allocator.alloc(3.0);
+        let product = allocator.alloc_op(6.0, product_backward, [a, b]);
+
+        // d = product * a = a^2 b, which survives clearing the tape
+        for _ in 0..2 {
+            allocator.clear_temps();
+            allocator.zero_grads();
+            let _ = product * a;
+            allocator.backward();
+            assert_eq!(allocator.get(a).grad, 12.0);
+            assert_eq!(allocator.get(b).grad, 4.0);
+        }
+
+        allocator.zero_grads();
+        allocator.backward_from(product);
+        assert_eq!(allocator.get(a).grad, 3.0);
+        assert_eq!(allocator.get(b).grad, 2.0);
+    }
+
+    fn product_forward(allocator: &Allocator<f64>, children: &[ValueId<f64>]) -> f64 {
+        allocator.get(children[0]).data * allocator.get(children[1]).data
+    }
+
+    #[test]
+    fn test_record_custom_op() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(2.0);
+        let b = allocator.alloc_t(3.0);
+        let product = allocator.record(product_forward, product_backward, None, [a, b]);
+        assert_eq!(
+            allocator.get(product).op,
+            Some(Op::Custom(product_backward))
+        );
+        assert_eq!(allocator.get(product).data, 6.0);
+
+        allocator.get_mut(b).set_data(5.0);
+        allocator.replay_forward();
+        assert_eq!(allocator.get(product).data, 10.0);
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 5.0);
+    }
+
+    #[test]
+    fn test_repeated_backward() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(2.0);
+        let b = allocator.alloc(3.0);
+        let hidden = a * b;
+        let first = hidden * hidden;
+        let second = hidden + a;
+
+        // Leaf gradients accumulate while intermediate ones start over on every pass
+        allocator.backward_from(first);
+        allocator.backward_from(first);
+        assert_eq!(allocator.get(a).grad, 2.0 * 2.0 * 6.0 * 3.0);
+        assert_eq!(allocator.get(hidden).grad, 12.0);
+
+        // Rows of the Jacobian of (first, second) with respect to (a, b), from one tape
+        let mut jacobian = vec![];
+        for output in [first, second] {
+            allocator.zero_grads();
+            allocator.backward_from(output);
+            jacobian.push([allocator.get(a).grad, allocator.get(b).grad]);
+        }
+        assert_eq!(jacobian, [[36.0, 24.0], [4.0, 2.0]]);
+
+        allocator.zero_grads();
+        allocator.backward();
+        allocator.backward();
+        assert_eq!(allocator.get(b).grad, 4.0);
+    }
+
+    #[test]
+    fn test_higher_order_grad() {
+        let mut allocator = Allocator::<f64>::new();
+        let x = allocator.alloc(2.0);
+        let y = crate::operators::powi(x, 3);
+
+        // dy/dx = 3x^2 as a node, then d(dy/dx)/dx = 6x through the ordinary backward
+        let dx = allocator.grad(y, &[x])[0];
+        assert_eq!(allocator.get(dx).data, 12.0);
+        assert_eq!(allocator.get(x).grad, 0.0);
+        allocator.backward_from(dx);
+        assert_eq!(allocator.get(x).grad, 12.0);
+
+        // Third order through the grad graph itself
+        let dxx = allocator.grad(dx, &[x])[0];
+        let dxxx = allocator.grad(dxx, &[x])[0];
+        assert_eq!(allocator.get(dxx).data, 12.0);
+        assert_eq!(allocator.get(dxxx).data, 6.0);
+    }
+
+    #[test]
+    fn test_gradient_penalty() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(0.5);
+        let x = allocator.alloc(1.5);
+        let y = crate::operators::tanh(w * x);
+
+        // penalty = (dy/dx)^2 = (w (1 - y^2))^2, differentiated with respect to w
+        let dx = allocator.grad(y, &[x])[0];
+        let penalty = dx * dx;
+        allocator.backward_from(penalty);
+
+        let t = (0.75f64).tanh();
+        let s = 1.0 - t * t;
+        let expected = 2.0 * (0.5 * s) * (s - 0.5 * 2.0 * t * s * 1.5);
+        assert!((allocator.get(penalty).data - (0.5 * s).powi(2)).abs() < 1e-12);
+        assert!((allocator.get(w).grad - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't support higher-order gradients")]
+    fn test_grad_unsupported_op() {
+        let mut allocator = Allocator::<f64>::new();
+        let x = allocator.alloc(2.0);
+        fn custom_backward(_: &mut Allocator<f64>, _: f64, _: f64, _: &[ValueId<f64>]) {}
+        let y = allocator.alloc_temp(2.0, custom_backward, [x]);
+        allocator.grad(y, &[x]);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        fn unrolled(allocator: &mut Allocator<f64>, w: ValueId<f64>, checkpointed: bool) {
+            let mut state = vec![allocator.alloc_t(0.5)];
+            for _ in 0..4 {
+                let step = move |x: &[ValueId<f64>]| {
+                    let mut h = x[0];
+                    for _ in 0..5 {
+                        h = crate::operators::tanh(h * w + 0.1);
+                    }
+                    vec![h, h * h]
+                };
+                state = if checkpointed {
+                    allocator.checkpoint(&state[..1], step)
+                } else {
+                    step(&state[..1])
+                };
+            }
+            let _ = state[0] + state[1];
+        }
+
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(0.8);
+        unrolled(&mut allocator, w, false);
+        let full_len = allocator.tape_len();
+        allocator.backward();
+        let expected = allocator.get(w).grad;
+
+        allocator.clear_temps();
+        allocator.zero_grads();
+        unrolled(&mut allocator, w, true);
+        assert!(allocator.tape_len() < full_len / 2);
+        allocator.backward();
+        assert!((allocator.get(w).grad - expected).abs() < 1e-12);
+
+        // The recomputed nodes are dropped again, so the tape can be swept repeatedly
+        let len = allocator.tape_len();
+        allocator.zero_grads();
+        allocator.backward();
+        assert_eq!(allocator.tape_len(), len);
+        assert!((allocator.get(w).grad - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_record_fused() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(3.0);
+        let x = allocator.alloc_t(2.0);
+        // (w * x, w + x) computed outside the tape
+        let (wx, xx) = (allocator.get(w).data, allocator.get(x).data);
+        let outputs =
+            allocator.record_fused(&[w, x], [wx * xx, wx + xx], move |allocator, grads| {
+                allocator.get_mut(w).add_grad(grads[0] * xx + grads[1]);
+                allocator.get_mut(x).add_grad(grads[0] * wx + grads[1]);
+            });
+        assert_eq!(allocator.gather_data(&outputs), vec![6.0, 5.0]);
+        assert_eq!(allocator.get(outputs[0]).op, Some(Op::Fused));
+
+        let _ = outputs[0] * 2.0 + outputs[1];
+        allocator.backward();
+        assert_eq!(allocator.get(w).grad, 5.0);
+        assert_eq!(allocator.get(x).grad, 7.0);
+
+        // Only the gradients reaching the outputs are propagated
+        allocator.zero_grads();
+        allocator.backward_from(outputs[1]);
+        assert_eq!(allocator.get(w).grad, 1.0);
+    }
+
+    #[test]
+    fn test_dump_json() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(2.0);
+        let b = allocator.alloc_t(0.5);
+        let _ = a * b;
+        let _ = crate::operators::ln(allocator.alloc_t(0.0));
+        allocator.backward();
+
+        assert_eq!(
+            allocator.dump_json(),
+            concat!(
+                r#"{"generation":0,"nodes":["#,
+                r#"{"id":0,"kind":"parameter","data":2,"grad":0,"requires_grad":true,"children":[]},"#,
+                r#"{"id":-1,"kind":"constant","data":0.5,"grad":0,"requires_grad":true,"children":[]},"#,
+                r#"{"id":-2,"kind":"op","op":"mul","data":1,"grad":0,"requires_grad":true,"children":[0,-1]},"#,
+                r#"{"id":-3,"kind":"constant","data":0,"grad":"inf","requires_grad":true,"children":[]},"#,
+                r#"{"id":-4,"kind":"op","op":"ln","data":"-inf","grad":1,"requires_grad":true,"children":[-3]}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(0.5);
+        let x = allocator.alloc_t(2.0);
+        let step = move |x: &[ValueId<f64>]| vec![crate::operators::tanh(x[0] * w + 1.0)];
+        let y = allocator.checkpoint(&[x], step);
+        let _ = crate::operators::sum(&mut allocator, &[y[0], x, w, x, w]);
+
+        // x, the checkpoint boundary and output, and the sum. The segment itself held a
+        // product, the constant 1, a sum and a tanh before being discarded.
+        let stats = allocator.stats();
+        assert_eq!(stats.permanent, 1);
+        assert_eq!(stats.temporary, 4);
+        assert_eq!(stats.ops, 3);
+        assert_eq!(stats.peak_tape_len, 5);
+        // Children are four bytes each: one for each checkpoint node and five for the sum
+        let value_size = crate::engine::Arena::<f64>::node_size();
+        assert_eq!(stats.bytes, 5 * value_size + 7 * std::mem::size_of::<u32>());
+
+        allocator.backward();
+        assert_eq!(allocator.stats().peak_tape_len, 8);
+        allocator.clear_temps();
+        assert_eq!(allocator.stats().peak_tape_len, 0);
+    }
+
+    #[test]
+    fn test_persist() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(3.0);
+        let loss = w * w;
+        let kept = allocator.persist(loss);
+
+        allocator.backward();
+        assert_eq!(allocator.get(w).grad, 6.0);
+        assert_eq!(allocator.get(kept).grad, 0.0);
+
+        allocator.clear_temps();
+        assert_eq!(allocator.get(kept).data, 9.0);
+    }
+
+    #[test]
+    fn test_free() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(1.0);
+        let b = allocator.alloc(2.0);
+        allocator.free(a);
+        assert_eq!(allocator.stats().permanent, 1);
+
+        // The slot is reused, but the new id doesn't compare equal to the freed one
+        let c = allocator.alloc(3.0);
+        assert!(c != a);
+        assert_eq!(allocator.stats().permanent, 2);
+        assert_eq!(allocator.get(c).data, 3.0);
+        assert_eq!(allocator.get(b).data, 2.0);
+        assert_eq!(
+            allocator.stats().bytes,
+            2 * crate::engine::Arena::<f64>::node_size()
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "was released by free()")]
+    fn test_use_after_free() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(1.0);
+        allocator.free(a);
+        allocator.get(a);
+    }
+
+    #[test]
+    #[should_panic(expected = "was already freed")]
+    fn test_double_free() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(1.0);
+        allocator.free(a);
+        allocator.alloc(2.0);
+        allocator.free(a);
+    }
+
+    #[test]
+    fn test_truncate_to() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(3.0);
+        let loss = w * w;
+
+        // A validation pass in between doesn't disturb the pending backward
+        let mark = allocator.mark();
+        let validation = w * 2.0 + 1.0;
+        assert_eq!(allocator.get(validation).data, 7.0);
+        allocator.truncate_to(mark);
+        assert_eq!(allocator.tape_len(), 1);
+
+        allocator.backward();
+        assert_eq!(allocator.get(loss).data, 9.0);
+        assert_eq!(allocator.get(w).grad, 6.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalidated by clear_temps()")]
+    fn test_stale_mark() {
+        let mut allocator = Allocator::<f64>::new();
+        let mark = allocator.mark();
+        allocator.clear_temps();
+        allocator.truncate_to(mark);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        use crate::optim::{Optimizer, SGD};
+
+        let mut allocator = Allocator::new();
+        let w = allocator.alloc(1.0f64);
+        let frozen = allocator.alloc(3.0);
+        let mut optimizer = SGD::with_momentum(vec![w], 0.1, 0.9);
+        let train = |allocator: &mut Allocator<f64>, optimizer: &mut SGD<f64>| {
+            let loss = w * w * frozen;
+            allocator.backward();
+            optimizer.step(allocator);
+            let data = allocator.get(loss).data;
+            allocator.clear_temps();
+            data
+        };
+        train(&mut allocator, &mut optimizer);
+
+        let snapshot = allocator.snapshot();
+        let saved_optimizer = optimizer.clone();
+        let expected = train(&mut allocator, &mut optimizer);
+        // A diverging trial step, rolled back along with the momentum
+        optimizer.set_lr(10.0);
+        train(&mut allocator, &mut optimizer);
+        allocator.restore(&snapshot);
+        optimizer = saved_optimizer;
+        assert_eq!(train(&mut allocator, &mut optimizer), expected);
+
+        // Values allocated after the snapshot are kept, gradients only restored on request
+        let later = allocator.alloc(5.0);
+        allocator.get_mut(w).set_grad(2.0);
+        let with_grads = allocator.snapshot_with_grads();
+        allocator.get_mut(w).set_grad(0.0);
+        allocator.restore(&snapshot);
+        assert_eq!(allocator.get(later).data, 5.0);
+        assert_eq!(allocator.get(w).grad, 0.0);
+        allocator.restore(&with_grads);
+        assert_eq!(allocator.get(w).grad, 2.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "permanent values were freed since the snapshot")]
+    fn test_restore_after_free() {
+        let mut allocator = Allocator::new();
+        let w = allocator.alloc(1.0f64);
+        let snapshot = allocator.snapshot();
+        allocator.free(w);
+        allocator.restore(&snapshot);
+    }
+
+    #[test]
+    fn test_double_buffered_tapes() {
+        let mut allocator = Allocator::new();
+        let w = allocator.alloc(0.5f64);
+        let first = allocator.active_tape();
+        let second = allocator.new_tape();
+        let record = |allocator: &mut Allocator<f64>, x: f64| {
+            let x = allocator.alloc_t(x);
+            let diff = w * x - 1.0;
+            diff * diff
+        };
+
+        // The second batch is recorded before the first one's backward pass and step
+        let first_loss = record(&mut allocator, 1.0);
+        allocator.set_label(first_loss, "first");
+        allocator.switch_tape(second);
+        let second_loss = record(&mut allocator, 3.0);
+        assert_eq!(allocator.label(first_loss), None);
+        allocator.switch_tape(first);
+        assert_eq!(allocator.label(first_loss), Some("first"));
+        allocator.backward();
+        assert_eq!(allocator.get(w).grad, -1.0);
+        w.step(0.1);
+        allocator.clear_temps();
+
+        // It still holds the activations computed with the old weight
+        allocator.switch_tape(second);
+        assert_eq!(allocator.active_tape(), second);
+        assert_eq!(allocator.tape_len(), 5);
+        assert_eq!(allocator.get(second_loss).data, 0.25);
+        allocator.backward();
+        assert_eq!(allocator.get(w).grad, 3.0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "belongs to another tape")]
+    fn test_id_from_another_tape() {
+        let mut allocator = Allocator::<f64>::new();
+        let first = allocator.active_tape();
+        let second = allocator.new_tape();
+        let x = allocator.alloc_t(1.0);
+        allocator.switch_tape(second);
+        allocator.alloc_t(2.0);
+        allocator.get(x);
+        allocator.switch_tape(first);
+    }
+
+    #[test]
+    fn test_bulk_parameter_ops() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(1.0);
+        let freed = allocator.alloc(0.0);
+        let b = allocator.alloc(2.0);
+        allocator.alloc_op(2.0, product_backward, [a, b]);
+        allocator.free(freed);
+
+        let _ = a * 3.0 + b * 4.0;
+        allocator.backward();
+        assert_eq!(allocator.total_grad_norm(), 5.0);
+        allocator.scale_grads(0.5);
+        assert_eq!(allocator.gather_grads(&[a, b]), vec![1.5, 2.0]);
+
+        let ids = allocator.permanents().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids.len(), 3);
+        assert!(ids[0] == a && ids[1] == b);
+        assert_eq!(allocator.params_mut().count(), 2);
+
+        allocator.copy_data_from(&[5.0, 6.0]);
+        assert_eq!(allocator.gather_data(&[a, b]), vec![5.0, 6.0]);
+
+        // A wrong length is caught before anything is written
+        let copy = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            allocator.copy_data_from(&[7.0, 8.0, 9.0])
+        }));
+        assert!(copy.is_err());
+        assert_eq!(allocator.gather_data(&[a, b]), vec![5.0, 6.0]);
+
+        // Data allocated with `alloc` counts as a parameter
+        let input = allocator.alloc(3.0);
+        assert_eq!(allocator.params_mut().count(), 3);
+        allocator.free(input);
+        assert_eq!(allocator.params_mut().count(), 2);
+    }
+    fn unreachable_backward(
+        _allocator: &mut Allocator<f64>,
+        _base_grad: f64,
+        _base_val: f64,
+        _children: &[ValueId<f64>],
+    ) {
+        panic!("backward ran for a node that doesn't reach the loss");
+    }
+
+    #[test]
+    fn test_skip_unreachable() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(3.0);
+        allocator.alloc_temp(0.0, unreachable_backward, [w]);
+        let _ = w * w;
+
+        allocator.set_skip_unreachable(true);
+        allocator.backward();
+        assert_eq!(allocator.get(w).grad, 6.0);
+    }
+
+    #[test]
+    fn test_backward_with_seeds() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(2.0);
+        let b = allocator.alloc(3.0);
+        let product = a * b;
+        let total = a + b;
+        let _metric = total * 10.0;
+
+        // v^T J for the Jacobian of (a * b, a + b) with respect to (a, b)
+        allocator.backward_with_seeds(&[(product, 0.5), (total, 2.0)]);
+        assert_eq!(allocator.get(a).grad, 0.5 * 3.0 + 2.0);
+        assert_eq!(allocator.get(b).grad, 0.5 * 2.0 + 2.0);
+
+        allocator.zero_grads();
+        allocator.backward_with_seeds(&[(product, 1.0), (product, 1.0)]);
+        assert_eq!(allocator.get(a).grad, 6.0);
+    }
+
+    #[test]
+    fn test_jacobian() {
+        let mut allocator = Allocator::<f64>::new();
+        let x = allocator.alloc(2.0);
+        let y = allocator.alloc(3.0);
+        allocator.get_mut(x).set_grad(7.0);
+
+        // (x * y, x + y, x^2)
+        let outputs = [x * y, x + y, crate::operators::powi(x, 2)];
+        let jacobian = allocator.jacobian(&outputs, &[x, y]);
+        assert_eq!(
+            jacobian,
+            vec![vec![3.0, 2.0], vec![1.0, 1.0], vec![4.0, 0.0]]
+        );
+        assert_eq!(allocator.get(x).grad, 7.0);
+        assert_eq!(allocator.get(y).grad, 0.0);
+    }
+
+    #[test]
+    fn test_set_data() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(3.0);
+        let x = allocator.alloc_t(2.0);
+        let y = w * x;
+
+        allocator.set_data(x, 5.0);
+        allocator.replay_forward();
+        assert_eq!(allocator.get(y).data, 15.0);
+        allocator.backward();
+        assert_eq!(allocator.get(w).grad, 5.0);
+
+        // Without anything recorded there is nothing to go stale
+        allocator.clear_temps();
+        allocator.set_data(w, 1.0);
+        let _ = w * w;
+        allocator.backward();
+        assert_eq!(allocator.get(w).grad, 7.0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "changed by set_data() after ops were recorded")]
+    fn test_stale_tape() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(3.0);
+        let _ = w * w;
+        allocator.set_data(w, 1.0);
+        allocator.backward();
+    }
+
+    #[test]
+    fn test_stale_tape_through_views() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(3.0);
+        allocator.get_mut(w).set_data(2.0);
+        assert!(!allocator.stale.get());
+
+        let _ = w * w;
+        allocator.get_mut(w).set_data(1.0);
+        assert!(allocator.stale.get());
+        allocator.replay_forward();
+        allocator.params_mut().next().unwrap().set_data(2.0);
+        assert!(allocator.stale.get());
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc_named("w1", 0.3);
+        fn custom_backward(_: &mut Allocator<f64>, _: f64, _: f64, _: &[ValueId<f64>]) {}
+        let loss = allocator.alloc_temp(0.09, custom_backward, [w * w]);
+        allocator.set_label(loss, "loss \"l1\"");
+
+        assert_eq!(allocator.label(w), Some("w1"));
+        assert_eq!(format!("{w:?}"), r#"ValueId(0, "w1")"#);
+        assert_eq!(format!("{:?}", ValueId::<f64>::default()), "ValueId(0)");
+        assert!(allocator
+            .dump_json()
+            .contains(r#"{"id":-2,"label":"loss \"l1\"","kind":"op""#));
+
+        let message = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            allocator.grad(loss, &[w]);
+        }))
+        .unwrap_err();
+        assert_eq!(
+            message.downcast_ref::<String>().unwrap(),
+            r#"op -2 (loss "l1") on the path to the root doesn't support higher-order gradients"#
+        );
+
+        allocator.clear_temps();
+        let fresh = allocator.alloc_t(1.0);
+        assert_eq!(allocator.label(fresh), None);
+        assert_eq!(allocator.label(w), Some("w1"));
+    }
+
+    #[test]
+    fn test_grad_stats() {
+        let mut allocator = Allocator::<f64>::new();
+        let params = [1.0, -3.0, 2.0].map(|x| allocator.alloc(x));
+        for (param, grad) in params.iter().zip([3.0, -4.0, 1.0]) {
+            allocator.get_mut(*param).set_grad(grad);
+        }
+        assert_eq!(allocator.grad_norm(&params[..2]), 5.0);
+
+        let stats = allocator.grad_stats(&params);
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.mean, 0.0);
+        assert_eq!(stats.max, 4.0);
+        assert!((stats.norm - 26f64.sqrt()).abs() < 1e-12);
+        assert!((stats.std - (26.0f64 / 3.0).sqrt()).abs() < 1e-12);
+        assert_eq!(allocator.grad_stats(&[]).max, 0.0);
+    }
+
+    #[test]
+    fn test_frozen_inputs_skip_recording() {
+        let mut allocator = Allocator::<f64>::new();
+        let raw = allocator.alloc(4.0);
+        raw.set_requires_grad(false);
+        let w = allocator.alloc(3.0);
+
+        // Preprocessing that only touches the frozen input records no backward edges
+        let feature = crate::operators::ln(raw * 0.5 + 1.0);
+        assert!(!allocator.get(feature).requires_grad);
+        assert_eq!(allocator.stats().ops, 0);
+
+        let out = w * feature;
+        assert_eq!(allocator.stats().ops, 1);
+        allocator.backward();
+        assert!((allocator.get(w).grad - 3f64.ln()).abs() < 1e-12);
+        assert_eq!(allocator.get(raw).grad, 0.0);
+        assert_eq!(allocator.get(feature).grad, 0.0);
+
+        // Replay still recomputes the frozen nodes
+        allocator.set_data(raw, 6.0);
+        allocator.replay_forward();
+        assert!((allocator.get(out).data - 3.0 * 4f64.ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_backward_weighted() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(2.0);
+        let regression = w * w;
+        let classification = w * 3.0;
+
+        let values = allocator.backward_weighted(&[(regression, 0.5), (classification, 2.0)]);
+        assert_eq!(values, vec![4.0, 6.0]);
+        // 0.5 * 2w + 2 * 3
+        assert_eq!(allocator.get(w).grad, 8.0);
+        assert_eq!(allocator.stats().temporary, 3);
+    }
+
+    #[test]
+    fn test_ids_follow_allocator() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(2.0);
+        assert_eq!(std::mem::size_of_val(&w), 12);
+
+        // A moved allocator is found again once it hands out ids
+        let mut moved = Box::new(allocator);
+        let y = w * moved.alloc_t(3.0);
+        assert_eq!(moved.get(y).data, 6.0);
+
+        let mut loaded = Allocator::new();
+        loaded.alloc(5.0);
+        let old = moved.replace(loaded);
+        assert_eq!(old.get(w).data, 2.0);
+        w.step(1.0);
+        assert_eq!(moved.get(w).data, 5.0);
+        assert_eq!(w.allocator(), &mut *moved as *mut Allocator<f64>);
+        assert!(ValueId::<f64>::default().allocator().is_null());
+    }
+
+    #[test]
+    fn test_many_allocators() {
+        // The registry grows past any fixed table, and every id keeps its allocator
+        let mut allocators = (0..5000)
+            .map(|_| Allocator::<f32>::try_new().unwrap())
+            .collect::<Vec<_>>();
+        let ids = allocators
+            .iter_mut()
+            .enumerate()
+            .map(|(i, allocator)| allocator.alloc(i as f32))
+            .collect::<Vec<_>>();
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(id.allocator(), &mut allocators[i] as *mut Allocator<f32>);
+            assert_eq!(allocators[i].get(*id).data, i as f32);
+        }
+
+        // Dropping allocators releases their slots without disturbing the others
+        allocators.truncate(10);
+        let mut allocator = Allocator::<f32>::new();
+        let fresh = allocator.alloc(1.0);
+        assert_eq!(fresh.allocator(), &mut allocator as *mut Allocator<f32>);
+        for (i, allocator) in allocators.iter_mut().enumerate() {
+            assert_eq!(ids[i].allocator(), allocator as *mut Allocator<f32>);
+        }
+    }
+}
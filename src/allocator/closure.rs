@@ -0,0 +1,376 @@
+use std::ops::Range;
+
+use super::{tape_index, Allocator, ValueId, TEMPORARY};
+use crate::{op::Op, operators::Num};
+
+// The computations a compiled closure is made of. Scalar ops map onto the binary kernels,
+// with the scalar held in a register like any other operand.
+#[derive(Clone, Copy)]
+enum Kernel {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Neg,
+    Powf,
+    Exp,
+    Ln,
+    Tanh,
+    Relu,
+    Abs,
+    Recip,
+    Detach,
+    Sum,
+    Mean,
+    Dot,
+    Affine,
+    Mse,
+}
+
+impl Kernel {
+    // The kernel computing `op`, and whether it takes the two children in reverse order
+    fn lower<T: Num>(op: Op<T>) -> Option<(Kernel, bool)> {
+        let kernel = match op {
+            Op::Add | Op::AddScalar => Kernel::Add,
+            Op::Sub => Kernel::Sub,
+            Op::RsubScalar => return Some((Kernel::Sub, true)),
+            Op::Mul | Op::MulScalar => Kernel::Mul,
+            Op::Div | Op::DivScalar => Kernel::Div,
+            Op::RdivScalar => return Some((Kernel::Div, true)),
+            Op::Neg => Kernel::Neg,
+            Op::Powf => Kernel::Powf,
+            Op::Exp => Kernel::Exp,
+            Op::Ln => Kernel::Ln,
+            Op::Tanh => Kernel::Tanh,
+            Op::Relu => Kernel::Relu,
+            Op::Abs => Kernel::Abs,
+            Op::Recip => Kernel::Recip,
+            Op::Detach => Kernel::Detach,
+            Op::Sum => Kernel::Sum,
+            Op::Mean => Kernel::Mean,
+            Op::Dot => Kernel::Dot,
+            Op::Affine => Kernel::Affine,
+            Op::Mse => Kernel::Mse,
+            _ => return None,
+        };
+        Some((kernel, false))
+    }
+}
+
+struct Instr {
+    kernel: Kernel,
+    // The operand registers, as a range of `Program::args`
+    args: Range<usize>,
+}
+
+// A tape lowered to flat arrays. The registers hold the parameters, then the constants
+// baked in at compile time, then the result of every instruction in order.
+struct Program<T: Num> {
+    registers: Vec<T>,
+    params: usize,
+    instrs: Vec<Instr>,
+    args: Vec<usize>,
+    root: usize,
+}
+
+fn accumulate<T: Num>(grads: &mut [T], register: usize, grad: T) {
+    grads[register] = grads[register] + grad;
+}
+
+impl<T: Num> Program<T> {
+    // Every register after running the program on `params`
+    fn forward(&self, params: &[T]) -> Vec<T> {
+        assert_eq!(
+            params.len(),
+            self.params,
+            "compiled closure got the wrong number of parameters"
+        );
+        let mut r = self.registers.clone();
+        r[..self.params].copy_from_slice(params);
+        let base = r.len() - self.instrs.len();
+        for (i, instr) in self.instrs.iter().enumerate() {
+            let a = &self.args[instr.args.clone()];
+            let sum = |a: &[usize]| a.iter().fold(T::zero(), |acc, x| acc + r[*x]);
+            let dot = |a: &[usize]| {
+                let (x, y) = a.split_at(a.len() / 2);
+                x.iter()
+                    .zip(y)
+                    .fold(T::zero(), |acc, (x, y)| acc + r[*x] * r[*y])
+            };
+            r[base + i] = match instr.kernel {
+                Kernel::Add => r[a[0]] + r[a[1]],
+                Kernel::Sub => r[a[0]] - r[a[1]],
+                Kernel::Mul => r[a[0]] * r[a[1]],
+                Kernel::Div => r[a[0]] / r[a[1]],
+                Kernel::Neg => -r[a[0]],
+                Kernel::Powf => r[a[0]].pow(r[a[1]]),
+                Kernel::Exp => r[a[0]].exp(),
+                Kernel::Ln => r[a[0]].ln(),
+                Kernel::Tanh => r[a[0]].tanh(),
+                Kernel::Relu if r[a[0]] > T::zero() => r[a[0]],
+                Kernel::Relu => T::zero(),
+                Kernel::Abs if r[a[0]] < T::zero() => -r[a[0]],
+                Kernel::Abs | Kernel::Detach => r[a[0]],
+                Kernel::Recip => T::one() / r[a[0]],
+                Kernel::Sum => sum(a),
+                Kernel::Mean => sum(a) / T::from_usize(a.len()).unwrap(),
+                Kernel::Dot => dot(a),
+                Kernel::Affine => dot(&a[..a.len() - 1]) + r[a[a.len() - 1]],
+                Kernel::Mse => {
+                    let (o, t) = a.split_at(a.len() / 2);
+                    o.iter().zip(t).fold(T::zero(), |acc, (o, t)| {
+                        let diff = r[*o] - r[*t];
+                        acc + diff * diff
+                    }) / T::from_usize(o.len()).unwrap()
+                }
+            };
+        }
+        r
+    }
+
+    // The gradient of the root with respect to the parameters, given the registers of a
+    // forward run. Mirrors the crate's backward functions.
+    fn backward(&self, r: &[T]) -> Vec<T> {
+        let mut g = vec![T::zero(); r.len()];
+        g[self.root] = T::one();
+        let base = r.len() - self.instrs.len();
+        for (i, instr) in self.instrs.iter().enumerate().rev() {
+            let (out, grad) = (r[base + i], g[base + i]);
+            let a = &self.args[instr.args.clone()];
+            match instr.kernel {
+                Kernel::Add => {
+                    accumulate(&mut g, a[0], grad);
+                    accumulate(&mut g, a[1], grad);
+                }
+                Kernel::Sub => {
+                    accumulate(&mut g, a[0], grad);
+                    accumulate(&mut g, a[1], -grad);
+                }
+                Kernel::Mul => {
+                    accumulate(&mut g, a[0], grad * r[a[1]]);
+                    accumulate(&mut g, a[1], grad * r[a[0]]);
+                }
+                Kernel::Div => {
+                    accumulate(&mut g, a[0], grad / r[a[1]]);
+                    accumulate(&mut g, a[1], -grad * out / r[a[1]]);
+                }
+                Kernel::Neg => accumulate(&mut g, a[0], -grad),
+                // The exponent is a constant
+                Kernel::Powf => {
+                    let (x, k) = (r[a[0]], r[a[1]]);
+                    accumulate(&mut g, a[0], grad * k * x.pow(k - T::one()));
+                }
+                Kernel::Exp => accumulate(&mut g, a[0], grad * out),
+                Kernel::Ln => accumulate(&mut g, a[0], grad / r[a[0]]),
+                Kernel::Tanh => accumulate(&mut g, a[0], grad * (T::one() - out * out)),
+                Kernel::Relu if out > T::zero() => accumulate(&mut g, a[0], grad),
+                Kernel::Relu | Kernel::Detach => {}
+                Kernel::Abs if r[a[0]] > T::zero() => accumulate(&mut g, a[0], grad),
+                Kernel::Abs if r[a[0]] < T::zero() => accumulate(&mut g, a[0], -grad),
+                Kernel::Abs => {}
+                Kernel::Recip => accumulate(&mut g, a[0], -grad * out * out),
+                Kernel::Sum => a.iter().for_each(|x| accumulate(&mut g, *x, grad)),
+                Kernel::Mean => {
+                    let grad = grad / T::from_usize(a.len()).unwrap();
+                    a.iter().for_each(|x| accumulate(&mut g, *x, grad));
+                }
+                Kernel::Dot | Kernel::Affine => {
+                    let (rest, bias) = match instr.kernel {
+                        Kernel::Affine => (&a[..a.len() - 1], Some(a[a.len() - 1])),
+                        _ => (a, None),
+                    };
+                    let (x, y) = rest.split_at(rest.len() / 2);
+                    for (x, y) in x.iter().zip(y) {
+                        let (x_val, y_val) = (r[*x], r[*y]);
+                        accumulate(&mut g, *x, grad * y_val);
+                        accumulate(&mut g, *y, grad * x_val);
+                    }
+                    if let Some(bias) = bias {
+                        accumulate(&mut g, bias, grad);
+                    }
+                }
+                Kernel::Mse => {
+                    let (o, t) = a.split_at(a.len() / 2);
+                    let scale = grad * (T::one() + T::one()) / T::from_usize(o.len()).unwrap();
+                    for (o, t) in o.iter().zip(t) {
+                        let diff = r[*o] - r[*t];
+                        accumulate(&mut g, *o, scale * diff);
+                        accumulate(&mut g, *t, -scale * diff);
+                    }
+                }
+            }
+        }
+        g.truncate(self.params);
+        g
+    }
+}
+
+impl<T: Num> Allocator<T> {
+    // Compiles the ops that `root` depends on into a closure mapping the data of every
+    // parameter, in `params_mut` order, to the data of `root`, e.g. to evaluate many
+    // perturbed parameter vectors in evolution strategies. The closure runs over flat
+    // arrays, without the allocator or per-node dispatch through `Op`. Temporary leaves
+    // such as inputs are baked in with their current data. Constants are folded first,
+    // see `fold_constants`. Panics on ops without a kernel, like custom ops.
+    pub fn compile_forward(&mut self, root: ValueId<T>) -> impl Fn(&[T]) -> T {
+        let program = self.lower(root);
+        move |params| program.forward(params)[program.root]
+    }
+
+    // Like `compile_forward`, with the closure also returning the gradient of `root` with
+    // respect to every parameter, frozen ones included
+    pub fn compile_backward(&mut self, root: ValueId<T>) -> impl Fn(&[T]) -> (T, Vec<T>) {
+        let program = self.lower(root);
+        move |params| {
+            let registers = program.forward(params);
+            (registers[program.root], program.backward(&registers))
+        }
+    }
+
+    fn lower(&mut self, root: ValueId<T>) -> Program<T> {
+        assert_eq!(
+            self.permanent_ops, 0,
+            "tapes with permanent ops can't be compiled into a closure"
+        );
+        self.get(root);
+        self.fold_constants();
+
+        let mut registers = vec![];
+        let mut slot_registers = vec![usize::MAX; self.permanent.len()];
+        for (param, _) in self.permanents() {
+            slot_registers[param.raw() as usize] = registers.len();
+            registers.push(T::zero());
+        }
+        let params = registers.len();
+
+        let mut needed = vec![false; self.temporary.len()];
+        if let Some(index) = root.tape_index() {
+            needed[index] = true;
+        }
+        for i in (0..needed.len()).rev() {
+            if needed[i] && self.temporary.forward(i).is_some() {
+                for child in self.temporary.children(i).iter() {
+                    if let Some(index) = tape_index(*child) {
+                        needed[index] = true;
+                    }
+                }
+            }
+        }
+
+        // Leaves get their registers first, so that instruction `i` writes register
+        // `base + i`
+        let mut temp_registers = vec![usize::MAX; needed.len()];
+        for i in (0..needed.len()).filter(|i| needed[*i]) {
+            if self.temporary.forward(i).is_none() {
+                assert!(
+                    self.temporary.op(i).is_none(),
+                    "op {} can't be compiled into a closure",
+                    self.describe(TEMPORARY | i as u32)
+                );
+                temp_registers[i] = registers.len();
+                registers.push(self.temporary.data[i]);
+            }
+        }
+
+        let mut instrs = vec![];
+        let mut args = vec![];
+        for i in (0..needed.len()).filter(|i| needed[*i]) {
+            if self.temporary.forward(i).is_none() {
+                continue;
+            }
+            let Some((kernel, reversed)) = self.temporary.op(i).and_then(Kernel::lower) else {
+                // Frozen ops record no op, and only the constant ones were folded
+                panic!(
+                    "op {} can't be compiled into a closure",
+                    self.describe(TEMPORARY | i as u32)
+                );
+            };
+            let start = args.len();
+            args.extend(
+                self.temporary
+                    .children(i)
+                    .iter()
+                    .map(|child| match tape_index(*child) {
+                        Some(index) => temp_registers[index],
+                        None => slot_registers[*child as usize],
+                    }),
+            );
+            if reversed {
+                args[start..].reverse();
+            }
+            temp_registers[i] = registers.len();
+            registers.push(T::zero());
+            instrs.push(Instr {
+                kernel,
+                args: start..args.len(),
+            });
+        }
+
+        let root = match root.tape_index() {
+            Some(index) => temp_registers[index],
+            None => slot_registers[root.raw() as usize],
+        };
+        Program {
+            registers,
+            params,
+            instrs,
+            args,
+            root,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        losses::mse,
+        nn::{Activation, MLP},
+        operators::{abs, exp, powi, relu},
+    };
+
+    #[test]
+    fn test_compile_closure() {
+        let mut allocator = Allocator::new();
+        let mlp = MLP::new(&mut allocator, &[2, 4, 2], Some(Activation::Tanh));
+        let build = |allocator: &mut Allocator<f64>| {
+            let inputs = [allocator.alloc_t(0.5), allocator.alloc_t(-1.0)];
+            let outputs = mlp.forward(&inputs);
+            let targets = [allocator.alloc_const(0.25), allocator.alloc_const(-0.5)];
+            let penalty = exp(relu(outputs[0] * 2.0)) + powi(1.0 - outputs[1], 2) / 4.0;
+            mse(allocator, &outputs, &targets) + abs(penalty)
+        };
+        let loss = build(&mut allocator);
+        let forward = allocator.compile_forward(loss);
+        let backward = allocator.compile_backward(loss);
+
+        let params = mlp.parameters();
+        for step in 0..3 {
+            let data = allocator.gather_data(&params);
+            allocator.clear_temps();
+            let loss = build(&mut allocator);
+            allocator.backward();
+            let (value, grads) = backward(&data);
+            assert_eq!(forward(&data), value);
+            assert!((allocator.get(loss).data - value).abs() < 1e-12);
+            for (a, b) in allocator.gather_grads(&params).iter().zip(grads) {
+                assert!((a - b).abs() < 1e-12);
+            }
+            let perturbed = data
+                .iter()
+                .map(|x| x + 0.1 * step as f64)
+                .collect::<Vec<_>>();
+            allocator.copy_data_from(&perturbed);
+            allocator.zero_grads();
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be compiled into a closure")]
+    fn test_compile_closure_unsupported_op() {
+        let mut allocator = Allocator::new();
+        let x = allocator.alloc(0.5f64);
+        let y = crate::operators::erf(x);
+        let _ = allocator.compile_forward(y);
+    }
+}
@@ -0,0 +1,324 @@
+use std::rc::Rc;
+
+use super::{tape_index, Allocator, ValueId, TEMPORARY};
+use crate::{engine::Value, operators::Num};
+
+// A forward pass compiled from the tape by `Allocator::compile`, for define-then-run use:
+// build the graph once in lazy mode, then `run` it on new inputs as often as needed. The
+// graph stays on the tape, so `backward_from` an output works after each run.
+pub struct Graph<T: Num> {
+    inputs: Vec<ValueId<T>>,
+    outputs: Vec<ValueId<T>>,
+    // Tape positions of the ops the outputs depend on, in execution order
+    schedule: Vec<usize>,
+    // Whether each tape position held an op left out of `schedule` when compiling
+    pruned: Rc<[bool]>,
+    complete: bool,
+}
+
+// The ops a `Graph::run` didn't recompute: those pruned by `compile`, and any recorded
+// after it
+#[derive(Clone)]
+pub(super) struct Pruned {
+    ops: Rc<[bool]>,
+    // The tape length at the run, later nodes were computed afterwards
+    until: usize,
+}
+
+impl Pruned {
+    pub(super) fn contains(&self, index: usize) -> bool {
+        index < self.until && self.ops.get(index).copied().unwrap_or(true)
+    }
+}
+
+impl<T: Num> Graph<T> {
+    pub fn inputs(&self) -> &[ValueId<T>] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[ValueId<T>] {
+        &self.outputs
+    }
+
+    // Number of ops executed by every `run`
+    pub fn len(&self) -> usize {
+        self.schedule.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schedule.is_empty()
+    }
+
+    // Loads `inputs` into the graph's inputs, executes the scheduled ops and returns the
+    // data of the outputs. Parameters are read as they are, so optimizer steps in between
+    // runs are picked up. Ops pruned by `compile` keep their old results: `backward_from`
+    // an output works after each run, while a `backward()` that reaches a pruned op panics
+    // in debug builds, as after `set_data`.
+    pub fn run(&self, allocator: &mut Allocator<T>, inputs: &[T]) -> Vec<T> {
+        assert_eq!(
+            inputs.len(),
+            self.inputs.len(),
+            "Graph::run got the wrong number of inputs"
+        );
+        // Fails for graphs invalidated by clear_temps() or compiled on another tape
+        for output in &self.outputs {
+            allocator.check_generation(*output);
+        }
+        allocator.scatter_data(&self.inputs, inputs);
+        for &index in &self.schedule {
+            let forward = allocator.temporary.forward(index).unwrap();
+            allocator.rerun(index, forward);
+        }
+        allocator.stale.set(false);
+        let grown = allocator.tape_len() > self.pruned.len();
+        allocator.pruned = (!self.complete || grown).then(|| Pruned {
+            ops: self.pruned.clone(),
+            until: allocator.tape_len(),
+        });
+        allocator.gather_data(&self.outputs)
+    }
+}
+
+impl<T: Num> Allocator<T> {
+    // Compiles the ops on the tape that `outputs` depend on into a `Graph` fed by `inputs`,
+    // which must be leaves such as `alloc_t` placeholders. Ops that don't contribute to
+    // the outputs are pruned, and constant ops are folded, see `fold_constants`. Panics if
+    // a needed op wasn't recorded with `record`.
+    pub fn compile(&mut self, inputs: &[ValueId<T>], outputs: &[ValueId<T>]) -> Graph<T> {
+        for input in inputs {
+            if let Some(index) = input.tape_index() {
+                self.check_generation(*input);
+                assert!(
+                    self.temporary.forward(index).is_none() && self.temporary.op(index).is_none(),
+                    "compile input {} isn't a leaf",
+                    self.describe(input.raw())
+                );
+            }
+        }
+
+        self.fold(inputs);
+
+        // Ops only refer to earlier positions on the tape, so one reverse sweep finds every
+        // ancestor of the outputs
+        let mut needed = vec![false; self.temporary.len()];
+        for output in outputs {
+            self.check_generation(*output);
+            if let Some(index) = output.tape_index() {
+                needed[index] = true;
+            }
+        }
+        let mut schedule = vec![];
+        for i in (0..needed.len()).rev() {
+            if !needed[i] {
+                continue;
+            }
+            if self.temporary.forward(i).is_none() {
+                assert!(
+                    self.temporary.op(i).is_none(),
+                    "op {} on the tape can't be compiled",
+                    self.describe(TEMPORARY | i as u32)
+                );
+                continue;
+            }
+            schedule.push(i);
+            for child in self.temporary.children(i).iter() {
+                if let Some(index) = tape_index(*child) {
+                    needed[index] = true;
+                }
+            }
+        }
+        schedule.reverse();
+
+        let mut pruned = (0..needed.len())
+            .map(|i| self.temporary.forward(i).is_some() || self.temporary.op(i).is_some())
+            .collect::<Vec<_>>();
+        for &index in &schedule {
+            pruned[index] = false;
+        }
+        Graph {
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+            schedule,
+            complete: !pruned.contains(&true),
+            pruned: pruned.into(),
+        }
+    }
+
+    // Precomputes ops whose children are all constants, i.e. temporaries without a
+    // gradient like the scalars from `alloc_const`, and turns them into constants too, so
+    // `replay_forward()` and compiled graphs skip them. Embedded preprocessing arithmetic
+    // is then computed once. Changing the data of a constant afterwards won't reach the
+    // folded ops. Returns the number of folded ops.
+    pub fn fold_constants(&mut self) -> usize {
+        self.fold(&[])
+    }
+
+    // `fold_constants` with `inputs` never treated as constants
+    fn fold(&mut self, inputs: &[ValueId<T>]) -> usize {
+        let mut constant = vec![false; self.temporary.len()];
+        let mut folded = 0;
+        for i in 0..constant.len() {
+            if self.temporary.requires_grad[i] || self.temporary.op(i).is_some() {
+                continue;
+            }
+            let Some(forward) = self.temporary.forward(i) else {
+                constant[i] = !inputs.iter().any(|input| input.tape_index() == Some(i));
+                continue;
+            };
+            let children = self.temporary.children(i);
+            let foldable = !children.is_empty()
+                && children
+                    .iter()
+                    .all(|child| tape_index(*child).is_some_and(|index| constant[index]));
+            if foldable {
+                self.rerun(i, forward);
+                let mut value = Value::from(self.temporary.data[i]);
+                value.requires_grad = false;
+                self.temporary.set(i, value);
+                constant[i] = true;
+                folded += 1;
+            }
+        }
+        folded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        nn::{Activation, MLP},
+        operators::{exp, tanh},
+    };
+
+    fn backward_step(
+        allocator: &mut Allocator<f64>,
+        mlp: &MLP<f64>,
+        loss: ValueId<f64>,
+    ) -> Vec<f64> {
+        allocator.zero_grads();
+        allocator.backward_from(loss);
+        allocator.gather_grads(&mlp.parameters())
+    }
+
+    #[test]
+    fn test_compile_and_run() {
+        let mut allocator = Allocator::new();
+        let mlp = MLP::new(&mut allocator, &[2, 4, 1], Some(Activation::Tanh));
+        let samples = [[1.0, -0.5], [0.25, 2.0]];
+        let expected = samples
+            .iter()
+            .map(|sample| {
+                let inputs = sample.map(|x| allocator.alloc_t(x));
+                let loss = tanh(mlp.forward(&inputs)[0]);
+                let data = allocator.get(loss).data;
+                let grads = backward_step(&mut allocator, &mlp, loss);
+                allocator.clear_temps();
+                (data, grads)
+            })
+            .collect::<Vec<_>>();
+
+        allocator.set_lazy(true);
+        let inputs = [allocator.alloc_t(0.0), allocator.alloc_t(0.0)];
+        let loss = tanh(mlp.forward(&inputs)[0]);
+        // Recorded alongside, but not needed for the loss
+        let unused = inputs[0] * inputs[1];
+        allocator.set_lazy(false);
+        assert_eq!(allocator.get(loss).data, 0.0);
+
+        let graph = allocator.compile(&inputs, &[loss]);
+        assert_eq!(graph.len(), allocator.stats().ops - 1);
+        for (sample, (data, grads)) in samples.iter().zip(expected) {
+            assert_eq!(graph.run(&mut allocator, sample), vec![data]);
+            assert_eq!(backward_step(&mut allocator, &mlp, loss), grads);
+        }
+        assert_eq!(allocator.get(unused).data, 0.0);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let mut allocator = Allocator::new();
+        let frozen = allocator.alloc(4.0);
+        frozen.set_requires_grad(false);
+        allocator.set_lazy(true);
+        let x = allocator.alloc_t(0.0);
+        // Preprocessing arithmetic on constants only
+        let scale = allocator.alloc_const(2.0) * allocator.alloc_const(3.0);
+        let offset = exp(allocator.alloc_const(0.0)) + 0.5;
+        // A frozen parameter may still change, so this one isn't folded
+        let shift = frozen * allocator.alloc_const(0.5);
+        let y = x * scale + offset + shift;
+        allocator.set_lazy(false);
+
+        let graph = allocator.compile(&[x], &[y]);
+        assert_eq!(allocator.get(scale).data, 6.0);
+        assert_eq!(allocator.get(offset).data, 1.5);
+        assert!(allocator.get(scale).previous.is_empty());
+        // shift, x * scale and the two additions
+        assert_eq!(graph.len(), 4);
+        assert_eq!(graph.run(&mut allocator, &[2.0]), vec![15.5]);
+        allocator.set_data(frozen, 0.0);
+        assert_eq!(graph.run(&mut allocator, &[2.0]), vec![13.5]);
+        allocator.backward_from(y);
+        assert_eq!(allocator.get(x).grad, 6.0);
+
+        // Folding an eagerly built tape keeps `replay_forward()` working
+        let mut allocator = Allocator::new();
+        let x = allocator.alloc_t(1.0);
+        let scale = allocator.alloc_const(2.0) * allocator.alloc_const(3.0);
+        let y = x * scale;
+        assert_eq!(allocator.fold_constants(), 1);
+        assert_eq!(allocator.fold_constants(), 0);
+        allocator.set_data(x, 2.0);
+        allocator.replay_forward();
+        assert_eq!(allocator.get(y).data, 12.0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "wasn't rerun by Graph::run")]
+    fn test_backward_through_pruned_op() {
+        let mut allocator = Allocator::new();
+        let w = allocator.alloc(2.0);
+        allocator.set_lazy(true);
+        let x = allocator.alloc_t(0.0);
+        let y = w * x;
+        let _metric = exp(x);
+        allocator.set_lazy(false);
+
+        let graph = allocator.compile(&[x], &[y]);
+        graph.run(&mut allocator, &[3.0]);
+        allocator.backward_from(y);
+        assert_eq!(allocator.get(w).grad, 3.0);
+        // The last node, which `backward()` starts from, was pruned
+        allocator.backward();
+    }
+
+    #[test]
+    fn test_run_complete_graph() {
+        let mut allocator = Allocator::new();
+        let w = allocator.alloc(2.0);
+        allocator.set_lazy(true);
+        let x = allocator.alloc_t(0.0);
+        let y = tanh(w * x);
+        allocator.set_lazy(false);
+
+        let graph = allocator.compile(&[x], &[y]);
+        graph.run(&mut allocator, &[0.5]);
+        allocator.backward();
+        let t = 1f64.tanh();
+        assert!((allocator.get(w).grad - (1.0 - t * t) * 0.5).abs() < 1e-12);
+    }
+
+    fn custom_backward(_: &mut Allocator<f64>, _: f64, _: f64, _: &[ValueId<f64>]) {}
+
+    #[test]
+    #[should_panic(expected = "can't be compiled")]
+    fn test_compile_custom_op() {
+        let mut allocator = Allocator::new();
+        allocator.set_lazy(true);
+        let x = allocator.alloc_t(0.0);
+        let y = allocator.alloc_temp(1.0, custom_backward, [tanh(x)]);
+        allocator.compile(&[x], &[y]);
+    }
+}
@@ -0,0 +1,279 @@
+use rayon::prelude::*;
+
+use super::{tape_index, Allocator, TEMPORARY};
+use crate::{
+    engine::{Children, Value},
+    op::Op,
+    operators::Num,
+};
+
+// Below this many nodes per partition, spawning work costs more than it saves
+const MIN_PARTITION_LEN: usize = 64;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Owner {
+    Unreached,
+    Partition(usize),
+    // Reached from more than one partition, e.g. weights normalized once per batch
+    Shared,
+}
+
+// A node copied into a partition, with its children renumbered to the partition's tape
+struct Node<T: Num> {
+    data: T,
+    grad: T,
+    requires_grad: bool,
+    op: Option<Op<T>>,
+    children: Vec<u32>,
+}
+
+struct Partition<T: Num> {
+    // Tape index of every node, in tape order. Shared nodes that this one feeds gradients
+    // into are included as leaves.
+    indices: Vec<usize>,
+    nodes: Vec<Node<T>>,
+}
+
+impl<T: Num + Send + Sync> Allocator<T> {
+    // `backward()` for losses that sum independent per-sample subgraphs: every child of the
+    // last node heads a partition, and partitions are swept on rayon's thread pool. Each
+    // partition accumulates parameter gradients into a buffer of its own, and the buffers
+    // are summed afterwards. Nodes shared by several partitions run once these are done.
+    // Backward functions only get to see their partition, so ops must only touch their
+    // children. Falls back to `backward()` where the tape can't be split this way.
+    pub fn backward_parallel(&mut self) {
+        let Some(root) = self.temporary.len().checked_sub(1) else {
+            return;
+        };
+        if self.permanent_ops > 0 || self.skip_unreachable || !self.segments.is_empty() {
+            self.backward();
+            return;
+        }
+        let owners = self.partition(root);
+        // A child of the root that also feeds into another one ends up shared, leaving its
+        // partition empty
+        let mut heads = owners
+            .iter()
+            .filter_map(|owner| match owner {
+                Owner::Partition(p) => Some(*p),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        heads.sort_unstable();
+        heads.dedup();
+        if heads.len() < 2 || root < heads.len() * MIN_PARTITION_LEN {
+            self.backward();
+            return;
+        }
+
+        self.reset_op_grads();
+        self.temporary.grad[root] = T::one();
+        let node = self.temp_id(root);
+        self.run_backward(node);
+
+        let partitions = heads
+            .into_iter()
+            .map(|p| self.collect_partition(&owners, p))
+            .collect::<Vec<_>>();
+        let data = &self.permanent.data;
+        let requires_grad = &self.permanent.requires_grad;
+        let results = partitions
+            .par_iter()
+            .map(|partition| sweep(data, requires_grad, partition))
+            .collect::<Vec<_>>();
+
+        for (partition, (permanent_grads, grads)) in partitions.iter().zip(results) {
+            for (grad, partial) in self.permanent.grad.iter_mut().zip(permanent_grads) {
+                *grad = *grad + partial;
+            }
+            for (i, (index, grad)) in partition.indices.iter().zip(grads).enumerate() {
+                // The head's gradient came from the root and is already in place
+                if i + 1 < partition.indices.len() {
+                    self.temporary.grad[*index] = self.temporary.grad[*index] + grad;
+                }
+            }
+        }
+
+        for i in (0..root).rev() {
+            if owners[i] == Owner::Shared {
+                let node = self.temp_id(i);
+                self.run_backward(node);
+            }
+        }
+    }
+
+    // Assigns every node below `root` to the partition of the root's child it feeds into
+    fn partition(&self, root: usize) -> Vec<Owner> {
+        let mut owners = vec![Owner::Unreached; root + 1];
+        let mut heads = 0;
+        for child in self.temporary.children(root).iter() {
+            if let Some(index) = tape_index(*child) {
+                if owners[index] == Owner::Unreached && self.temporary.op(index).is_some() {
+                    owners[index] = Owner::Partition(heads);
+                    heads += 1;
+                }
+            }
+        }
+        for i in (0..root).rev() {
+            let owner = owners[i];
+            if owner == Owner::Unreached || self.temporary.op(i).is_none() {
+                continue;
+            }
+            for child in self.temporary.children(i).iter() {
+                let Some(index) = tape_index(*child) else {
+                    continue;
+                };
+                owners[index] = match owners[index] {
+                    Owner::Unreached => owner,
+                    existing if existing == owner => owner,
+                    _ => Owner::Shared,
+                };
+            }
+        }
+        owners
+    }
+
+    fn collect_partition(&self, owners: &[Owner], p: usize) -> Partition<T> {
+        let own = |i: usize| owners[i] == Owner::Partition(p);
+        let mut indices = vec![];
+        for i in (0..owners.len()).filter(|i| own(*i)) {
+            indices.push(i);
+            if self.temporary.op(i).is_some() {
+                let children = self.temporary.children(i).iter();
+                indices.extend(children.filter_map(|child| tape_index(*child)));
+            }
+        }
+        indices.sort_unstable();
+        indices.dedup();
+        let mut local = vec![0; owners.len()];
+        for (j, index) in indices.iter().enumerate() {
+            local[*index] = TEMPORARY | j as u32;
+        }
+
+        // The head is the partition's last node, and the only one with a gradient yet
+        let head = *indices.last().unwrap();
+        let nodes = indices
+            .iter()
+            .map(|&i| {
+                let children = if own(i) && self.temporary.op(i).is_some() {
+                    self.temporary
+                        .children(i)
+                        .iter()
+                        .map(|child| tape_index(*child).map_or(*child, |index| local[index]))
+                        .collect()
+                } else {
+                    vec![]
+                };
+                Node {
+                    data: self.temporary.data[i],
+                    grad: if i == head {
+                        self.temporary.grad[i]
+                    } else {
+                        T::zero()
+                    },
+                    requires_grad: self.temporary.requires_grad[i],
+                    op: self.temporary.op(i).filter(|_| own(i)),
+                    children,
+                }
+            })
+            .collect();
+        Partition { indices, nodes }
+    }
+}
+
+// Runs the backward pass of one partition on an allocator of its own, holding copies of
+// the parameters. Returns the parameter gradients and those of the partition's nodes.
+fn sweep<T: Num>(data: &[T], requires_grad: &[bool], partition: &Partition<T>) -> (Vec<T>, Vec<T>) {
+    let mut allocator = Allocator::new();
+    for (data, requires_grad) in data.iter().zip(requires_grad) {
+        let value = allocator.alloc(*data);
+        *allocator.get_mut(value).requires_grad = *requires_grad;
+    }
+    for node in &partition.nodes {
+        allocator.temporary.push(Value {
+            data: node.data,
+            grad: node.grad,
+            requires_grad: node.requires_grad,
+            previous: Children::from_raw(&node.children),
+            op: node.op,
+            grad_fn: None,
+            forward: None,
+        });
+    }
+    for i in (0..partition.nodes.len()).rev() {
+        let node = allocator.temp_id(i);
+        allocator.run_backward(node);
+    }
+    (allocator.permanent.grad, allocator.temporary.grad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::ValueId,
+        nn::{Activation, MLP},
+        operators::{sum, tanh},
+    };
+
+    // Runs both backward passes over the same tape and compares every gradient
+    fn assert_same_grads(allocator: &mut Allocator<f64>) {
+        allocator.backward();
+        let expected = (
+            allocator.permanent.grad.clone(),
+            allocator.temporary.grad.clone(),
+        );
+        allocator.zero_grads();
+        allocator.backward_parallel();
+        let grads = (&allocator.permanent.grad, &allocator.temporary.grad);
+        for (expected, grads) in [(&expected.0, grads.0), (&expected.1, grads.1)] {
+            assert_eq!(expected.len(), grads.len());
+            for (a, b) in expected.iter().zip(grads) {
+                assert!((a - b).abs() < 1e-12, "{a} != {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_backward_batch() {
+        let mut allocator = Allocator::new();
+        let mlp = MLP::new(&mut allocator, &[3, 8, 8, 1], Some(Activation::Tanh));
+        let losses = (0..8)
+            .map(|i| {
+                let x = [0.1, -0.2, 0.3].map(|x| allocator.alloc_t(x * i as f64));
+                let diff = mlp.forward(&x)[0] - 0.5;
+                diff * diff
+            })
+            .collect::<Vec<_>>();
+        sum(&mut allocator, &losses);
+        assert!(
+            allocator.partition(allocator.tape_len() - 1)[..allocator.tape_len() - 1]
+                .iter()
+                .all(|owner| matches!(owner, Owner::Partition(_)))
+        );
+        assert_same_grads(&mut allocator);
+    }
+
+    #[test]
+    fn test_parallel_backward_shared_nodes() {
+        let mut allocator = Allocator::new();
+        let w = (0..4).map(|_| allocator.alloc(0.3f64)).collect::<Vec<_>>();
+        // Computed once and used by every sample
+        let scaled = w.iter().map(|w| *w * 0.5).collect::<Vec<ValueId<f64>>>();
+        let mut losses = (0..4)
+            .map(|i| {
+                let mut h = allocator.alloc_t(i as f64);
+                for _ in 0..40 {
+                    h = tanh(scaled[i] * h + w[(i + 1) % 4]);
+                }
+                h * h
+            })
+            .collect::<Vec<_>>();
+        // A loss that also feeds into another one is shared as well
+        losses.push(losses[0] * 2.0);
+        sum(&mut allocator, &losses);
+        let owners = allocator.partition(allocator.tape_len() - 1);
+        assert!(owners.contains(&Owner::Shared));
+        assert_same_grads(&mut allocator);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    fmt,
+    ptr::{self, NonNull},
+    sync::{
+        atomic::{AtomicPtr, AtomicU32, Ordering},
+        Mutex,
+    },
+};
+
+// Live allocators by slot. A `ValueId` finds its allocator through the slot number it
+// carries instead of a pointer. Slots live in chunks that double in size, so the registry
+// grows without moving entries other threads may be reading: chunk k holds the 2^k slots
+// from 2^k - 1 on. Slot 0 stays empty for `ValueId::default()`.
+const CHUNKS: usize = 32;
+
+static CHUNK_TABLE: [AtomicPtr<AtomicPtr<()>>; CHUNKS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; CHUNKS];
+// The lowest slot never handed out, and released slots for reuse
+static NEXT_SLOT: AtomicU32 = AtomicU32::new(1);
+static FREE_SLOTS: Mutex<Vec<u32>> = Mutex::new(vec![]);
+
+// Marks a slot that is taken, but whose allocator hasn't handed out ids yet
+fn reserved() -> *mut () {
+    NonNull::dangling().as_ptr()
+}
+
+#[inline(always)]
+fn chunk_of(slot: u32) -> (usize, usize) {
+    let position = slot as u64 + 1;
+    let chunk = 63 - position.leading_zeros() as usize;
+    (chunk, (position - (1 << chunk)) as usize)
+}
+
+// The entry of `slot`, allocating its chunk on first use
+fn entry(slot: u32) -> &'static AtomicPtr<()> {
+    let (chunk, offset) = chunk_of(slot);
+    let table = &CHUNK_TABLE[chunk];
+    let mut entries = table.load(Ordering::Acquire);
+    if entries.is_null() {
+        let fresh = (0..1usize << chunk)
+            .map(|_| AtomicPtr::<()>::new(ptr::null_mut()))
+            .collect::<Box<[_]>>();
+        let fresh = Box::into_raw(fresh) as *mut AtomicPtr<()>;
+        entries = match table.compare_exchange(
+            ptr::null_mut(),
+            fresh,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => fresh,
+            Err(existing) => {
+                // Another thread allocated the chunk first
+                let len = 1usize << chunk;
+                drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(fresh, len)) });
+                existing
+            }
+        };
+    }
+    // Chunks are never freed, so entries live for the rest of the program
+    unsafe { &*entries.add(offset) }
+}
+
+// Returned by `Allocator::try_new` once every one of the 2^32 - 1 slots is taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooManyAllocators;
+
+impl fmt::Display for TooManyAllocators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("too many live allocators")
+    }
+}
+
+impl std::error::Error for TooManyAllocators {}
+
+// Owns a registry slot for as long as its allocator lives. The allocator publishes its
+// address whenever it hands out ids, so an allocator that was moved since creating its
+// first ids is found again once it creates more.
+pub(crate) struct Handle {
+    slot: u32,
+    entry: &'static AtomicPtr<()>,
+}
+
+impl Handle {
+    pub(crate) fn new() -> Result<Self, TooManyAllocators> {
+        let reused = FREE_SLOTS.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let slot = match reused {
+            Some(slot) => slot,
+            None => NEXT_SLOT
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                    next.checked_add(1)
+                })
+                .map_err(|_| TooManyAllocators)?,
+        };
+        let entry = entry(slot);
+        entry.store(reserved(), Ordering::Release);
+        Ok(Handle { slot, entry })
+    }
+
+    #[inline(always)]
+    pub(crate) fn slot(&self) -> u32 {
+        self.slot
+    }
+
+    #[inline(always)]
+    pub(crate) fn publish(&self, allocator: *mut ()) {
+        if self.entry.load(Ordering::Relaxed) != allocator {
+            self.entry.store(allocator, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.entry.store(ptr::null_mut(), Ordering::Release);
+        FREE_SLOTS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(self.slot);
+    }
+}
+
+// The allocator published in `slot`, or null if there is none
+#[inline(always)]
+pub(crate) fn lookup(slot: u32) -> *mut () {
+    let (chunk, offset) = chunk_of(slot);
+    let entries = CHUNK_TABLE[chunk].load(Ordering::Acquire);
+    if entries.is_null() {
+        return ptr::null_mut();
+    }
+    let allocator = unsafe { (*entries.add(offset)).load(Ordering::Acquire) };
+    if allocator == reserved() {
+        ptr::null_mut()
+    } else {
+        allocator
+    }
+}
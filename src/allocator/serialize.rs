@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{tape_index, Allocator};
+use crate::{
+    engine::{Arena, Children, Value},
+    op::Op,
+    operators::Num,
+};
+
+// A node as written to disk. Children are raw ids, which stay valid because slots and the
+// tape are restored in place. Ops are stored by name.
+#[derive(Serialize, Deserialize)]
+struct Node<T> {
+    data: T,
+    grad: T,
+    requires_grad: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    op: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    children: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State<T> {
+    permanent: Vec<Node<T>>,
+    permanent_generations: Vec<u32>,
+    free_slots: Vec<usize>,
+    generation: u32,
+    labels: HashMap<u32, String>,
+    tape: Vec<Node<T>>,
+}
+
+fn nodes<T: Num, E: ser::Error>(arena: &Arena<T>) -> Result<Vec<Node<T>>, E> {
+    arena
+        .iter()
+        .map(|value| {
+            let op = match value.op {
+                Some(Op::Custom(_)) => return Err(E::custom("custom ops can't be serialized")),
+                Some(op) => Some(op.name().to_string()),
+                None => None,
+            };
+            Ok(Node {
+                data: value.data,
+                grad: value.grad,
+                requires_grad: value.requires_grad,
+                op,
+                children: value.previous.to_vec(),
+            })
+        })
+        .collect()
+}
+
+// `on_tape` is whether `nodes` are the tape, whose nodes may only refer to permanent slots
+// and earlier positions on the tape. Permanent nodes may only refer to permanent slots.
+fn arena<T: Num, E: de::Error>(
+    nodes: Vec<Node<T>>,
+    permanent: usize,
+    on_tape: bool,
+) -> Result<Arena<T>, E> {
+    let mut arena = Arena::new();
+    for (position, node) in nodes.into_iter().enumerate() {
+        let op = match node.op {
+            Some(name) => match Op::from_name(&name) {
+                Some(Op::Checkpoint) => {
+                    return Err(E::custom("checkpointed segments can't be deserialized"))
+                }
+                Some(Op::Fused) => return Err(E::custom("fused ops can't be deserialized")),
+                Some(op) => Some(op),
+                None => return Err(E::custom(format!("unknown op {name:?}"))),
+            },
+            None => None,
+        };
+        if let Some(op) = op {
+            if !op.accepts(node.children.len()) {
+                return Err(E::custom(format!(
+                    "{} node with {} children",
+                    op.name(),
+                    node.children.len()
+                )));
+            }
+        }
+        let valid = |raw: &u32| match tape_index(*raw) {
+            Some(index) => on_tape && index < position,
+            None => (*raw as usize) < permanent,
+        };
+        if !node.children.iter().all(valid) {
+            return Err(E::custom("child id out of bounds"));
+        }
+        arena.push(Value {
+            data: node.data,
+            grad: node.grad,
+            requires_grad: node.requires_grad,
+            previous: Children::from_raw(&node.children),
+            op,
+            grad_fn: None,
+            forward: None,
+        });
+    }
+    Ok(arena)
+}
+
+// Saves parameters, gradients, labels and the active tape, e.g. to resume training. Call
+// `clear_temps()` first to save the parameters only. Ops recorded with `Op::Custom` and
+// checkpointed segments can't be saved. A restored tape supports `backward()`, while
+// `replay_forward()` and `grad()` need the graph to be rebuilt. Load the result into the
+// allocator the model was built on with `Allocator::replace`, so the model's ids stay valid.
+impl<T: Num + Serialize> Serialize for Allocator<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !self.segments.is_empty() {
+            return Err(ser::Error::custom(
+                "checkpointed segments can't be serialized",
+            ));
+        }
+        State {
+            permanent: nodes(&self.permanent)?,
+            permanent_generations: self.permanent_generations.clone(),
+            free_slots: self.free_slots.clone(),
+            generation: self.generation,
+            labels: self.labels.clone(),
+            tape: nodes(&self.temporary)?,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Num + Deserialize<'de>> Deserialize<'de> for Allocator<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = State::<T>::deserialize(deserializer)?;
+        let permanent = state.permanent.len();
+        if state.permanent_generations.len() != permanent
+            || state.free_slots.iter().any(|slot| *slot >= permanent)
+        {
+            return Err(de::Error::custom("inconsistent permanent slots"));
+        }
+
+        let mut allocator = Allocator::new();
+        allocator.permanent = arena(state.permanent, permanent, false)?;
+        allocator.temporary = arena(state.tape, permanent, true)?;
+        allocator.permanent_generations = state.permanent_generations;
+        allocator.free_slots = state.free_slots;
+        allocator.generation = state.generation;
+        allocator.last_generation = state.generation;
+        allocator.labels = state.labels;
+        allocator.permanent_ops = allocator.permanent.op_count();
+        Ok(allocator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::TEMPORARY,
+        nn::{Activation, MLP},
+        operators::tanh,
+    };
+
+    #[test]
+    fn test_resume_training() {
+        let mut allocator = Allocator::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        allocator.set_label(mlp.parameters()[0], "w0");
+        let step = |allocator: &mut Allocator<f64>| {
+            let inputs = [allocator.alloc_t(1.0), allocator.alloc_t(-0.5)];
+            let diff = mlp.forward(&inputs)[0] - 0.5;
+            let loss = diff * diff;
+            allocator.backward();
+            mlp.parameters().iter().for_each(|p| p.step(0.1));
+            let data = allocator.get(loss).data;
+            allocator.clear_temps();
+            data
+        };
+        step(&mut allocator);
+        let saved = serde_json::to_string(&allocator).unwrap();
+        let expected = (0..3).map(|_| step(&mut allocator)).collect::<Vec<_>>();
+
+        // Restored in place, the model's parameter ids point at the saved values again
+        allocator.replace(serde_json::from_str(&saved).unwrap());
+        assert_eq!(allocator.label(mlp.parameters()[0]), Some("w0"));
+        let resumed = (0..3).map(|_| step(&mut allocator)).collect::<Vec<_>>();
+        assert_eq!(resumed, expected);
+    }
+
+    #[test]
+    fn test_serialize_tape() {
+        let mut allocator = Allocator::new();
+        let w = allocator.alloc(0.5f64);
+        let x = allocator.alloc_t(2.0);
+        let y = tanh(w * x) + w;
+        allocator.backward();
+        let grad = allocator.get(w).grad;
+
+        let mut restored: Allocator<f64> =
+            serde_json::from_str(&serde_json::to_string(&allocator).unwrap()).unwrap();
+        assert_eq!(restored.dump_json(), allocator.dump_json());
+        restored.zero_grads();
+        restored.backward();
+        let w = restored.permanents().next().unwrap().0;
+        assert_eq!(restored.get(w).grad, grad);
+        assert_eq!(restored.tape_len(), allocator.tape_len());
+        assert_eq!(allocator.get(y).op, Some(Op::Add));
+
+        fn custom_backward(
+            _: &mut Allocator<f64>,
+            _: f64,
+            _: f64,
+            _: &[crate::allocator::ValueId<f64>],
+        ) {
+        }
+        allocator.alloc_temp(1.0, custom_backward, [y]);
+        assert!(serde_json::to_string(&allocator).is_err());
+        assert!(serde_json::from_str::<Allocator<f64>>(
+            r#"{"permanent":[],"permanent_generations":[],"free_slots":[],"generation":0,"labels":{},"tape":[{"data":1,"grad":0,"requires_grad":true,"op":"exp","children":[5]}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_deserialize_malformed_tape() {
+        let load = |tape: &str| {
+            let json = format!(
+                r#"{{"permanent":[{{"data":1,"grad":0,"requires_grad":true}}],"permanent_generations":[0],"free_slots":[],"generation":0,"labels":{{}},"tape":[{tape}]}}"#
+            );
+            serde_json::from_str::<Allocator<f64>>(&json)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+        let node = |op: &str, children: &[u32]| {
+            format!(
+                r#"{{"data":1,"grad":0,"requires_grad":true,"op":"{op}","children":{children:?}}}"#
+            )
+        };
+        assert!(load(&node("add", &[0, 0])).is_ok());
+        assert!(load(&node("add", &[0]))
+            .unwrap_err()
+            .contains("add node with 1 children"));
+        assert!(load(&node("mse", &[0, 0, 0])).is_err());
+
+        // Tape nodes can only refer to earlier ones, which rules out cycles
+        let first = node("exp", &[0]);
+        assert!(load(&format!("{first},{}", node("exp", &[TEMPORARY]))).is_ok());
+        for child in [TEMPORARY | 1, TEMPORARY | 2] {
+            assert!(load(&format!("{first},{}", node("exp", &[child])))
+                .unwrap_err()
+                .contains("child id out of bounds"));
+        }
+    }
+}
@@ -1,13 +1,99 @@
-use crate::allocator::{BackwardFn, ValueId};
+use crate::allocator::{BackwardFn, ForwardFn, GradFn, ValueId};
+use crate::op::Op;
 use crate::operators::Num;
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::ops::Deref;
 
+const INLINE_CHILDREN: usize = 4;
+
+// Children of a node being created as raw ids (see `ValueId::raw`), which the allocator
+// turns back into `ValueId`s when it needs them. The arena copies them into a buffer
+// shared by all nodes. Up to four fit inline in the space the heap variant takes anyway,
+// so only building n-ary ops (sums, dot products, fused layers) allocates.
+#[derive(Clone)]
+pub enum Children {
+    Inline(u8, [u32; INLINE_CHILDREN]),
+    Heap(Box<[u32]>),
+}
+
+impl Children {
+    pub fn from_slice<T: Num>(children: &[ValueId<T>]) -> Self {
+        if children.len() <= INLINE_CHILDREN {
+            let mut ids = [0; INLINE_CHILDREN];
+            for (id, child) in ids.iter_mut().zip(children) {
+                *id = child.raw();
+            }
+            Children::Inline(children.len() as u8, ids)
+        } else {
+            Children::Heap(children.iter().map(|child| child.raw()).collect())
+        }
+    }
+
+    // For nodes rebuilt from stored raw ids
+    #[cfg(any(test, feature = "rayon", feature = "serde"))]
+    pub(crate) fn from_raw(children: &[u32]) -> Self {
+        if children.len() <= INLINE_CHILDREN {
+            let mut ids = [0; INLINE_CHILDREN];
+            ids[..children.len()].copy_from_slice(children);
+            Children::Inline(children.len() as u8, ids)
+        } else {
+            Children::Heap(children.into())
+        }
+    }
+}
+
+impl Default for Children {
+    fn default() -> Self {
+        Children::Inline(0, [0; INLINE_CHILDREN])
+    }
+}
+
+impl Deref for Children {
+    type Target = [u32];
+
+    #[inline(always)]
+    fn deref(&self) -> &[u32] {
+        match self {
+            Children::Inline(len, ids) => &ids[..*len as usize],
+            Children::Heap(ids) => ids,
+        }
+    }
+}
+
+impl<T: Num, const N: usize> From<[ValueId<T>; N]> for Children {
+    fn from(children: [ValueId<T>; N]) -> Self {
+        Children::from_slice(&children)
+    }
+}
+
+impl<T: Num> From<&[ValueId<T>]> for Children {
+    fn from(children: &[ValueId<T>]) -> Self {
+        Children::from_slice(children)
+    }
+}
+
+impl<T: Num> From<Vec<ValueId<T>>> for Children {
+    fn from(children: Vec<ValueId<T>>) -> Self {
+        Children::from_slice(&children)
+    }
+}
+
+// A node as it is created. The allocator stores nodes split into one vector per field
+// (see `Arena`), so lookups return a `ValueRef` or `ValueMut` view instead.
 #[derive(Clone)]
 pub struct Value<T: Num> {
     pub data: T,
     pub grad: T,
-    pub(crate) previous: [ValueId<T>; 2],
-    pub(crate) backward: Option<BackwardFn<T>>,
+    // Frozen values neither accumulate gradients nor get updated by `step` or optimizers
+    pub requires_grad: bool,
+    pub(crate) previous: Children,
+    pub(crate) op: Option<Op<T>>,
+    // Builds the backward pass as new nodes, for ops that support higher-order gradients
+    pub(crate) grad_fn: Option<GradFn<T>>,
+    // Recomputes the data from the children, for ops that support replay
+    pub(crate) forward: Option<ForwardFn<T>>,
 }
 
 impl Debug for Value<f32> {
@@ -21,48 +107,460 @@ impl<T: Num> Value<T> {
         Value {
             data,
             grad: T::zero(),
-            backward: None,
-            previous: [ValueId::default(), ValueId::default()],
+            requires_grad: true,
+            op: None,
+            grad_fn: None,
+            forward: None,
+            previous: Children::default(),
         }
     }
 
-    pub fn new(data: T, backward: BackwardFn<T>, previous: [ValueId<T>; 2]) -> Value<T> {
+    // A node of an op defined outside the crate, tagged `Op::Custom(backward)`
+    pub fn new(data: T, backward: BackwardFn<T>, previous: impl Into<Children>) -> Value<T> {
+        Value::with_op(data, Op::Custom(backward), previous)
+    }
+
+    pub fn with_op(data: T, op: Op<T>, previous: impl Into<Children>) -> Value<T> {
         Value {
             data,
             grad: T::zero(),
-            backward: Some(backward),
-            previous,
+            requires_grad: true,
+            op: Some(op),
+            grad_fn: None,
+            forward: None,
+            previous: previous.into(),
         }
     }
 
+    // Like the methods of `ValueMut`, for nodes that aren't stored yet
     pub fn set_data(&mut self, data: T) {
         self.data = data;
     }
 
     #[inline(always)]
     pub fn step(&mut self, lr: T) {
-        self.data = self.data - lr * self.grad;
-        self.grad = T::zero();
+        if self.requires_grad {
+            self.data = self.data - lr * self.grad;
+            self.grad = T::zero();
+        }
+    }
+
+    #[inline(always)]
+    pub fn add_grad(&mut self, grad: T) {
+        if self.requires_grad {
+            self.grad = self.grad + grad;
+        }
+    }
+}
+
+// A read-only view of a stored node
+#[derive(Clone, Copy)]
+pub struct ValueRef<'a, T: Num> {
+    pub data: T,
+    pub grad: T,
+    pub requires_grad: bool,
+    pub(crate) previous: &'a [u32],
+    pub op: Option<Op<T>>,
+    pub(crate) grad_fn: Option<GradFn<T>>,
+    pub(crate) forward: Option<ForwardFn<T>>,
+}
+
+impl<T: Num> Debug for ValueRef<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Value")
+            .field("data", &self.data.to_string())
+            .field("grad", &self.grad.to_string())
+            .finish()
+    }
+}
+
+// A mutable view of a stored node's data. Its op and children are replaced through the
+// arena, see `Arena::set`.
+pub struct ValueMut<'a, T: Num> {
+    pub data: &'a mut T,
+    pub grad: &'a mut T,
+    pub requires_grad: &'a mut bool,
+    // The allocator's stale flag while ops are recorded, see `Allocator::set_data`
+    pub(crate) stale: Option<&'a Cell<bool>>,
+}
+
+impl<T: Num> ValueMut<'_, T> {
+    // Unlike assigning to `data`, this marks ops already on the tape as stale
+    pub fn set_data(&mut self, data: T) {
+        *self.data = data;
+        if let Some(stale) = self.stale {
+            stale.set(true);
+        }
+    }
+
+    #[inline(always)]
+    pub fn step(&mut self, lr: T) {
+        if !*self.requires_grad {
+            return;
+        }
+        *self.data = *self.data - lr * *self.grad;
+        *self.grad = T::zero();
     }
 
     #[inline(always)]
     pub fn add_grad(&mut self, grad: T) {
-        self.grad = self.grad + grad;
+        if !*self.requires_grad {
+            return;
+        }
+        *self.grad = *self.grad + grad;
+    }
+
+    #[inline(always)]
+    pub fn set_grad(&mut self, grad: T) {
+        *self.grad = grad;
+    }
+
+    #[inline(always)]
+    pub fn zero_grad(&mut self) {
+        *self.grad = T::zero();
+    }
+}
+
+// The functions of a node. Only a handful of combinations occur on a tape, so the arena
+// stores each of them once and nodes refer to them by index.
+#[derive(Clone, Copy)]
+struct Kind<T: Num> {
+    op: Option<Op<T>>,
+    grad_fn: Option<GradFn<T>>,
+    forward: Option<ForwardFn<T>>,
+}
+
+// A kind with its functions by address, which can be hashed
+type KindKey<T> = (Option<Op<T>>, usize, usize);
+
+impl<T: Num> Kind<T> {
+    fn key(&self) -> KindKey<T> {
+        (
+            self.op,
+            self.grad_fn.map_or(0, |grad_fn| grad_fn as usize),
+            self.forward.map_or(0, |forward| forward as usize),
+        )
+    }
+}
+
+// Kinds looked up recently, by forward function, so recording mostly skips the hash map
+const RECENT_KINDS: usize = 16;
+
+// Node storage for one of the allocator's arenas as a structure of arrays, one vector per
+// field, all of the same length. The backward sweep mostly reads data and grad, which
+// stay densely packed instead of being interleaved with children and function pointers,
+// and bulk gradient updates run over plain slices. The children of all nodes share one
+// buffer, and a node's functions are an index into a table of the distinct ones.
+pub(crate) struct Arena<T: Num> {
+    pub(crate) data: Vec<T>,
+    pub(crate) grad: Vec<T>,
+    pub(crate) requires_grad: Vec<bool>,
+    kind: Vec<u32>,
+    // Start and length of each node's children in `edges`, as raw ids
+    spans: Vec<(u32, u32)>,
+    edges: Vec<u32>,
+    // Children that `set` moved to the end of `edges` lie below this, so `truncate` keeps
+    // them even when later nodes are dropped
+    pinned: usize,
+    kinds: Vec<Kind<T>>,
+    kind_index: HashMap<KindKey<T>, u32>,
+    recent: [u32; RECENT_KINDS],
+}
+
+impl<T: Num> Arena<T> {
+    pub(crate) fn new() -> Self {
+        let leaf = Kind {
+            op: None,
+            grad_fn: None,
+            forward: None,
+        };
+        Arena {
+            data: vec![],
+            grad: vec![],
+            requires_grad: vec![],
+            kind: vec![],
+            spans: vec![],
+            edges: vec![],
+            pinned: 0,
+            kinds: vec![leaf],
+            kind_index: HashMap::from([(leaf.key(), 0)]),
+            recent: [0; RECENT_KINDS],
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    #[inline(always)]
+    fn intern(&mut self, kind: Kind<T>) -> u32 {
+        let key = kind.key();
+        let recent = &mut self.recent[(key.2 >> 4) % RECENT_KINDS];
+        if self.kinds[*recent as usize].key() == key {
+            return *recent;
+        }
+        let kinds = &mut self.kinds;
+        *recent = *self.kind_index.entry(key).or_insert_with(|| {
+            kinds.push(kind);
+            (kinds.len() - 1) as u32
+        });
+        *recent
+    }
+
+    // Appends `children` to the shared buffer and returns their span
+    #[inline(always)]
+    fn push_edges(&mut self, children: &[u32]) -> (u32, u32) {
+        let start = self.edges.len();
+        assert!(
+            start + children.len() <= u32::MAX as usize,
+            "too many children in one arena"
+        );
+        self.edges.extend_from_slice(children);
+        (start as u32, children.len() as u32)
+    }
+
+    #[inline(always)]
+    pub(crate) fn push(&mut self, value: Value<T>) {
+        let kind = self.intern(Kind {
+            op: value.op,
+            grad_fn: value.grad_fn,
+            forward: value.forward,
+        });
+        let span = self.push_edges(&value.previous);
+        self.data.push(value.data);
+        self.grad.push(value.grad);
+        self.requires_grad.push(value.requires_grad);
+        self.kind.push(kind);
+        self.spans.push(span);
+    }
+
+    // Replaces the whole node at `index`, e.g. to turn an op into a constant or to rewrite
+    // it into a fused op. Children that don't fit in place of the old ones are appended.
+    pub(crate) fn set(&mut self, index: usize, value: Value<T>) {
+        let kind = self.intern(Kind {
+            op: value.op,
+            grad_fn: value.grad_fn,
+            forward: value.forward,
+        });
+        self.set_children(index, &value.previous);
+        self.data[index] = value.data;
+        self.grad[index] = value.grad;
+        self.requires_grad[index] = value.requires_grad;
+        self.kind[index] = kind;
+    }
+
+    pub(crate) fn set_children(&mut self, index: usize, children: &[u32]) {
+        let (start, len) = self.spans[index];
+        if children.len() <= len as usize {
+            let start = start as usize;
+            self.edges[start..start + children.len()].copy_from_slice(children);
+            self.spans[index].1 = children.len() as u32;
+        } else {
+            self.spans[index] = self.push_edges(children);
+            self.pinned = self.edges.len();
+        }
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        if len >= self.len() {
+            return;
+        }
+        if len == 0 {
+            self.pinned = 0;
+        }
+        let edges = self.spans[len].0 as usize;
+        self.edges.truncate(edges.max(self.pinned));
+        self.data.truncate(len);
+        self.grad.truncate(len);
+        self.requires_grad.truncate(len);
+        self.kind.truncate(len);
+        self.spans.truncate(len);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    #[inline(always)]
+    fn kind_of(&self, index: usize) -> &Kind<T> {
+        &self.kinds[self.kind[index] as usize]
+    }
+
+    #[inline(always)]
+    pub(crate) fn op(&self, index: usize) -> Option<Op<T>> {
+        self.kind_of(index).op
+    }
+
+    #[inline(always)]
+    pub(crate) fn forward(&self, index: usize) -> Option<ForwardFn<T>> {
+        self.kind_of(index).forward
+    }
+
+    // The raw ids of the children of the node at `index`
+    #[inline(always)]
+    pub(crate) fn children(&self, index: usize) -> &[u32] {
+        let (start, len) = self.spans[index];
+        &self.edges[start as usize..(start + len) as usize]
+    }
+
+    // Nodes recorded by an op, as opposed to leaves
+    pub(crate) fn op_count(&self) -> usize {
+        (0..self.len()).filter(|i| self.op(*i).is_some()).count()
+    }
+
+    // Children stored in the arena, including ones left behind by `set`
+    pub(crate) fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    // The fields are read without bounds checks of their own: every vector has the same
+    // length, so checking one covers all of them, and unused fields cost nothing
+    #[inline(always)]
+    pub(crate) fn get(&self, index: usize) -> ValueRef<'_, T> {
+        assert!(index < self.len(), "node index out of bounds");
+        unsafe {
+            let kind = self
+                .kinds
+                .get_unchecked(*self.kind.get_unchecked(index) as usize);
+            let (start, len) = *self.spans.get_unchecked(index);
+            ValueRef {
+                data: *self.data.get_unchecked(index),
+                grad: *self.grad.get_unchecked(index),
+                requires_grad: *self.requires_grad.get_unchecked(index),
+                previous: self
+                    .edges
+                    .get_unchecked(start as usize..(start + len) as usize),
+                op: kind.op,
+                grad_fn: kind.grad_fn,
+                forward: kind.forward,
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_mut(&mut self, index: usize) -> ValueMut<'_, T> {
+        assert!(index < self.len(), "node index out of bounds");
+        unsafe {
+            ValueMut {
+                data: self.data.get_unchecked_mut(index),
+                grad: self.grad.get_unchecked_mut(index),
+                requires_grad: self.requires_grad.get_unchecked_mut(index),
+                stale: None,
+            }
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = ValueRef<'_, T>> + Clone + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = ValueMut<'_, T>> + '_ {
+        self.data
+            .iter_mut()
+            .zip(&mut self.grad)
+            .zip(&mut self.requires_grad)
+            .map(|((data, grad), requires_grad)| ValueMut {
+                data,
+                grad,
+                requires_grad,
+                stale: None,
+            })
+    }
+
+    // Bytes used per node, not counting its children
+    pub(crate) fn node_size() -> usize {
+        2 * std::mem::size_of::<T>()
+            + std::mem::size_of::<bool>()
+            + std::mem::size_of::<u32>()
+            + std::mem::size_of::<(u32, u32)>()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{Arena, Children, Value};
+    use crate::op::Op;
     use crate::{
         allocator::Allocator,
         operators::{exp, pow},
     };
 
+    #[test]
+    fn test_children_storage() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(1.0);
+        let b = allocator.alloc(2.0);
+        let c = allocator.alloc(3.0);
+
+        let unary = Children::from([a]);
+        assert!(matches!(unary, Children::Inline(1, _)));
+        assert_eq!(unary.len(), 1);
+
+        let binary = Children::from([a, b]);
+        assert!(matches!(binary, Children::Inline(2, _)));
+        assert_eq!(binary.len(), 2);
+
+        let quaternary = Children::from(vec![a, b, c, a]);
+        assert!(matches!(quaternary, Children::Inline(4, _)));
+        assert_eq!(quaternary[3], a.raw());
+
+        let quinary = Children::from(vec![a, b, c, a, b]);
+        assert!(matches!(quinary, Children::Heap(_)));
+        assert_eq!(quinary.len(), 5);
+
+        // Raw u32 ids keep both variants within three words
+        assert_eq!(std::mem::size_of::<Children>(), 24);
+        assert_eq!(std::mem::size_of::<crate::allocator::ValueId<f64>>(), 12);
+        // Data, grad, the requires_grad flag, a kind index and a span into the shared
+        // children buffer
+        assert_eq!(Arena::<f64>::node_size(), 29);
+    }
+
+    #[test]
+    fn test_arena_rewrites() {
+        let mut arena = Arena::<f64>::new();
+        arena.push(Value::from(1.0));
+        arena.push(Value::with_op(2.0, Op::Add, Children::from_raw(&[0, 0])));
+        arena.push(Value::with_op(3.0, Op::Neg, Children::from_raw(&[1])));
+
+        // Growing children moves them to the end, where truncation leaves them alone
+        arena.set(
+            1,
+            Value::with_op(2.0, Op::Sum, Children::from_raw(&[0, 0, 0])),
+        );
+        arena.truncate(2);
+        assert_eq!(arena.children(1), &[0, 0, 0]);
+        assert_eq!(arena.get(1).op, Some(Op::Sum));
+        arena.push(Value::with_op(4.0, Op::Neg, Children::from_raw(&[1])));
+        assert_eq!(arena.children(2), &[1]);
+        assert_eq!(arena.op_count(), 2);
+
+        // Shrinking children keeps them in place
+        arena.set_children(1, &[0]);
+        assert_eq!(arena.get(1).previous, &[0]);
+        arena.clear();
+        assert_eq!(arena.edge_count(), 0);
+    }
+
     #[test]
     fn test_value_creation() {
         let mut allocator = Allocator::new();
         let value = allocator.alloc(3.0);
         assert_eq!(allocator.get(value).data, 3.0);
+
+        let mut value = Value::from(3.0);
+        value.add_grad(2.0);
+        value.step(0.5);
+        assert_eq!((value.data, value.grad), (2.0, 0.0));
+        value.requires_grad = false;
+        value.add_grad(1.0);
+        value.set_data(4.0);
+        assert_eq!((value.data, value.grad), (4.0, 0.0));
     }
 
     #[test]
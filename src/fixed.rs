@@ -0,0 +1,450 @@
+use std::{
+    fmt::{self, Debug, Display},
+    ops::{Add, Div, Mul, Neg, Rem, Sub},
+};
+
+use num::{pow::Pow, FromPrimitive, One, Zero};
+use rand::{
+    distributions::uniform::{SampleBorrow, SampleUniform, UniformInt, UniformSampler},
+    Rng,
+};
+
+use crate::operators::Num;
+
+pub const FRAC_BITS: u32 = 16;
+const ONE: i64 = 1 << FRAC_BITS;
+// ln(2) and ln(10) in Q16.16
+const LN_2: i64 = 45426;
+const LN_10: i64 = 150902;
+
+// A Q16.16 fixed-point number: an i32 counting 1/65536ths, covering about ±32768. Every
+// op, including exp/ln/tanh, uses integer arithmetic only, so it runs on targets without
+// an FPU. Results that don't fit saturate instead of wrapping, and undefined results
+// (division by zero, ln of a non-positive number) saturate towards the matching
+// infinity, or are zero where there is none (0 / 0, sqrt of a negative number).
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fixed(pub i32);
+
+impl Fixed {
+    pub const MAX: Fixed = Fixed(i32::MAX);
+    pub const MIN: Fixed = Fixed(i32::MIN);
+    // The smallest positive value, 2^-16
+    pub const EPSILON: Fixed = Fixed(1);
+
+    pub fn from_bits(bits: i32) -> Self {
+        Fixed(bits)
+    }
+
+    pub fn to_bits(self) -> i32 {
+        self.0
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / ONE as f64
+    }
+
+    #[inline(always)]
+    fn saturate(x: i64) -> Self {
+        Fixed(x.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+    }
+
+    #[inline(always)]
+    fn raw(self) -> i64 {
+        self.0 as i64
+    }
+}
+
+impl Display for Fixed {
+    // Up to 5 decimals with trailing zeros trimmed, or exactly the requested precision
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(5).min(9);
+        let magnitude = self.raw().unsigned_abs();
+        let scale = 10u64.pow(precision as u32);
+        let mut int = magnitude >> FRAC_BITS;
+        let mut frac = ((magnitude & (ONE as u64 - 1)) * scale + ONE as u64 / 2) >> FRAC_BITS;
+        if frac == scale {
+            int += 1;
+            frac = 0;
+        }
+        let sign = if self.0 < 0 && (int > 0 || frac > 0) {
+            "-"
+        } else {
+            ""
+        };
+        let mut digits = format!("{frac:0precision$}");
+        if f.precision().is_none() {
+            digits.truncate(digits.trim_end_matches('0').len());
+        }
+        let number = if digits.is_empty() {
+            format!("{sign}{int}")
+        } else {
+            format!("{sign}{int}.{digits}")
+        };
+        f.pad_integral(true, "", &number)
+    }
+}
+
+impl Debug for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fixed({self})")
+    }
+}
+
+impl Add for Fixed {
+    type Output = Self;
+
+    #[inline(always)]
+    fn add(self, other: Self) -> Self {
+        Fixed(self.0.saturating_add(other.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Self;
+
+    #[inline(always)]
+    fn sub(self, other: Self) -> Self {
+        Fixed(self.0.saturating_sub(other.0))
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Self;
+
+    // Rounds to nearest rather than truncating, so repeated products don't drift down
+    #[inline(always)]
+    fn mul(self, other: Self) -> Self {
+        Fixed::saturate((self.raw() * other.raw() + ONE / 2) >> FRAC_BITS)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Self;
+
+    #[inline(always)]
+    fn div(self, other: Self) -> Self {
+        if other.0 == 0 {
+            return match self.0.signum() {
+                1 => Fixed::MAX,
+                -1 => Fixed::MIN,
+                _ => Fixed(0),
+            };
+        }
+        Fixed::saturate((self.raw() << FRAC_BITS) / other.raw())
+    }
+}
+
+impl Rem for Fixed {
+    type Output = Self;
+
+    fn rem(self, other: Self) -> Self {
+        if other.0 == 0 {
+            return Fixed(0);
+        }
+        Fixed(self.0.wrapping_rem(other.0))
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Self;
+
+    #[inline(always)]
+    fn neg(self) -> Self {
+        Fixed(self.0.saturating_neg())
+    }
+}
+
+impl Pow<Fixed> for Fixed {
+    type Output = Self;
+
+    // Integer exponents are exact repeated products, which also handles negative bases
+    fn pow(self, exponent: Self) -> Self {
+        if exponent.0 & (ONE as i32 - 1) == 0 {
+            return self.powi(exponent.0 >> FRAC_BITS);
+        }
+        if self.0 <= 0 {
+            return Fixed(0);
+        }
+        (exponent * self.ln()).exp()
+    }
+}
+
+impl Zero for Fixed {
+    fn zero() -> Self {
+        Fixed(0)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl One for Fixed {
+    fn one() -> Self {
+        Fixed(ONE as i32)
+    }
+}
+
+impl num::Num for Fixed {
+    type FromStrRadixErr = <f64 as num::Num>::FromStrRadixErr;
+
+    fn from_str_radix(s: &str, radix: u32) -> Result<Self, Self::FromStrRadixErr> {
+        f64::from_str_radix(s, radix).map(|x| Fixed::from_f64(x).unwrap())
+    }
+}
+
+impl FromPrimitive for Fixed {
+    fn from_i64(n: i64) -> Option<Self> {
+        Some(Fixed::saturate(n.saturating_mul(ONE)))
+    }
+
+    fn from_u64(n: u64) -> Option<Self> {
+        Fixed::from_i64(n.min(i64::MAX as u64) as i64)
+    }
+
+    fn from_f64(x: f64) -> Option<Self> {
+        if x.is_nan() {
+            return Some(Fixed(0));
+        }
+        Some(Fixed::saturate((x * ONE as f64).round() as i64))
+    }
+}
+
+// Samples the raw bits uniformly, so every representable value is equally likely
+pub struct UniformFixed(UniformInt<i32>);
+
+impl UniformSampler for UniformFixed {
+    type X = Fixed;
+
+    fn new<B1, B2>(low: B1, high: B2) -> Self
+    where
+        B1: SampleBorrow<Fixed> + Sized,
+        B2: SampleBorrow<Fixed> + Sized,
+    {
+        UniformFixed(UniformInt::new(low.borrow().0, high.borrow().0))
+    }
+
+    fn new_inclusive<B1, B2>(low: B1, high: B2) -> Self
+    where
+        B1: SampleBorrow<Fixed> + Sized,
+        B2: SampleBorrow<Fixed> + Sized,
+    {
+        UniformFixed(UniformInt::new_inclusive(low.borrow().0, high.borrow().0))
+    }
+
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Fixed {
+        Fixed(self.0.sample(rng))
+    }
+}
+
+impl SampleUniform for Fixed {
+    type Sampler = UniformFixed;
+}
+
+// exp(r) for r in [0, ln 2) by its Taylor series, in Horner form
+fn exp_reduced(r: i64) -> i64 {
+    let mut sum = ONE;
+    for k in (1..=7).rev() {
+        sum = ONE + (sum * r / k + ONE / 2) / ONE;
+    }
+    sum
+}
+
+impl Num for Fixed {
+    // exp(x) = 2^k exp(r) with x = k ln 2 + r
+    fn exp(self) -> Self {
+        let x = self.raw();
+        let k = x.div_euclid(LN_2);
+        let r = x.rem_euclid(LN_2);
+        let base = exp_reduced(r);
+        if k >= 16 {
+            return Fixed::MAX;
+        }
+        if k <= -(FRAC_BITS as i64 + 2) {
+            return Fixed(0);
+        }
+        if k >= 0 {
+            Fixed::saturate(base << k)
+        } else {
+            Fixed(((base + (1 << (-k - 1))) >> -k) as i32)
+        }
+    }
+
+    // ln(x) = k ln 2 + ln(m) with x = 2^k m and m in [1, 2), and
+    // ln(m) = 2 atanh(z) = 2 (z + z^3/3 + z^5/5 + ...) with z = (m - 1) / (m + 1) <= 1/3
+    fn ln(self) -> Self {
+        if self.0 <= 0 {
+            return Fixed::MIN;
+        }
+        let x = self.raw();
+        let k = 63 - x.leading_zeros() as i64 - FRAC_BITS as i64;
+        // m in Q2.30 for extra precision in the series
+        let m = x << (14 - k);
+        let one = 1i64 << 30;
+        let z = ((m - one) << 30) / (m + one);
+        let z2 = (z * z) >> 30;
+        let mut term = z;
+        let mut series = 0;
+        for n in (1..=13).step_by(2) {
+            series += term / n;
+            term = (term * z2) >> 30;
+        }
+        let ln_m = (2 * series) >> (30 - FRAC_BITS);
+        Fixed::saturate(k * LN_2 + ln_m)
+    }
+
+    fn log(self, base: Self) -> Self {
+        self.ln() / base.ln()
+    }
+
+    fn log2(self) -> Self {
+        self.ln() / Fixed(LN_2 as i32)
+    }
+
+    fn log10(self) -> Self {
+        self.ln() / Fixed(LN_10 as i32)
+    }
+
+    fn powi(self, n: i32) -> Self {
+        let mut result = Fixed::one();
+        let mut base = self;
+        let mut e = n.unsigned_abs();
+        while e > 0 {
+            if e & 1 == 1 {
+                result = result * base;
+            }
+            base = base * base;
+            e >>= 1;
+        }
+        if n < 0 {
+            Fixed::one() / result
+        } else {
+            result
+        }
+    }
+
+    // tanh(x) = (1 - e^-2|x|) / (1 + e^-2|x|) with the sign of x, which stays in range
+    fn tanh(self) -> Self {
+        let e = (Fixed(self.0.saturating_abs()) * Fixed::from_i64(-2).unwrap()).exp();
+        let t = (Fixed::one() - e) / (Fixed::one() + e);
+        if self.0 < 0 {
+            -t
+        } else {
+            t
+        }
+    }
+
+    // Abramowitz and Stegun 7.1.26, accurate to about 1.5e-7 before rounding to Q16.16
+    fn erf(self) -> Self {
+        let coefficient = |x: f64| Fixed::from_f64(x).unwrap();
+        let x = Fixed(self.0.saturating_abs());
+        let t = Fixed::one() / (Fixed::one() + coefficient(0.3275911) * x);
+        let poly = [
+            1.061405429,
+            -1.453152027,
+            1.421413741,
+            -0.284496736,
+            0.254829592,
+        ]
+        .into_iter()
+        .fold(Fixed::zero(), |acc, a| (acc + coefficient(a)) * t);
+        let y = Fixed::one() - poly * (-(x * x)).exp();
+        if self.0 < 0 {
+            -y
+        } else {
+            y
+        }
+    }
+
+    // Integer square root of the value scaled by 2^16
+    fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Fixed(0);
+        }
+        let n = (self.raw() as u64) << FRAC_BITS;
+        let mut root = 0u64;
+        let mut bit = 1u64 << 62;
+        while bit > n {
+            bit >>= 2;
+        }
+        let mut rest = n;
+        while bit != 0 {
+            if rest >= root + bit {
+                rest -= root + bit;
+                root = (root >> 1) + bit;
+            } else {
+                root >>= 1;
+            }
+            bit >>= 2;
+        }
+        Fixed(root as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::Allocator,
+        losses::mse,
+        nn::{Activation, MLP},
+    };
+
+    fn fixed(x: f64) -> Fixed {
+        Fixed::from_f64(x).unwrap()
+    }
+
+    #[test]
+    fn test_fixed_arithmetic() {
+        assert_eq!(fixed(1.5) * fixed(-2.25), fixed(-3.375));
+        assert_eq!(fixed(7.0) / fixed(2.0), fixed(3.5));
+        assert_eq!(fixed(30000.0) + fixed(30000.0), Fixed::MAX);
+        assert_eq!(fixed(1.0) / Fixed::zero(), Fixed::MAX);
+        assert_eq!(fixed(-2.0).powi(3), fixed(-8.0));
+        assert_eq!(fixed(9.0).sqrt(), fixed(3.0));
+
+        assert_eq!(fixed(-1.5).to_string(), "-1.5");
+        assert_eq!(fixed(2.0).to_string(), "2");
+        assert_eq!(format!("{:.2}", fixed(0.125)), "0.13");
+
+        type Case = (fn(Fixed) -> Fixed, fn(f64) -> f64, &'static [f64]);
+        let cases: [Case; 5] = [
+            (Fixed::exp, f64::exp, &[-5.0, -0.3, 0.0, 0.7, 4.0]),
+            (Fixed::ln, f64::ln, &[0.01, 0.5, 1.0, 3.0, 1000.0]),
+            (Fixed::tanh, f64::tanh, &[-3.0, -0.2, 0.0, 0.5, 10.0]),
+            (Fixed::erf, libm::erf, &[-2.0, -0.1, 0.0, 0.4, 1.5]),
+            (Fixed::sqrt, f64::sqrt, &[0.01, 2.0, 1000.0]),
+        ];
+        for (f, reference, inputs) in cases {
+            for x in inputs {
+                let expected = reference(*x);
+                let error = (f(fixed(*x)).to_f64() - expected).abs();
+                assert!(error <= 1e-3 * expected.abs().max(1.0), "{x}: {error}");
+            }
+        }
+        assert!((fixed(2.0).pow(fixed(0.5)).to_f64() - 2f64.sqrt()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_fixed_training() {
+        let mut allocator = Allocator::<Fixed>::new();
+        let mut mlp = MLP::new(&mut allocator, &[1, 4, 1], Some(Activation::Tanh));
+        let inputs = [-1.0, -0.5, 0.5, 1.0].map(|x| vec![allocator.alloc(fixed(x))]);
+        let targets = [0.5, 0.2, -0.2, -0.5].map(|x| allocator.alloc(fixed(x)));
+
+        let mut losses = vec![];
+        for _ in 0..100 {
+            let outputs = inputs
+                .iter()
+                .map(|input| mlp.forward(input)[0])
+                .collect::<Vec<_>>();
+            let loss = mse(&mut allocator, &outputs, &targets);
+            losses.push(allocator.get(loss).data);
+            allocator.backward();
+            mlp.step(fixed(0.1));
+            allocator.clear_temps();
+        }
+        assert!(losses.last().unwrap() < losses.first().unwrap());
+    }
+}
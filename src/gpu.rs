@@ -0,0 +1,674 @@
+use wgpu::util::DeviceExt;
+
+use crate::{
+    allocator::ValueId,
+    nn::{gather_rows, Activation, Layer, MLP},
+};
+
+// One thread per (sample, neuron) pair, each summing a row of inputs against a row of
+// weights and applying the activation. Both are row-major, so `weights` holds one row per
+// neuron.
+const FORWARD_SHADER: &str = r#"
+struct Dims {
+    batch: u32,
+    inputs: u32,
+    outputs: u32,
+    activation: u32,
+}
+
+@group(0) @binding(0) var<uniform> dims: Dims;
+@group(0) @binding(1) var<storage, read> rows: array<f32>;
+@group(0) @binding(2) var<storage, read> weights: array<f32>;
+@group(0) @binding(3) var<storage, read> bias: array<f32>;
+@group(0) @binding(4) var<storage, read_write> outputs: array<f32>;
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let sample = id.x;
+    let neuron = id.y;
+    if (sample >= dims.batch || neuron >= dims.outputs) {
+        return;
+    }
+    var sum = bias[neuron];
+    for (var i = 0u; i < dims.inputs; i++) {
+        sum += rows[sample * dims.inputs + i] * weights[neuron * dims.inputs + i];
+    }
+    switch dims.activation {
+        case 1u: {
+            sum = tanh(sum);
+        }
+        case 2u: {
+            sum = max(sum, 0.0);
+        }
+        default: {}
+    }
+    outputs[sample * dims.outputs + neuron] = sum;
+}
+"#;
+
+// The backward pass of `FORWARD_SHADER`, one entry point per gradient. Each one folds the
+// activation's derivative into its sum instead of storing the gradients of the weighted
+// sums first.
+const BACKWARD_SHADER: &str = r#"
+struct Dims {
+    batch: u32,
+    inputs: u32,
+    outputs: u32,
+    activation: u32,
+}
+
+@group(0) @binding(0) var<uniform> dims: Dims;
+@group(0) @binding(1) var<storage, read> rows: array<f32>;
+@group(0) @binding(2) var<storage, read> weights: array<f32>;
+@group(0) @binding(3) var<storage, read> outputs: array<f32>;
+@group(0) @binding(4) var<storage, read> grads: array<f32>;
+@group(0) @binding(5) var<storage, read_write> result: array<f32>;
+
+// The gradient of a weighted sum, from the gradient and value of its output
+fn delta(sample: u32, neuron: u32) -> f32 {
+    let i = sample * dims.outputs + neuron;
+    let y = outputs[i];
+    switch dims.activation {
+        case 1u: {
+            return grads[i] * (1.0 - y * y);
+        }
+        case 2u: {
+            return select(0.0, grads[i], y > 0.0);
+        }
+        default: {
+            return grads[i];
+        }
+    }
+}
+
+@compute @workgroup_size(8, 8)
+fn grad_inputs(@builtin(global_invocation_id) id: vec3<u32>) {
+    let sample = id.x;
+    let input = id.y;
+    if (sample >= dims.batch || input >= dims.inputs) {
+        return;
+    }
+    var sum = 0.0;
+    for (var o = 0u; o < dims.outputs; o++) {
+        sum += delta(sample, o) * weights[o * dims.inputs + input];
+    }
+    result[sample * dims.inputs + input] = sum;
+}
+
+@compute @workgroup_size(8, 8)
+fn grad_weights(@builtin(global_invocation_id) id: vec3<u32>) {
+    let neuron = id.x;
+    let input = id.y;
+    if (neuron >= dims.outputs || input >= dims.inputs) {
+        return;
+    }
+    var sum = 0.0;
+    for (var s = 0u; s < dims.batch; s++) {
+        sum += delta(s, neuron) * rows[s * dims.inputs + input];
+    }
+    result[neuron * dims.inputs + input] = sum;
+}
+
+// One workgroup per neuron sums its deltas over the batch
+var<workgroup> partial: array<f32, 64>;
+
+@compute @workgroup_size(64)
+fn grad_bias(
+    @builtin(workgroup_id) group: vec3<u32>,
+    @builtin(local_invocation_index) lane: u32,
+) {
+    let neuron = group.x;
+    var sum = 0.0;
+    for (var s = lane; s < dims.batch; s += 64u) {
+        sum += delta(s, neuron);
+    }
+    partial[lane] = sum;
+    workgroupBarrier();
+    for (var stride = 32u; stride > 0u; stride /= 2u) {
+        if (lane < stride) {
+            partial[lane] += partial[lane + stride];
+        }
+        workgroupBarrier();
+    }
+    if (lane == 0u) {
+        result[neuron] = partial[0];
+    }
+}
+"#;
+
+const WORKGROUP_SIZE: u32 = 8;
+
+// Activations as the shaders number them
+const LINEAR: u32 = 0;
+const TANH: u32 = 1;
+const RELU: u32 = 2;
+
+// Runs batched layers on a GPU through wgpu. A layer's weighted sums and activations are
+// computed by one kernel, and its backward pass by three more: the gradients of the
+// inputs and of the weights, and the gradients of the bias as a reduction over the batch.
+// The outputs are recorded as a fused op (see `Allocator::record_fused`), so the ids
+// handed back are ordinary `ValueId`s and the loss, `backward()` and optimizers work as
+// with `forward_batch`. Data is f32, since that's what GPUs compute in. Clones share the
+// device.
+#[derive(Clone)]
+pub struct Gpu {
+    pub(crate) device: wgpu::Device,
+    pub(crate) queue: wgpu::Queue,
+    pub(crate) forward: wgpu::ComputePipeline,
+    pub(crate) grad_inputs: wgpu::ComputePipeline,
+    pub(crate) grad_weights: wgpu::ComputePipeline,
+    pub(crate) grad_bias: wgpu::ComputePipeline,
+    pub(crate) name: String,
+}
+
+// The buffers of a layer's forward pass, kept on the GPU for its backward pass
+struct Saved {
+    dims: wgpu::Buffer,
+    rows: wgpu::Buffer,
+    weights: wgpu::Buffer,
+    outputs: wgpu::Buffer,
+    batch: u32,
+    inputs: u32,
+    neurons: u32,
+}
+
+impl Gpu {
+    // The default adapter, or None if there is none. The backends can be narrowed with
+    // the WGPU_BACKEND environment variable, e.g. WGPU_BACKEND=vulkan.
+    pub fn new() -> Option<Self> {
+        let instance =
+            wgpu::Instance::new(wgpu::InstanceDescriptor::new_without_display_handle_from_env());
+        let adapter =
+            pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+                .ok()?;
+        let (device, queue) =
+            pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default())).ok()?;
+        let pipeline = |label, source: &str, entry_point| {
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(label),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: None,
+                module: &module,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        Some(Gpu {
+            forward: pipeline("forward", FORWARD_SHADER, "main"),
+            grad_inputs: pipeline("grad_inputs", BACKWARD_SHADER, "grad_inputs"),
+            grad_weights: pipeline("grad_weights", BACKWARD_SHADER, "grad_weights"),
+            grad_bias: pipeline("grad_bias", BACKWARD_SHADER, "grad_bias"),
+            name: adapter.get_info().name,
+            device,
+            queue,
+        })
+    }
+
+    // The adapter's name, e.g. to log which device a run used
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // sums[s][o] = bias[o] + sum_i rows[s][i] * weights[o][i], with `inputs` values in every
+    // row of `rows` (one per sample) and of `weights` (one per output), all row-major
+    pub fn affine(&self, rows: &[f32], weights: &[f32], bias: &[f32], inputs: usize) -> Vec<f32> {
+        if rows.is_empty() || bias.is_empty() {
+            return vec![];
+        }
+        self.forward(rows, weights, bias, inputs, LINEAR).0
+    }
+
+    // `affine` followed by `activation`, keeping the buffers needed by `backward`
+    fn forward(
+        &self,
+        rows: &[f32],
+        weights: &[f32],
+        bias: &[f32],
+        inputs: usize,
+        activation: u32,
+    ) -> (Vec<f32>, Saved) {
+        let outputs = bias.len();
+        assert!(inputs > 0, "affine needs at least one input");
+        assert_eq!(
+            weights.len(),
+            outputs * inputs,
+            "affine with mismatched weight and bias lengths"
+        );
+        assert!(
+            rows.len().is_multiple_of(inputs),
+            "affine with rows of the wrong size"
+        );
+        let batch = rows.len() / inputs;
+
+        let dims = [batch as u32, inputs as u32, outputs as u32, activation];
+        let saved = Saved {
+            dims: self
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("dims"),
+                    contents: &dims.map(u32::to_le_bytes).concat(),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                }),
+            rows: self.upload("rows", rows),
+            weights: self.upload("weights", weights),
+            outputs: self.output("outputs", batch * outputs),
+            batch: dims[0],
+            inputs: dims[1],
+            neurons: dims[2],
+        };
+        let bias = self.upload("bias", bias);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        self.dispatch(
+            &mut encoder,
+            &self.forward,
+            &[
+                (0, &saved.dims),
+                (1, &saved.rows),
+                (2, &saved.weights),
+                (3, &bias),
+                (4, &saved.outputs),
+            ],
+            (
+                saved.batch.div_ceil(WORKGROUP_SIZE),
+                saved.neurons.div_ceil(WORKGROUP_SIZE),
+            ),
+        );
+        let mut results = self.read(encoder, &[&saved.outputs]);
+        (results.remove(0), saved)
+    }
+
+    // The gradients of the inputs, weights and bias of the forward pass in `saved`, given
+    // the gradients of its outputs, in the layouts `forward` took them in
+    fn backward(&self, saved: &Saved, grads: &[f32]) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+        let (batch, inputs, neurons) = (saved.batch, saved.inputs, saved.neurons);
+        let grads = self.upload("grads", grads);
+        let grad_inputs = self.output("grad_inputs", (batch * inputs) as usize);
+        let grad_weights = self.output("grad_weights", (neurons * inputs) as usize);
+        let grad_bias = self.output("grad_bias", neurons as usize);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        let shared = [(0, &saved.dims), (3, &saved.outputs), (4, &grads)];
+        self.dispatch(
+            &mut encoder,
+            &self.grad_inputs,
+            &[
+                shared[0],
+                (2, &saved.weights),
+                shared[1],
+                shared[2],
+                (5, &grad_inputs),
+            ],
+            (
+                batch.div_ceil(WORKGROUP_SIZE),
+                inputs.div_ceil(WORKGROUP_SIZE),
+            ),
+        );
+        self.dispatch(
+            &mut encoder,
+            &self.grad_weights,
+            &[
+                shared[0],
+                (1, &saved.rows),
+                shared[1],
+                shared[2],
+                (5, &grad_weights),
+            ],
+            (
+                neurons.div_ceil(WORKGROUP_SIZE),
+                inputs.div_ceil(WORKGROUP_SIZE),
+            ),
+        );
+        self.dispatch(
+            &mut encoder,
+            &self.grad_bias,
+            &[shared[0], shared[1], shared[2], (5, &grad_bias)],
+            (neurons, 1),
+        );
+        let mut results = self
+            .read(encoder, &[&grad_inputs, &grad_weights, &grad_bias])
+            .into_iter();
+        let mut next = || results.next().unwrap();
+        (next(), next(), next())
+    }
+
+    // Records a run of `pipeline` with each buffer bound to the binding it is paired with
+    fn dispatch(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::ComputePipeline,
+        bindings: &[(u32, &wgpu::Buffer)],
+        workgroups: (u32, u32),
+    ) {
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &bindings
+                .iter()
+                .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                    binding: *binding,
+                    resource: buffer.as_entire_binding(),
+                })
+                .collect::<Vec<_>>(),
+        });
+        let mut pass = encoder.begin_compute_pass(&Default::default());
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(workgroups.0, workgroups.1, 1);
+    }
+
+    // Submits `encoder` and copies `buffers` back once its kernels have run
+    fn read(&self, mut encoder: wgpu::CommandEncoder, buffers: &[&wgpu::Buffer]) -> Vec<Vec<f32>> {
+        let readbacks = buffers
+            .iter()
+            .map(|buffer| {
+                let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("readback"),
+                    size: buffer.size(),
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                encoder.copy_buffer_to_buffer(buffer, 0, &readback, 0, buffer.size());
+                readback
+            })
+            .collect::<Vec<_>>();
+        self.queue.submit([encoder.finish()]);
+
+        for readback in &readbacks {
+            readback.map_async(wgpu::MapMode::Read, .., |result| {
+                result.expect("couldn't read back results from the GPU")
+            });
+        }
+        self.device
+            .poll(wgpu::PollType::wait_indefinitely())
+            .expect("the GPU didn't finish its kernels");
+        readbacks
+            .iter()
+            .map(|readback| {
+                let view = readback.get_mapped_range(..).unwrap();
+                view.chunks_exact(size_of::<f32>())
+                    .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn upload(&self, label: &str, data: &[f32]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: &data
+                    .iter()
+                    .flat_map(|x| x.to_le_bytes())
+                    .collect::<Vec<_>>(),
+                usage: wgpu::BufferUsages::STORAGE,
+            })
+    }
+
+    // A buffer of `len` floats for a kernel to write
+    fn output(&self, label: &str, len: usize) -> wgpu::Buffer {
+        self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (len * size_of::<f32>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+}
+
+// The number the shaders use for `activation`, if they implement it
+fn activation_code(activation: &Option<Activation<f32>>) -> Option<u32> {
+    match activation {
+        None => Some(LINEAR),
+        Some(Activation::Tanh) => Some(TANH),
+        Some(Activation::Relu) => Some(RELU),
+        Some(_) => None,
+    }
+}
+
+impl Layer<f32> {
+    // `forward_batch` with the whole layer run on `gpu`, forward and backward. Layers whose
+    // neurons don't share a linear, tanh or relu activation run on the host instead.
+    pub fn forward_batch_gpu(
+        &self,
+        gpu: &Gpu,
+        batch: &[impl AsRef<[ValueId<f32>]>],
+    ) -> Vec<Vec<ValueId<f32>>> {
+        let Some(first) = self.neurons.first() else {
+            return vec![vec![]; batch.len()];
+        };
+        let activation = activation_code(&first.activation).filter(|code| {
+            self.neurons
+                .iter()
+                .all(|neuron| activation_code(&neuron.activation) == Some(*code))
+        });
+        let Some(activation) = activation else {
+            return self.forward_batch(batch);
+        };
+        if first.weights.is_empty() || batch.is_empty() {
+            return self.forward_batch(batch);
+        }
+        let allocator = unsafe { first.allocator().as_mut().unwrap() };
+        let inputs = first.weights.len();
+        let rows = gather_rows(allocator, batch, inputs);
+        let weights = self
+            .neurons
+            .iter()
+            .flat_map(|neuron| neuron.weights.iter().copied())
+            .collect::<Vec<_>>();
+        let biases = self
+            .neurons
+            .iter()
+            .map(|neuron| neuron.bias)
+            .collect::<Vec<_>>();
+        let bias = biases
+            .iter()
+            .map(|bias| bias.map_or(0.0, |bias| allocator.get(bias).data))
+            .collect::<Vec<_>>();
+        let (outputs, saved) = gpu.forward(
+            &rows,
+            &allocator.gather_data(&weights),
+            &bias,
+            inputs,
+            activation,
+        );
+
+        let samples = batch
+            .iter()
+            .flat_map(|inputs| inputs.as_ref().iter().copied())
+            .collect::<Vec<_>>();
+        let mut children = samples.clone();
+        children.extend(self.parameters());
+        let gpu = gpu.clone();
+        let outputs = allocator.record_fused(&children, outputs, move |allocator, grads| {
+            let (grad_inputs, grad_weights, grad_bias) = gpu.backward(&saved, grads);
+            for (x, grad) in samples.iter().zip(grad_inputs) {
+                allocator.get_mut(*x).add_grad(grad);
+            }
+            for (w, grad) in weights.iter().zip(grad_weights) {
+                allocator.get_mut(*w).add_grad(grad);
+            }
+            for (b, grad) in biases.iter().zip(grad_bias) {
+                if let Some(b) = b {
+                    allocator.get_mut(*b).add_grad(grad);
+                }
+            }
+        });
+        outputs
+            .chunks(self.neurons.len())
+            .map(<[_]>::to_vec)
+            .collect()
+    }
+}
+
+impl MLP<f32> {
+    // `forward` for every sample in `batch`, one layer at a time on `gpu`, see
+    // `Layer::forward_batch_gpu`
+    pub fn forward_batch_gpu(
+        &self,
+        gpu: &Gpu,
+        batch: &[impl AsRef<[ValueId<f32>]>],
+    ) -> Vec<Vec<ValueId<f32>>> {
+        let batch = batch
+            .iter()
+            .map(|inputs| inputs.as_ref().to_vec())
+            .collect::<Vec<_>>();
+        self.layers
+            .iter()
+            .fold(batch, |acc, layer| layer.forward_batch_gpu(gpu, &acc))
+    }
+}
+
+// These need a GPU adapter (a software one like llvmpipe will do), so they only run with
+// `cargo test --features wgpu -- --ignored`
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        allocator::Allocator,
+        nn::{Init, Neuron},
+        op::Op,
+        optim::{Optimizer, SGD},
+    };
+
+    fn gpu() -> Gpu {
+        Gpu::new().expect("no GPU adapter")
+    }
+
+    // Sums over the batch are added up in a different order than on the host
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-5 * b.abs().max(1.0), "{a} != {b}");
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn test_gpu_affine() {
+        let gpu = gpu();
+        let rows = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let weights = [1.0, 0.0, 0.5, 0.5];
+        let sums = gpu.affine(&rows, &weights, &[0.0, 1.0], 2);
+        assert_eq!(sums, vec![1.0, 2.5, 3.0, 4.5, 5.0, 6.5]);
+        assert!(gpu.affine(&[], &weights, &[0.0, 1.0], 2).is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn test_gpu_forward_batch() {
+        let gpu = gpu();
+        let rng = &mut StdRng::seed_from_u64(0);
+        for activation in [Some(Activation::Tanh), Some(Activation::Relu), None] {
+            let mut allocator = Allocator::<f32>::new();
+            // More samples, inputs and neurons than fit in one workgroup, and more samples
+            // than the bias reduction sums per lane
+            let layer = Layer::with_rng(&mut allocator, 19, 10, activation, Init::default(), rng);
+            let batch = (0..70)
+                .map(|s| {
+                    (0..19)
+                        .map(|i| allocator.alloc_t(((s * 19 + i) % 7) as f32 / 7.0 - 0.5))
+                        .collect()
+                })
+                .collect::<Vec<Vec<_>>>();
+
+            let outputs = layer.forward_batch_gpu(&gpu, &batch);
+            let expected = layer.forward_batch(&batch);
+            assert_eq!(allocator.get(outputs[0][0]).op, Some(Op::Fused));
+            for (output, expected) in outputs.iter().zip(&expected) {
+                assert_close(
+                    &allocator.gather_data(output),
+                    &allocator.gather_data(expected),
+                );
+            }
+
+            // The GPU computes the same gradients for the parameters and the inputs
+            let inputs = batch.concat();
+            let mut grads = vec![];
+            for outputs in [&outputs, &expected] {
+                let mut loss = allocator.alloc_t(0.0);
+                for (s, output) in outputs.iter().enumerate() {
+                    for (o, y) in output.iter().enumerate() {
+                        loss = loss + *y * ((s + o) % 5) as f32;
+                    }
+                }
+                allocator.zero_grads();
+                allocator.backward_from(loss);
+                grads.push((
+                    allocator.gather_grads(&layer.parameters()),
+                    allocator.gather_grads(&inputs),
+                ));
+            }
+            assert_close(&grads[0].0, &grads[1].0);
+            assert_close(&grads[0].1, &grads[1].1);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn test_gpu_host_fallback() {
+        let gpu = gpu();
+        let mut allocator = Allocator::<f32>::new();
+        let rng = &mut StdRng::seed_from_u64(0);
+        let neuron = Neuron::without_bias_with_rng(&mut allocator, 3, None, Init::default(), rng);
+        let batch = [[1.0, 2.0, 3.0].map(|x| allocator.alloc_t(x))];
+
+        // Neurons without a bias only skip its gradient
+        let layer = Layer {
+            neurons: vec![neuron],
+        };
+        let output = layer.forward_batch_gpu(&gpu, &batch)[0][0];
+        let expected = layer.forward_batch(&batch)[0][0];
+        assert!((allocator.get(output).data - allocator.get(expected).data).abs() < 1e-5);
+
+        // Activations without a kernel run on the host
+        let layer = Layer::with_rng(
+            &mut allocator,
+            3,
+            2,
+            Some(Activation::LeakyRelu(0.1)),
+            Init::default(),
+            rng,
+        );
+        let outputs = layer.forward_batch_gpu(&gpu, &batch);
+        assert_ne!(allocator.get(outputs[0][0]).op, Some(Op::Fused));
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn test_gpu_training() {
+        let gpu = gpu();
+        let mut allocator = Allocator::<f32>::new();
+        let mlp = MLP::with_rng(
+            &mut allocator,
+            &[2, 8, 1],
+            Some(Activation::Tanh),
+            Init::default(),
+            &mut StdRng::seed_from_u64(0),
+        );
+        let mut optimizer = SGD::with_momentum(mlp.parameters(), 0.05, 0.9);
+        let samples = [([0.0, 1.0], 0.5), ([1.0, 0.0], -0.5)];
+        let mut losses = vec![];
+        for _ in 0..100 {
+            let batch = samples.map(|(x, _)| x.map(|x| allocator.alloc_t(x)));
+            let outputs = mlp.forward_batch_gpu(&gpu, &batch);
+            let mut total = allocator.alloc_t(0.0);
+            for (output, (_, target)) in outputs.iter().zip(samples) {
+                let diff = output[0] - target;
+                total = total + diff * diff;
+            }
+            losses.push(allocator.get(total).data);
+            allocator.backward();
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+        assert!(losses[99] < losses[0] * 0.1);
+    }
+}
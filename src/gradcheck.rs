@@ -0,0 +1,121 @@
+use std::cmp::Ordering;
+
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::Num,
+};
+
+// An input whose analytic gradient disagrees with the central difference estimate
+pub struct Mismatch<T: Num> {
+    pub index: usize,
+    pub analytic: T,
+    pub numeric: T,
+    pub error: T,
+}
+
+// Compares the gradients backward computes for `f` at `inputs` against central differences
+// (f(x + eps) - f(x - eps)) / 2eps. The error is relative for gradients larger than one
+// and absolute otherwise, and every input whose error exceeds `tol` is reported. `f`
+// builds the graph from the input ids on a fresh allocator, and is called 2n + 1 times.
+pub fn gradcheck<T: Num>(
+    f: impl Fn(&mut Allocator<T>, &[ValueId<T>]) -> ValueId<T>,
+    inputs: &[T],
+    eps: T,
+    tol: T,
+) -> Result<(), Vec<Mismatch<T>>> {
+    let mut allocator = Allocator::new();
+    let ids = inputs
+        .iter()
+        .map(|x| allocator.alloc(*x))
+        .collect::<Vec<_>>();
+    let output = f(&mut allocator, &ids);
+    allocator.backward_from(output);
+    let analytic = allocator.gather_grads(&ids);
+
+    let mut evaluate = |index: usize, x: T| {
+        allocator.clear_temps();
+        allocator.get_mut(ids[index]).set_data(x);
+        let output = f(&mut allocator, &ids);
+        let data = allocator.get(output).data;
+        allocator.get_mut(ids[index]).set_data(inputs[index]);
+        data
+    };
+
+    let two = T::one() + T::one();
+    let mismatches = inputs
+        .iter()
+        .zip(analytic)
+        .enumerate()
+        .filter_map(|(index, (x, analytic))| {
+            let numeric = (evaluate(index, *x + eps) - evaluate(index, *x - eps)) / (two * eps);
+            let scale = [T::one(), abs(analytic), abs(numeric)]
+                .into_iter()
+                .fold(T::zero(), |acc, x| if x > acc { x } else { acc });
+            let error = abs(analytic - numeric) / scale;
+            // A NaN error counts as a mismatch too
+            let within = matches!(
+                error.partial_cmp(&tol),
+                Some(Ordering::Less | Ordering::Equal)
+            );
+            (!within).then_some(Mismatch {
+                index,
+                analytic,
+                numeric,
+                error,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches)
+    }
+}
+
+fn abs<T: Num>(x: T) -> T {
+    if x < T::zero() {
+        -x
+    } else {
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operators::{cosine_similarity, erf, tanh};
+
+    #[test]
+    fn test_gradcheck_operators() {
+        let f = |allocator: &mut Allocator<f64>, x: &[ValueId<f64>]| {
+            let cos = cosine_similarity(allocator, &x[..2], &x[2..]);
+            tanh(x[0] * x[1]) + erf(x[2]) / x[3] + cos
+        };
+        assert!(gradcheck(f, &[0.3, -1.2, 0.7, 2.0], 1e-6, 1e-6).is_ok());
+    }
+
+    fn wrong_backward(
+        allocator: &mut Allocator<f64>,
+        base_grad: f64,
+        _base_val: f64,
+        children: &[ValueId<f64>],
+    ) {
+        // Should be 2x
+        let x = allocator.get(children[0]).data;
+        allocator.get_mut(children[0]).add_grad(base_grad * x);
+    }
+
+    #[test]
+    fn test_gradcheck_reports_mismatches() {
+        let f = |allocator: &mut Allocator<f64>, x: &[ValueId<f64>]| {
+            let squared = allocator.get(x[0]).data.powi(2);
+            allocator.alloc_temp(squared, wrong_backward, [x[0]]) + x[1]
+        };
+        let mismatches = gradcheck(f, &[3.0, 1.0], 1e-6, 1e-6).err().unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].index, 0);
+        assert_eq!(mismatches[0].analytic, 3.0);
+        assert!((mismatches[0].numeric - 6.0).abs() < 1e-6);
+    }
+}
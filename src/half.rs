@@ -0,0 +1,374 @@
+use std::{
+    fmt::{self, Display},
+    ops::{Add, Div, Mul, Neg, Rem, Sub},
+};
+
+use num::{pow::Pow, FromPrimitive, One, ToPrimitive, Zero};
+use rand::{
+    distributions::uniform::{SampleBorrow, SampleUniform, UniformFloat, UniformSampler},
+    Rng,
+};
+
+use crate::{
+    allocator::{Allocator, ValueId},
+    op::Op,
+    operators::{cast_grad, Num},
+};
+
+// 16-bit floats for the generic engine. `Num` needs traits from `num` and `rand` that
+// can't be implemented for `half`'s types directly, so they are wrapped. Storage is 16
+// bits, while every op computes in f32 and rounds the result, like most f16 hardware.
+macro_rules! half_float {
+    ($name:ident, $inner:ty) => {
+        #[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub struct $name(pub $inner);
+
+        impl $name {
+            #[inline(always)]
+            pub fn from_f32(x: f32) -> Self {
+                $name(<$inner>::from_f32(x))
+            }
+
+            #[inline(always)]
+            pub fn to_f32(self) -> f32 {
+                self.0.to_f32()
+            }
+
+            #[inline(always)]
+            fn map(self, f: impl Fn(f32) -> f32) -> Self {
+                Self::from_f32(f(self.to_f32()))
+            }
+        }
+
+        impl HalfFloat for $name {
+            #[inline(always)]
+            fn to_f32(self) -> f32 {
+                $name::to_f32(self)
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                Display::fmt(&self.0, f)
+            }
+        }
+
+        half_float!(@binary $name, Add, add, +);
+        half_float!(@binary $name, Sub, sub, -);
+        half_float!(@binary $name, Mul, mul, *);
+        half_float!(@binary $name, Div, div, /);
+        half_float!(@binary $name, Rem, rem, %);
+
+        impl Neg for $name {
+            type Output = Self;
+
+            #[inline(always)]
+            fn neg(self) -> Self {
+                $name(-self.0)
+            }
+        }
+
+        impl Pow<$name> for $name {
+            type Output = Self;
+
+            #[inline(always)]
+            fn pow(self, exponent: Self) -> Self {
+                Self::from_f32(self.to_f32().powf(exponent.to_f32()))
+            }
+        }
+
+        impl Zero for $name {
+            fn zero() -> Self {
+                $name(<$inner>::ZERO)
+            }
+
+            fn is_zero(&self) -> bool {
+                self.0 == <$inner>::ZERO
+            }
+        }
+
+        impl One for $name {
+            fn one() -> Self {
+                $name(<$inner>::ONE)
+            }
+        }
+
+        impl num::Num for $name {
+            type FromStrRadixErr = <f32 as num::Num>::FromStrRadixErr;
+
+            fn from_str_radix(s: &str, radix: u32) -> Result<Self, Self::FromStrRadixErr> {
+                f32::from_str_radix(s, radix).map(Self::from_f32)
+            }
+        }
+
+        impl FromPrimitive for $name {
+            fn from_i64(n: i64) -> Option<Self> {
+                Some(Self::from_f32(n as f32))
+            }
+
+            fn from_u64(n: u64) -> Option<Self> {
+                Some(Self::from_f32(n as f32))
+            }
+
+            fn from_f64(x: f64) -> Option<Self> {
+                Some($name(<$inner>::from_f64(x)))
+            }
+        }
+
+        impl SampleUniform for $name {
+            type Sampler = Uniform<$name>;
+        }
+
+        impl Num for $name {
+            #[inline(always)]
+            fn exp(self) -> Self {
+                self.map(f32::exp)
+            }
+
+            #[inline(always)]
+            fn ln(self) -> Self {
+                self.map(f32::ln)
+            }
+
+            #[inline(always)]
+            fn log(self, base: Self) -> Self {
+                self.map(|x| x.log(base.to_f32()))
+            }
+
+            #[inline(always)]
+            fn log2(self) -> Self {
+                self.map(f32::log2)
+            }
+
+            #[inline(always)]
+            fn log10(self) -> Self {
+                self.map(f32::log10)
+            }
+
+            #[inline(always)]
+            fn powi(self, n: i32) -> Self {
+                self.map(|x| x.powi(n))
+            }
+
+            #[inline(always)]
+            fn tanh(self) -> Self {
+                self.map(f32::tanh)
+            }
+
+            #[inline(always)]
+            fn erf(self) -> Self {
+                self.map(libm::erff)
+            }
+
+            #[inline(always)]
+            fn sqrt(self) -> Self {
+                self.map(f32::sqrt)
+            }
+        }
+    };
+    (@binary $name:ident, $trait:ident, $method:ident, $op:tt) => {
+        impl $trait for $name {
+            type Output = Self;
+
+            #[inline(always)]
+            fn $method(self, other: Self) -> Self {
+                Self::from_f32(self.to_f32() $op other.to_f32())
+            }
+        }
+    };
+}
+
+// The 16-bit types, which widen to f32 for mixed-precision training. Either the tape runs
+// in 16 bits with f32 master weights in the optimizer, see `optim::mixed::MixedPrecision`,
+// or it runs in f32 with 16-bit casts on it, see `cast` and `nn::Autocast`. Rounding from
+// f32 goes through `FromPrimitive`.
+pub trait HalfFloat: Num {
+    fn to_f32(self) -> f32;
+}
+
+half_float!(F16, ::half::f16);
+half_float!(BF16, ::half::bf16);
+
+// Casts at the boundary of a model running in 16 bits, whose inputs and outputs are f32
+impl<H: HalfFloat> Allocator<H> {
+    pub fn alloc_t_f32(&mut self, data: &[f32]) -> Vec<ValueId<H>> {
+        data.iter()
+            .map(|x| self.alloc_t(H::from_f32(*x).unwrap()))
+            .collect()
+    }
+
+    pub fn gather_data_f32(&self, values: &[ValueId<H>]) -> Vec<f32> {
+        values.iter().map(|v| self.get(*v).data.to_f32()).collect()
+    }
+}
+
+// Rounds `x` to the precision and range of `H` while staying in `T`, so parts of an f32 or
+// f64 model can run in 16 bits next to parameters kept at full precision. The gradient
+// passes through unchanged, like PyTorch's casts.
+pub fn cast<H: HalfFloat, T: Num + ToPrimitive>(x: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = x.allocator().as_mut().unwrap();
+        let autocast = allocator.autocast.take();
+        let result = allocator.record_op(cast_forward::<T, H>, Op::Cast, Some(cast_grad::<T>), [x]);
+        allocator.autocast = autocast;
+        result
+    }
+}
+
+pub(crate) fn cast_forward<T: Num + ToPrimitive, H: HalfFloat>(
+    allocator: &Allocator<T>,
+    children: &[ValueId<T>],
+) -> T {
+    let x = allocator.get(children[0]).data.to_f64().unwrap();
+    T::from_f32(H::from_f64(x).unwrap().to_f32()).unwrap()
+}
+
+impl<T: Num + ToPrimitive> Allocator<T> {
+    // While set, every recorded op runs as if in `H`: its result is cast to `H`, and so are
+    // the permanent values it reads, e.g. parameters, which keep their full precision for
+    // the optimizer. Temporaries from before are read as they are, so inputs are cast
+    // with `cast` first. See `nn::Autocast` for the same at the boundary of a module.
+    pub fn set_autocast<H: HalfFloat>(&mut self, enabled: bool) {
+        self.autocast = enabled.then_some(cast_forward::<T, H> as _);
+    }
+}
+
+// Samples in f32 and rounds, resampling values that round up to an exclusive upper bound
+pub struct Uniform<X> {
+    sampler: UniformFloat<f32>,
+    high: X,
+    inclusive: bool,
+}
+
+macro_rules! half_uniform {
+    ($name:ident) => {
+        impl UniformSampler for Uniform<$name> {
+            type X = $name;
+
+            fn new<B1, B2>(low: B1, high: B2) -> Self
+            where
+                B1: SampleBorrow<$name> + Sized,
+                B2: SampleBorrow<$name> + Sized,
+            {
+                let (low, high) = (*low.borrow(), *high.borrow());
+                Uniform {
+                    sampler: UniformFloat::new(low.to_f32(), high.to_f32()),
+                    high,
+                    inclusive: false,
+                }
+            }
+
+            fn new_inclusive<B1, B2>(low: B1, high: B2) -> Self
+            where
+                B1: SampleBorrow<$name> + Sized,
+                B2: SampleBorrow<$name> + Sized,
+            {
+                let (low, high) = (*low.borrow(), *high.borrow());
+                Uniform {
+                    sampler: UniformFloat::new_inclusive(low.to_f32(), high.to_f32()),
+                    high,
+                    inclusive: true,
+                }
+            }
+
+            fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> $name {
+                loop {
+                    let x = $name::from_f32(self.sampler.sample(rng));
+                    if self.inclusive || x < self.high {
+                        return x;
+                    }
+                }
+            }
+        }
+    };
+}
+
+half_uniform!(F16);
+half_uniform!(BF16);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::Allocator,
+        nn::{Activation, MLP},
+        operators::tanh,
+    };
+
+    #[test]
+    fn test_half_arithmetic() {
+        let mut allocator = Allocator::<F16>::new();
+        let x = allocator.alloc(F16::from_f32(0.5));
+        let y = tanh(x * x + F16::from_f32(1.0));
+        allocator.backward();
+
+        let expected = 1.25f32.tanh();
+        assert!((allocator.get(y).data.to_f32() - expected).abs() < 1e-3);
+        let grad = (1.0 - expected * expected) * 2.0 * 0.5;
+        assert!((allocator.get(x).grad.to_f32() - grad).abs() < 1e-3);
+
+        // bf16 keeps f32's range at the cost of precision
+        let big = BF16::from_f32(1e30);
+        assert!((big * big).to_f32().is_infinite());
+        assert!((BF16::from_f32(1e20) * BF16::from_f32(1e10))
+            .to_f32()
+            .is_finite());
+        assert!(F16::from_f32(1e5).to_f32().is_infinite());
+    }
+
+    #[test]
+    fn test_half_sampling() {
+        let mut rng = rand::thread_rng();
+        let (low, high) = (F16::from_f32(-1.0), F16::from_f32(1.0));
+        for _ in 0..1000 {
+            let x = rng.gen_range(low..high);
+            assert!(x >= low && x < high);
+        }
+
+        let mut allocator = Allocator::<BF16>::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        let inputs = vec![allocator.alloc(BF16::one()), allocator.alloc(BF16::zero())];
+        let output = mlp.forward(&inputs)[0];
+        assert!(allocator.get(output).data.to_f32().abs() <= 1.0);
+    }
+
+    #[test]
+    fn test_cast() {
+        let mut allocator = Allocator::<f64>::new();
+        let x = allocator.alloc(0.1f64);
+        let y = cast::<F16, _>(x) * 3.0;
+        assert_eq!(
+            allocator.get(y).data,
+            F16::from_f32(0.1).to_f32() as f64 * 3.0
+        );
+        let big = allocator.alloc_t(1e5);
+        let (narrow, wide) = (cast::<F16, _>(big), cast::<BF16, _>(big));
+        assert_eq!(allocator.get(narrow).data, f64::INFINITY);
+        assert!(allocator.get(wide).data.is_finite());
+
+        // Straight through, and recomputed with the rounding by a replay
+        allocator.backward_from(y);
+        assert_eq!(allocator.get(x).grad, 3.0);
+        allocator.get_mut(x).set_data(0.3);
+        allocator.replay_forward();
+        assert_eq!(
+            allocator.get(y).data,
+            F16::from_f32(0.3).to_f32() as f64 * 3.0
+        );
+        allocator.clear_temps();
+
+        // Autocast rounds what ops read from parameters and what they produce
+        allocator.set_autocast::<F16>(true);
+        let z = allocator.alloc_t(1.0) + x;
+        allocator.set_autocast::<F16>(false);
+        let plain = allocator.alloc_t(1.0) + x;
+        assert_eq!(allocator.get(z).op, Some(Op::Cast));
+        assert_eq!(
+            allocator.get(z).data,
+            F16::from_f64(1.3).unwrap().to_f32() as f64
+        );
+        assert_eq!(allocator.get(plain).data, 1.3);
+        assert_eq!(allocator.get(x).data, 0.3);
+    }
+}
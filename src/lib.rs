@@ -1,18 +1,28 @@
 pub mod allocator;
 pub mod engine;
+#[cfg(feature = "fixed")]
+pub mod fixed;
+#[cfg(feature = "wgpu")]
+pub mod gpu;
+pub mod gradcheck;
+#[cfg(feature = "half")]
+pub mod half;
+pub mod losses;
 pub mod nn;
+pub mod op;
 pub mod operators;
+pub mod optim;
 
 #[cfg(test)]
+#[allow(clippy::useless_vec, clippy::clone_on_copy)]
 mod tests {
     use crate::allocator::Allocator;
-    use crate::nn::MLP;
-    use crate::operators::tanh;
+    use crate::nn::{Activation, MLP};
 
     #[test]
     fn test_mlp_training() {
         let mut allocator = Allocator::new();
-        let mut mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(tanh));
+        let mut mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
 
         let inputs = vec![
             vec![allocator.alloc(0.0), allocator.alloc(0.0)],
@@ -45,4 +55,51 @@ mod tests {
             assert!(diff.abs() < 0.3);
         }
     }
+
+    #[test]
+    fn test_static_graph_replay() {
+        let mut allocator = Allocator::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        let initial = mlp.get_flat_params(&allocator);
+        let samples = [([0.0, 0.0], 0.0), ([0.0, 1.0], 1.0), ([1.0, 0.0], 1.0)];
+
+        // Rebuilding the tape for every sample
+        let mut rebuilt = vec![];
+        for _ in 0..50 {
+            for (input, target) in samples {
+                let input = input.map(|x| allocator.alloc_t(x));
+                let output = mlp.forward(&input)[0];
+                let diff = output - target;
+                let loss = diff * diff;
+                rebuilt.push(allocator.get(loss).data);
+                allocator.backward();
+                mlp.parameters().iter().for_each(|p| p.step(0.1));
+                allocator.clear_temps();
+            }
+        }
+
+        // Recording it once and replaying it with new inputs and targets
+        mlp.set_flat_params(&mut allocator, &initial);
+        let input = [allocator.alloc_t(0.0), allocator.alloc_t(0.0)];
+        let target = allocator.alloc_t(0.0);
+        let diff = mlp.forward(&input)[0] - target;
+        let loss = diff * diff;
+        let tape_len = allocator.tape_len();
+        let mut replayed = vec![];
+        for _ in 0..50 {
+            for (x, y) in samples {
+                allocator.scatter_data(&input, &x);
+                allocator.scatter_data(&[target], &[y]);
+                allocator.replay_forward();
+                replayed.push(allocator.get(loss).data);
+                allocator.backward();
+                mlp.parameters().iter().for_each(|p| p.step(0.1));
+            }
+        }
+
+        assert_eq!(allocator.tape_len(), tape_len);
+        assert_eq!(rebuilt, replayed);
+        let epoch_loss = |epoch: &[f64]| epoch.iter().sum::<f64>();
+        assert!(epoch_loss(&replayed[147..]) < epoch_loss(&replayed[..3]));
+    }
 }
@@ -0,0 +1,702 @@
+use crate::{
+    allocator::{Allocator, ValueId},
+    op::Op,
+    operators::{self, cosine_similarity, ln, relu, Num},
+};
+
+pub fn mse<T: Num>(
+    allocator: &mut Allocator<T>,
+    outputs: &[ValueId<T>],
+    targets: &[ValueId<T>],
+) -> ValueId<T> {
+    assert_eq!(
+        outputs.len(),
+        targets.len(),
+        "mse with mismatched output and target lengths"
+    );
+    assert!(!outputs.is_empty(), "mse of empty slices");
+
+    let children = outputs.iter().chain(targets).copied().collect::<Vec<_>>();
+    allocator.record_op(mse_forward::<T>, Op::Mse, Some(mse_grad::<T>), children)
+}
+
+fn mse_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (outputs, targets) = children.split_at(children.len() / 2);
+    let n = T::from_usize(outputs.len()).unwrap();
+    outputs.iter().zip(targets).fold(T::zero(), |acc, (o, t)| {
+        let diff = allocator.get(*o).data - allocator.get(*t).data;
+        acc + diff * diff
+    }) / n
+}
+
+// Children are laid out as [outputs..., targets...]
+pub(crate) fn mse_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let (outputs, targets) = children.split_at(children.len() / 2);
+    let scale = base_grad * (T::one() + T::one()) / T::from_usize(outputs.len()).unwrap();
+    for (o, t) in outputs.iter().zip(targets) {
+        let diff = allocator.get(*o).data - allocator.get(*t).data;
+        allocator.get_mut(*o).add_grad(scale * diff);
+        allocator.get_mut(*t).add_grad(-scale * diff);
+    }
+}
+
+fn mse_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (outputs, targets) = children.split_at(children.len() / 2);
+    let scale = grad * ((T::one() + T::one()) / T::from_usize(outputs.len()).unwrap());
+    let grads = outputs
+        .iter()
+        .zip(targets)
+        .map(|(o, t)| scale * (*o - *t))
+        .collect::<Vec<_>>();
+    let negated = grads.iter().map(|g| Some(-*g)).collect::<Vec<_>>();
+    grads.into_iter().map(Some).chain(negated).collect()
+}
+
+// Quadratic for |o - t| <= delta and linear beyond it, averaged over all elements
+pub fn huber<T: Num>(
+    allocator: &mut Allocator<T>,
+    outputs: &[ValueId<T>],
+    targets: &[ValueId<T>],
+    delta: T,
+) -> ValueId<T> {
+    assert_eq!(
+        outputs.len(),
+        targets.len(),
+        "huber with mismatched output and target lengths"
+    );
+    assert!(!outputs.is_empty(), "huber of empty slices");
+    assert!(delta > T::zero(), "huber delta must be positive");
+
+    let mut children = Vec::with_capacity(outputs.len() * 2 + 1);
+    children.extend_from_slice(outputs);
+    children.extend_from_slice(targets);
+    children.push(allocator.alloc_const(delta));
+    allocator.record_op(
+        huber_forward::<T>,
+        Op::Huber,
+        Some(huber_grad::<T>),
+        children,
+    )
+}
+
+fn huber_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (delta, rest) = children.split_last().unwrap();
+    let delta = allocator.get(*delta).data;
+    let (outputs, targets) = rest.split_at(rest.len() / 2);
+    let half = T::one() / (T::one() + T::one());
+    let n = T::from_usize(outputs.len()).unwrap();
+    outputs.iter().zip(targets).fold(T::zero(), |acc, (o, t)| {
+        let diff = allocator.get(*o).data - allocator.get(*t).data;
+        let abs = if diff < T::zero() { -diff } else { diff };
+        acc + if abs <= delta {
+            half * diff * diff
+        } else {
+            delta * (abs - half * delta)
+        }
+    }) / n
+}
+
+// Children are laid out as [outputs..., targets..., delta]
+pub(crate) fn huber_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let (delta, rest) = children.split_last().unwrap();
+    let delta = allocator.get(*delta).data;
+    let (outputs, targets) = rest.split_at(rest.len() / 2);
+    let scale = base_grad / T::from_usize(outputs.len()).unwrap();
+    for (o, t) in outputs.iter().zip(targets) {
+        let diff = allocator.get(*o).data - allocator.get(*t).data;
+        let grad = if diff > delta {
+            delta
+        } else if diff < -delta {
+            -delta
+        } else {
+            diff
+        };
+        allocator.get_mut(*o).add_grad(scale * grad);
+        allocator.get_mut(*t).add_grad(-scale * grad);
+    }
+}
+
+fn huber_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (delta, rest) = children.split_last().unwrap();
+    let delta = allocator.get(*delta).data;
+    let (outputs, targets) = rest.split_at(rest.len() / 2);
+    let scale = grad / T::from_usize(outputs.len()).unwrap();
+    let grads = outputs
+        .iter()
+        .zip(targets)
+        .map(|(o, t)| {
+            let diff = allocator.get(*o).data - allocator.get(*t).data;
+            if diff > delta {
+                scale * delta
+            } else if diff < -delta {
+                scale * -delta
+            } else {
+                scale * (*o - *t)
+            }
+        })
+        .collect::<Vec<_>>();
+    let negated = grads.iter().map(|g| Some(-*g)).collect::<Vec<_>>();
+    grads
+        .into_iter()
+        .map(Some)
+        .chain(negated)
+        .chain([None])
+        .collect()
+}
+
+// Softmax followed by the negative log-likelihood of `target`, fused into one node
+pub fn cross_entropy<T: Num>(
+    allocator: &mut Allocator<T>,
+    logits: &[ValueId<T>],
+    target: usize,
+) -> ValueId<T> {
+    assert!(target < logits.len(), "cross_entropy target out of range");
+
+    // The target logit is repeated as the last child, so the backward doesn't need to
+    // know its index: it receives softmax(logits) like every other logit and then -1
+    let mut children = Vec::with_capacity(logits.len() + 1);
+    children.extend_from_slice(logits);
+    children.push(logits[target]);
+    allocator.record_op(
+        cross_entropy_forward::<T>,
+        Op::CrossEntropy,
+        Some(cross_entropy_grad::<T>),
+        children,
+    )
+}
+
+fn cross_entropy_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (target, logits) = children.split_last().unwrap();
+    let data = logits
+        .iter()
+        .map(|l| allocator.get(*l).data)
+        .collect::<Vec<_>>();
+    log_sum_exp(&data) - allocator.get(*target).data
+}
+
+pub(crate) fn cross_entropy_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let (target, logits) = children.split_last().unwrap();
+    let data = logits
+        .iter()
+        .map(|l| allocator.get(*l).data)
+        .collect::<Vec<_>>();
+    let probs = softmax(&data);
+    for (logit, p) in logits.iter().zip(probs) {
+        allocator.get_mut(*logit).add_grad(base_grad * p);
+    }
+    allocator.get_mut(*target).add_grad(-base_grad);
+}
+
+fn cross_entropy_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (_, logits) = children.split_last().unwrap();
+    operators::softmax(allocator, logits)
+        .into_iter()
+        .map(|p| Some(grad * p))
+        .chain([Some(-grad)])
+        .collect()
+}
+
+// Negative log-likelihood of `target` given log-probabilities, e.g. from a log-softmax
+pub fn nll<T: Num>(
+    allocator: &mut Allocator<T>,
+    log_probs: &[ValueId<T>],
+    target: usize,
+) -> ValueId<T> {
+    assert!(target < log_probs.len(), "nll target out of range");
+
+    allocator.record_op(
+        nll_forward::<T>,
+        Op::Nll,
+        Some(nll_grad::<T>),
+        [log_probs[target]],
+    )
+}
+
+fn nll_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    -allocator.get(children[0]).data
+}
+
+pub(crate) fn nll_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    allocator.get_mut(children[0]).add_grad(-base_grad);
+}
+
+fn nll_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(-grad)]
+}
+
+// KL(p || q) = sum(p_i * ln(p_i / q_i)), with 0 * ln(0) taken as 0
+pub fn kl_div<T: Num>(
+    allocator: &mut Allocator<T>,
+    p: &[ValueId<T>],
+    q: &[ValueId<T>],
+) -> ValueId<T> {
+    assert_eq!(
+        p.len(),
+        q.len(),
+        "kl_div of distributions with different lengths"
+    );
+
+    let children = p.iter().chain(q).copied().collect::<Vec<_>>();
+    allocator.record_op(
+        kl_div_forward::<T>,
+        Op::KlDiv,
+        Some(kl_div_grad::<T>),
+        children,
+    )
+}
+
+fn kl_div_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (p, q) = children.split_at(children.len() / 2);
+    p.iter().zip(q).fold(T::zero(), |acc, (p, q)| {
+        let p = allocator.get(*p).data;
+        let q = allocator.get(*q).data;
+        if p > T::zero() {
+            acc + p * (p / q).ln()
+        } else {
+            acc
+        }
+    })
+}
+
+// Children are laid out as [p..., q...]
+pub(crate) fn kl_div_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let (p_ids, q_ids) = children.split_at(children.len() / 2);
+    for (p_id, q_id) in p_ids.iter().zip(q_ids) {
+        let p = allocator.get(*p_id).data;
+        let q = allocator.get(*q_id).data;
+        if p > T::zero() {
+            allocator
+                .get_mut(*p_id)
+                .add_grad(base_grad * ((p / q).ln() + T::one()));
+            allocator.get_mut(*q_id).add_grad(-base_grad * p / q);
+        }
+    }
+}
+
+fn kl_div_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (p, q) = children.split_at(children.len() / 2);
+    let mut p_grads = Vec::with_capacity(p.len());
+    let mut q_grads = Vec::with_capacity(q.len());
+    for (p, q) in p.iter().zip(q) {
+        if allocator.get(*p).data > T::zero() {
+            p_grads.push(Some(grad * (ln(*p) - ln(*q) + T::one())));
+            q_grads.push(Some(-(grad * *p / *q)));
+        } else {
+            p_grads.push(None);
+            q_grads.push(None);
+        }
+    }
+    p_grads.extend(q_grads);
+    p_grads
+}
+
+// 1 - cos(a, b) for similar pairs and max(0, cos(a, b) - margin) for dissimilar ones
+pub fn cosine_embedding_loss<T: Num>(
+    allocator: &mut Allocator<T>,
+    a: &[ValueId<T>],
+    b: &[ValueId<T>],
+    similar: bool,
+    margin: T,
+) -> ValueId<T> {
+    let cos = cosine_similarity(allocator, a, b);
+    if similar {
+        -cos + T::one()
+    } else {
+        relu(cos - margin)
+    }
+}
+
+// lambda * sum(p_i^2), to be added onto a loss
+pub fn l2_penalty<T: Num>(
+    allocator: &mut Allocator<T>,
+    params: &[ValueId<T>],
+    lambda: T,
+) -> ValueId<T> {
+    let mut children = Vec::with_capacity(params.len() + 1);
+    children.extend_from_slice(params);
+    children.push(allocator.alloc_const(lambda));
+    allocator.record_op(
+        l2_penalty_forward::<T>,
+        Op::L2Penalty,
+        Some(l2_penalty_grad::<T>),
+        children,
+    )
+}
+
+fn l2_penalty_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (lambda, params) = children.split_last().unwrap();
+    allocator.get(*lambda).data
+        * params.iter().fold(T::zero(), |acc, p| {
+            let p = allocator.get(*p).data;
+            acc + p * p
+        })
+}
+
+// Children are laid out as [params..., lambda]
+pub(crate) fn l2_penalty_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let (lambda, params) = children.split_last().unwrap();
+    let scale = base_grad * allocator.get(*lambda).data * (T::one() + T::one());
+    for p in params {
+        let data = allocator.get(*p).data;
+        allocator.get_mut(*p).add_grad(scale * data);
+    }
+}
+
+fn l2_penalty_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (lambda, params) = children.split_last().unwrap();
+    let scale = grad * (allocator.get(*lambda).data * (T::one() + T::one()));
+    params
+        .iter()
+        .map(|p| Some(scale * *p))
+        .chain([None])
+        .collect()
+}
+
+// lambda * sum(|p_i|), to be added onto a loss
+pub fn l1_penalty<T: Num>(
+    allocator: &mut Allocator<T>,
+    params: &[ValueId<T>],
+    lambda: T,
+) -> ValueId<T> {
+    let mut children = Vec::with_capacity(params.len() + 1);
+    children.extend_from_slice(params);
+    children.push(allocator.alloc_const(lambda));
+    allocator.record_op(
+        l1_penalty_forward::<T>,
+        Op::L1Penalty,
+        Some(l1_penalty_grad::<T>),
+        children,
+    )
+}
+
+fn l1_penalty_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (lambda, params) = children.split_last().unwrap();
+    allocator.get(*lambda).data
+        * params.iter().fold(T::zero(), |acc, p| {
+            let p = allocator.get(*p).data;
+            acc + if p < T::zero() { -p } else { p }
+        })
+}
+
+// Children are laid out as [params..., lambda], with sign(0) = 0 as in `abs`
+pub(crate) fn l1_penalty_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let (lambda, params) = children.split_last().unwrap();
+    let scale = base_grad * allocator.get(*lambda).data;
+    for p in params {
+        let data = allocator.get(*p).data;
+        if data > T::zero() {
+            allocator.get_mut(*p).add_grad(scale);
+        } else if data < T::zero() {
+            allocator.get_mut(*p).add_grad(-scale);
+        }
+    }
+}
+
+fn l1_penalty_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (lambda, params) = children.split_last().unwrap();
+    let scale = grad * allocator.get(*lambda).data;
+    params
+        .iter()
+        .map(|p| {
+            let data = allocator.get(*p).data;
+            if data > T::zero() {
+                Some(scale)
+            } else if data < T::zero() {
+                Some(-scale)
+            } else {
+                None
+            }
+        })
+        .chain([None])
+        .collect()
+}
+
+fn max<T: Num>(data: &[T]) -> T {
+    data.iter()
+        .fold(data[0], |acc, x| if *x > acc { *x } else { acc })
+}
+
+fn log_sum_exp<T: Num>(data: &[T]) -> T {
+    let max = max(data);
+    data.iter()
+        .fold(T::zero(), |acc, x| acc + (*x - max).exp())
+        .ln()
+        + max
+}
+
+fn softmax<T: Num>(data: &[T]) -> Vec<T> {
+    let max = max(data);
+    let exps = data.iter().map(|x| (*x - max).exp()).collect::<Vec<_>>();
+    let total = exps.iter().fold(T::zero(), |acc, x| acc + *x);
+    exps.into_iter().map(|x| x / total).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mse() {
+        let mut allocator = Allocator::new();
+        let outputs = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
+        let targets = vec![allocator.alloc(0.0), allocator.alloc(4.0)];
+        let loss = mse(&mut allocator, &outputs, &targets);
+        assert_eq!(allocator.get(loss).data, 2.5);
+
+        allocator.backward();
+        assert_eq!(allocator.get(outputs[0]).grad, 1.0);
+        assert_eq!(allocator.get(outputs[1]).grad, -2.0);
+        assert_eq!(allocator.get(targets[0]).grad, -1.0);
+        assert_eq!(allocator.get(targets[1]).grad, 2.0);
+    }
+
+    #[test]
+    fn test_cross_entropy() {
+        let mut allocator = Allocator::new();
+        let logits = vec![
+            allocator.alloc(1.0),
+            allocator.alloc(2.0),
+            allocator.alloc(3.0),
+        ];
+        let loss = cross_entropy(&mut allocator, &logits, 1);
+
+        let total = 1.0f64.exp() + 2.0f64.exp() + 3.0f64.exp();
+        let expected = total.ln() - 2.0;
+        assert!((allocator.get(loss).data - expected).abs() < 1e-12);
+
+        allocator.backward();
+        assert!((allocator.get(logits[0]).grad - 1.0f64.exp() / total).abs() < 1e-12);
+        assert!((allocator.get(logits[1]).grad - (2.0f64.exp() / total - 1.0)).abs() < 1e-12);
+        assert!((allocator.get(logits[2]).grad - 3.0f64.exp() / total).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_cross_entropy_large_logits() {
+        let mut allocator = Allocator::new();
+        let logits = vec![allocator.alloc(1000.0), allocator.alloc(1000.0)];
+        let loss = cross_entropy(&mut allocator, &logits, 0);
+        assert!((allocator.get(loss).data - 2.0f64.ln()).abs() < 1e-12);
+
+        allocator.backward();
+        assert_eq!(allocator.get(logits[0]).grad, -0.5);
+        assert_eq!(allocator.get(logits[1]).grad, 0.5);
+    }
+
+    #[test]
+    fn test_nll() {
+        let mut allocator = Allocator::new();
+        let log_probs = vec![allocator.alloc(0.25f64.ln()), allocator.alloc(0.75f64.ln())];
+        let loss = nll(&mut allocator, &log_probs, 1);
+        assert_eq!(allocator.get(loss).data, -(0.75f64.ln()));
+
+        allocator.backward();
+        assert_eq!(allocator.get(log_probs[0]).grad, 0.0);
+        assert_eq!(allocator.get(log_probs[1]).grad, -1.0);
+    }
+
+    #[test]
+    fn test_huber() {
+        let mut allocator = Allocator::new();
+        let outputs = vec![allocator.alloc(0.5), allocator.alloc(5.0)];
+        let targets = vec![allocator.alloc(0.0), allocator.alloc(1.0)];
+        let loss = huber(&mut allocator, &outputs, &targets, 1.0);
+        // (0.5 * 0.5^2 + 1.0 * (4.0 - 0.5)) / 2
+        assert_eq!(allocator.get(loss).data, 1.8125);
+
+        allocator.backward();
+        assert_eq!(allocator.get(outputs[0]).grad, 0.25);
+        assert_eq!(allocator.get(outputs[1]).grad, 0.5);
+        assert_eq!(allocator.get(targets[0]).grad, -0.25);
+        assert_eq!(allocator.get(targets[1]).grad, -0.5);
+    }
+
+    #[test]
+    fn test_kl_div() {
+        let mut allocator = Allocator::new();
+        let p = vec![
+            allocator.alloc(0.5),
+            allocator.alloc(0.5),
+            allocator.alloc(0.0),
+        ];
+        let q = vec![
+            allocator.alloc(0.25),
+            allocator.alloc(0.5),
+            allocator.alloc(0.25),
+        ];
+        let loss = kl_div(&mut allocator, &p, &q);
+        assert_eq!(allocator.get(loss).data, 0.5 * 2.0f64.ln());
+
+        allocator.backward();
+        assert_eq!(allocator.get(p[0]).grad, 2.0f64.ln() + 1.0);
+        assert_eq!(allocator.get(p[1]).grad, 1.0);
+        assert_eq!(allocator.get(p[2]).grad, 0.0);
+        assert_eq!(allocator.get(q[0]).grad, -2.0);
+        assert_eq!(allocator.get(q[1]).grad, -1.0);
+        assert_eq!(allocator.get(q[2]).grad, 0.0);
+    }
+
+    #[test]
+    fn test_cosine_embedding_loss() {
+        let mut allocator = Allocator::new();
+        let a = vec![allocator.alloc(1.0), allocator.alloc(0.0)];
+        let b = vec![allocator.alloc(0.0), allocator.alloc(2.0)];
+
+        let loss = cosine_embedding_loss(&mut allocator, &a, &b, true, 0.0);
+        assert_eq!(allocator.get(loss).data, 1.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a[1]).grad, -1.0);
+        assert_eq!(allocator.get(b[0]).grad, -0.5);
+        allocator.zero_grads();
+
+        let loss = cosine_embedding_loss(&mut allocator, &a, &b, false, 0.0);
+        assert_eq!(allocator.get(loss).data, 0.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a[1]).grad, 0.0);
+        assert_eq!(allocator.get(b[0]).grad, 0.0);
+    }
+
+    #[test]
+    fn test_l2_penalty() {
+        let mut allocator = Allocator::new();
+        let params = vec![allocator.alloc(1.0), allocator.alloc(-2.0)];
+        let penalty = l2_penalty(&mut allocator, &params, 0.5);
+        assert_eq!(allocator.get(penalty).data, 2.5);
+
+        allocator.backward();
+        assert_eq!(allocator.get(params[0]).grad, 1.0);
+        assert_eq!(allocator.get(params[1]).grad, -2.0);
+    }
+
+    #[test]
+    fn test_l1_penalty() {
+        let mut allocator = Allocator::new();
+        let params = vec![
+            allocator.alloc(1.0),
+            allocator.alloc(-2.0),
+            allocator.alloc(0.0),
+        ];
+        let penalty = l1_penalty(&mut allocator, &params, 0.5);
+        assert_eq!(allocator.get(penalty).data, 1.5);
+
+        allocator.backward();
+        assert_eq!(allocator.get(params[0]).grad, 0.5);
+        assert_eq!(allocator.get(params[1]).grad, -0.5);
+        assert_eq!(allocator.get(params[2]).grad, 0.0);
+    }
+
+    #[test]
+    fn test_higher_order_grad_through_losses() {
+        let mut allocator = Allocator::<f64>::new();
+        let w = allocator.alloc(1.5);
+        let x = allocator.alloc(2.0);
+        let t = allocator.alloc(1.0);
+
+        // mse = (w x - t)^2, so d/dw = 2 x (w x - t) and d2/dw2 = 2 x^2
+        let loss = mse(&mut allocator, &[w * x], &[t]);
+        let dw = allocator.grad(loss, &[w])[0];
+        assert_eq!(allocator.get(dw).data, 8.0);
+        let dww = allocator.grad(dw, &[w])[0];
+        assert_eq!(allocator.get(dww).data, 8.0);
+
+        // d/dw of cross_entropy([w x, 0], 0) = x (softmax_0 - 1), matching backward
+        let zero = allocator.alloc_const(0.0);
+        let loss = cross_entropy(&mut allocator, &[w * x, zero], 0);
+        let dw = allocator.grad(loss, &[w])[0];
+        let p = 1.0 / (1.0 + (-3.0f64).exp());
+        assert!((allocator.get(dw).data - 2.0 * (p - 1.0)).abs() < 1e-12);
+        allocator.backward_from(loss);
+        assert!((allocator.get(w).grad - allocator.get(dw).data).abs() < 1e-12);
+
+        // Every other loss is differentiable by grad too
+        let q = allocator.alloc(0.25);
+        let r = allocator.alloc(0.75);
+        let losses = [
+            huber(&mut allocator, &[w * x], &[t], 1.0),
+            huber(&mut allocator, &[w], &[t], 1.0),
+            nll(&mut allocator, &[w * x], 0),
+            kl_div(&mut allocator, &[q, r], &[r, q]),
+            l2_penalty(&mut allocator, &[w, x], 0.5),
+            l1_penalty(&mut allocator, &[w, x], 0.5),
+        ];
+        for loss in losses {
+            let grads = allocator.grad(loss, &[w, q]);
+            allocator.zero_grads();
+            allocator.backward_from(loss);
+            for (param, grad) in [w, q].into_iter().zip(grads) {
+                assert!((allocator.get(param).grad - allocator.get(grad).data).abs() < 1e-12);
+            }
+        }
+    }
+}
@@ -1,27 +1,290 @@
+mod activation;
+mod attention;
+#[cfg(feature = "half")]
+mod autocast;
+mod autoencoder;
+mod conv;
+mod embedding;
+mod hooks;
+mod init;
+mod multi_head;
+mod noise;
+mod norm;
+mod pool;
+mod rnn;
+mod transformer;
+pub use activation::{Activation, PReLU};
+pub use attention::Attention;
+#[cfg(feature = "half")]
+pub use autocast::Autocast;
+pub use autoencoder::Autoencoder;
+pub use conv::{flatten, Conv1d, Conv2d};
+pub use embedding::Embedding;
+pub use hooks::{HookHandle, Hooked};
+pub use init::Init;
+pub use multi_head::MultiHead;
+pub use noise::GaussianNoise;
+pub use norm::LayerNorm;
+pub use pool::{AvgPool1d, AvgPool2d, MaxPool1d, MaxPool2d};
+pub use rnn::RnnCell;
+pub use transformer::TransformerBlock;
+
+use std::{collections::HashSet, ops::RangeBounds};
+
 use rand::Rng;
 
 use crate::{
     allocator::{Allocator, ValueId},
-    operators::Num,
+    operators::{affine, dot, dot_slices, record_affine, softmax, Num},
 };
 
+pub trait Module<T: Num> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>>;
+    fn parameters(&self) -> Vec<ValueId<T>>;
+
+    // Parameters in `parameters()` order, each with a '.'-separated path such as
+    // "0.1.weight.2" for weight 2 of neuron 1 in layer 0
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        self.parameters()
+            .into_iter()
+            .enumerate()
+            .map(|(i, param)| (i.to_string(), param))
+            .collect()
+    }
+
+    fn num_parameters(&self) -> usize {
+        self.parameters().len()
+    }
+
+    // A one-line description with shapes and activation, e.g. "Layer(2 -> 3, tanh)"
+    fn describe(&self) -> String {
+        let name = std::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name).to_string()
+    }
+
+    // A table of the module's layers and parameter counts. The allocator is needed to
+    // tell trainable parameters apart from frozen ones.
+    fn summary(&self, allocator: &Allocator<T>) -> String {
+        summary_table(
+            &[(self.describe(), self.num_parameters())],
+            &self.parameters(),
+            allocator,
+        )
+    }
+
+    // Gradient statistics per top-level submodule (the first component of each parameter
+    // path, e.g. the layer index of an MLP), to be called after `backward()`
+    fn grad_report(&self, allocator: &Allocator<T>) -> String {
+        let mut groups: Vec<(String, Vec<ValueId<T>>)> = vec![];
+        for (name, param) in self.named_parameters() {
+            let group = name.split('.').next().unwrap_or(&name).to_string();
+            match groups.last_mut() {
+                Some((last, params)) if *last == group => params.push(param),
+                _ => groups.push((group, vec![param])),
+            }
+        }
+
+        let mut rows = vec![["Layer", "Params", "Norm", "Mean", "Std", "Max"].map(String::from)];
+        for (name, params) in groups {
+            let stats = allocator.grad_stats(&params);
+            rows.push([
+                name,
+                stats.count.to_string(),
+                stats.norm.to_string(),
+                stats.mean.to_string(),
+                stats.std.to_string(),
+                stats.max.to_string(),
+            ]);
+        }
+
+        let widths = (0..6)
+            .map(|col| rows.iter().map(|row| row[col].len()).max().unwrap() + 2)
+            .collect::<Vec<_>>();
+        let mut report = String::new();
+        for row in &rows {
+            let cells = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect::<String>();
+            report.push_str(cells.trim_end());
+            report.push('\n');
+        }
+        report
+    }
+
+    // Switches between training and evaluation behaviour for modules that have one, such
+    // as noise injection. Containers forward it to every submodule.
+    fn set_training(&self, _training: bool) {}
+
+    fn train(&self) {
+        self.set_training(true);
+    }
+
+    fn eval(&self) {
+        self.set_training(false);
+    }
+
+    fn zero_grad(&self) {
+        for param in self.parameters() {
+            param.zero_grad();
+        }
+    }
+}
+
+fn summary_table<T: Num>(
+    rows: &[(String, usize)],
+    params: &[ValueId<T>],
+    allocator: &Allocator<T>,
+) -> String {
+    let width = rows
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(5)
+        + 4;
+    let trainable = params
+        .iter()
+        .filter(|param| allocator.get(**param).requires_grad)
+        .count();
+
+    let mut summary = format!("{:<width$}Params\n", "Layer");
+    for (name, count) in rows {
+        summary.push_str(&format!("{name:<width$}{count}\n"));
+    }
+    summary.push_str(&format!("Total params: {}\n", params.len()));
+    summary.push_str(&format!("Trainable params: {trainable}\n"));
+    summary.push_str(&format!(
+        "Non-trainable params: {}\n",
+        params.len() - trainable
+    ));
+    summary
+}
+
+fn activation_name<T: Num>(activation: &Option<Activation<T>>) -> String {
+    activation
+        .as_ref()
+        .map_or("linear".to_string(), |activation| activation.name())
+}
+
+// Prepends `prefix.` to every path
+pub(crate) fn prefixed<T: Num>(
+    prefix: &str,
+    named: Vec<(String, ValueId<T>)>,
+) -> Vec<(String, ValueId<T>)> {
+    named
+        .into_iter()
+        .map(|(name, param)| (format!("{prefix}.{name}"), param))
+        .collect()
+}
+
 pub struct Neuron<T: Num> {
     pub(crate) weights: Vec<ValueId<T>>,
-    pub(crate) bias: ValueId<T>,
-    pub(crate) activation: Option<fn(ValueId<T>) -> ValueId<T>>,
+    pub(crate) bias: Option<ValueId<T>>,
+    pub(crate) activation: Option<Activation<T>>,
 }
 
 impl<T: Num> Neuron<T> {
     pub fn new(
         allocator: &mut Allocator<T>,
         num_inputs: usize,
-        activation: Option<fn(ValueId<T>) -> ValueId<T>>,
+        activation: Option<Activation<T>>,
+    ) -> Self {
+        Self::with_init(allocator, num_inputs, activation, Init::default())
+    }
+
+    pub fn with_init(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+    ) -> Self {
+        Self::with_rng(
+            allocator,
+            num_inputs,
+            activation,
+            init,
+            &mut rand::thread_rng(),
+        )
+    }
+
+    // Samples the parameters from `rng`, e.g. a seeded `StdRng` for reproducible runs. Every
+    // module that samples its parameters has a `with_rng` constructor like this one.
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Self::init(allocator, num_inputs, 1, activation, &init, true, rng)
+    }
+
+    // For neurons followed by a normalization layer, which makes a bias redundant
+    pub fn without_bias(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+    ) -> Self {
+        let mut rng = rand::thread_rng();
+        Self::without_bias_with_rng(allocator, num_inputs, activation, init, &mut rng)
+    }
+
+    pub fn without_bias_with_rng(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Self::init(allocator, num_inputs, 1, activation, &init, false, rng)
+    }
+
+    // Uses existing parameters as weights, so gradients from every neuron sharing them
+    // accumulate into the same values. Only the bias is freshly allocated.
+    pub fn with_shared_weights(
+        allocator: &mut Allocator<T>,
+        weights: Vec<ValueId<T>>,
+        activation: Option<Activation<T>>,
     ) -> Self {
         let mut rng = rand::thread_rng();
+        Self::with_shared_weights_and_rng(allocator, weights, activation, &mut rng)
+    }
+
+    pub fn with_shared_weights_and_rng(
+        allocator: &mut Allocator<T>,
+        weights: Vec<ValueId<T>>,
+        activation: Option<Activation<T>>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let bias = Init::default().bias(rng, weights.len(), 1);
+        Neuron {
+            weights,
+            bias: Some(allocator.alloc(bias)),
+            activation,
+        }
+    }
+
+    fn init(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        fan_out: usize,
+        activation: Option<Activation<T>>,
+        init: &Init<T>,
+        bias: bool,
+        rng: &mut impl Rng,
+    ) -> Self {
+        assert!(
+            bias || num_inputs > 0,
+            "a neuron without a bias needs at least one input"
+        );
         let weights = (0..num_inputs)
-            .map(|_| allocator.alloc(rng.gen_range(-T::one()..T::one())))
+            .map(|_| allocator.alloc(init.weight(rng, num_inputs, fan_out)))
             .collect();
-        let bias = allocator.alloc(rng.gen_range(-T::one()..T::one()));
+        let bias = bias.then(|| allocator.alloc(init.bias(rng, num_inputs, fan_out)));
         Neuron {
             weights,
             bias,
@@ -30,19 +293,116 @@ impl<T: Num> Neuron<T> {
     }
 
     pub fn forward(&self, inputs: &[ValueId<T>]) -> ValueId<T> {
-        let sum = self
-            .weights
-            .iter()
-            .zip(inputs)
-            .map(|(w, i)| *w * *i)
-            .fold(self.bias, |acc, x| acc + x);
+        let sum = match self.bias {
+            Some(bias) => affine(&self.weights, inputs, bias),
+            None => {
+                let allocator = unsafe { self.allocator().as_mut().unwrap() };
+                dot(allocator, &self.weights, inputs)
+            }
+        };
 
-        if let Some(activation) = self.activation {
-            activation(sum)
+        if let Some(activation) = &self.activation {
+            activation.apply(sum)
         } else {
             sum
         }
     }
+
+    // `forward` for every sample in `batch`. The weighted sums are computed on contiguous
+    // copies of the data, which vectorizes, before one node per sample is recorded.
+    pub fn forward_batch(&self, batch: &[impl AsRef<[ValueId<T>]>]) -> Vec<ValueId<T>> {
+        let allocator = unsafe { self.allocator().as_mut().unwrap() };
+        let rows = gather_rows(allocator, batch, self.weights.len());
+        self.forward_rows(allocator, batch, &rows)
+    }
+
+    // The allocator holding the parameters. A neuron has a bias or at least one weight.
+    pub(crate) fn allocator(&self) -> *mut Allocator<T> {
+        self.weights
+            .first()
+            .or(self.bias.as_ref())
+            .unwrap()
+            .allocator()
+    }
+
+    // `rows` holds the data of `batch`, one contiguous row per sample
+    fn forward_rows(
+        &self,
+        allocator: &mut Allocator<T>,
+        batch: &[impl AsRef<[ValueId<T>]>],
+        rows: &[T],
+    ) -> Vec<ValueId<T>> {
+        let weights = allocator.gather_data(&self.weights);
+        let bias = self.bias.map_or(T::zero(), |bias| allocator.get(bias).data);
+        let len = weights.len();
+        batch
+            .iter()
+            .enumerate()
+            .map(|(i, inputs)| {
+                let row = &rows[i * len..(i + 1) * len];
+                let data = dot_slices(&weights, row) + bias;
+                let sum = record_affine(allocator, &self.weights, inputs.as_ref(), self.bias, data);
+                match &self.activation {
+                    Some(activation) => activation.apply(sum),
+                    None => sum,
+                }
+            })
+            .collect()
+    }
+
+    pub fn parameters(&self) -> Vec<ValueId<T>> {
+        let mut params = self.weights.clone();
+        params.extend(self.bias);
+        params
+    }
+}
+
+// Copies the data of every sample in `batch` into one row-major buffer
+pub(crate) fn gather_rows<T: Num>(
+    allocator: &Allocator<T>,
+    batch: &[impl AsRef<[ValueId<T>]>],
+    num_inputs: usize,
+) -> Vec<T> {
+    let mut rows = Vec::with_capacity(batch.len() * num_inputs);
+    for inputs in batch {
+        let inputs = inputs.as_ref();
+        assert_eq!(
+            inputs.len(),
+            num_inputs,
+            "forward_batch got a sample of the wrong size"
+        );
+        rows.extend(inputs.iter().map(|input| allocator.get(*input).data));
+    }
+    rows
+}
+
+impl<T: Num> Module<T> for Neuron<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        vec![Neuron::forward(self, inputs)]
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        Neuron::parameters(self)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        let mut named = self
+            .weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| (format!("weight.{i}"), *weight))
+            .collect::<Vec<_>>();
+        named.extend(self.bias.map(|bias| ("bias".to_string(), bias)));
+        named
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "Neuron({} -> 1, {})",
+            self.weights.len(),
+            activation_name(&self.activation)
+        )
+    }
 }
 
 pub struct Layer<T: Num> {
@@ -54,20 +414,228 @@ impl<T: Num> Layer<T> {
         allocator: &mut Allocator<T>,
         num_inputs: usize,
         num_neurons: usize,
-        activation: Option<fn(ValueId<T>) -> ValueId<T>>,
+        activation: Option<Activation<T>>,
+    ) -> Self {
+        Self::with_init(
+            allocator,
+            num_inputs,
+            num_neurons,
+            activation,
+            Init::default(),
+        )
+    }
+
+    pub fn with_init(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        num_neurons: usize,
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+    ) -> Self {
+        let mut rng = rand::thread_rng();
+        Self::with_rng(
+            allocator,
+            num_inputs,
+            num_neurons,
+            activation,
+            init,
+            &mut rng,
+        )
+    }
+
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        num_neurons: usize,
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Self::init(
+            allocator,
+            num_inputs,
+            num_neurons,
+            activation,
+            &init,
+            true,
+            rng,
+        )
+    }
+
+    pub fn without_bias(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        num_neurons: usize,
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+    ) -> Self {
+        let mut rng = rand::thread_rng();
+        Self::without_bias_with_rng(
+            allocator,
+            num_inputs,
+            num_neurons,
+            activation,
+            init,
+            &mut rng,
+        )
+    }
+
+    pub fn without_bias_with_rng(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        num_neurons: usize,
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Self::init(
+            allocator,
+            num_inputs,
+            num_neurons,
+            activation,
+            &init,
+            false,
+            rng,
+        )
+    }
+
+    fn init(
+        allocator: &mut Allocator<T>,
+        num_inputs: usize,
+        num_neurons: usize,
+        activation: Option<Activation<T>>,
+        init: &Init<T>,
+        bias: bool,
+        rng: &mut impl Rng,
     ) -> Self {
         let neurons = (0..num_neurons)
-            .map(|_| Neuron::new(allocator, num_inputs, activation))
+            .map(|_| {
+                Neuron::init(
+                    allocator,
+                    num_inputs,
+                    num_neurons,
+                    activation.clone(),
+                    init,
+                    bias,
+                    rng,
+                )
+            })
             .collect();
         Layer { neurons }
     }
 
+    // One row of weights per neuron, e.g. `other.transposed_weights()` to tie a decoder
+    // layer to its encoder
+    pub fn with_shared_weights(
+        allocator: &mut Allocator<T>,
+        weights: Vec<Vec<ValueId<T>>>,
+        activation: Option<Activation<T>>,
+    ) -> Self {
+        let mut rng = rand::thread_rng();
+        Self::with_shared_weights_and_rng(allocator, weights, activation, &mut rng)
+    }
+
+    pub fn with_shared_weights_and_rng(
+        allocator: &mut Allocator<T>,
+        weights: Vec<Vec<ValueId<T>>>,
+        activation: Option<Activation<T>>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let neurons = weights
+            .into_iter()
+            .map(|row| Neuron::with_shared_weights_and_rng(allocator, row, activation.clone(), rng))
+            .collect();
+        Layer { neurons }
+    }
+
+    pub fn weights(&self) -> Vec<Vec<ValueId<T>>> {
+        self.neurons
+            .iter()
+            .map(|neuron| neuron.weights.clone())
+            .collect()
+    }
+
+    // [num_inputs][num_neurons], the weights of a layer mapping this layer's outputs back
+    // to its inputs
+    pub fn transposed_weights(&self) -> Vec<Vec<ValueId<T>>> {
+        let num_inputs = self.neurons.first().map_or(0, |n| n.weights.len());
+        (0..num_inputs)
+            .map(|i| self.neurons.iter().map(|n| n.weights[i]).collect())
+            .collect()
+    }
+
     pub fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
         self.neurons
             .iter()
             .map(|neuron| neuron.forward(inputs))
             .collect()
     }
+
+    // `forward` for every sample in `batch`, one output vector per sample. The inputs are
+    // gathered once and shared by all neurons, see `Neuron::forward_batch`.
+    pub fn forward_batch(&self, batch: &[impl AsRef<[ValueId<T>]>]) -> Vec<Vec<ValueId<T>>> {
+        let Some(first) = self.neurons.first() else {
+            return vec![vec![]; batch.len()];
+        };
+        let allocator = unsafe { first.allocator().as_mut().unwrap() };
+        let rows = gather_rows(allocator, batch, first.weights.len());
+        let mut outputs = vec![Vec::with_capacity(self.neurons.len()); batch.len()];
+        for neuron in &self.neurons {
+            let neuron_outputs = neuron.forward_rows(allocator, batch, &rows);
+            for (output, value) in outputs.iter_mut().zip(neuron_outputs) {
+                output.push(value);
+            }
+        }
+        outputs
+    }
+
+    pub fn parameters(&self) -> Vec<ValueId<T>> {
+        self.neurons
+            .iter()
+            .flat_map(|neuron| neuron.parameters())
+            .collect()
+    }
+
+    // Frozen parameters keep their values through backward passes and optimizer steps
+    pub fn freeze(&self) {
+        for param in self.parameters() {
+            param.set_requires_grad(false);
+        }
+    }
+
+    pub fn unfreeze(&self) {
+        for param in self.parameters() {
+            param.set_requires_grad(true);
+        }
+    }
+}
+
+impl<T: Num> Module<T> for Layer<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        Layer::forward(self, inputs)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        Layer::parameters(self)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        self.neurons
+            .iter()
+            .enumerate()
+            .flat_map(|(i, neuron)| prefixed(&i.to_string(), neuron.named_parameters()))
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        let num_inputs = self.neurons.first().map_or(0, |n| n.weights.len());
+        let activation = self.neurons.first().map_or(&None, |n| &n.activation);
+        format!(
+            "Layer({num_inputs} -> {}, {})",
+            self.neurons.len(),
+            activation_name(activation)
+        )
+    }
 }
 
 pub struct MLP<T: Num> {
@@ -78,11 +646,75 @@ impl<T: Num> MLP<T> {
     pub fn new(
         allocator: &mut Allocator<T>,
         sizes: &[usize],
-        activation: Option<fn(ValueId<T>) -> ValueId<T>>,
+        activation: Option<Activation<T>>,
+    ) -> Self {
+        Self::with_init(allocator, sizes, activation, Init::default())
+    }
+
+    pub fn with_init(
+        allocator: &mut Allocator<T>,
+        sizes: &[usize],
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+    ) -> Self {
+        Self::with_rng(allocator, sizes, activation, init, &mut rand::thread_rng())
+    }
+
+    // Samples every layer from `rng`, see `Neuron::with_rng`
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        sizes: &[usize],
+        activation: Option<Activation<T>>,
+        init: Init<T>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let layers = sizes
+            .windows(2)
+            .map(|w| Layer::init(allocator, w[0], w[1], activation.clone(), &init, true, rng))
+            .collect();
+        MLP { layers }
+    }
+
+    // weights[l][j][i] connects input i of layer l to its neuron j, and biases[l][j] is
+    // that neuron's bias
+    pub fn from_weights(
+        allocator: &mut Allocator<T>,
+        sizes: &[usize],
+        weights: &[Vec<Vec<T>>],
+        biases: &[Vec<T>],
+        activation: Option<Activation<T>>,
     ) -> Self {
+        assert_eq!(
+            weights.len(),
+            sizes.len().saturating_sub(1),
+            "MLP weights don't match the number of layers"
+        );
+        assert_eq!(
+            biases.len(),
+            weights.len(),
+            "MLP biases don't match the number of layers"
+        );
+
         let layers = sizes
             .windows(2)
-            .map(|w| Layer::new(allocator, w[0], w[1], activation))
+            .zip(weights.iter().zip(biases))
+            .map(|(w, (layer_weights, layer_biases))| {
+                assert_eq!(layer_weights.len(), w[1], "MLP layer weight rows mismatch");
+                assert_eq!(layer_biases.len(), w[1], "MLP layer bias count mismatch");
+                let neurons = layer_weights
+                    .iter()
+                    .zip(layer_biases)
+                    .map(|(row, bias)| {
+                        assert_eq!(row.len(), w[0], "MLP neuron weight count mismatch");
+                        Neuron {
+                            weights: row.iter().map(|w| allocator.alloc(*w)).collect(),
+                            bias: Some(allocator.alloc(*bias)),
+                            activation: activation.clone(),
+                        }
+                    })
+                    .collect();
+                Layer { neurons }
+            })
             .collect();
         MLP { layers }
     }
@@ -93,29 +725,299 @@ impl<T: Num> MLP<T> {
             .fold(inputs.to_vec(), |acc, layer| layer.forward(&acc))
     }
 
+    pub fn parameters(&self) -> Vec<ValueId<T>> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.parameters())
+            .collect()
+    }
+
+    // Parameters in `parameters()` order, for optimizers and tools working on plain vectors
+    pub fn get_flat_params(&self, allocator: &Allocator<T>) -> Vec<T> {
+        allocator.gather_data(&self.parameters())
+    }
+
+    pub fn set_flat_params(&self, allocator: &mut Allocator<T>, params: &[T]) {
+        allocator.scatter_data(&self.parameters(), params);
+    }
+
+    pub fn get_flat_grads(&self, allocator: &Allocator<T>) -> Vec<T> {
+        allocator.gather_grads(&self.parameters())
+    }
+
+    pub fn set_flat_grads(&self, allocator: &mut Allocator<T>, grads: &[T]) {
+        allocator.scatter_grads(&self.parameters(), grads);
+    }
+
+    pub fn freeze_layers(&self, range: impl RangeBounds<usize>) {
+        for layer in &self.layers[(range.start_bound().cloned(), range.end_bound().cloned())] {
+            layer.freeze();
+        }
+    }
+
+    pub fn unfreeze_layers(&self, range: impl RangeBounds<usize>) {
+        for layer in &self.layers[(range.start_bound().cloned(), range.end_bound().cloned())] {
+            layer.unfreeze();
+        }
+    }
+
+    pub fn summary(&self, allocator: &Allocator<T>) -> String {
+        let rows = self
+            .layers
+            .iter()
+            .map(|layer| (layer.describe(), layer.num_parameters()))
+            .collect::<Vec<_>>();
+        summary_table(&rows, &self.parameters(), allocator)
+    }
+
     pub fn step(&mut self, lr: T) {
         for layer in self.layers.iter_mut() {
             for neuron in layer.neurons.iter_mut() {
                 for weight in neuron.weights.iter_mut() {
                     weight.step(lr);
                 }
-                neuron.bias.step(lr);
+                if let Some(bias) = neuron.bias {
+                    bias.step(lr);
+                }
             }
         }
     }
 }
 
+impl<T: Num> Module<T> for MLP<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        MLP::forward(self, inputs)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        MLP::parameters(self)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, layer)| prefixed(&i.to_string(), layer.named_parameters()))
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        let mut sizes = self
+            .layers
+            .first()
+            .map(|layer| layer.neurons[0].weights.len().to_string())
+            .into_iter()
+            .chain(
+                self.layers
+                    .iter()
+                    .map(|layer| layer.neurons.len().to_string()),
+            )
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        if let Some(layer) = self.layers.first() {
+            sizes = format!("{sizes}, {}", activation_name(&layer.neurons[0].activation));
+        }
+        format!("MLP({sizes})")
+    }
+
+    fn summary(&self, allocator: &Allocator<T>) -> String {
+        MLP::summary(self, allocator)
+    }
+}
+
+pub struct Sequential<T: Num> {
+    pub(crate) modules: Vec<Box<dyn Module<T>>>,
+}
+
+impl<T: Num> Sequential<T> {
+    pub fn new(modules: Vec<Box<dyn Module<T>>>) -> Self {
+        Sequential { modules }
+    }
+
+    pub fn push(&mut self, module: impl Module<T> + 'static) {
+        self.modules.push(Box::new(module));
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+}
+
+impl<T: Num> Module<T> for Sequential<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        self.modules
+            .iter()
+            .fold(inputs.to_vec(), |acc, module| module.forward(&acc))
+    }
+
+    // Shared parameters are listed once, so optimizers don't step them twice
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        let mut seen = HashSet::new();
+        self.modules
+            .iter()
+            .flat_map(|module| module.parameters())
+            .filter(|param| seen.insert(*param))
+            .collect()
+    }
+
+    // A shared parameter keeps the path of the first module that uses it
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        let mut seen = HashSet::new();
+        self.modules
+            .iter()
+            .enumerate()
+            .flat_map(|(i, module)| prefixed(&i.to_string(), module.named_parameters()))
+            .filter(|(_, param)| seen.insert(*param))
+            .collect()
+    }
+
+    fn set_training(&self, training: bool) {
+        for module in &self.modules {
+            module.set_training(training);
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("Sequential({} modules)", self.modules.len())
+    }
+
+    fn summary(&self, allocator: &Allocator<T>) -> String {
+        let rows = self
+            .modules
+            .iter()
+            .map(|module| (module.describe(), module.num_parameters()))
+            .collect::<Vec<_>>();
+        summary_table(&rows, &self.parameters(), allocator)
+    }
+}
+
+// Wraps a module that maps inputs to outputs of the same size, computing x + f(x)
+pub struct Residual<M> {
+    pub(crate) inner: M,
+}
+
+impl<M> Residual<M> {
+    pub fn new(inner: M) -> Self {
+        Residual { inner }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+}
+
+impl<T: Num, M: Module<T>> Module<T> for Residual<M> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        let outputs = self.inner.forward(inputs);
+        assert_eq!(
+            outputs.len(),
+            inputs.len(),
+            "Residual inner module changes the dimensionality"
+        );
+        inputs.iter().zip(outputs).map(|(x, fx)| *x + fx).collect()
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        self.inner.parameters()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        self.inner.named_parameters()
+    }
+
+    fn set_training(&self, training: bool) {
+        self.inner.set_training(training);
+    }
+
+    fn describe(&self) -> String {
+        format!("Residual({})", self.inner.describe())
+    }
+}
+
+// Turns a module producing one logit per class into a classifier. `forward` returns
+// softmax probabilities; train on `forward_logits` with `cross_entropy` instead, which is
+// more stable than taking the log of the probabilities.
+pub struct Classifier<M> {
+    pub(crate) inner: M,
+}
+
+impl<M> Classifier<M> {
+    pub fn new(inner: M) -> Self {
+        Classifier { inner }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn forward_logits<T: Num>(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>>
+    where
+        M: Module<T>,
+    {
+        self.inner.forward(inputs)
+    }
+
+    // Index of the largest logit, which is also the most probable class
+    pub fn predict<T: Num>(&self, allocator: &Allocator<T>, inputs: &[ValueId<T>]) -> usize
+    where
+        M: Module<T>,
+    {
+        let logits = self.forward_logits(inputs);
+        assert!(!logits.is_empty(), "Classifier produced no logits");
+        let mut best = 0;
+        for (i, logit) in logits.iter().enumerate().skip(1) {
+            if allocator.get(*logit).data > allocator.get(logits[best]).data {
+                best = i;
+            }
+        }
+        best
+    }
+}
+
+impl<T: Num, M: Module<T>> Module<T> for Classifier<M> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        let logits = self.forward_logits(inputs);
+        let allocator = unsafe { logits[0].allocator().as_mut().unwrap() };
+        softmax(allocator, &logits)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        self.inner.parameters()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        self.inner.named_parameters()
+    }
+
+    fn set_training(&self, training: bool) {
+        self.inner.set_training(training);
+    }
+
+    fn describe(&self) -> String {
+        format!("Classifier({})", self.inner.describe())
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::legacy_numeric_constants)]
 mod tests {
     use std::f64::EPSILON;
 
     use super::*;
-    use crate::operators::tanh;
+    use crate::{
+        losses::{cross_entropy, mse},
+        operators::sum,
+        optim::{Optimizer, SGD},
+    };
 
     #[test]
     fn test_neuron() {
         let mut allocator = Allocator::new();
-        let neuron = Neuron::new(&mut allocator, 2, Some(tanh));
+        let neuron = Neuron::new(&mut allocator, 2, Some(Activation::Tanh));
         let inputs = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
         let output = neuron.forward(&inputs);
 
@@ -124,7 +1026,7 @@ mod tests {
             .iter()
             .map(|w| allocator.get(*w).data)
             .collect::<Vec<_>>();
-        let bias = allocator.get(neuron.bias).data;
+        let bias = allocator.get(neuron.bias.unwrap()).data;
 
         assert_eq!(weights.len(), 2);
         assert_eq!(
@@ -139,7 +1041,7 @@ mod tests {
     #[test]
     fn test_layer() {
         let mut allocator = Allocator::new();
-        let layer = Layer::new(&mut allocator, 2, 3, Some(tanh));
+        let layer = Layer::new(&mut allocator, 2, 3, Some(Activation::Tanh));
         let inputs = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
         let outputs = layer.forward(&inputs);
         assert_eq!(outputs.len(), 3);
@@ -150,7 +1052,7 @@ mod tests {
                 .iter()
                 .map(|w| allocator.get(*w).data)
                 .collect::<Vec<_>>();
-            let bias = allocator.get(neuron.bias).data;
+            let bias = allocator.get(neuron.bias.unwrap()).data;
 
             assert_eq!(
                 allocator.get(*output).data,
@@ -162,10 +1064,88 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_forward_batch_no_inputs() {
+        let mut allocator = Allocator::new();
+        let layer = Layer::with_init(&mut allocator, 0, 2, None, Init::Constant(0.5));
+        let batch = vec![vec![]; 3];
+        let outputs = layer.forward_batch(&batch);
+        assert_eq!(outputs.len(), 3);
+        for output in &outputs {
+            assert_eq!(allocator.gather_data(output), vec![0.5, 0.5]);
+        }
+        let neuron = &layer.neurons[0];
+        assert_eq!(allocator.get(neuron.forward(&[])).data, 0.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "a neuron without a bias needs at least one input")]
+    fn test_bias_free_neuron_without_inputs() {
+        let mut allocator = Allocator::<f64>::new();
+        Neuron::without_bias(&mut allocator, 0, None, Init::default());
+    }
+
+    #[test]
+    fn test_forward_batch() {
+        let mut allocator = Allocator::new();
+        // 19 inputs cover both the SIMD lanes and the remainder
+        let layer = Layer::new(&mut allocator, 19, 3, Some(Activation::Tanh));
+        let neuron = Neuron::without_bias(&mut allocator, 19, None, Init::default());
+        let batch = (0..4)
+            .map(|i| {
+                (0..19)
+                    .map(|j| allocator.alloc_t((i * 19 + j) as f64 / 40.0 - 1.0))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let outputs = layer.forward_batch(&batch);
+        let sums = neuron.forward_batch(&batch);
+        assert_eq!(outputs.len(), 4);
+        for ((inputs, outputs), sum) in batch.iter().zip(&outputs).zip(&sums) {
+            let expected = layer.forward(inputs);
+            assert_eq!(allocator.get(expected[0]).op, allocator.get(outputs[0]).op);
+            for (a, b) in allocator
+                .gather_data(&expected)
+                .iter()
+                .zip(allocator.gather_data(outputs))
+            {
+                assert!((a - b).abs() < 1e-12);
+            }
+            let expected = allocator.get(neuron.forward(inputs)).data;
+            assert!((allocator.get(*sum).data - expected).abs() < 1e-12);
+        }
+
+        // Gradients flow to the weights and inputs as through `forward`
+        let flat = outputs.iter().flatten().copied().collect::<Vec<_>>();
+        let loss = sum(&mut allocator, &flat);
+        allocator.backward_from(loss);
+        let grads = allocator.gather_grads(&layer.parameters());
+        let input_grads = allocator.gather_grads(&batch[1]);
+        allocator.zero_grads();
+        let flat = batch
+            .iter()
+            .flat_map(|inputs| layer.forward(inputs))
+            .collect::<Vec<_>>();
+        let loss = sum(&mut allocator, &flat);
+        allocator.backward_from(loss);
+        let expected = [
+            allocator.gather_grads(&layer.parameters()),
+            allocator.gather_grads(&batch[1]),
+        ];
+        for (a, b) in [grads, input_grads]
+            .iter()
+            .flatten()
+            .zip(expected.iter().flatten())
+        {
+            assert!((a - b).abs() < 1e-12);
+        }
+    }
+
     #[test]
     fn test_mlp() {
         let mut allocator = Allocator::new();
-        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(tanh));
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
         let inputs = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
         let outputs = mlp.forward(&inputs);
         assert_eq!(outputs.len(), 1);
@@ -186,7 +1166,7 @@ mod tests {
                     .iter()
                     .map(|w| allocator.get(*w).data)
                     .collect::<Vec<_>>();
-                let bias = allocator.get(neuron.bias).data;
+                let bias = allocator.get(neuron.bias.unwrap()).data;
 
                 let expected_output = weights
                     .iter()
@@ -199,4 +1179,508 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_mlp_parameters() {
+        let mut allocator = Allocator::<f64>::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        let params = mlp.parameters();
+        assert_eq!(params.len(), 3 * (2 + 1) + (3 + 1));
+
+        let first = &mlp.layers[0].neurons[0];
+        assert_eq!(
+            allocator.get(params[0]).data,
+            allocator.get(first.weights[0]).data
+        );
+        assert_eq!(
+            allocator.get(params[2]).data,
+            allocator.get(first.bias.unwrap()).data
+        );
+    }
+
+    #[test]
+    fn test_module_trait() {
+        let mut allocator = Allocator::new();
+        let modules: Vec<Box<dyn Module<f64>>> = vec![
+            Box::new(Neuron::new(&mut allocator, 2, Some(Activation::Tanh))),
+            Box::new(Layer::new(&mut allocator, 2, 3, Some(Activation::Tanh))),
+            Box::new(MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh))),
+        ];
+        let inputs = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
+
+        for (module, (outputs, params)) in modules.iter().zip([(1, 3), (3, 9), (1, 13)]) {
+            assert_eq!(module.forward(&inputs).len(), outputs);
+            assert_eq!(module.parameters().len(), params);
+        }
+
+        let output = modules[2].forward(&inputs)[0];
+        let loss = output * output;
+        assert!(allocator.get(loss).data >= 0.0);
+        allocator.backward();
+        assert!(modules[2]
+            .parameters()
+            .iter()
+            .any(|p| allocator.get(*p).grad != 0.0));
+
+        modules[2].zero_grad();
+        assert!(modules[2]
+            .parameters()
+            .iter()
+            .all(|p| allocator.get(*p).grad == 0.0));
+    }
+
+    #[test]
+    fn test_sequential() {
+        let mut allocator = Allocator::new();
+        let mut model = Sequential::new(vec![
+            Box::new(Layer::new(&mut allocator, 2, 3, Some(Activation::Tanh))),
+            Box::new(MLP::new(&mut allocator, &[3, 4, 2], Some(Activation::Tanh))),
+        ]);
+        model.push(Neuron::new(&mut allocator, 2, None));
+        assert_eq!(model.len(), 3);
+        assert_eq!(model.parameters().len(), 9 + (16 + 10) + 3);
+
+        let inputs = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
+        let outputs = model.forward(&inputs);
+        assert_eq!(outputs.len(), 1);
+
+        let hidden = model.modules[0].forward(&inputs);
+        let hidden = model.modules[1].forward(&hidden);
+        let expected = model.modules[2].forward(&hidden);
+        assert_eq!(
+            allocator.get(outputs[0]).data,
+            allocator.get(expected[0]).data
+        );
+    }
+
+    #[test]
+    fn test_residual() {
+        let mut allocator = Allocator::new();
+        let residual = Residual::new(Layer::new(&mut allocator, 2, 2, Some(Activation::Tanh)));
+        assert_eq!(residual.parameters().len(), 6);
+
+        let inputs = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
+        let outputs = residual.forward(&inputs);
+        let inner = residual.inner().forward(&inputs);
+        for i in 0..2 {
+            assert_eq!(
+                allocator.get(outputs[i]).data,
+                allocator.get(inputs[i]).data + allocator.get(inner[i]).data
+            );
+        }
+
+        // The skip path adds exactly 1 to the gradient flowing through the inner module
+        let residual = Residual::new(Layer::new(&mut allocator, 1, 1, None));
+        let x = allocator.alloc(0.5f64);
+        let output = residual.forward(&[x]);
+        assert_eq!(output.len(), 1);
+        allocator.backward();
+        let weight = allocator.get(residual.inner().neurons[0].weights[0]).data;
+        assert!((allocator.get(x).grad - (1.0 + weight)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_classifier() {
+        let mut allocator = Allocator::new();
+        let classifier = Classifier::new(Layer::new(&mut allocator, 2, 3, None));
+        assert_eq!(classifier.parameters().len(), 9);
+
+        let inputs = vec![allocator.alloc(1.0f64), allocator.alloc(-2.0)];
+        let logits = classifier
+            .forward_logits(&inputs)
+            .iter()
+            .map(|l| allocator.get(*l).data)
+            .collect::<Vec<_>>();
+        let probs = classifier
+            .forward(&inputs)
+            .iter()
+            .map(|p| allocator.get(*p).data)
+            .collect::<Vec<_>>();
+        assert!((probs.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+
+        let expected = (0..3)
+            .max_by(|a, b| logits[*a].partial_cmp(&logits[*b]).unwrap())
+            .unwrap();
+        assert_eq!(classifier.predict(&allocator, &inputs), expected);
+        assert!(probs.iter().all(|p| *p <= probs[expected]));
+    }
+
+    #[test]
+    fn test_classifier_training() {
+        let mut allocator = Allocator::new();
+        let classifier = Classifier::new(MLP::new(&mut allocator, &[2, 2], None));
+        let mut optimizer = SGD::new(classifier.parameters(), 0.5);
+        let inputs = [
+            vec![allocator.alloc(1.0f64), allocator.alloc(0.0)],
+            vec![allocator.alloc(0.0), allocator.alloc(1.0)],
+        ];
+
+        for _ in 0..50 {
+            for (target, input) in inputs.iter().enumerate() {
+                let logits = classifier.forward_logits(input);
+                cross_entropy(&mut allocator, &logits, target);
+                allocator.backward();
+                optimizer.step(&mut allocator);
+                optimizer.zero_grad(&mut allocator);
+                allocator.clear_temps();
+            }
+        }
+
+        for (target, input) in inputs.iter().enumerate() {
+            assert_eq!(classifier.predict(&allocator, input), target);
+        }
+    }
+
+    #[test]
+    fn test_init_schemes() {
+        let mut allocator = Allocator::<f64>::new();
+        let data = |allocator: &Allocator<f64>, layer: &Layer<f64>| {
+            layer
+                .parameters()
+                .iter()
+                .map(|p| allocator.get(*p).data)
+                .collect::<Vec<_>>()
+        };
+
+        let layer = Layer::with_init(&mut allocator, 3, 2, None, Init::Constant(0.5));
+        assert!(data(&allocator, &layer).iter().all(|p| *p == 0.5));
+
+        let layer = Layer::with_init(&mut allocator, 4, 2, None, Init::Uniform(0.0, 0.1));
+        assert!(data(&allocator, &layer)
+            .iter()
+            .all(|p| (0.0..0.1).contains(p)));
+
+        // Biases start at zero, weights within sqrt(6 / (fan_in + fan_out))
+        let layer = Layer::with_init(
+            &mut allocator,
+            100,
+            50,
+            Some(Activation::Tanh),
+            Init::Xavier,
+        );
+        let limit = (6.0f64 / 150.0).sqrt();
+        for neuron in layer.neurons.iter() {
+            assert_eq!(allocator.get(neuron.bias.unwrap()).data, 0.0);
+            assert!(neuron
+                .weights
+                .iter()
+                .all(|w| allocator.get(*w).data.abs() < limit));
+        }
+
+        let layer = Layer::with_init(&mut allocator, 200, 50, None, Init::He);
+        let weights = layer
+            .neurons
+            .iter()
+            .flat_map(|n| n.weights.iter().map(|w| allocator.get(*w).data))
+            .collect::<Vec<_>>();
+        let var = weights.iter().map(|w| w * w).sum::<f64>() / weights.len() as f64;
+        assert!((var - 2.0 / 200.0).abs() < 0.002);
+
+        let mlp = MLP::with_init(
+            &mut allocator,
+            &[2, 3, 1],
+            None,
+            Init::Custom(Box::new(|fan_in, fan_out| (fan_in * 10 + fan_out) as f64)),
+        );
+        assert_eq!(
+            allocator.get(mlp.layers[0].neurons[0].weights[0]).data,
+            23.0
+        );
+        assert_eq!(
+            allocator.get(mlp.layers[1].neurons[0].bias.unwrap()).data,
+            31.0
+        );
+    }
+
+    #[test]
+    fn test_without_bias() {
+        let mut allocator = Allocator::new();
+        let layer = Layer::without_bias(
+            &mut allocator,
+            2,
+            3,
+            Some(Activation::Tanh),
+            Init::default(),
+        );
+        assert_eq!(layer.parameters().len(), 6);
+        assert!(layer.neurons.iter().all(|n| n.bias.is_none()));
+
+        let inputs = vec![allocator.alloc(1.0f64), allocator.alloc(2.0)];
+        let outputs = layer.forward(&inputs);
+        for (neuron, output) in layer.neurons.iter().zip(outputs.iter()) {
+            let expected = (allocator.get(neuron.weights[0]).data
+                + 2.0 * allocator.get(neuron.weights[1]).data)
+                .tanh();
+            assert!((allocator.get(*output).data - expected).abs() < 1e-12);
+        }
+
+        allocator.backward();
+        let y = allocator.get(outputs[2]).data;
+        let grad = allocator.get(layer.neurons[2].weights[1]).grad;
+        assert!((grad - 2.0 * (1.0 - y * y)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_shared_weights() {
+        let mut allocator = Allocator::new();
+        let encoder = Layer::new(&mut allocator, 3, 2, None);
+        let decoder =
+            Layer::with_shared_weights(&mut allocator, encoder.transposed_weights(), None);
+        assert_eq!(decoder.neurons.len(), 3);
+        assert!(decoder.neurons[2].weights[1] == encoder.neurons[1].weights[2]);
+        let weights = encoder
+            .weights()
+            .iter()
+            .map(|row| row.iter().map(|w| allocator.get(*w).data).collect())
+            .collect::<Vec<Vec<f64>>>();
+
+        let mut model = Sequential::new(vec![]);
+        model.push(encoder);
+        model.push(decoder);
+        // 6 shared weights plus 2 encoder and 3 decoder biases
+        assert_eq!(model.parameters().len(), 11);
+
+        let x = [1.0f64, -1.0, 0.5];
+        let inputs = x.map(|v| allocator.alloc(v));
+        let hidden = model.modules[0].forward(&inputs);
+        let outputs = model.modules[1].forward(&hidden);
+        sum(&mut allocator, &outputs);
+        allocator.backward();
+
+        // W_ij receives h_i through the decoder plus x_j * sum_k W_ik through the encoder
+        let h = hidden
+            .iter()
+            .map(|v| allocator.get(*v).data)
+            .collect::<Vec<_>>();
+        let shared = model.modules[0].parameters()[2];
+        let expected = h[0] + x[2] * weights[0].iter().sum::<f64>();
+        assert!((allocator.get(shared).grad - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_mlp_from_weights() {
+        let mut allocator = Allocator::new();
+        let weights = vec![
+            vec![vec![1.0, -1.0], vec![0.5, 0.5], vec![0.0, 2.0]],
+            vec![vec![1.0, 2.0, 3.0]],
+        ];
+        let biases = vec![vec![0.0, 0.1, -0.1], vec![0.25]];
+        let mlp = MLP::from_weights(
+            &mut allocator,
+            &[2, 3, 1],
+            &weights,
+            &biases,
+            Some(Activation::Relu),
+        );
+        assert_eq!(mlp.parameters().len(), 13);
+        assert_eq!(allocator.get(mlp.layers[0].neurons[1].weights[0]).data, 0.5);
+
+        // hidden = relu([-1, 1.6, 3.9]) = [0, 1.6, 3.9]
+        let inputs = vec![allocator.alloc(1.0f64), allocator.alloc(2.0)];
+        let output = mlp.forward(&inputs)[0];
+        let expected = 2.0 * 1.6 + 3.0 * 3.9 + 0.25;
+        assert!((allocator.get(output).data - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_mlp_flat_params() {
+        let mut allocator = Allocator::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        let params = (0..13).map(|i| i as f64 / 10.0).collect::<Vec<_>>();
+        mlp.set_flat_params(&mut allocator, &params);
+        assert_eq!(mlp.get_flat_params(&allocator), params);
+        assert_eq!(allocator.get(mlp.layers[0].neurons[1].weights[0]).data, 0.3);
+
+        let inputs = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
+        let output = mlp.forward(&inputs)[0];
+        allocator.backward();
+        let grads = mlp.get_flat_grads(&allocator);
+        assert_eq!(grads.len(), 13);
+        // The last parameter is the output bias
+        let y = allocator.get(output).data;
+        assert!((grads[12] - (1.0 - y * y)).abs() < 1e-12);
+
+        mlp.set_flat_grads(&mut allocator, &[0.0; 13]);
+        assert!(mlp.get_flat_grads(&allocator).iter().all(|g| *g == 0.0));
+    }
+
+    #[test]
+    fn test_freeze_layers() {
+        let mut allocator = Allocator::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        mlp.freeze_layers(..1);
+        let frozen = mlp.layers[0].parameters();
+        let before = allocator.gather_data(&frozen);
+        let mut optimizer = SGD::with_momentum(mlp.parameters(), 0.1, 0.9);
+
+        let inputs = vec![allocator.alloc(1.0f64), allocator.alloc(2.0)];
+        let target = allocator.alloc(0.5);
+        for _ in 0..3 {
+            let outputs = mlp.forward(&inputs);
+            mse(&mut allocator, &outputs, &[target]);
+            allocator.backward();
+            assert!(allocator.gather_grads(&frozen).iter().all(|g| *g == 0.0));
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+        assert_eq!(allocator.gather_data(&frozen), before);
+        assert!(mlp.layers[1]
+            .parameters()
+            .iter()
+            .all(|p| allocator.get(*p).requires_grad));
+
+        // Gradients still flow through frozen layers to earlier values
+        assert!(allocator.get(inputs[0]).grad != 0.0);
+
+        mlp.unfreeze_layers(..);
+        assert!(frozen.iter().all(|p| allocator.get(*p).requires_grad));
+    }
+
+    #[test]
+    fn test_named_parameters() {
+        let mut allocator = Allocator::<f64>::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        let named = mlp.named_parameters();
+        assert_eq!(mlp.num_parameters(), 13);
+        assert_eq!(named.len(), 13);
+        assert_eq!(named[0].0, "0.0.weight.0");
+        assert_eq!(named[2].0, "0.0.bias");
+        assert_eq!(named[12].0, "1.0.bias");
+        for ((_, named), param) in named.iter().zip(mlp.parameters()) {
+            assert!(*named == param);
+        }
+
+        let mut model = Sequential::new(vec![]);
+        model.push(Layer::without_bias(
+            &mut allocator,
+            2,
+            2,
+            None,
+            Init::default(),
+        ));
+        model.push(Residual::new(mlp));
+        let names = model
+            .named_parameters()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(model.num_parameters(), 17);
+        assert_eq!(names[3], "0.1.weight.1");
+        assert_eq!(names[4], "1.0.0.weight.0");
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut allocator = Allocator::<f64>::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        mlp.freeze_layers(1..);
+        let summary = mlp.summary(&allocator);
+        let lines = summary.lines().collect::<Vec<_>>();
+        assert_eq!(lines[1], "Layer(2 -> 3, tanh)    9");
+        assert_eq!(lines[2], "Layer(3 -> 1, tanh)    4");
+        assert_eq!(lines[3], "Total params: 13");
+        assert_eq!(lines[4], "Trainable params: 9");
+        assert_eq!(lines[5], "Non-trainable params: 4");
+        assert_eq!(Module::summary(&mlp, &allocator), summary);
+
+        let mut model = Sequential::new(vec![]);
+        model.push(mlp);
+        model.push(Classifier::new(Layer::new(&mut allocator, 1, 2, None)));
+        model.push(LayerNorm::new(&mut allocator, 2));
+        let summary = model.summary(&allocator);
+        let rows = summary.lines().skip(1).take(3).collect::<Vec<_>>();
+        assert!(rows[0].starts_with("MLP(2 -> 3 -> 1, tanh)"));
+        assert!(rows[1].starts_with("Classifier(Layer(1 -> 2, linear))"));
+        assert!(rows[2].starts_with("LayerNorm(2)") && rows[2].ends_with('4'));
+    }
+
+    #[test]
+    fn test_grad_report() {
+        let mut allocator = Allocator::<f64>::new();
+        let mlp = MLP::new(&mut allocator, &[2, 3, 1], Some(Activation::Tanh));
+        let inputs = vec![allocator.alloc(0.5), allocator.alloc(-1.0)];
+        mlp.forward(&inputs);
+        allocator.backward();
+
+        let report = mlp.grad_report(&allocator);
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("Layer  Params  Norm"));
+        assert!(lines[1].starts_with("0      9       "));
+        assert!(lines[2].starts_with("1      4       "));
+
+        let stats = allocator.grad_stats(&mlp.layers[1].parameters());
+        assert!(stats.max > 0.0);
+        assert!(lines[2].contains(&format!("  {}  ", stats.norm)));
+        assert!(lines[2].ends_with(&stats.max.to_string()));
+    }
+
+    #[test]
+    fn test_seeded_init() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut allocator = Allocator::<f64>::new();
+        let build = |allocator: &mut Allocator<f64>, seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mlp = MLP::with_rng(allocator, &[2, 3, 1], None, Init::He, &mut rng);
+            mlp.get_flat_params(allocator)
+        };
+        let first = build(&mut allocator, 7);
+        assert_eq!(first.len(), 13);
+        assert_eq!(build(&mut allocator, 7), first);
+        assert_ne!(build(&mut allocator, 8), first);
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let layer = Layer::with_rng(&mut allocator, 2, 3, None, Init::He, &mut rng);
+        let neuron = Neuron::with_rng(&mut allocator, 3, None, Init::He, &mut rng);
+        let mut params = allocator.gather_data(&layer.parameters());
+        params.extend(allocator.gather_data(&neuron.parameters()));
+        assert_eq!(params, first);
+    }
+
+    #[test]
+    fn test_seeded_modules() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        // Every sampling constructor draws from the given rng only
+        let build = |seed| {
+            let mut allocator = Allocator::<f64>::new();
+            let rng = &mut StdRng::seed_from_u64(seed);
+            let encoder =
+                Layer::without_bias_with_rng(&mut allocator, 2, 3, None, Init::default(), rng);
+            let modules: Vec<Box<dyn Module<f64>>> = vec![
+                Box::new(Layer::with_shared_weights_and_rng(
+                    &mut allocator,
+                    encoder.transposed_weights(),
+                    None,
+                    rng,
+                )),
+                Box::new(Conv1d::with_rng(&mut allocator, 1, 2, 3, 1, 0, rng)),
+                Box::new(Conv2d::with_rng(
+                    &mut allocator,
+                    1,
+                    1,
+                    (3, 3),
+                    (2, 2),
+                    1,
+                    0,
+                    rng,
+                )),
+                Box::new(Attention::with_rng(&mut allocator, 2, 2, rng)),
+                Box::new(RnnCell::with_rng(&mut allocator, 1, 2, rng)),
+                Box::new(TransformerBlock::with_rng(&mut allocator, 2, 4, rng)),
+            ];
+            let neuron =
+                Neuron::without_bias_with_rng(&mut allocator, 2, None, Init::default(), rng);
+            let embedding = Embedding::with_rng(&mut allocator, 3, 2, rng);
+            let mut params = encoder.parameters();
+            params.extend(modules.iter().flat_map(|module| module.parameters()));
+            params.extend(neuron.parameters());
+            params.extend(embedding.parameters());
+            allocator.gather_data(&params)
+        };
+        assert_eq!(build(3), build(3));
+        assert_ne!(build(3), build(4));
+    }
 }
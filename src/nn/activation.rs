@@ -0,0 +1,150 @@
+use std::rc::Rc;
+
+use super::{prefixed, Module};
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::{clamp, relu, tanh, Num},
+};
+
+// The nonlinearity applied to each neuron's output. Variants carry their own
+// hyperparameters, and `Custom` accepts any closure, including capturing ones.
+#[derive(Clone)]
+pub enum Activation<T: Num> {
+    Tanh,
+    Relu,
+    // max(x, slope * x) for slope < 1
+    LeakyRelu(T),
+    // clamp(x, lo, hi)
+    Clamp(T, T),
+    Custom(Rc<dyn Fn(ValueId<T>) -> ValueId<T>>),
+}
+
+impl<T: Num> Activation<T> {
+    pub fn custom(f: impl Fn(ValueId<T>) -> ValueId<T> + 'static) -> Self {
+        Activation::Custom(Rc::new(f))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Activation::Tanh => "tanh".to_string(),
+            Activation::Relu => "relu".to_string(),
+            Activation::LeakyRelu(slope) => format!("leaky_relu({slope})"),
+            Activation::Clamp(lo, hi) => format!("clamp({lo}, {hi})"),
+            Activation::Custom(_) => "custom".to_string(),
+        }
+    }
+
+    pub fn apply(&self, x: ValueId<T>) -> ValueId<T> {
+        match self {
+            Activation::Tanh => tanh(x),
+            Activation::Relu => relu(x),
+            // relu(x) - slope * relu(-x)
+            Activation::LeakyRelu(slope) => relu(x) - relu(-x) * *slope,
+            Activation::Clamp(lo, hi) => clamp(x, *lo, *hi),
+            Activation::Custom(f) => f(x),
+        }
+    }
+}
+
+// max(x, a * x) where the negative slope `a` is trainable, either one slope shared by
+// every input or one per input. Since it owns parameters it is a module of its own,
+// placed after a linear layer (e.g. in a `Sequential`) instead of as an `Activation`.
+pub struct PReLU<T: Num> {
+    pub(crate) slopes: Vec<ValueId<T>>,
+}
+
+impl<T: Num> PReLU<T> {
+    pub fn new(allocator: &mut Allocator<T>, num_slopes: usize, init: T) -> Self {
+        assert!(num_slopes > 0, "PReLU needs at least one slope");
+        let slopes = (0..num_slopes).map(|_| allocator.alloc(init)).collect();
+        PReLU { slopes }
+    }
+
+    pub fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        assert!(
+            self.slopes.len() == 1 || self.slopes.len() == inputs.len(),
+            "PReLU input size doesn't match the number of slopes"
+        );
+        inputs
+            .iter()
+            .zip(self.slopes.iter().cycle())
+            .map(|(x, slope)| relu(*x) - relu(-*x) * *slope)
+            .collect()
+    }
+}
+
+impl<T: Num> Module<T> for PReLU<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        PReLU::forward(self, inputs)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        self.slopes.clone()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        prefixed(
+            "slope",
+            self.slopes
+                .iter()
+                .enumerate()
+                .map(|(i, slope)| (i.to_string(), *slope))
+                .collect(),
+        )
+    }
+
+    fn describe(&self) -> String {
+        format!("PReLU({} slopes)", self.slopes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activations() {
+        let mut allocator = Allocator::new();
+        let x = allocator.alloc(-2.0f64);
+
+        let cases = [
+            (Activation::Tanh, (-2.0f64).tanh()),
+            (Activation::Relu, 0.0),
+            (Activation::LeakyRelu(0.1), -0.2),
+            (Activation::Clamp(-1.0, 1.0), -1.0),
+            (Activation::custom(move |v| v * 3.0), -6.0),
+        ];
+        for (activation, expected) in cases {
+            let y = activation.apply(x);
+            assert!((allocator.get(y).data - expected).abs() < 1e-12);
+        }
+
+        allocator.clear_temps();
+        Activation::LeakyRelu(0.1).apply(x);
+        allocator.backward();
+        assert!((allocator.get(x).grad - 0.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_prelu() {
+        let mut allocator = Allocator::new();
+        let prelu = PReLU::new(&mut allocator, 2, 0.25);
+        assert_eq!(prelu.num_parameters(), 2);
+
+        let inputs = vec![allocator.alloc(-2.0f64), allocator.alloc(3.0)];
+        let outputs = prelu.forward(&inputs);
+        assert_eq!(allocator.get(outputs[0]).data, -0.5);
+        assert_eq!(allocator.get(outputs[1]).data, 3.0);
+
+        // d/da of a * x for negative x is x, and positive inputs don't touch their slope
+        let total = crate::operators::sum(&mut allocator, &outputs);
+        assert_eq!(allocator.get(total).data, 2.5);
+        allocator.backward();
+        assert_eq!(allocator.get(prelu.slopes[0]).grad, -2.0);
+        assert_eq!(allocator.get(prelu.slopes[1]).grad, 0.0);
+        assert_eq!(allocator.get(inputs[0]).grad, 0.25);
+
+        let shared = PReLU::new(&mut allocator, 1, 0.1);
+        assert_eq!(shared.forward(&inputs).len(), 2);
+    }
+}
@@ -0,0 +1,201 @@
+use rand::Rng;
+
+use super::{prefixed, Init, Layer, Module};
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::{dot, softmax, Num},
+};
+
+// Single-head scaled dot-product attention over a sequence of `d_model`-sized feature
+// vectors: softmax(Q K^T / sqrt(d_k)) V, with linear Q/K/V projections to `d_k`
+pub struct Attention<T: Num> {
+    pub(crate) d_model: usize,
+    pub(crate) d_k: usize,
+    pub(crate) query: Layer<T>,
+    pub(crate) key: Layer<T>,
+    pub(crate) value: Layer<T>,
+}
+
+impl<T: Num> Attention<T> {
+    pub fn new(allocator: &mut Allocator<T>, d_model: usize, d_k: usize) -> Self {
+        Self::with_rng(allocator, d_model, d_k, &mut rand::thread_rng())
+    }
+
+    // See `Neuron::with_rng`
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        d_model: usize,
+        d_k: usize,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut projection =
+            || Layer::with_rng(allocator, d_model, d_k, None, Init::default(), rng);
+        Attention {
+            d_model,
+            d_k,
+            query: projection(),
+            key: projection(),
+            value: projection(),
+        }
+    }
+
+    pub fn forward_sequence(&self, sequence: &[Vec<ValueId<T>>]) -> Vec<Vec<ValueId<T>>> {
+        if sequence.is_empty() {
+            return vec![];
+        }
+        let allocator = unsafe { sequence[0][0].allocator().as_mut().unwrap() };
+
+        let queries = sequence
+            .iter()
+            .map(|x| self.query.forward(x))
+            .collect::<Vec<_>>();
+        let keys = sequence
+            .iter()
+            .map(|x| self.key.forward(x))
+            .collect::<Vec<_>>();
+        let values = sequence
+            .iter()
+            .map(|x| self.value.forward(x))
+            .collect::<Vec<_>>();
+        // Column j of V, so each output feature is a single dot product with the weights
+        let value_columns = (0..self.d_k)
+            .map(|j| values.iter().map(|v| v[j]).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let scale = T::one() / T::from_usize(self.d_k).unwrap().sqrt();
+        queries
+            .iter()
+            .map(|q| {
+                let scores = keys
+                    .iter()
+                    .map(|k| dot(allocator, q, k) * scale)
+                    .collect::<Vec<_>>();
+                let weights = softmax(allocator, &scores);
+                value_columns
+                    .iter()
+                    .map(|column| dot(allocator, &weights, column))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+// As a module, the sequence is flattened to [seq_len * d_model] and the output to
+// [seq_len * d_k]
+impl<T: Num> Module<T> for Attention<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        assert_eq!(
+            inputs.len() % self.d_model,
+            0,
+            "Attention input length is not a multiple of d_model"
+        );
+        let sequence = inputs
+            .chunks(self.d_model)
+            .map(|x| x.to_vec())
+            .collect::<Vec<_>>();
+        self.forward_sequence(&sequence).concat()
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        let mut params = self.query.parameters();
+        params.extend(self.key.parameters());
+        params.extend(self.value.parameters());
+        params
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        let mut named = prefixed("query", self.query.named_parameters());
+        named.extend(prefixed("key", self.key.named_parameters()));
+        named.extend(prefixed("value", self.value.named_parameters()));
+        named
+    }
+
+    fn describe(&self) -> String {
+        format!("Attention({} -> {})", self.d_model, self.d_k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(allocator: &Allocator<f64>, values: &[ValueId<f64>]) -> Vec<f64> {
+        values.iter().map(|v| allocator.get(*v).data).collect()
+    }
+
+    #[test]
+    fn test_attention() {
+        let mut allocator = Allocator::new();
+        let attention = Attention::new(&mut allocator, 3, 2);
+        assert_eq!(attention.parameters().len(), 3 * 2 * (3 + 1));
+
+        let sequence = (0..4)
+            .map(|i| {
+                (0..3)
+                    .map(|j| allocator.alloc((i * 3 + j) as f64 / 10.0))
+                    .collect()
+            })
+            .collect::<Vec<Vec<_>>>();
+        let outputs = attention.forward_sequence(&sequence);
+        assert_eq!(outputs.len(), 4);
+        assert!(outputs.iter().all(|o| o.len() == 2));
+
+        // Recompute the first output by hand from the projections
+        let project = |layer: &Layer<f64>, x: &[ValueId<f64>]| -> Vec<f64> {
+            layer
+                .neurons
+                .iter()
+                .map(|n| {
+                    data(&allocator, &n.weights)
+                        .iter()
+                        .zip(data(&allocator, x))
+                        .map(|(w, x)| w * x)
+                        .sum::<f64>()
+                        + allocator.get(n.bias.unwrap()).data
+                })
+                .collect()
+        };
+        let q = project(&attention.query, &sequence[0]);
+        let scores = sequence
+            .iter()
+            .map(|x| {
+                let k = project(&attention.key, x);
+                (q[0] * k[0] + q[1] * k[1]) / 2.0f64.sqrt()
+            })
+            .collect::<Vec<_>>();
+        let max = scores.iter().cloned().fold(f64::MIN, f64::max);
+        let exps = scores.iter().map(|s| (s - max).exp()).collect::<Vec<_>>();
+        let total = exps.iter().sum::<f64>();
+        let expected = (0..2)
+            .map(|j| {
+                sequence
+                    .iter()
+                    .zip(exps.iter())
+                    .map(|(x, e)| e / total * project(&attention.value, x)[j])
+                    .sum::<f64>()
+            })
+            .collect::<Vec<_>>();
+        let actual = data(&allocator, &outputs[0]);
+        assert!((actual[0] - expected[0]).abs() < 1e-12);
+        assert!((actual[1] - expected[1]).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_attention_module_backward() {
+        let mut allocator = Allocator::new();
+        let attention = Attention::new(&mut allocator, 2, 2);
+        let inputs = (0..6)
+            .map(|i| allocator.alloc(i as f64 / 6.0))
+            .collect::<Vec<_>>();
+        let outputs = Module::forward(&attention, &inputs);
+        assert_eq!(outputs.len(), 6);
+
+        let total = crate::operators::sum(&mut allocator, &outputs);
+        assert!(allocator.get(total).data.is_finite());
+        allocator.backward();
+        assert!(attention
+            .parameters()
+            .iter()
+            .any(|p| allocator.get(*p).grad != 0.0));
+    }
+}
@@ -0,0 +1,168 @@
+use std::marker::PhantomData;
+
+use num::ToPrimitive;
+
+use super::Module;
+use crate::{
+    allocator::{Allocator, ForwardFn, ValueId},
+    half::{cast, cast_forward, HalfFloat},
+    operators::Num,
+};
+
+// Runs a module in the 16-bit type `H` on a tape of `T`, e.g. an f32 model whose forward
+// pass should see f16 rounding. Inputs are cast on the way in, and inside the module every
+// op casts the parameters it reads and its result, see `Allocator::set_autocast`. The
+// parameters themselves stay in `T`, so any optimizer updates them at full precision.
+pub struct Autocast<H, M> {
+    pub(crate) inner: M,
+    pub(crate) precision: PhantomData<H>,
+}
+
+impl<H: HalfFloat, M> Autocast<H, M> {
+    pub fn new(inner: M) -> Self {
+        Autocast {
+            inner,
+            precision: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+}
+
+// Puts back the precision of the enclosing region, also if the inner forward panics
+struct Restore<T: Num> {
+    allocator: *mut Allocator<T>,
+    outer: Option<ForwardFn<T>>,
+}
+
+impl<T: Num> Drop for Restore<T> {
+    fn drop(&mut self) {
+        unsafe { (*self.allocator).autocast = self.outer }
+    }
+}
+
+impl<T: Num + ToPrimitive, H: HalfFloat, M: Module<T>> Module<T> for Autocast<H, M> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        let Some(first) = inputs.first() else {
+            return self.inner.forward(inputs);
+        };
+        let inputs = inputs.iter().map(|x| cast::<H, T>(*x)).collect::<Vec<_>>();
+        let allocator = first.allocator();
+        let _restore = Restore {
+            allocator,
+            outer: unsafe { (*allocator).autocast.replace(cast_forward::<T, H>) },
+        };
+        self.inner.forward(&inputs)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        self.inner.parameters()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        self.inner.named_parameters()
+    }
+
+    fn set_training(&self, training: bool) {
+        self.inner.set_training(training);
+    }
+
+    fn describe(&self) -> String {
+        let name = std::any::type_name::<H>();
+        let name = name.rsplit("::").next().unwrap_or(name);
+        format!("Autocast<{name}>({})", self.inner.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        half::{BF16, F16},
+        nn::{Activation, Init, Layer, MLP},
+        op::Op,
+        optim::{Optimizer, SGD},
+    };
+
+    #[test]
+    fn test_autocast_forward() {
+        let mut allocator = Allocator::<f32>::new();
+        let layer = Layer::new(&mut allocator, 2, 1, None);
+        let model = Autocast::<F16, _>::new(layer);
+        assert_eq!(
+            model.describe(),
+            format!("Autocast<F16>({})", model.inner().describe())
+        );
+
+        let params = model.parameters();
+        let precise = [0.1f32, 0.2, 0.3];
+        for (param, data) in params.iter().zip(precise) {
+            allocator.get_mut(*param).set_data(data);
+        }
+        let inputs = vec![allocator.alloc_t(1.0 / 3.0), allocator.alloc_t(2.0 / 3.0)];
+        let output = model.forward(&inputs)[0];
+        assert_eq!(allocator.get(output).op, Some(Op::Cast));
+
+        // Every operand is rounded to f16 before the op
+        let round = |x: f32| F16::from_f32(x).to_f32();
+        let expected = round(
+            round(precise[0]) * round(1.0 / 3.0)
+                + round(precise[1]) * round(2.0 / 3.0)
+                + round(precise[2]),
+        );
+        assert_eq!(allocator.get(output).data, expected);
+        assert!(allocator.autocast.is_none());
+
+        // The parameters keep full precision and receive the gradients through the casts
+        allocator.backward();
+        assert_eq!(allocator.gather_data(&params), precise.to_vec());
+        assert_eq!(allocator.get(params[2]).grad, 1.0);
+        assert_eq!(allocator.get(params[0]).grad, round(1.0 / 3.0));
+    }
+
+    #[test]
+    fn test_autocast_training() {
+        let mut allocator = Allocator::<f32>::new();
+        let mlp = MLP::with_rng(
+            &mut allocator,
+            &[2, 4, 1],
+            Some(Activation::Tanh),
+            Init::default(),
+            &mut StdRng::seed_from_u64(0),
+        );
+        let model = Autocast::<BF16, _>::new(mlp);
+        let mut optimizer = SGD::with_momentum(model.parameters(), 0.05, 0.9);
+        let samples = [([0.0, 1.0], 0.5), ([1.0, 0.0], -0.5)];
+        let mut losses = vec![];
+        for _ in 0..100 {
+            let mut total = 0.0;
+            for (x, target) in samples {
+                let inputs = x.map(|x| allocator.alloc_t(x));
+                let diff = model.forward(&inputs)[0] - target;
+                let loss = diff * diff;
+                total += allocator.get(loss).data;
+                allocator.backward();
+                allocator.clear_temps();
+            }
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            losses.push(total);
+        }
+        assert!(losses[99] < losses[0] * 0.1);
+    }
+
+    #[test]
+    fn test_autocast_panic() {
+        let mut allocator = Allocator::<f32>::new();
+        let model = Autocast::<F16, _>::new(Layer::new(&mut allocator, 2, 1, None));
+        let inputs = [1.0, 2.0, 3.0].map(|x| allocator.alloc_t(x));
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| model.forward(&inputs)));
+        assert!(result.is_err());
+        assert!(allocator.autocast.is_none());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+
+use super::{prefixed, Activation, Layer, Module, MLP};
+use crate::{
+    allocator::{Allocator, ValueId},
+    losses::mse,
+    operators::Num,
+};
+
+// An encoder MLP over `encoder_sizes` (input size first, code size last) and a decoder
+// MLP over the same sizes reversed
+pub struct Autoencoder<T: Num> {
+    pub(crate) encoder: MLP<T>,
+    pub(crate) decoder: MLP<T>,
+}
+
+impl<T: Num> Autoencoder<T> {
+    pub fn new(
+        allocator: &mut Allocator<T>,
+        encoder_sizes: &[usize],
+        activation: Option<Activation<T>>,
+    ) -> Self {
+        let decoder_sizes = encoder_sizes.iter().rev().copied().collect::<Vec<_>>();
+        Autoencoder {
+            encoder: MLP::new(allocator, encoder_sizes, activation.clone()),
+            decoder: MLP::new(allocator, &decoder_sizes, activation),
+        }
+    }
+
+    // Each decoder layer reuses the transposed weights of its mirrored encoder layer, so
+    // only the decoder biases are extra parameters
+    pub fn with_tied_weights(
+        allocator: &mut Allocator<T>,
+        encoder_sizes: &[usize],
+        activation: Option<Activation<T>>,
+    ) -> Self {
+        let encoder = MLP::new(allocator, encoder_sizes, activation.clone());
+        let layers = encoder
+            .layers
+            .iter()
+            .rev()
+            .map(|layer| {
+                Layer::with_shared_weights(
+                    allocator,
+                    layer.transposed_weights(),
+                    activation.clone(),
+                )
+            })
+            .collect();
+        Autoencoder {
+            encoder,
+            decoder: MLP { layers },
+        }
+    }
+
+    pub fn encoder(&self) -> &MLP<T> {
+        &self.encoder
+    }
+
+    pub fn decoder(&self) -> &MLP<T> {
+        &self.decoder
+    }
+
+    pub fn encode(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        self.encoder.forward(inputs)
+    }
+
+    pub fn decode(&self, code: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        self.decoder.forward(code)
+    }
+
+    pub fn reconstruct(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        self.decode(&self.encode(inputs))
+    }
+
+    // Mean squared error between the inputs and their reconstruction
+    pub fn reconstruction_loss(
+        &self,
+        allocator: &mut Allocator<T>,
+        inputs: &[ValueId<T>],
+    ) -> ValueId<T> {
+        let outputs = self.reconstruct(inputs);
+        mse(allocator, &outputs, inputs)
+    }
+}
+
+impl<T: Num> Module<T> for Autoencoder<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        self.reconstruct(inputs)
+    }
+
+    // Tied weights are listed once, under the encoder
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        let mut seen = HashSet::new();
+        self.encoder
+            .parameters()
+            .into_iter()
+            .chain(self.decoder.parameters())
+            .filter(|param| seen.insert(*param))
+            .collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        let mut seen = HashSet::new();
+        prefixed("encoder", self.encoder.named_parameters())
+            .into_iter()
+            .chain(prefixed("decoder", self.decoder.named_parameters()))
+            .filter(|(_, param)| seen.insert(*param))
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "Autoencoder({}, {})",
+            self.encoder.describe(),
+            self.decoder.describe()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optim::{Optimizer, SGD};
+
+    #[test]
+    fn test_autoencoder() {
+        let mut allocator = Allocator::new();
+        let autoencoder = Autoencoder::new(&mut allocator, &[4, 3, 2], Some(Activation::Tanh));
+        assert_eq!(autoencoder.num_parameters(), (15 + 8) + (9 + 16));
+
+        let inputs = (0..4)
+            .map(|i| allocator.alloc(i as f64 / 4.0))
+            .collect::<Vec<_>>();
+        assert_eq!(autoencoder.encode(&inputs).len(), 2);
+        let reconstruction = autoencoder.reconstruct(&inputs);
+        assert_eq!(reconstruction.len(), 4);
+        let code = autoencoder.encode(&inputs);
+        let decoded = autoencoder.decode(&code);
+        assert_eq!(
+            allocator.get(decoded[3]).data,
+            allocator.get(reconstruction[3]).data
+        );
+    }
+
+    #[test]
+    fn test_tied_autoencoder_training() {
+        let mut allocator = Allocator::new();
+        let autoencoder =
+            Autoencoder::with_tied_weights(&mut allocator, &[4, 2], Some(Activation::Tanh));
+        // 8 shared weights, 2 encoder biases and 4 decoder biases
+        assert_eq!(autoencoder.num_parameters(), 14);
+        assert!(
+            autoencoder.decoder().layers[0].neurons[3].weights[1]
+                == autoencoder.encoder().layers[0].neurons[1].weights[3]
+        );
+
+        let samples = [[0.5, -0.5, 0.5, -0.5], [0.25, 0.25, -0.25, -0.25]]
+            .map(|sample| sample.map(|x| allocator.alloc(x)));
+        let mut optimizer = SGD::new(autoencoder.parameters(), 0.2);
+        let mut losses = vec![];
+        for _ in 0..100 {
+            let mut total = 0.0;
+            for sample in samples.iter() {
+                let loss = autoencoder.reconstruction_loss(&mut allocator, sample);
+                total += allocator.get(loss).data;
+                allocator.backward();
+                optimizer.step(&mut allocator);
+                optimizer.zero_grad(&mut allocator);
+                allocator.clear_temps();
+            }
+            losses.push(total);
+        }
+        assert!(losses.last().unwrap() < &(losses[0] / 2.0));
+    }
+}
@@ -0,0 +1,471 @@
+use rand::Rng;
+
+use super::Module;
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::{affine, Num},
+};
+
+// 1-D convolution over inputs laid out channel-major as [in_channels * length]. Outputs
+// use the same layout, [out_channels * out_length]. Zero padding is implicit: padded
+// positions are skipped instead of being multiplied by zero-valued nodes.
+pub struct Conv1d<T: Num> {
+    pub(crate) in_channels: usize,
+    pub(crate) out_channels: usize,
+    pub(crate) kernel_size: usize,
+    pub(crate) stride: usize,
+    pub(crate) padding: usize,
+    // [out_channels][in_channels * kernel_size]
+    pub(crate) weights: Vec<Vec<ValueId<T>>>,
+    pub(crate) biases: Vec<ValueId<T>>,
+}
+
+impl<T: Num> Conv1d<T> {
+    pub fn new(
+        allocator: &mut Allocator<T>,
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        stride: usize,
+        padding: usize,
+    ) -> Self {
+        let mut rng = rand::thread_rng();
+        Self::with_rng(
+            allocator,
+            in_channels,
+            out_channels,
+            kernel_size,
+            stride,
+            padding,
+            &mut rng,
+        )
+    }
+
+    // See `Neuron::with_rng`
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        stride: usize,
+        padding: usize,
+        rng: &mut impl Rng,
+    ) -> Self {
+        assert!(kernel_size > 0 && stride > 0, "invalid Conv1d geometry");
+        assert!(in_channels > 0, "Conv1d needs at least one input channel");
+
+        let weights = (0..out_channels)
+            .map(|_| {
+                (0..in_channels * kernel_size)
+                    .map(|_| allocator.alloc(rng.gen_range(-T::one()..T::one())))
+                    .collect()
+            })
+            .collect();
+        let biases = (0..out_channels)
+            .map(|_| allocator.alloc(rng.gen_range(-T::one()..T::one())))
+            .collect();
+        Conv1d {
+            in_channels,
+            out_channels,
+            kernel_size,
+            stride,
+            padding,
+            weights,
+            biases,
+        }
+    }
+
+    pub fn output_length(&self, length: usize) -> usize {
+        let padded = length + 2 * self.padding;
+        assert!(
+            padded >= self.kernel_size,
+            "Conv1d input shorter than kernel"
+        );
+        (padded - self.kernel_size) / self.stride + 1
+    }
+}
+
+impl<T: Num> Module<T> for Conv1d<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        assert_eq!(
+            inputs.len() % self.in_channels,
+            0,
+            "Conv1d input length is not a multiple of in_channels"
+        );
+        let length = inputs.len() / self.in_channels;
+        let out_length = self.output_length(length);
+
+        let mut outputs = Vec::with_capacity(self.out_channels * out_length);
+        let mut window_weights = Vec::with_capacity(self.in_channels * self.kernel_size);
+        let mut window_inputs = Vec::with_capacity(self.in_channels * self.kernel_size);
+        for (weights, bias) in self.weights.iter().zip(self.biases.iter()) {
+            for position in 0..out_length {
+                window_weights.clear();
+                window_inputs.clear();
+                for channel in 0..self.in_channels {
+                    for k in 0..self.kernel_size {
+                        // Index into the unpadded input, skipping the zero padding
+                        let index = (position * self.stride + k).wrapping_sub(self.padding);
+                        if index < length {
+                            window_weights.push(weights[channel * self.kernel_size + k]);
+                            window_inputs.push(inputs[channel * length + index]);
+                        }
+                    }
+                }
+                outputs.push(affine(&window_weights, &window_inputs, *bias));
+            }
+        }
+        outputs
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        self.weights
+            .iter()
+            .flatten()
+            .chain(self.biases.iter())
+            .copied()
+            .collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        named_conv_parameters(&self.weights, &self.biases)
+    }
+}
+
+// 2-D convolution over inputs laid out as [in_channels * height * width], row-major
+// within each channel. Outputs use the same layout with out_channels and the output size.
+pub struct Conv2d<T: Num> {
+    pub(crate) in_channels: usize,
+    pub(crate) out_channels: usize,
+    pub(crate) input_size: (usize, usize),
+    pub(crate) kernel_size: (usize, usize),
+    pub(crate) stride: usize,
+    pub(crate) padding: usize,
+    // [out_channels][in_channels * kernel_height * kernel_width]
+    pub(crate) weights: Vec<Vec<ValueId<T>>>,
+    pub(crate) biases: Vec<ValueId<T>>,
+}
+
+impl<T: Num> Conv2d<T> {
+    pub fn new(
+        allocator: &mut Allocator<T>,
+        in_channels: usize,
+        out_channels: usize,
+        input_size: (usize, usize),
+        kernel_size: (usize, usize),
+        stride: usize,
+        padding: usize,
+    ) -> Self {
+        let mut rng = rand::thread_rng();
+        Self::with_rng(
+            allocator,
+            in_channels,
+            out_channels,
+            input_size,
+            kernel_size,
+            stride,
+            padding,
+            &mut rng,
+        )
+    }
+
+    // See `Neuron::with_rng`
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        in_channels: usize,
+        out_channels: usize,
+        input_size: (usize, usize),
+        kernel_size: (usize, usize),
+        stride: usize,
+        padding: usize,
+        rng: &mut impl Rng,
+    ) -> Self {
+        assert!(
+            kernel_size.0 > 0 && kernel_size.1 > 0 && stride > 0,
+            "invalid Conv2d geometry"
+        );
+        assert!(in_channels > 0, "Conv2d needs at least one input channel");
+        assert!(
+            input_size.0 + 2 * padding >= kernel_size.0
+                && input_size.1 + 2 * padding >= kernel_size.1,
+            "Conv2d input smaller than kernel"
+        );
+
+        let weights = (0..out_channels)
+            .map(|_| {
+                (0..in_channels * kernel_size.0 * kernel_size.1)
+                    .map(|_| allocator.alloc(rng.gen_range(-T::one()..T::one())))
+                    .collect()
+            })
+            .collect();
+        let biases = (0..out_channels)
+            .map(|_| allocator.alloc(rng.gen_range(-T::one()..T::one())))
+            .collect();
+        Conv2d {
+            in_channels,
+            out_channels,
+            input_size,
+            kernel_size,
+            stride,
+            padding,
+            weights,
+            biases,
+        }
+    }
+
+    pub fn output_size(&self) -> (usize, usize) {
+        let (height, width) = self.input_size;
+        let (kernel_height, kernel_width) = self.kernel_size;
+        (
+            (height + 2 * self.padding - kernel_height) / self.stride + 1,
+            (width + 2 * self.padding - kernel_width) / self.stride + 1,
+        )
+    }
+}
+
+impl<T: Num> Module<T> for Conv2d<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        let (height, width) = self.input_size;
+        let (kernel_height, kernel_width) = self.kernel_size;
+        assert_eq!(
+            inputs.len(),
+            self.in_channels * height * width,
+            "Conv2d input doesn't match in_channels * height * width"
+        );
+        let (out_height, out_width) = self.output_size();
+
+        let mut outputs = Vec::with_capacity(self.out_channels * out_height * out_width);
+        let mut window_weights = Vec::with_capacity(self.weights.first().map_or(0, Vec::len));
+        let mut window_inputs = Vec::with_capacity(window_weights.capacity());
+        for (weights, bias) in self.weights.iter().zip(self.biases.iter()) {
+            for out_row in 0..out_height {
+                for out_col in 0..out_width {
+                    window_weights.clear();
+                    window_inputs.clear();
+                    for channel in 0..self.in_channels {
+                        for ki in 0..kernel_height {
+                            let row = (out_row * self.stride + ki).wrapping_sub(self.padding);
+                            if row >= height {
+                                continue;
+                            }
+                            for kj in 0..kernel_width {
+                                let col = (out_col * self.stride + kj).wrapping_sub(self.padding);
+                                if col < width {
+                                    window_weights.push(
+                                        weights[(channel * kernel_height + ki) * kernel_width + kj],
+                                    );
+                                    window_inputs
+                                        .push(inputs[(channel * height + row) * width + col]);
+                                }
+                            }
+                        }
+                    }
+                    outputs.push(affine(&window_weights, &window_inputs, *bias));
+                }
+            }
+        }
+        outputs
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        self.weights
+            .iter()
+            .flatten()
+            .chain(self.biases.iter())
+            .copied()
+            .collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        named_conv_parameters(&self.weights, &self.biases)
+    }
+}
+
+// "weight.{out_channel}.{i}" and "bias.{out_channel}", in `parameters()` order
+fn named_conv_parameters<T: Num>(
+    weights: &[Vec<ValueId<T>>],
+    biases: &[ValueId<T>],
+) -> Vec<(String, ValueId<T>)> {
+    let weights = weights.iter().enumerate().flat_map(|(o, kernel)| {
+        kernel
+            .iter()
+            .enumerate()
+            .map(move |(i, w)| (format!("weight.{o}.{i}"), *w))
+    });
+    let biases = biases
+        .iter()
+        .enumerate()
+        .map(|(o, b)| (format!("bias.{o}"), *b));
+    weights.chain(biases).collect()
+}
+
+// Concatenates per-channel (or per-sample) feature maps into one flat input
+pub fn flatten<T: Num>(values: &[Vec<ValueId<T>>]) -> Vec<ValueId<T>> {
+    values.iter().flatten().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        losses::mse,
+        nn::Layer,
+        operators::sum,
+        optim::{Optimizer, SGD},
+    };
+
+    fn set_weights(allocator: &mut Allocator<f64>, conv: &Conv1d<f64>, weights: &[f64], bias: f64) {
+        for (w, data) in conv.weights[0].iter().zip(weights) {
+            allocator.get_mut(*w).set_data(*data);
+        }
+        allocator.get_mut(conv.biases[0]).set_data(bias);
+    }
+
+    #[test]
+    fn test_conv1d() {
+        let mut allocator = Allocator::new();
+        let conv = Conv1d::new(&mut allocator, 1, 1, 3, 1, 0);
+        set_weights(&mut allocator, &conv, &[1.0, 0.0, -1.0], 0.5);
+        assert_eq!(conv.parameters().len(), 4);
+
+        let inputs = [1.0, 2.0, 4.0, 8.0]
+            .iter()
+            .map(|x| allocator.alloc(*x))
+            .collect::<Vec<_>>();
+        let outputs = conv.forward(&inputs);
+        let data = outputs
+            .iter()
+            .map(|o| allocator.get(*o).data)
+            .collect::<Vec<_>>();
+        assert_eq!(data, vec![-2.5, -5.5]);
+
+        let loss = outputs[0] + outputs[1];
+        allocator.backward();
+        assert_eq!(allocator.get(loss).grad, 1.0);
+        // The kernel is shared, so its gradient sums over both positions
+        assert_eq!(allocator.get(conv.weights[0][0]).grad, 3.0);
+        assert_eq!(allocator.get(conv.weights[0][1]).grad, 6.0);
+        assert_eq!(allocator.get(conv.weights[0][2]).grad, 12.0);
+        assert_eq!(allocator.get(conv.biases[0]).grad, 2.0);
+    }
+
+    #[test]
+    fn test_conv1d_stride_padding_channels() {
+        let mut allocator = Allocator::new();
+        let conv = Conv1d::new(&mut allocator, 2, 3, 2, 2, 1);
+        assert_eq!(conv.output_length(5), 3);
+
+        let inputs = (0..10)
+            .map(|x| allocator.alloc(x as f64))
+            .collect::<Vec<_>>();
+        let outputs = conv.forward(&inputs);
+        assert_eq!(outputs.len(), 3 * 3);
+
+        // The first window covers [pad, x_0] on each channel
+        let w = &conv.weights[1];
+        let expected = allocator.get(w[1]).data * 0.0
+            + allocator.get(w[3]).data * 5.0
+            + allocator.get(conv.biases[1]).data;
+        assert!((allocator.get(outputs[3]).data - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_conv2d() {
+        let mut allocator = Allocator::new();
+        let conv = Conv2d::new(&mut allocator, 1, 1, (3, 3), (2, 2), 1, 0);
+        assert_eq!(conv.output_size(), (2, 2));
+        for (w, data) in conv.weights[0].iter().zip([1.0, 2.0, 3.0, 4.0]) {
+            allocator.get_mut(*w).set_data(data);
+        }
+        allocator.get_mut(conv.biases[0]).set_data(0.0);
+
+        let inputs = (1..=9)
+            .map(|x| allocator.alloc(x as f64))
+            .collect::<Vec<_>>();
+        let outputs = conv.forward(&inputs);
+        let data = outputs
+            .iter()
+            .map(|o| allocator.get(*o).data)
+            .collect::<Vec<_>>();
+        // [[1, 2], [4, 5]] . [[1, 2], [3, 4]] = 37, and so on for each window
+        assert_eq!(data, vec![37.0, 47.0, 67.0, 77.0]);
+
+        let loss = sum(&mut allocator, &outputs);
+        assert_eq!(allocator.get(loss).data, 228.0);
+        allocator.backward();
+        assert_eq!(
+            allocator.get(conv.weights[0][0]).grad,
+            1.0 + 2.0 + 4.0 + 5.0
+        );
+        assert_eq!(allocator.get(inputs[4]).grad, 1.0 + 2.0 + 3.0 + 4.0);
+    }
+
+    #[test]
+    fn test_conv2d_padding_and_flatten() {
+        let mut allocator = Allocator::new();
+        let conv = Conv2d::new(&mut allocator, 2, 3, (4, 4), (3, 3), 2, 1);
+        assert_eq!(conv.output_size(), (2, 2));
+
+        let channels = (0..2)
+            .map(|_| (0..16).map(|_| allocator.alloc(1.0)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let inputs = flatten(&channels);
+        assert_eq!(inputs.len(), 32);
+
+        let outputs = conv.forward(&inputs);
+        assert_eq!(outputs.len(), 3 * 2 * 2);
+        assert_eq!(conv.parameters().len(), 3 * 2 * 9 + 3);
+
+        // The top-left window only overlaps the bottom-right 2x2 of the kernel
+        let w = &conv.weights[0];
+        let expected = [4, 5, 7, 8, 13, 14, 16, 17]
+            .iter()
+            .map(|i| allocator.get(w[*i]).data)
+            .sum::<f64>()
+            + allocator.get(conv.biases[0]).data;
+        assert!((allocator.get(outputs[0]).data - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_tiny_cnn_training() {
+        let mut allocator = Allocator::new();
+        let conv = Conv2d::new(&mut allocator, 1, 2, (4, 4), (2, 2), 2, 0);
+        let head = Layer::new(&mut allocator, 8, 1, None);
+        let mut params = conv.parameters();
+        params.extend(head.parameters());
+        let mut optimizer = SGD::new(params, 0.01);
+
+        let inputs = (0..16)
+            .map(|x| allocator.alloc(x as f64 / 16.0))
+            .collect::<Vec<_>>();
+        let target = [allocator.alloc(1.0)];
+
+        let mut losses = vec![];
+        for _ in 0..30 {
+            let features = conv.forward(&inputs);
+            let output = head.forward(&features);
+            let loss = mse(&mut allocator, &output, &target);
+            losses.push(allocator.get(loss).data);
+            allocator.backward();
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+        assert!(losses.last().unwrap() < losses.first().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Conv1d needs at least one input channel")]
+    fn test_conv1d_no_channels() {
+        let mut allocator = Allocator::<f64>::new();
+        Conv1d::new(&mut allocator, 0, 1, 2, 1, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Conv2d needs at least one input channel")]
+    fn test_conv2d_no_channels() {
+        let mut allocator = Allocator::<f64>::new();
+        Conv2d::new(&mut allocator, 0, 1, (3, 3), (2, 2), 1, 0);
+    }
+}
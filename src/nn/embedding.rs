@@ -0,0 +1,118 @@
+use std::{cell::RefCell, collections::BTreeSet};
+
+use rand::Rng;
+
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::Num,
+};
+
+// A lookup table of `num_embeddings` rows of `dim` trainable values. Rows returned by
+// `lookup` are the parameters themselves, so no tape nodes are created, and the rows
+// used since the last `take_touched` are tracked for sparse optimizer updates.
+pub struct Embedding<T: Num> {
+    pub(crate) dim: usize,
+    pub(crate) rows: Vec<Vec<ValueId<T>>>,
+    pub(crate) touched: RefCell<BTreeSet<usize>>,
+}
+
+impl<T: Num> Embedding<T> {
+    pub fn new(allocator: &mut Allocator<T>, num_embeddings: usize, dim: usize) -> Self {
+        Self::with_rng(allocator, num_embeddings, dim, &mut rand::thread_rng())
+    }
+
+    // See `Neuron::with_rng`
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        num_embeddings: usize,
+        dim: usize,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let rows = (0..num_embeddings)
+            .map(|_| {
+                (0..dim)
+                    .map(|_| allocator.alloc(rng.gen_range(-T::one()..T::one())))
+                    .collect()
+            })
+            .collect();
+        Embedding {
+            dim,
+            rows,
+            touched: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn lookup(&self, index: usize) -> Vec<ValueId<T>> {
+        self.touched.borrow_mut().insert(index);
+        self.rows[index].clone()
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn num_embeddings(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn parameters(&self) -> Vec<ValueId<T>> {
+        self.rows.iter().flatten().copied().collect()
+    }
+
+    // Returns the parameters of every row looked up since the last call, and resets
+    // the tracking. Pass the result to `Optimizer::step_sparse`.
+    pub fn take_touched(&self) -> Vec<ValueId<T>> {
+        let touched = std::mem::take(&mut *self.touched.borrow_mut());
+        touched
+            .into_iter()
+            .flat_map(|row| self.rows[row].iter().copied())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        operators::sum,
+        optim::{Optimizer, SGD},
+    };
+
+    #[test]
+    fn test_embedding_sparse_step() {
+        let mut allocator = Allocator::new();
+        let embedding = Embedding::<f64>::new(&mut allocator, 1000, 4);
+        let mut optimizer = SGD::with_momentum(embedding.parameters(), 0.1, 0.9);
+        let before = allocator.gather_data(&embedding.parameters());
+
+        let a = embedding.lookup(3);
+        let b = embedding.lookup(42);
+        let c = embedding.lookup(3);
+        let inputs = [a, b, c].concat();
+        sum(&mut allocator, &inputs);
+        allocator.backward();
+
+        let touched = embedding.take_touched();
+        assert_eq!(touched.len(), 2 * 4);
+        optimizer.step_sparse(&mut allocator, &touched);
+        optimizer.zero_grad_sparse(&mut allocator, &touched);
+        assert!(embedding.take_touched().is_empty());
+
+        let after = allocator.gather_data(&embedding.parameters());
+        for (row, (before, after)) in before.chunks(4).zip(after.chunks(4)).enumerate() {
+            match row {
+                // Row 3 was used twice, so its gradient is 2
+                3 => before
+                    .iter()
+                    .zip(after)
+                    .for_each(|(b, a)| assert!((b - 0.2 - a).abs() < 1e-12)),
+                42 => before
+                    .iter()
+                    .zip(after)
+                    .for_each(|(b, a)| assert!((b - 0.1 - a).abs() < 1e-12)),
+                _ => assert_eq!(before, after),
+            }
+        }
+        assert!(touched.iter().all(|p| allocator.get(*p).grad == 0.0));
+    }
+}
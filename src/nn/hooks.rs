@@ -0,0 +1,153 @@
+use std::cell::{Cell, RefCell};
+
+use super::Module;
+use crate::{allocator::ValueId, operators::Num};
+
+type PreHook<T> = Box<dyn Fn(&[ValueId<T>])>;
+type PostHook<T> = Box<dyn Fn(&[ValueId<T>], &[ValueId<T>])>;
+
+// Identifies a registered hook so it can be removed again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HookHandle(usize);
+
+// Wraps a module to run callbacks around its forward pass, e.g. to capture the activations
+// of an intermediate layer inside a `Sequential` without changing the model code. Pre-hooks
+// see the inputs, post-hooks the inputs and outputs, each in registration order. Hooks are
+// registered through `&self` so they can be added after the model is built.
+pub struct Hooked<T: Num, M> {
+    pub(crate) inner: M,
+    pub(crate) pre_hooks: RefCell<Vec<(HookHandle, PreHook<T>)>>,
+    pub(crate) post_hooks: RefCell<Vec<(HookHandle, PostHook<T>)>>,
+    pub(crate) next_handle: Cell<usize>,
+}
+
+impl<T: Num, M> Hooked<T, M> {
+    pub fn new(inner: M) -> Self {
+        Hooked {
+            inner,
+            pre_hooks: RefCell::new(vec![]),
+            post_hooks: RefCell::new(vec![]),
+            next_handle: Cell::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn register_forward_pre_hook(&self, hook: impl Fn(&[ValueId<T>]) + 'static) -> HookHandle {
+        let handle = self.next_handle();
+        self.pre_hooks.borrow_mut().push((handle, Box::new(hook)));
+        handle
+    }
+
+    pub fn register_forward_hook(
+        &self,
+        hook: impl Fn(&[ValueId<T>], &[ValueId<T>]) + 'static,
+    ) -> HookHandle {
+        let handle = self.next_handle();
+        self.post_hooks.borrow_mut().push((handle, Box::new(hook)));
+        handle
+    }
+
+    // Returns false if the hook was already removed
+    pub fn remove_hook(&self, handle: HookHandle) -> bool {
+        let mut pre_hooks = self.pre_hooks.borrow_mut();
+        let mut post_hooks = self.post_hooks.borrow_mut();
+        let before = pre_hooks.len() + post_hooks.len();
+        pre_hooks.retain(|(h, _)| *h != handle);
+        post_hooks.retain(|(h, _)| *h != handle);
+        pre_hooks.len() + post_hooks.len() < before
+    }
+
+    fn next_handle(&self) -> HookHandle {
+        let handle = self.next_handle.get();
+        self.next_handle.set(handle + 1);
+        HookHandle(handle)
+    }
+}
+
+impl<T: Num, M: Module<T>> Module<T> for Hooked<T, M> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        for (_, hook) in self.pre_hooks.borrow().iter() {
+            hook(inputs);
+        }
+        let outputs = self.inner.forward(inputs);
+        for (_, hook) in self.post_hooks.borrow().iter() {
+            hook(inputs, &outputs);
+        }
+        outputs
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        self.inner.parameters()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        self.inner.named_parameters()
+    }
+
+    fn set_training(&self, training: bool) {
+        self.inner.set_training(training);
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        allocator::Allocator,
+        nn::{Activation, Layer, Sequential},
+    };
+
+    #[test]
+    fn test_forward_hooks() {
+        let mut allocator = Allocator::new();
+        let hidden = Hooked::new(Layer::new(&mut allocator, 2, 3, Some(Activation::Tanh)));
+
+        let seen_inputs = Rc::new(Cell::new(0));
+        let activations = Rc::new(RefCell::new(vec![]));
+        let counter = seen_inputs.clone();
+        hidden.register_forward_pre_hook(move |inputs| counter.set(counter.get() + inputs.len()));
+        let captured = activations.clone();
+        hidden.register_forward_hook(move |_, outputs| {
+            captured.borrow_mut().push(outputs.to_vec());
+        });
+        assert_eq!(hidden.describe(), hidden.inner().describe());
+
+        let mut model = Sequential::new(vec![Box::new(hidden)]);
+        model.push(Layer::new(&mut allocator, 3, 1, None));
+        assert_eq!(model.parameters().len(), 9 + 4);
+
+        let inputs = vec![allocator.alloc(0.5f64), allocator.alloc(-1.0)];
+        model.forward(&inputs);
+        model.forward(&inputs);
+        assert_eq!(seen_inputs.get(), 4);
+        assert_eq!(activations.borrow().len(), 2);
+        let captured = &activations.borrow()[1];
+        assert_eq!(captured.len(), 3);
+        assert!(captured.iter().all(|a| allocator.get(*a).data.abs() < 1.0));
+    }
+
+    #[test]
+    fn test_remove_hook() {
+        let mut allocator = Allocator::new();
+        let layer = Hooked::new(Layer::new(&mut allocator, 1, 1, None));
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let handle = layer.register_forward_hook(move |_, _| counter.set(counter.get() + 1));
+
+        let inputs = vec![allocator.alloc(1.0f64)];
+        layer.forward(&inputs);
+        assert!(layer.remove_hook(handle));
+        assert!(!layer.remove_hook(handle));
+        layer.forward(&inputs);
+        assert_eq!(calls.get(), 1);
+    }
+}
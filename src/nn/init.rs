@@ -0,0 +1,59 @@
+use rand::Rng;
+
+use crate::operators::Num;
+
+// How a layer's parameters are sampled. `fan_in` is the number of inputs per neuron and
+// `fan_out` the number of neurons in the layer. The Xavier and He schemes set biases to
+// zero; every other scheme samples biases the same way as weights.
+pub enum Init<T: Num> {
+    // uniform(low, high)
+    Uniform(T, T),
+    // normal(mean, std)
+    Normal(T, T),
+    // Glorot: uniform(-a, a) with a = sqrt(6 / (fan_in + fan_out)), suited to tanh
+    Xavier,
+    // Kaiming: normal(0, sqrt(2 / fan_in)), suited to relu
+    He,
+    Constant(T),
+    // Called with (fan_in, fan_out) for every weight and bias
+    Custom(Box<dyn Fn(usize, usize) -> T>),
+}
+
+impl<T: Num> Default for Init<T> {
+    fn default() -> Self {
+        Init::Uniform(-T::one(), T::one())
+    }
+}
+
+impl<T: Num> Init<T> {
+    pub fn weight(&self, rng: &mut impl Rng, fan_in: usize, fan_out: usize) -> T {
+        match self {
+            Init::Uniform(low, high) => rng.gen_range(*low..*high),
+            Init::Normal(mean, std) => *mean + *std * standard_normal(rng),
+            Init::Xavier => {
+                let limit = T::from_f64((6.0 / (fan_in + fan_out) as f64).sqrt()).unwrap();
+                rng.gen_range(-limit..limit)
+            }
+            Init::He => {
+                let std = T::from_f64((2.0 / fan_in as f64).sqrt()).unwrap();
+                std * standard_normal(rng)
+            }
+            Init::Constant(value) => *value,
+            Init::Custom(f) => f(fan_in, fan_out),
+        }
+    }
+
+    pub fn bias(&self, rng: &mut impl Rng, fan_in: usize, fan_out: usize) -> T {
+        match self {
+            Init::Xavier | Init::He => T::zero(),
+            _ => self.weight(rng, fan_in, fan_out),
+        }
+    }
+}
+
+// Box-Muller transform, since only uniform sampling is available without rand_distr
+pub(crate) fn standard_normal<T: Num>(rng: &mut impl Rng) -> T {
+    let u1 = 1.0 - rng.gen::<f64>();
+    let u2 = rng.gen::<f64>();
+    T::from_f64((-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()).unwrap()
+}
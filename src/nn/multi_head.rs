@@ -0,0 +1,112 @@
+use super::{prefixed, Layer, Module, MLP};
+use crate::{allocator::ValueId, operators::Num};
+
+// A shared MLP trunk feeding several independent head layers, e.g. for multi-task
+// learning or actor-critic. Summing the per-head losses before backward() accumulates
+// every head's gradient into the trunk.
+pub struct MultiHead<T: Num> {
+    pub(crate) trunk: MLP<T>,
+    pub(crate) heads: Vec<Layer<T>>,
+}
+
+impl<T: Num> MultiHead<T> {
+    pub fn new(trunk: MLP<T>, heads: Vec<Layer<T>>) -> Self {
+        MultiHead { trunk, heads }
+    }
+
+    pub fn trunk(&self) -> &MLP<T> {
+        &self.trunk
+    }
+
+    pub fn heads(&self) -> &[Layer<T>] {
+        &self.heads
+    }
+
+    // One output vector per head, all computed from a single trunk forward pass
+    pub fn forward_heads(&self, inputs: &[ValueId<T>]) -> Vec<Vec<ValueId<T>>> {
+        let features = self.trunk.forward(inputs);
+        self.heads
+            .iter()
+            .map(|head| head.forward(&features))
+            .collect()
+    }
+}
+
+// As a module, the head outputs are concatenated in order
+impl<T: Num> Module<T> for MultiHead<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        self.forward_heads(inputs).concat()
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        let mut params = self.trunk.parameters();
+        params.extend(self.heads.iter().flat_map(|head| head.parameters()));
+        params
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        let mut named = prefixed("trunk", self.trunk.named_parameters());
+        for (i, head) in self.heads.iter().enumerate() {
+            named.extend(prefixed(&format!("head.{i}"), head.named_parameters()));
+        }
+        named
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "MultiHead({}, {} heads)",
+            self.trunk.describe(),
+            self.heads.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{allocator::Allocator, losses::mse, nn::Activation, operators::sum};
+
+    #[test]
+    fn test_multi_head() {
+        let mut allocator = Allocator::new();
+        let trunk = MLP::new(&mut allocator, &[2, 4], Some(Activation::Tanh));
+        let heads = vec![
+            Layer::new(&mut allocator, 4, 3, None),
+            Layer::new(&mut allocator, 4, 1, None),
+        ];
+        let model = MultiHead::new(trunk, heads);
+        assert_eq!(model.num_parameters(), 12 + 15 + 5);
+        assert_eq!(model.named_parameters()[12].0, "head.0.0.weight.0");
+
+        let inputs = vec![allocator.alloc(0.5f64), allocator.alloc(-1.0)];
+        let policy_target = [0.0, 1.0, 0.0].map(|t| allocator.alloc(t));
+        let value_target = [allocator.alloc(0.3)];
+        let trunk_weight = model.trunk().parameters()[0];
+
+        // Backward through each head alone, then through the summed losses
+        let mut head_grads = vec![];
+        for head in 0..2 {
+            let outputs = model.forward_heads(&inputs);
+            assert_eq!(outputs[0].len(), 3);
+            assert_eq!(outputs[1].len(), 1);
+            let targets: &[ValueId<f64>] = if head == 0 {
+                &policy_target
+            } else {
+                &value_target
+            };
+            mse(&mut allocator, &outputs[head], targets);
+            allocator.backward();
+            head_grads.push(allocator.get(trunk_weight).grad);
+            model.zero_grad();
+            allocator.clear_temps();
+        }
+
+        let outputs = model.forward_heads(&inputs);
+        let policy_loss = mse(&mut allocator, &outputs[0], &policy_target);
+        let value_loss = mse(&mut allocator, &outputs[1], &value_target);
+        sum(&mut allocator, &[policy_loss, value_loss]);
+        allocator.backward();
+        let combined = allocator.get(trunk_weight).grad;
+        assert!((combined - (head_grads[0] + head_grads[1])).abs() < 1e-12);
+    }
+}
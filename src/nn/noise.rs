@@ -0,0 +1,100 @@
+use std::cell::{Cell, RefCell};
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use super::{init::standard_normal, Module};
+use crate::{allocator::ValueId, operators::Num};
+
+// Adds zero-mean Gaussian noise with standard deviation `sigma` to every input while
+// training, and is the identity in eval mode. The noise is a constant, so gradients pass
+// through unchanged.
+pub struct GaussianNoise<T: Num> {
+    pub(crate) sigma: T,
+    pub(crate) training: Cell<bool>,
+    pub(crate) rng: RefCell<StdRng>,
+}
+
+impl<T: Num> GaussianNoise<T> {
+    pub fn new(sigma: T) -> Self {
+        Self::with_rng(sigma, StdRng::from_entropy())
+    }
+
+    pub fn with_seed(sigma: T, seed: u64) -> Self {
+        Self::with_rng(sigma, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(sigma: T, rng: StdRng) -> Self {
+        GaussianNoise {
+            sigma,
+            training: Cell::new(true),
+            rng: RefCell::new(rng),
+        }
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training.get()
+    }
+}
+
+impl<T: Num> Module<T> for GaussianNoise<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        if !self.training.get() {
+            return inputs.to_vec();
+        }
+        let mut rng = self.rng.borrow_mut();
+        inputs
+            .iter()
+            .map(|x| *x + self.sigma * standard_normal::<T>(&mut *rng))
+            .collect()
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        vec![]
+    }
+
+    fn set_training(&self, training: bool) {
+        self.training.set(training);
+    }
+
+    fn describe(&self) -> String {
+        format!("GaussianNoise({})", self.sigma)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{allocator::Allocator, nn::Sequential};
+
+    #[test]
+    fn test_gaussian_noise() {
+        let mut allocator = Allocator::new();
+        let inputs = (0..1000)
+            .map(|_| allocator.alloc(1.0f64))
+            .collect::<Vec<_>>();
+
+        let noise = GaussianNoise::with_seed(0.5, 42);
+        let outputs = noise.forward(&inputs);
+        let data = outputs
+            .iter()
+            .map(|v| allocator.get(*v).data)
+            .collect::<Vec<_>>();
+        let mean = data.iter().sum::<f64>() / 1000.0;
+        let var = data.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / 1000.0;
+        assert!((mean - 1.0).abs() < 0.05);
+        assert!((var.sqrt() - 0.5).abs() < 0.05);
+
+        // The same seed reproduces the same noise
+        let again = GaussianNoise::with_seed(0.5, 42).forward(&inputs);
+        assert_eq!(allocator.get(again[7]).data, data[7]);
+
+        allocator.backward();
+        assert_eq!(allocator.get(inputs[999]).grad, 1.0);
+
+        let mut model = Sequential::new(vec![]);
+        model.push(GaussianNoise::with_seed(0.5, 0));
+        model.eval();
+        let outputs = model.forward(&inputs[..3]);
+        assert!(outputs.iter().zip(&inputs).all(|(o, x)| *o == *x));
+    }
+}
@@ -0,0 +1,112 @@
+use super::Module;
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::{mean, powf, Num},
+};
+
+// Normalizes each `dim`-sized feature vector to zero mean and unit variance, then applies
+// a learned per-feature scale (initialized to 1) and shift (initialized to 0)
+pub struct LayerNorm<T: Num> {
+    pub(crate) dim: usize,
+    pub(crate) eps: T,
+    pub(crate) gamma: Vec<ValueId<T>>,
+    pub(crate) beta: Vec<ValueId<T>>,
+}
+
+impl<T: Num> LayerNorm<T> {
+    pub fn new(allocator: &mut Allocator<T>, dim: usize) -> Self {
+        Self::with_eps(allocator, dim, T::from_f64(1e-5).unwrap())
+    }
+
+    pub fn with_eps(allocator: &mut Allocator<T>, dim: usize, eps: T) -> Self {
+        let gamma = (0..dim).map(|_| allocator.alloc(T::one())).collect();
+        let beta = (0..dim).map(|_| allocator.alloc(T::zero())).collect();
+        LayerNorm {
+            dim,
+            eps,
+            gamma,
+            beta,
+        }
+    }
+
+    pub fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        assert_eq!(inputs.len(), self.dim, "LayerNorm input size mismatch");
+
+        let allocator = unsafe { inputs[0].allocator().as_mut().unwrap() };
+        let mu = mean(allocator, inputs);
+        let centered = inputs.iter().map(|x| *x - mu).collect::<Vec<_>>();
+        let squares = centered.iter().map(|x| *x * *x).collect::<Vec<_>>();
+        let var = mean(allocator, &squares);
+        let inv_std = powf(var + self.eps, -T::from_f64(0.5).unwrap());
+        centered
+            .iter()
+            .zip(self.gamma.iter().zip(&self.beta))
+            .map(|(x, (gamma, beta))| *x * inv_std * *gamma + *beta)
+            .collect()
+    }
+
+    pub fn parameters(&self) -> Vec<ValueId<T>> {
+        let mut params = self.gamma.clone();
+        params.extend_from_slice(&self.beta);
+        params
+    }
+}
+
+impl<T: Num> Module<T> for LayerNorm<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        LayerNorm::forward(self, inputs)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        LayerNorm::parameters(self)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        let gamma = self
+            .gamma
+            .iter()
+            .enumerate()
+            .map(|(i, g)| (format!("gamma.{i}"), *g));
+        let beta = self
+            .beta
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (format!("beta.{i}"), *b));
+        gamma.chain(beta).collect()
+    }
+
+    fn describe(&self) -> String {
+        format!("LayerNorm({})", self.dim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_norm() {
+        let mut allocator = Allocator::new();
+        let norm = LayerNorm::new(&mut allocator, 4);
+        assert_eq!(norm.parameters().len(), 8);
+
+        let inputs = [1.0, 2.0, 3.0, 6.0].map(|x| allocator.alloc(x));
+        let outputs = norm.forward(&inputs);
+        let data = outputs
+            .iter()
+            .map(|v| allocator.get(*v).data)
+            .collect::<Vec<f64>>();
+        let mean = data.iter().sum::<f64>() / 4.0;
+        let var = data.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / 4.0;
+        assert!(mean.abs() < 1e-9);
+        assert!((var - 1.0).abs() < 1e-4);
+
+        // The outputs always sum to zero, so shifting every input changes nothing
+        let total = crate::operators::sum(&mut allocator, &outputs);
+        allocator.backward();
+        let input_grad = inputs.iter().map(|v| allocator.get(*v).grad).sum::<f64>();
+        assert!(allocator.get(total).data.abs() < 1e-9);
+        assert!(input_grad.abs() < 1e-9);
+        assert_eq!(allocator.get(norm.beta[0]).grad, 1.0);
+    }
+}
@@ -0,0 +1,292 @@
+use super::Module;
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::{max, mean, Num},
+};
+
+type Reduce<T> = fn(&mut Allocator<T>, &[ValueId<T>]) -> ValueId<T>;
+
+// Pools windows of inputs laid out as [channels * length], without padding
+fn pool1d<T: Num>(
+    inputs: &[ValueId<T>],
+    channels: usize,
+    kernel_size: usize,
+    stride: usize,
+    reduce: Reduce<T>,
+) -> Vec<ValueId<T>> {
+    assert_eq!(
+        inputs.len() % channels,
+        0,
+        "pooling input length is not a multiple of channels"
+    );
+    let length = inputs.len() / channels;
+    assert!(length >= kernel_size, "pooling input shorter than kernel");
+    let out_length = (length - kernel_size) / stride + 1;
+
+    let allocator = unsafe { inputs[0].allocator().as_mut().unwrap() };
+    let mut outputs = Vec::with_capacity(channels * out_length);
+    for channel in inputs.chunks(length) {
+        for position in 0..out_length {
+            let start = position * stride;
+            outputs.push(reduce(allocator, &channel[start..start + kernel_size]));
+        }
+    }
+    outputs
+}
+
+// Pools windows of inputs laid out as [channels * height * width], without padding
+fn pool2d<T: Num>(
+    inputs: &[ValueId<T>],
+    channels: usize,
+    input_size: (usize, usize),
+    kernel_size: (usize, usize),
+    stride: usize,
+    reduce: Reduce<T>,
+) -> Vec<ValueId<T>> {
+    let (height, width) = input_size;
+    let (kernel_height, kernel_width) = kernel_size;
+    assert_eq!(
+        inputs.len(),
+        channels * height * width,
+        "pooling input doesn't match channels * height * width"
+    );
+    let (out_height, out_width) = pool2d_output_size(input_size, kernel_size, stride);
+
+    let allocator = unsafe { inputs[0].allocator().as_mut().unwrap() };
+    let mut outputs = Vec::with_capacity(channels * out_height * out_width);
+    let mut window = Vec::with_capacity(kernel_height * kernel_width);
+    for channel in inputs.chunks(height * width) {
+        for out_row in 0..out_height {
+            for out_col in 0..out_width {
+                window.clear();
+                for row in out_row * stride..out_row * stride + kernel_height {
+                    let start = row * width + out_col * stride;
+                    window.extend_from_slice(&channel[start..start + kernel_width]);
+                }
+                outputs.push(reduce(allocator, &window));
+            }
+        }
+    }
+    outputs
+}
+
+fn pool2d_output_size(
+    (height, width): (usize, usize),
+    (kernel_height, kernel_width): (usize, usize),
+    stride: usize,
+) -> (usize, usize) {
+    assert!(
+        height >= kernel_height && width >= kernel_width,
+        "pooling input smaller than kernel"
+    );
+    (
+        (height - kernel_height) / stride + 1,
+        (width - kernel_width) / stride + 1,
+    )
+}
+
+pub struct MaxPool1d {
+    pub(crate) channels: usize,
+    pub(crate) kernel_size: usize,
+    pub(crate) stride: usize,
+}
+
+impl MaxPool1d {
+    pub fn new(channels: usize, kernel_size: usize, stride: usize) -> Self {
+        assert!(kernel_size > 0 && stride > 0, "invalid pooling geometry");
+        MaxPool1d {
+            channels,
+            kernel_size,
+            stride,
+        }
+    }
+}
+
+impl<T: Num> Module<T> for MaxPool1d {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        pool1d(inputs, self.channels, self.kernel_size, self.stride, max)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        vec![]
+    }
+}
+
+pub struct AvgPool1d {
+    pub(crate) channels: usize,
+    pub(crate) kernel_size: usize,
+    pub(crate) stride: usize,
+}
+
+impl AvgPool1d {
+    pub fn new(channels: usize, kernel_size: usize, stride: usize) -> Self {
+        assert!(kernel_size > 0 && stride > 0, "invalid pooling geometry");
+        AvgPool1d {
+            channels,
+            kernel_size,
+            stride,
+        }
+    }
+}
+
+impl<T: Num> Module<T> for AvgPool1d {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        pool1d(inputs, self.channels, self.kernel_size, self.stride, mean)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        vec![]
+    }
+}
+
+pub struct MaxPool2d {
+    pub(crate) channels: usize,
+    pub(crate) input_size: (usize, usize),
+    pub(crate) kernel_size: (usize, usize),
+    pub(crate) stride: usize,
+}
+
+impl MaxPool2d {
+    pub fn new(
+        channels: usize,
+        input_size: (usize, usize),
+        kernel_size: (usize, usize),
+        stride: usize,
+    ) -> Self {
+        assert!(
+            kernel_size.0 > 0 && kernel_size.1 > 0 && stride > 0,
+            "invalid pooling geometry"
+        );
+        MaxPool2d {
+            channels,
+            input_size,
+            kernel_size,
+            stride,
+        }
+    }
+
+    pub fn output_size(&self) -> (usize, usize) {
+        pool2d_output_size(self.input_size, self.kernel_size, self.stride)
+    }
+}
+
+impl<T: Num> Module<T> for MaxPool2d {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        pool2d(
+            inputs,
+            self.channels,
+            self.input_size,
+            self.kernel_size,
+            self.stride,
+            max,
+        )
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        vec![]
+    }
+}
+
+pub struct AvgPool2d {
+    pub(crate) channels: usize,
+    pub(crate) input_size: (usize, usize),
+    pub(crate) kernel_size: (usize, usize),
+    pub(crate) stride: usize,
+}
+
+impl AvgPool2d {
+    pub fn new(
+        channels: usize,
+        input_size: (usize, usize),
+        kernel_size: (usize, usize),
+        stride: usize,
+    ) -> Self {
+        assert!(
+            kernel_size.0 > 0 && kernel_size.1 > 0 && stride > 0,
+            "invalid pooling geometry"
+        );
+        AvgPool2d {
+            channels,
+            input_size,
+            kernel_size,
+            stride,
+        }
+    }
+
+    pub fn output_size(&self) -> (usize, usize) {
+        pool2d_output_size(self.input_size, self.kernel_size, self.stride)
+    }
+}
+
+impl<T: Num> Module<T> for AvgPool2d {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        pool2d(
+            inputs,
+            self.channels,
+            self.input_size,
+            self.kernel_size,
+            self.stride,
+            mean,
+        )
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(allocator: &Allocator<f64>, values: &[ValueId<f64>]) -> Vec<f64> {
+        values.iter().map(|v| allocator.get(*v).data).collect()
+    }
+
+    #[test]
+    fn test_pool1d() {
+        let mut allocator = Allocator::new();
+        let inputs = [1.0, 3.0, 2.0, 0.0, 5.0, 4.0, 6.0, 8.0]
+            .iter()
+            .map(|x| allocator.alloc(*x))
+            .collect::<Vec<_>>();
+
+        let outputs = Module::<f64>::forward(&MaxPool1d::new(2, 2, 2), &inputs);
+        assert_eq!(data(&allocator, &outputs), vec![3.0, 2.0, 5.0, 8.0]);
+
+        allocator.backward();
+        assert_eq!(allocator.get(inputs[7]).grad, 1.0);
+        assert_eq!(allocator.get(inputs[6]).grad, 0.0);
+
+        let outputs = Module::<f64>::forward(&AvgPool1d::new(1, 3, 1), &inputs);
+        assert_eq!(outputs.len(), 6);
+        assert_eq!(data(&allocator, &outputs[..2]), vec![2.0, 5.0 / 3.0]);
+    }
+
+    #[test]
+    fn test_pool2d() {
+        let mut allocator = Allocator::new();
+        let inputs = (1..=16)
+            .map(|x| allocator.alloc(x as f64))
+            .collect::<Vec<_>>();
+
+        let pool = MaxPool2d::new(1, (4, 4), (2, 2), 2);
+        assert_eq!(pool.output_size(), (2, 2));
+        let outputs = Module::<f64>::forward(&pool, &inputs);
+        assert_eq!(data(&allocator, &outputs), vec![6.0, 8.0, 14.0, 16.0]);
+
+        allocator.backward();
+        assert_eq!(allocator.get(inputs[15]).grad, 1.0);
+        assert_eq!(allocator.get(inputs[14]).grad, 0.0);
+
+        let pool = AvgPool2d::new(1, (4, 4), (2, 2), 2);
+        let outputs = Module::<f64>::forward(&pool, &inputs);
+        assert_eq!(data(&allocator, &outputs), vec![3.5, 5.5, 11.5, 13.5]);
+
+        // Only the last window is seeded by backward()
+        allocator.zero_grads();
+        allocator.backward();
+        assert_eq!(allocator.get(inputs[10]).grad, 0.25);
+        assert_eq!(allocator.get(inputs[0]).grad, 0.0);
+    }
+}
@@ -0,0 +1,173 @@
+use rand::Rng;
+
+use super::{Activation, Init, Layer, Module};
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::Num,
+};
+
+// h' = tanh(W [x, h] + b), where the input and previous hidden state are concatenated
+// and fed through a single tanh layer
+pub struct RnnCell<T: Num> {
+    pub(crate) input_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) layer: Layer<T>,
+}
+
+impl<T: Num> RnnCell<T> {
+    pub fn new(allocator: &mut Allocator<T>, input_size: usize, hidden_size: usize) -> Self {
+        Self::with_rng(allocator, input_size, hidden_size, &mut rand::thread_rng())
+    }
+
+    // See `Neuron::with_rng`
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        input_size: usize,
+        hidden_size: usize,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let layer = Layer::with_rng(
+            allocator,
+            input_size + hidden_size,
+            hidden_size,
+            Some(Activation::Tanh),
+            Init::default(),
+            rng,
+        );
+        RnnCell {
+            input_size,
+            hidden_size,
+            layer,
+        }
+    }
+
+    // A zero hidden state on the tape, to start an unrolled sequence from
+    pub fn initial_state(&self, allocator: &mut Allocator<T>) -> Vec<ValueId<T>> {
+        (0..self.hidden_size)
+            .map(|_| allocator.alloc_t(T::zero()))
+            .collect()
+    }
+
+    pub fn step(&self, input: &[ValueId<T>], hidden: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        assert_eq!(input.len(), self.input_size, "RnnCell input size mismatch");
+        assert_eq!(
+            hidden.len(),
+            self.hidden_size,
+            "RnnCell hidden size mismatch"
+        );
+
+        let mut combined = Vec::with_capacity(self.input_size + self.hidden_size);
+        combined.extend_from_slice(input);
+        combined.extend_from_slice(hidden);
+        self.layer.forward(&combined)
+    }
+
+    // Runs the cell over a whole sequence and returns the hidden state after each step.
+    // Everything is recorded on the tape, so backward() performs BPTT through all steps.
+    pub fn unroll(
+        &self,
+        inputs: &[Vec<ValueId<T>>],
+        initial: &[ValueId<T>],
+    ) -> Vec<Vec<ValueId<T>>> {
+        let mut states: Vec<Vec<ValueId<T>>> = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let hidden = states.last().map_or(initial, |h| h.as_slice());
+            let next = self.step(input, hidden);
+            states.push(next);
+        }
+        states
+    }
+}
+
+// As a module, the input is the concatenation [x, h] and the output is the next h
+impl<T: Num> Module<T> for RnnCell<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        let (input, hidden) = inputs.split_at(self.input_size);
+        self.step(input, hidden)
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        self.layer.parameters()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        self.layer.named_parameters()
+    }
+
+    fn describe(&self) -> String {
+        format!("RnnCell({} -> {})", self.input_size, self.hidden_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        losses::mse,
+        optim::{Optimizer, SGD},
+    };
+
+    #[test]
+    fn test_rnn_step() {
+        let mut allocator = Allocator::new();
+        let cell = RnnCell::new(&mut allocator, 2, 3);
+        assert_eq!(cell.parameters().len(), 3 * (2 + 3 + 1));
+
+        let input = vec![allocator.alloc(1.0f64), allocator.alloc(-1.0)];
+        let hidden = cell.initial_state(&mut allocator);
+        let next = cell.step(&input, &hidden);
+        assert_eq!(next.len(), 3);
+
+        let neuron = &cell.layer.neurons[0];
+        let expected = (allocator.get(neuron.weights[0]).data
+            - allocator.get(neuron.weights[1]).data
+            + allocator.get(neuron.bias.unwrap()).data)
+            .tanh();
+        assert!((allocator.get(next[0]).data - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_rnn_unroll_bptt() {
+        let mut allocator = Allocator::new();
+        let cell = RnnCell::new(&mut allocator, 1, 2);
+        let inputs = (0..4)
+            .map(|i| vec![allocator.alloc(i as f64)])
+            .collect::<Vec<_>>();
+
+        let initial = cell.initial_state(&mut allocator);
+        let states = cell.unroll(&inputs, &initial);
+        assert_eq!(states.len(), 4);
+
+        // The first input only influences the loss through the recurrence
+        let target = [allocator.alloc(0.5), allocator.alloc(-0.5)];
+        let loss = mse(&mut allocator, &states[3], &target);
+        assert!(allocator.get(loss).data > 0.0);
+        allocator.backward();
+        assert!(allocator.get(inputs[0][0]).grad != 0.0);
+    }
+
+    #[test]
+    fn test_rnn_training() {
+        let mut allocator = Allocator::new();
+        let cell = RnnCell::new(&mut allocator, 1, 2);
+        let mut optimizer = SGD::new(cell.parameters(), 0.1);
+        let inputs = [0.5, -0.5, 0.25]
+            .iter()
+            .map(|x| vec![allocator.alloc(*x)])
+            .collect::<Vec<_>>();
+        let target = [allocator.alloc(0.3), allocator.alloc(-0.3)];
+
+        let mut losses = vec![];
+        for _ in 0..50 {
+            let initial = cell.initial_state(&mut allocator);
+            let states = cell.unroll(&inputs, &initial);
+            let loss = mse(&mut allocator, states.last().unwrap(), &target);
+            losses.push(allocator.get(loss).data);
+            allocator.backward();
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+        assert!(losses.last().unwrap() < losses.first().unwrap());
+    }
+}
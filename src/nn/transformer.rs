@@ -0,0 +1,158 @@
+use rand::Rng;
+
+use super::{prefixed, Activation, Attention, Init, Layer, LayerNorm, Module};
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::Num,
+};
+
+// A pre-norm transformer encoder block over a sequence of `d_model`-sized feature vectors:
+//   x = x + Attention(LayerNorm(x))
+//   x = x + FeedForward(LayerNorm(x))
+// where the feed-forward network is applied to each position independently
+pub struct TransformerBlock<T: Num> {
+    pub(crate) d_model: usize,
+    pub(crate) attention: Attention<T>,
+    pub(crate) attention_norm: LayerNorm<T>,
+    pub(crate) hidden: Layer<T>,
+    pub(crate) output: Layer<T>,
+    pub(crate) feed_forward_norm: LayerNorm<T>,
+}
+
+impl<T: Num> TransformerBlock<T> {
+    pub fn new(allocator: &mut Allocator<T>, d_model: usize, d_ff: usize) -> Self {
+        Self::with_rng(allocator, d_model, d_ff, &mut rand::thread_rng())
+    }
+
+    // See `Neuron::with_rng`
+    pub fn with_rng(
+        allocator: &mut Allocator<T>,
+        d_model: usize,
+        d_ff: usize,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let relu = Some(Activation::Relu);
+        TransformerBlock {
+            d_model,
+            attention: Attention::with_rng(allocator, d_model, d_model, rng),
+            attention_norm: LayerNorm::new(allocator, d_model),
+            hidden: Layer::with_rng(allocator, d_model, d_ff, relu, Init::default(), rng),
+            output: Layer::with_rng(allocator, d_ff, d_model, None, Init::default(), rng),
+            feed_forward_norm: LayerNorm::new(allocator, d_model),
+        }
+    }
+
+    pub fn forward_sequence(&self, sequence: &[Vec<ValueId<T>>]) -> Vec<Vec<ValueId<T>>> {
+        let normed = sequence
+            .iter()
+            .map(|x| self.attention_norm.forward(x))
+            .collect::<Vec<_>>();
+        let attended = self.attention.forward_sequence(&normed);
+        let sequence = sequence
+            .iter()
+            .zip(attended)
+            .map(|(x, a)| residual(x, &a))
+            .collect::<Vec<_>>();
+
+        sequence
+            .iter()
+            .map(|x| {
+                let normed = self.feed_forward_norm.forward(x);
+                let ff = self.output.forward(&self.hidden.forward(&normed));
+                residual(x, &ff)
+            })
+            .collect()
+    }
+}
+
+fn residual<T: Num>(x: &[ValueId<T>], fx: &[ValueId<T>]) -> Vec<ValueId<T>> {
+    x.iter().zip(fx).map(|(x, fx)| *x + *fx).collect()
+}
+
+// As a module, the sequence is flattened to [seq_len * d_model] in and out
+impl<T: Num> Module<T> for TransformerBlock<T> {
+    fn forward(&self, inputs: &[ValueId<T>]) -> Vec<ValueId<T>> {
+        assert_eq!(
+            inputs.len() % self.d_model,
+            0,
+            "TransformerBlock input length is not a multiple of d_model"
+        );
+        let sequence = inputs
+            .chunks(self.d_model)
+            .map(|x| x.to_vec())
+            .collect::<Vec<_>>();
+        self.forward_sequence(&sequence).concat()
+    }
+
+    fn parameters(&self) -> Vec<ValueId<T>> {
+        let mut params = self.attention_norm.parameters();
+        params.extend(self.attention.parameters());
+        params.extend(self.feed_forward_norm.parameters());
+        params.extend(self.hidden.parameters());
+        params.extend(self.output.parameters());
+        params
+    }
+
+    fn named_parameters(&self) -> Vec<(String, ValueId<T>)> {
+        let mut named = prefixed("attention_norm", self.attention_norm.named_parameters());
+        named.extend(prefixed("attention", self.attention.named_parameters()));
+        named.extend(prefixed(
+            "feed_forward_norm",
+            self.feed_forward_norm.named_parameters(),
+        ));
+        named.extend(prefixed("hidden", self.hidden.named_parameters()));
+        named.extend(prefixed("output", self.output.named_parameters()));
+        named
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "TransformerBlock({}, d_ff {})",
+            self.d_model,
+            self.hidden.neurons.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        losses::mse,
+        optim::{Optimizer, SGD},
+    };
+
+    #[test]
+    fn test_transformer_block_training() {
+        let mut allocator = Allocator::new();
+        let block = TransformerBlock::new(&mut allocator, 4, 8);
+        let num_params = 2 * 4 + 3 * 4 * 5 + 2 * 4 + 8 * 5 + 4 * 9;
+        assert_eq!(block.parameters().len(), num_params);
+        let named = block.named_parameters();
+        assert_eq!(named.len(), num_params);
+        assert_eq!(named[0].0, "attention_norm.gamma.0");
+        assert_eq!(named[8].0, "attention.query.0.weight.0");
+
+        let inputs = (0..12)
+            .map(|i| allocator.alloc(((i * 7) % 5) as f64 / 5.0 - 0.4))
+            .collect::<Vec<_>>();
+        let targets = (0..12)
+            .map(|i| allocator.alloc(if i % 2 == 0 { 0.5 } else { -0.5 }))
+            .collect::<Vec<_>>();
+
+        let mut optimizer = SGD::new(block.parameters(), 0.05);
+        let mut losses = vec![];
+        for _ in 0..30 {
+            let outputs = Module::forward(&block, &inputs);
+            assert_eq!(outputs.len(), 12);
+            let loss = mse(&mut allocator, &outputs, &targets);
+            losses.push(allocator.get(loss).data);
+            allocator.backward();
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+        assert!(losses.iter().all(|l| l.is_finite()));
+        assert!(losses.last().unwrap() < losses.first().unwrap());
+    }
+}
@@ -0,0 +1,317 @@
+use crate::{
+    allocator::{checkpoint_backward, Allocator, BackwardFn, ValueId},
+    losses,
+    operators::{self, Num},
+};
+
+// What produced a node, dispatched by `backward()`. Unlike a bare function pointer it can
+// be named, compared and matched on, e.g. by printers and fusion passes. `Custom` is the
+// escape hatch for ops defined outside the crate, see `Allocator::alloc_temp`.
+#[derive(Clone, Copy)]
+pub enum Op<T: Num> {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Neg,
+    AddScalar,
+    RsubScalar,
+    MulScalar,
+    DivScalar,
+    RdivScalar,
+    Pow,
+    Powf,
+    Fma,
+    Exp,
+    Ln,
+    Log,
+    Tanh,
+    Erf,
+    Relu,
+    Select,
+    Clamp,
+    ClampStraightThrough,
+    Abs,
+    Detach,
+    Cast,
+    Recip,
+    Sum,
+    Mean,
+    Dot,
+    CosineSimilarity,
+    Affine,
+    Max,
+    Mse,
+    Huber,
+    CrossEntropy,
+    Nll,
+    KlDiv,
+    L2Penalty,
+    L1Penalty,
+    Checkpoint,
+    Fused,
+    Custom(BackwardFn<T>),
+}
+
+impl<T: Num> Op<T> {
+    // Every op except `Custom`
+    pub fn builtins() -> [Op<T>; 41] {
+        [
+            Op::Add,
+            Op::Sub,
+            Op::Mul,
+            Op::Div,
+            Op::Neg,
+            Op::AddScalar,
+            Op::RsubScalar,
+            Op::MulScalar,
+            Op::DivScalar,
+            Op::RdivScalar,
+            Op::Pow,
+            Op::Powf,
+            Op::Fma,
+            Op::Exp,
+            Op::Ln,
+            Op::Log,
+            Op::Tanh,
+            Op::Erf,
+            Op::Relu,
+            Op::Select,
+            Op::Clamp,
+            Op::ClampStraightThrough,
+            Op::Abs,
+            Op::Detach,
+            Op::Cast,
+            Op::Recip,
+            Op::Sum,
+            Op::Mean,
+            Op::Dot,
+            Op::CosineSimilarity,
+            Op::Affine,
+            Op::Max,
+            Op::Mse,
+            Op::Huber,
+            Op::CrossEntropy,
+            Op::Nll,
+            Op::KlDiv,
+            Op::L2Penalty,
+            Op::L1Penalty,
+            Op::Checkpoint,
+            Op::Fused,
+        ]
+    }
+
+    // The built-in op called `name`, the inverse of `name()`
+    pub fn from_name(name: &str) -> Option<Op<T>> {
+        Op::builtins().into_iter().find(|op| op.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Mul => "mul",
+            Op::Div => "div",
+            Op::Neg => "neg",
+            Op::AddScalar => "add_scalar",
+            Op::RsubScalar => "rsub_scalar",
+            Op::MulScalar => "mul_scalar",
+            Op::DivScalar => "div_scalar",
+            Op::RdivScalar => "rdiv_scalar",
+            Op::Pow => "pow",
+            Op::Powf => "powf",
+            Op::Fma => "fma",
+            Op::Exp => "exp",
+            Op::Ln => "ln",
+            Op::Log => "log",
+            Op::Tanh => "tanh",
+            Op::Erf => "erf",
+            Op::Relu => "relu",
+            Op::Select => "select",
+            Op::Clamp => "clamp",
+            Op::ClampStraightThrough => "clamp_straight_through",
+            Op::Abs => "abs",
+            Op::Detach => "detach",
+            Op::Cast => "cast",
+            Op::Recip => "recip",
+            Op::Sum => "sum",
+            Op::Mean => "mean",
+            Op::Dot => "dot",
+            Op::CosineSimilarity => "cosine_similarity",
+            Op::Affine => "affine",
+            Op::Max => "max",
+            Op::Mse => "mse",
+            Op::Huber => "huber",
+            Op::CrossEntropy => "cross_entropy",
+            Op::Nll => "nll",
+            Op::KlDiv => "kl_div",
+            Op::L2Penalty => "l2_penalty",
+            Op::L1Penalty => "l1_penalty",
+            Op::Checkpoint => "checkpoint",
+            Op::Fused => "fused",
+            Op::Custom(_) => "custom",
+        }
+    }
+
+    // Whether a node of this op can have `len` children, given how its builder lays them
+    // out. Backward functions index their children without checking, so nodes from
+    // outside the crate (e.g. a loaded tape) are validated against this first.
+    #[cfg(feature = "serde")]
+    pub(crate) fn accepts(&self, len: usize) -> bool {
+        match self {
+            Op::Neg
+            | Op::Exp
+            | Op::Ln
+            | Op::Tanh
+            | Op::Erf
+            | Op::Relu
+            | Op::Abs
+            | Op::Detach
+            | Op::Cast
+            | Op::Recip
+            | Op::Nll => len == 1,
+            Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Div
+            | Op::AddScalar
+            | Op::RsubScalar
+            | Op::MulScalar
+            | Op::DivScalar
+            | Op::RdivScalar
+            | Op::Pow
+            | Op::Powf
+            | Op::Log => len == 2,
+            Op::Fma | Op::Select | Op::Clamp | Op::ClampStraightThrough => len == 3,
+            Op::Sum | Op::Checkpoint | Op::Fused | Op::Custom(_) => true,
+            Op::Mean | Op::Max | Op::L2Penalty | Op::L1Penalty => len >= 1,
+            Op::Dot | Op::CosineSimilarity | Op::KlDiv => len.is_multiple_of(2),
+            Op::Mse => len >= 2 && len.is_multiple_of(2),
+            // [weights..., inputs..., bias] and [outputs..., targets..., delta]
+            Op::Affine | Op::Huber => !len.is_multiple_of(2),
+            // The logits followed by the target logit
+            Op::CrossEntropy => len >= 2,
+        }
+    }
+
+    // Accumulates the gradients of the children from the output's gradient and data
+    #[inline(always)]
+    pub(crate) fn backward(
+        self,
+        allocator: &mut Allocator<T>,
+        grad: T,
+        data: T,
+        children: &[ValueId<T>],
+    ) {
+        match self {
+            Op::Add => operators::add_backward(allocator, grad, data, children),
+            Op::Sub => operators::sub_backward(allocator, grad, data, children),
+            Op::Mul => operators::mul_backward(allocator, grad, data, children),
+            Op::Div => operators::div_backward(allocator, grad, data, children),
+            Op::Neg => operators::neg_backward(allocator, grad, data, children),
+            Op::AddScalar => operators::add_scalar_backward(allocator, grad, data, children),
+            Op::RsubScalar => operators::rsub_scalar_backward(allocator, grad, data, children),
+            Op::MulScalar => operators::mul_scalar_backward(allocator, grad, data, children),
+            Op::DivScalar => operators::div_scalar_backward(allocator, grad, data, children),
+            Op::RdivScalar => operators::rdiv_scalar_backward(allocator, grad, data, children),
+            Op::Pow => operators::pow_backward(allocator, grad, data, children),
+            Op::Powf => operators::powf_backward(allocator, grad, data, children),
+            Op::Fma => operators::fma_backward(allocator, grad, data, children),
+            Op::Exp => operators::exp_backward(allocator, grad, data, children),
+            Op::Ln => operators::ln_backward(allocator, grad, data, children),
+            Op::Log => operators::log_backward(allocator, grad, data, children),
+            Op::Tanh => operators::tanh_backward(allocator, grad, data, children),
+            Op::Erf => operators::erf_backward(allocator, grad, data, children),
+            Op::Relu => operators::relu_backward(allocator, grad, data, children),
+            Op::Select => operators::select_backward(allocator, grad, data, children),
+            Op::Clamp => operators::clamp_backward(allocator, grad, data, children),
+            Op::ClampStraightThrough => {
+                operators::clamp_straight_through_backward(allocator, grad, data, children)
+            }
+            Op::Abs => operators::abs_backward(allocator, grad, data, children),
+            Op::Detach => operators::detach_backward(allocator, grad, data, children),
+            Op::Cast => operators::cast_backward(allocator, grad, data, children),
+            Op::Recip => operators::recip_backward(allocator, grad, data, children),
+            Op::Sum => operators::sum_backward(allocator, grad, data, children),
+            Op::Mean => operators::mean_backward(allocator, grad, data, children),
+            Op::Dot => operators::dot_backward(allocator, grad, data, children),
+            Op::CosineSimilarity => {
+                operators::cosine_similarity_backward(allocator, grad, data, children)
+            }
+            Op::Affine => operators::affine_backward(allocator, grad, data, children),
+            Op::Max => operators::max_backward(allocator, grad, data, children),
+            Op::Mse => losses::mse_backward(allocator, grad, data, children),
+            Op::Huber => losses::huber_backward(allocator, grad, data, children),
+            Op::CrossEntropy => losses::cross_entropy_backward(allocator, grad, data, children),
+            Op::Nll => losses::nll_backward(allocator, grad, data, children),
+            Op::KlDiv => losses::kl_div_backward(allocator, grad, data, children),
+            Op::L2Penalty => losses::l2_penalty_backward(allocator, grad, data, children),
+            Op::L1Penalty => losses::l1_penalty_backward(allocator, grad, data, children),
+            Op::Checkpoint | Op::Fused => checkpoint_backward(allocator, grad, data, children),
+            Op::Custom(backward) => backward(allocator, grad, data, children),
+        }
+    }
+}
+
+impl<T: Num> PartialEq for Op<T> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Op::Custom(a), Op::Custom(b)) => std::ptr::fn_addr_eq(*a, *b),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl<T: Num> Eq for Op<T> {}
+
+impl<T: Num> std::hash::Hash for Op<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let Op::Custom(backward) = self {
+            (*backward as usize).hash(state);
+        }
+    }
+}
+
+impl<T: Num> std::fmt::Debug for Op<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operators::tanh;
+
+    fn double_backward(
+        allocator: &mut Allocator<f64>,
+        grad: f64,
+        _: f64,
+        children: &[ValueId<f64>],
+    ) {
+        allocator.get_mut(children[0]).add_grad(2.0 * grad);
+    }
+
+    #[test]
+    fn test_op_tags() {
+        let mut allocator = Allocator::new();
+        let x = allocator.alloc(0.5f64);
+        let y = tanh(x * x);
+        let z = allocator.alloc_temp(2.0 * allocator.get(y).data, double_backward, [y]);
+        allocator.backward();
+
+        assert_eq!(allocator.get(x).op, None);
+        assert_eq!(allocator.get(y).op, Some(Op::Tanh));
+        assert_eq!(allocator.get(z).op.unwrap().name(), "custom");
+        assert_eq!(allocator.get(z).op, Some(Op::Custom(double_backward)));
+        assert_ne!(Op::<f64>::Add, Op::Mul);
+        for op in Op::<f64>::builtins() {
+            assert_eq!(Op::from_name(op.name()), Some(op));
+        }
+        assert_eq!(Op::<f64>::from_name("custom"), None);
+
+        let t = 0.25f64.tanh();
+        assert!((allocator.get(x).grad - 2.0 * (1.0 - t * t) * 2.0 * 0.5).abs() < 1e-12);
+    }
+}
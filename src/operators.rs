@@ -1,7 +1,10 @@
-use crate::allocator::{Allocator, ValueId};
+use crate::allocator::{Allocator, ForwardFn, GradFn, ValueId};
+use crate::engine::{Value, ValueRef};
+use crate::op::Op;
 use num::pow::Pow;
-use num::Num as BaseNum;
+use num::{FromPrimitive, Num as BaseNum};
 use rand::distributions::uniform::SampleUniform;
+use std::collections::HashMap;
 use std::{
     fmt::Display,
     ops::{Add, Div, Mul, Neg, Sub},
@@ -15,10 +18,17 @@ pub trait Num:
     + Display
     + PartialOrd
     + SampleUniform
+    + FromPrimitive
 {
     fn exp(self) -> Self;
     fn ln(self) -> Self;
+    fn log(self, base: Self) -> Self;
+    fn log2(self) -> Self;
+    fn log10(self) -> Self;
+    fn powi(self, n: i32) -> Self;
     fn tanh(self) -> Self;
+    fn erf(self) -> Self;
+    fn sqrt(self) -> Self;
 }
 impl Num for f32 {
     #[inline(always)]
@@ -31,10 +41,40 @@ impl Num for f32 {
         self.ln()
     }
 
+    #[inline(always)]
+    fn log(self, base: Self) -> Self {
+        self.log(base)
+    }
+
+    #[inline(always)]
+    fn log2(self) -> Self {
+        self.log2()
+    }
+
+    #[inline(always)]
+    fn log10(self) -> Self {
+        self.log10()
+    }
+
+    #[inline(always)]
+    fn powi(self, n: i32) -> Self {
+        self.powi(n)
+    }
+
     #[inline(always)]
     fn tanh(self) -> Self {
         self.tanh()
     }
+
+    #[inline(always)]
+    fn erf(self) -> Self {
+        libm::erff(self)
+    }
+
+    #[inline(always)]
+    fn sqrt(self) -> Self {
+        self.sqrt()
+    }
 }
 impl Num for f64 {
     #[inline(always)]
@@ -47,10 +87,40 @@ impl Num for f64 {
         self.ln()
     }
 
+    #[inline(always)]
+    fn log(self, base: Self) -> Self {
+        self.log(base)
+    }
+
+    #[inline(always)]
+    fn log2(self) -> Self {
+        self.log2()
+    }
+
+    #[inline(always)]
+    fn log10(self) -> Self {
+        self.log10()
+    }
+
+    #[inline(always)]
+    fn powi(self, n: i32) -> Self {
+        self.powi(n)
+    }
+
     #[inline(always)]
     fn tanh(self) -> Self {
         self.tanh()
     }
+
+    #[inline(always)]
+    fn erf(self) -> Self {
+        libm::erf(self)
+    }
+
+    #[inline(always)]
+    fn sqrt(self) -> Self {
+        self.sqrt()
+    }
 }
 
 impl<T: Num> Add for ValueId<T> {
@@ -58,17 +128,25 @@ impl<T: Num> Add for ValueId<T> {
 
     #[inline(always)]
     fn add(self, other: ValueId<T>) -> ValueId<T> {
-        assert!(self.allocator == other.allocator);
+        assert!(self.allocator() == other.allocator());
 
         unsafe {
-            let allocator = self.allocator.as_mut().unwrap();
-            let result = allocator.get(self).data + allocator.get(other).data;
-            allocator.alloc_temp(result, add_backward::<T>, [self, other])
+            let allocator = self.allocator().as_mut().unwrap();
+            allocator.record_op(
+                add_forward::<T>,
+                Op::Add,
+                Some(add_grad::<T>),
+                [self, other],
+            )
         }
     }
 }
 
-fn add_backward<T: Num>(
+fn add_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data + allocator.get(children[1]).data
+}
+
+pub(crate) fn add_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
     _base_val: T,
@@ -78,22 +156,39 @@ fn add_backward<T: Num>(
     allocator.get_mut(children[1]).add_grad(base_grad);
 }
 
+fn add_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad), Some(grad)]
+}
+
 impl<T: Num + Copy> Mul for ValueId<T> {
     type Output = ValueId<T>;
 
     #[inline(always)]
     fn mul(self, other: ValueId<T>) -> ValueId<T> {
-        assert!(self.allocator == other.allocator);
+        assert!(self.allocator() == other.allocator());
 
         unsafe {
-            let allocator = self.allocator.as_mut().unwrap();
-            let result = allocator.get(self).data * allocator.get(other).data;
-            allocator.alloc_temp(result, mul_backward::<T>, [self, other])
+            let allocator = self.allocator().as_mut().unwrap();
+            allocator.record_op(
+                mul_forward::<T>,
+                Op::Mul,
+                Some(mul_grad::<T>),
+                [self, other],
+            )
         }
     }
 }
 
-fn mul_backward<T: Num>(
+fn mul_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data * allocator.get(children[1]).data
+}
+
+pub(crate) fn mul_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
     _base_val: T,
@@ -105,20 +200,32 @@ fn mul_backward<T: Num>(
     allocator.get_mut(children[1]).add_grad(base_grad * a);
 }
 
+fn mul_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad * children[1]), Some(grad * children[0])]
+}
+
 impl<T: Num> Neg for ValueId<T> {
     type Output = ValueId<T>;
 
     #[inline(always)]
     fn neg(self) -> ValueId<T> {
         unsafe {
-            let allocator = self.allocator.as_mut().unwrap();
-            let result = allocator.get(self).data * -T::one();
-            allocator.alloc_temp(result, neg_backward::<T>, [self, ValueId::default()])
+            let allocator = self.allocator().as_mut().unwrap();
+            allocator.record_op(neg_forward::<T>, Op::Neg, Some(neg_grad::<T>), [self])
         }
     }
 }
 
-fn neg_backward<T: Num>(
+fn neg_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data * -T::one()
+}
+
+pub(crate) fn neg_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
     _base_val: T,
@@ -127,6 +234,15 @@ fn neg_backward<T: Num>(
     allocator.get_mut(children[0]).add_grad(-base_grad);
 }
 
+fn neg_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(-grad)]
+}
+
 impl<T: Num> Sub for ValueId<T> {
     type Output = ValueId<T>;
 
@@ -136,18 +252,53 @@ impl<T: Num> Sub for ValueId<T> {
     }
 }
 
+// Only produced by `fuse`, which rewrites a + -b into a single node
+fn sub_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data - allocator.get(children[1]).data
+}
+
+pub(crate) fn sub_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    allocator.get_mut(children[0]).add_grad(base_grad);
+    allocator.get_mut(children[1]).add_grad(-base_grad);
+}
+
+fn sub_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad), Some(-grad)]
+}
+
 #[inline(always)]
 pub fn pow<T: Num>(this: ValueId<T>, other: ValueId<T>) -> ValueId<T> {
-    assert!(this.allocator == other.allocator);
+    assert!(this.allocator() == other.allocator());
 
     unsafe {
-        let allocator = this.allocator.as_mut().unwrap();
-        let result = allocator.get(this).data.pow(allocator.get(other).data);
-        allocator.alloc_temp(result, pow_backward::<T>, [this, other])
+        let allocator = this.allocator().as_mut().unwrap();
+        allocator.record_op(
+            pow_forward::<T>,
+            Op::Pow,
+            Some(pow_grad::<T>),
+            [this, other],
+        )
     }
 }
 
-fn pow_backward<T: Num>(
+fn pow_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator
+        .get(children[0])
+        .data
+        .pow(allocator.get(children[1]).data)
+}
+
+pub(crate) fn pow_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
     base_val: T,
@@ -163,16 +314,120 @@ fn pow_backward<T: Num>(
         .add_grad(base_grad * base_val * a.ln());
 }
 
+fn pow_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (a, b) = (children[0], children[1]);
+    vec![Some(grad * b * out / a), Some(grad * out * ln(a))]
+}
+
+#[inline(always)]
+pub fn powf<T: Num>(this: ValueId<T>, k: T) -> ValueId<T> {
+    unsafe {
+        let allocator = this.allocator().as_mut().unwrap();
+        let k = allocator.alloc_const(k);
+        allocator.record_op(powf_forward::<T>, Op::Powf, Some(powf_grad::<T>), [this, k])
+    }
+}
+
+#[inline(always)]
+pub fn powi<T: Num>(this: ValueId<T>, n: i32) -> ValueId<T> {
+    unsafe {
+        let allocator = this.allocator().as_mut().unwrap();
+        let n = allocator.alloc_const(T::from_i32(n).unwrap());
+        allocator.record_op(powf_forward::<T>, Op::Powf, Some(powf_grad::<T>), [this, n])
+    }
+}
+
+fn powf_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator
+        .get(children[0])
+        .data
+        .pow(allocator.get(children[1]).data)
+}
+
+// The exponent is a constant, so unlike pow_backward this neither divides by the base
+// nor takes its logarithm, which keeps negative bases with integer exponents NaN-free
+pub(crate) fn powf_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let a = allocator.get(children[0]).data;
+    let k = allocator.get(children[1]).data;
+    allocator
+        .get_mut(children[0])
+        .add_grad(base_grad * k * a.pow(k - T::one()));
+}
+
+fn powf_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let k = allocator.get(children[1]).data;
+    vec![Some(grad * powf(children[0], k - T::one()) * k), None]
+}
+
+#[inline(always)]
+pub fn fma<T: Num>(a: ValueId<T>, b: ValueId<T>, c: ValueId<T>) -> ValueId<T> {
+    assert!(a.allocator() == b.allocator() && a.allocator() == c.allocator());
+
+    unsafe {
+        let allocator = a.allocator().as_mut().unwrap();
+        allocator.record_op(fma_forward::<T>, Op::Fma, Some(fma_grad::<T>), [a, b, c])
+    }
+}
+
+fn fma_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data * allocator.get(children[1]).data
+        + allocator.get(children[2]).data
+}
+
+pub(crate) fn fma_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let a = allocator.get(children[0]).data;
+    let b = allocator.get(children[1]).data;
+    allocator.get_mut(children[0]).add_grad(base_grad * b);
+    allocator.get_mut(children[1]).add_grad(base_grad * a);
+    allocator.get_mut(children[2]).add_grad(base_grad);
+}
+
+fn fma_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![
+        Some(grad * children[1]),
+        Some(grad * children[0]),
+        Some(grad),
+    ]
+}
+
 #[inline(always)]
 pub fn exp<T: Num>(this: ValueId<T>) -> ValueId<T> {
     unsafe {
-        let allocator = this.allocator.as_mut().unwrap();
-        let result = allocator.get(this).data.exp();
-        allocator.alloc_temp(result, exp_backward::<T>, [this, ValueId::default()])
+        let allocator = this.allocator().as_mut().unwrap();
+        allocator.record_op(exp_forward::<T>, Op::Exp, Some(exp_grad::<T>), [this])
     }
 }
 
-fn exp_backward<T: Num>(
+fn exp_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data.exp()
+}
+
+pub(crate) fn exp_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
     base_val: T,
@@ -183,16 +438,28 @@ fn exp_backward<T: Num>(
         .add_grad(base_grad * base_val);
 }
 
+fn exp_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad * out)]
+}
+
 #[inline(always)]
 pub fn ln<T: Num>(v: ValueId<T>) -> ValueId<T> {
     unsafe {
-        let allocator = v.allocator.as_mut().unwrap();
-        let result = allocator.get(v).data.ln();
-        allocator.alloc_temp(result, ln_backward::<T>, [v, ValueId::default()])
+        let allocator = v.allocator().as_mut().unwrap();
+        allocator.record_op(ln_forward::<T>, Op::Ln, Some(ln_grad::<T>), [v])
     }
 }
 
-fn ln_backward<T: Num>(
+fn ln_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data.ln()
+}
+
+pub(crate) fn ln_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
     _base_val: T,
@@ -204,16 +471,86 @@ fn ln_backward<T: Num>(
         .add_grad(base_grad * T::one() / a);
 }
 
+fn ln_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad / children[0])]
+}
+
+#[inline(always)]
+pub fn log<T: Num>(v: ValueId<T>, base: T) -> ValueId<T> {
+    unsafe {
+        let allocator = v.allocator().as_mut().unwrap();
+        let base = allocator.alloc_const(base);
+        allocator.record_op(log_forward::<T>, Op::Log, Some(log_grad::<T>), [v, base])
+    }
+}
+
+#[inline(always)]
+pub fn log2<T: Num>(v: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = v.allocator().as_mut().unwrap();
+        let base = allocator.alloc_const(T::from_u8(2).unwrap());
+        allocator.record_op(log_forward::<T>, Op::Log, Some(log_grad::<T>), [v, base])
+    }
+}
+
+#[inline(always)]
+pub fn log10<T: Num>(v: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = v.allocator().as_mut().unwrap();
+        let base = allocator.alloc_const(T::from_u8(10).unwrap());
+        allocator.record_op(log_forward::<T>, Op::Log, Some(log_grad::<T>), [v, base])
+    }
+}
+
+fn log_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator
+        .get(children[0])
+        .data
+        .log(allocator.get(children[1]).data)
+}
+
+// The base is a constant, so only the argument receives a gradient
+pub(crate) fn log_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let a = allocator.get(children[0]).data;
+    let base = allocator.get(children[1]).data;
+    allocator
+        .get_mut(children[0])
+        .add_grad(base_grad / (a * base.ln()));
+}
+
+fn log_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let base = allocator.get(children[1]).data;
+    vec![Some(grad / children[0] / base.ln()), None]
+}
+
 #[inline(always)]
 pub fn tanh<T: Num>(this: ValueId<T>) -> ValueId<T> {
     unsafe {
-        let allocator = this.allocator.as_mut().unwrap();
-        let result = allocator.get(this).data.tanh();
-        allocator.alloc_temp(result, tanh_backward::<T>, [this, ValueId::default()])
+        let allocator = this.allocator().as_mut().unwrap();
+        allocator.record_op(tanh_forward::<T>, Op::Tanh, Some(tanh_grad::<T>), [this])
     }
 }
 
-fn tanh_backward<T: Num>(
+fn tanh_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data.tanh()
+}
+
+pub(crate) fn tanh_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
     base_val: T,
@@ -224,20 +561,70 @@ fn tanh_backward<T: Num>(
         .add_grad(base_grad * (T::one() - base_val.pow(T::one() + T::one())));
 }
 
+fn tanh_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad * rsub_scalar(T::one(), out * out))]
+}
+
+#[inline(always)]
+pub fn erf<T: Num>(this: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = this.allocator().as_mut().unwrap();
+        allocator.record_op(erf_forward::<T>, Op::Erf, Some(erf_grad::<T>), [this])
+    }
+}
+
+fn erf_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data.erf()
+}
+
+pub(crate) fn erf_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    // d/dx erf(x) = 2 / sqrt(pi) * exp(-x^2)
+    let a = allocator.get(children[0]).data;
+    let scale = T::from_f64(std::f64::consts::FRAC_2_SQRT_PI).unwrap();
+    allocator
+        .get_mut(children[0])
+        .add_grad(base_grad * scale * (-(a * a)).exp());
+}
+
+fn erf_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let scale = T::from_f64(std::f64::consts::FRAC_2_SQRT_PI).unwrap();
+    let a = children[0];
+    vec![Some(grad * exp(-(a * a)) * scale)]
+}
+
 #[inline(always)]
 pub fn relu<T: Num>(this: ValueId<T>) -> ValueId<T> {
     unsafe {
-        let allocator = this.allocator.as_mut().unwrap();
-        let result = if allocator.get(this).data > T::zero() {
-            allocator.get(this).data
-        } else {
-            T::zero()
-        };
-        allocator.alloc_temp(result, relu_backward::<T>, [this, ValueId::default()])
+        let allocator = this.allocator().as_mut().unwrap();
+        allocator.record_op(relu_forward::<T>, Op::Relu, Some(relu_grad::<T>), [this])
+    }
+}
+
+fn relu_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let a = allocator.get(children[0]).data;
+    if a > T::zero() {
+        a
+    } else {
+        T::zero()
     }
 }
 
-fn relu_backward<T: Num>(
+pub(crate) fn relu_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
     base_val: T,
@@ -253,70 +640,1186 @@ fn relu_backward<T: Num>(
     );
 }
 
-impl<T: Num> Div for ValueId<T> {
-    type Output = ValueId<T>;
+fn relu_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let slope = if allocator.get(out).data > T::zero() {
+        T::one()
+    } else {
+        T::zero()
+    };
+    vec![Some(grad * slope)]
+}
 
-    #[inline(always)]
-    fn div(self, other: ValueId<T>) -> ValueId<T> {
-        assert!(self.allocator == other.allocator);
+// Picks `if_true` when `cond` is positive and `if_false` otherwise. The condition
+// itself receives no gradient.
+#[inline(always)]
+pub fn select<T: Num>(cond: ValueId<T>, if_true: ValueId<T>, if_false: ValueId<T>) -> ValueId<T> {
+    assert!(cond.allocator() == if_true.allocator() && cond.allocator() == if_false.allocator());
 
-        unsafe {
-            let allocator = self.allocator.as_mut().unwrap();
-            let result = allocator.get(self).data / allocator.get(other).data;
-            allocator.alloc_temp(result, div_backward::<T>, [self, other])
-        }
+    unsafe {
+        let allocator = cond.allocator().as_mut().unwrap();
+        allocator.record_op(
+            select_forward::<T>,
+            Op::Select,
+            Some(select_grad::<T>),
+            [cond, if_true, if_false],
+        )
     }
 }
 
-fn div_backward<T: Num>(
+fn select_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    if allocator.get(children[0]).data > T::zero() {
+        allocator.get(children[1]).data
+    } else {
+        allocator.get(children[2]).data
+    }
+}
+
+pub(crate) fn select_backward<T: Num>(
     allocator: &mut Allocator<T>,
     base_grad: T,
-    base_val: T,
+    _base_val: T,
     children: &[ValueId<T>],
 ) {
-    let b = allocator.get(children[1]).data;
+    let branch = if allocator.get(children[0]).data > T::zero() {
+        children[1]
+    } else {
+        children[2]
+    };
+    allocator.get_mut(branch).add_grad(base_grad);
+}
 
-    allocator
-        .get_mut(children[0])
-        .add_grad(base_grad * T::one() / b);
-    allocator
-        .get_mut(children[1])
-        .add_grad(base_grad * -base_val * T::one() / b);
+fn select_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    if allocator.get(children[0]).data > T::zero() {
+        vec![None, Some(grad), None]
+    } else {
+        vec![None, None, Some(grad)]
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Gradients are zero outside of [lo, hi]
+#[inline(always)]
+pub fn clamp<T: Num>(this: ValueId<T>, lo: T, hi: T) -> ValueId<T> {
+    alloc_clamp(this, lo, hi, Op::Clamp, clamp_grad::<T>)
+}
 
-    #[test]
-    fn test_addition() {
-        let mut allocator = Allocator::new();
-        let a = allocator.alloc(3.0);
-        let b = allocator.alloc(4.0);
-        let c = a + b;
-        assert_eq!(allocator.get(c).data, 7.0);
+// Gradients pass through unchanged, as if the clamp were the identity
+#[inline(always)]
+pub fn clamp_straight_through<T: Num>(this: ValueId<T>, lo: T, hi: T) -> ValueId<T> {
+    alloc_clamp(
+        this,
+        lo,
+        hi,
+        Op::ClampStraightThrough,
+        clamp_straight_through_grad::<T>,
+    )
+}
 
-        allocator.backward();
-        assert_eq!(allocator.get(a).grad, 1.0);
-        assert_eq!(allocator.get(b).grad, 1.0);
-        assert_eq!(allocator.get(c).grad, 1.0);
+#[inline(always)]
+fn alloc_clamp<T: Num>(
+    this: ValueId<T>,
+    lo: T,
+    hi: T,
+    op: Op<T>,
+    grad_fn: GradFn<T>,
+) -> ValueId<T> {
+    assert!(lo <= hi, "clamp with lo > hi");
+
+    unsafe {
+        let allocator = this.allocator().as_mut().unwrap();
+        let lo = allocator.alloc_const(lo);
+        let hi = allocator.alloc_const(hi);
+        allocator.record_op(clamp_forward::<T>, op, Some(grad_fn), [this, lo, hi])
     }
+}
 
-    #[test]
-    fn test_multiplication() {
-        let mut allocator = Allocator::new();
-        let a = allocator.alloc(3.0);
-        let b = allocator.alloc(4.0);
-        let c = a * b;
-        assert_eq!(allocator.get(c).data, 12.0);
+fn clamp_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let a = allocator.get(children[0]).data;
+    let lo = allocator.get(children[1]).data;
+    let hi = allocator.get(children[2]).data;
+    if a < lo {
+        lo
+    } else if a > hi {
+        hi
+    } else {
+        a
+    }
+}
 
-        allocator.backward();
-        assert_eq!(allocator.get(a).grad, 4.0);
-        assert_eq!(allocator.get(b).grad, 3.0);
-        assert_eq!(allocator.get(c).grad, 1.0);
+pub(crate) fn clamp_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let a = allocator.get(children[0]).data;
+    let lo = allocator.get(children[1]).data;
+    let hi = allocator.get(children[2]).data;
+    if a >= lo && a <= hi {
+        allocator.get_mut(children[0]).add_grad(base_grad);
     }
+}
 
-    #[test]
+pub(crate) fn clamp_straight_through_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    allocator.get_mut(children[0]).add_grad(base_grad);
+}
+
+fn clamp_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let a = allocator.get(children[0]).data;
+    let lo = allocator.get(children[1]).data;
+    let hi = allocator.get(children[2]).data;
+    vec![(a >= lo && a <= hi).then_some(grad), None, None]
+}
+
+fn clamp_straight_through_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad), None, None]
+}
+
+#[inline(always)]
+pub fn abs<T: Num>(this: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = this.allocator().as_mut().unwrap();
+        allocator.record_op(abs_forward::<T>, Op::Abs, Some(abs_grad::<T>), [this])
+    }
+}
+
+fn abs_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let a = allocator.get(children[0]).data;
+    if a < T::zero() {
+        -a
+    } else {
+        a
+    }
+}
+
+pub(crate) fn abs_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    // Subgradient sign(x), with sign(0) = 0
+    let a = allocator.get(children[0]).data;
+    let sign = if a > T::zero() {
+        T::one()
+    } else if a < T::zero() {
+        -T::one()
+    } else {
+        T::zero()
+    };
+    allocator.get_mut(children[0]).add_grad(base_grad * sign);
+}
+
+fn abs_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let a = allocator.get(children[0]).data;
+    let sign = if a > T::zero() {
+        T::one()
+    } else if a < T::zero() {
+        -T::one()
+    } else {
+        T::zero()
+    };
+    vec![Some(grad * sign)]
+}
+
+// The same data with no gradient flowing back to the ancestors, e.g. for target networks
+// or truncated BPTT. The input is kept as a child so a replayed tape stays up to date.
+#[inline(always)]
+pub fn detach<T: Num>(this: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = this.allocator().as_mut().unwrap();
+        allocator.record_op(
+            detach_forward::<T>,
+            Op::Detach,
+            Some(detach_grad::<T>),
+            [this],
+        )
+    }
+}
+
+fn detach_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data
+}
+
+pub(crate) fn detach_backward<T: Num>(
+    _allocator: &mut Allocator<T>,
+    _base_grad: T,
+    _base_val: T,
+    _children: &[ValueId<T>],
+) {
+}
+
+fn detach_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    _grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![None]
+}
+
+// The rounding is treated as the identity, see `half::cast`
+pub(crate) fn cast_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    allocator.get_mut(children[0]).add_grad(base_grad);
+}
+
+pub(crate) fn cast_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad)]
+}
+
+// exp(x_i - logsumexp(x)), which shares the stability of `logsumexp`
+pub fn softmax<T: Num>(allocator: &mut Allocator<T>, values: &[ValueId<T>]) -> Vec<ValueId<T>> {
+    let lse = logsumexp(allocator, values);
+    values.iter().map(|v| exp(*v - lse)).collect()
+}
+
+// Sums all values into a single node instead of a chain of binary additions
+pub fn sum<T: Num>(allocator: &mut Allocator<T>, values: &[ValueId<T>]) -> ValueId<T> {
+    allocator.record_op(sum_forward::<T>, Op::Sum, Some(sum_grad::<T>), values)
+}
+
+fn sum_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    children
+        .iter()
+        .fold(T::zero(), |acc, v| acc + allocator.get(*v).data)
+}
+
+pub(crate) fn sum_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    for child in children {
+        allocator.get_mut(*child).add_grad(base_grad);
+    }
+}
+
+fn sum_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad); children.len()]
+}
+
+pub fn mean<T: Num>(allocator: &mut Allocator<T>, values: &[ValueId<T>]) -> ValueId<T> {
+    assert!(!values.is_empty(), "mean of an empty slice");
+
+    allocator.record_op(mean_forward::<T>, Op::Mean, Some(mean_grad::<T>), values)
+}
+
+fn mean_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    sum_forward(allocator, children) / T::from_usize(children.len()).unwrap()
+}
+
+pub(crate) fn mean_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let grad = base_grad / T::from_usize(children.len()).unwrap();
+    for child in children {
+        allocator.get_mut(*child).add_grad(grad);
+    }
+}
+
+fn mean_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let grad = grad / T::from_usize(children.len()).unwrap();
+    vec![Some(grad); children.len()]
+}
+
+pub fn dot<T: Num>(allocator: &mut Allocator<T>, a: &[ValueId<T>], b: &[ValueId<T>]) -> ValueId<T> {
+    assert_eq!(
+        a.len(),
+        b.len(),
+        "dot product of slices with different lengths"
+    );
+
+    let children = a.iter().chain(b).copied().collect::<Vec<_>>();
+    allocator.record_op(dot_forward::<T>, Op::Dot, Some(dot_grad::<T>), children)
+}
+
+fn dot_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (a, b) = children.split_at(children.len() / 2);
+    a.iter().zip(b).fold(T::zero(), |acc, (x, y)| {
+        acc + allocator.get(*x).data * allocator.get(*y).data
+    })
+}
+
+// Children are laid out as [a_0, ..., a_n-1, b_0, ..., b_n-1]
+pub(crate) fn dot_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let (a, b) = children.split_at(children.len() / 2);
+    for (x, y) in a.iter().zip(b) {
+        let x_val = allocator.get(*x).data;
+        let y_val = allocator.get(*y).data;
+        allocator.get_mut(*x).add_grad(base_grad * y_val);
+        allocator.get_mut(*y).add_grad(base_grad * x_val);
+    }
+}
+
+fn dot_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (a, b) = children.split_at(children.len() / 2);
+    b.iter().chain(a).map(|other| Some(grad * *other)).collect()
+}
+
+pub fn cosine_similarity<T: Num>(
+    allocator: &mut Allocator<T>,
+    a: &[ValueId<T>],
+    b: &[ValueId<T>],
+) -> ValueId<T> {
+    assert_eq!(
+        a.len(),
+        b.len(),
+        "cosine similarity of slices with different lengths"
+    );
+
+    let children = a.iter().chain(b).copied().collect::<Vec<_>>();
+    allocator.record_op(
+        cosine_similarity_forward::<T>,
+        Op::CosineSimilarity,
+        Some(cosine_similarity_grad::<T>),
+        children,
+    )
+}
+
+fn cosine_terms<T: Num>(allocator: &Allocator<T>, a: &[ValueId<T>], b: &[ValueId<T>]) -> (T, T, T) {
+    a.iter().zip(b).fold(
+        (T::zero(), T::zero(), T::zero()),
+        |(dot, a_sq, b_sq), (x, y)| {
+            let x = allocator.get(*x).data;
+            let y = allocator.get(*y).data;
+            (dot + x * y, a_sq + x * x, b_sq + y * y)
+        },
+    )
+}
+
+fn cosine_similarity_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (a, b) = children.split_at(children.len() / 2);
+    let (dot, a_sq, b_sq) = cosine_terms(allocator, a, b);
+    dot / (a_sq * b_sq).sqrt()
+}
+
+// Children are laid out as [a..., b...]
+pub(crate) fn cosine_similarity_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    base_val: T,
+    children: &[ValueId<T>],
+) {
+    // d cos / d a_i = b_i / (|a| |b|) - cos * a_i / |a|^2, and symmetrically for b
+    let (a, b) = children.split_at(children.len() / 2);
+    let (_, a_sq, b_sq) = cosine_terms(allocator, a, b);
+    let norms = (a_sq * b_sq).sqrt();
+    for (x, y) in a.iter().zip(b) {
+        let x_val = allocator.get(*x).data;
+        let y_val = allocator.get(*y).data;
+        allocator
+            .get_mut(*x)
+            .add_grad(base_grad * (y_val / norms - base_val * x_val / a_sq));
+        allocator
+            .get_mut(*y)
+            .add_grad(base_grad * (x_val / norms - base_val * y_val / b_sq));
+    }
+}
+
+fn cosine_similarity_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (a, b) = children.split_at(children.len() / 2);
+    let a_sq = dot(allocator, a, a);
+    let b_sq = dot(allocator, b, b);
+    let norms = powf(a_sq * b_sq, T::one() / (T::one() + T::one()));
+    let a_grads = a.iter().zip(b).map(|(x, y)| *y / norms - out * *x / a_sq);
+    let b_grads = a.iter().zip(b).map(|(x, y)| *x / norms - out * *y / b_sq);
+    a_grads.chain(b_grads).map(|g| Some(grad * g)).collect()
+}
+
+// Lanes of independent partial sums in `dot_slices`. Unlike a single running sum they
+// don't depend on each other, so the compiler can keep them in SIMD registers.
+const LANES: usize = 8;
+
+// The dot product of two contiguous slices, summed in `LANES` interleaved partial sums. The
+// result may differ from a sequential sum in the last bits.
+pub(crate) fn dot_slices<T: Num>(a: &[T], b: &[T]) -> T {
+    assert_eq!(
+        a.len(),
+        b.len(),
+        "dot product of slices with different lengths"
+    );
+    let mut lanes = [T::zero(); LANES];
+    let (a_chunks, b_chunks) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+    let (a_rest, b_rest) = (a_chunks.remainder(), b_chunks.remainder());
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for lane in 0..LANES {
+            lanes[lane] = lanes[lane] + x[lane] * y[lane];
+        }
+    }
+    let rest = a_rest
+        .iter()
+        .zip(b_rest)
+        .fold(T::zero(), |acc, (x, y)| acc + *x * *y);
+    lanes.into_iter().fold(rest, |acc, lane| acc + lane)
+}
+
+// Records `affine(weights, inputs, bias)`, or `dot(weights, inputs)` without a bias, with a
+// result computed by the caller
+pub(crate) fn record_affine<T: Num>(
+    allocator: &mut Allocator<T>,
+    weights: &[ValueId<T>],
+    inputs: &[ValueId<T>],
+    bias: Option<ValueId<T>>,
+    data: T,
+) -> ValueId<T> {
+    assert_eq!(
+        weights.len(),
+        inputs.len(),
+        "affine with mismatched weight and input lengths"
+    );
+
+    let mut children = Vec::with_capacity(weights.len() * 2 + 1);
+    children.extend_from_slice(weights);
+    children.extend_from_slice(inputs);
+    match bias {
+        Some(bias) => {
+            children.push(bias);
+            allocator.record_computed(
+                data,
+                affine_forward::<T>,
+                Op::Affine,
+                Some(affine_grad::<T>),
+                children,
+            )
+        }
+        None => allocator.record_computed(
+            data,
+            dot_forward::<T>,
+            Op::Dot,
+            Some(dot_grad::<T>),
+            children,
+        ),
+    }
+}
+
+// Computes sum(w_i * x_i) + bias as a single node
+pub fn affine<T: Num>(
+    weights: &[ValueId<T>],
+    inputs: &[ValueId<T>],
+    bias: ValueId<T>,
+) -> ValueId<T> {
+    assert_eq!(
+        weights.len(),
+        inputs.len(),
+        "affine with mismatched weight and input lengths"
+    );
+
+    unsafe {
+        let allocator = bias.allocator().as_mut().unwrap();
+        let mut children = Vec::with_capacity(weights.len() * 2 + 1);
+        children.extend_from_slice(weights);
+        children.extend_from_slice(inputs);
+        children.push(bias);
+        allocator.record_op(
+            affine_forward::<T>,
+            Op::Affine,
+            Some(affine_grad::<T>),
+            children,
+        )
+    }
+}
+
+fn affine_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    let (bias, rest) = children.split_last().unwrap();
+    dot_forward(allocator, rest) + allocator.get(*bias).data
+}
+
+// Children are laid out as [w_0, ..., w_n-1, x_0, ..., x_n-1, bias]
+pub(crate) fn affine_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    base_val: T,
+    children: &[ValueId<T>],
+) {
+    let (bias, rest) = children.split_last().unwrap();
+    dot_backward(allocator, base_grad, base_val, rest);
+    allocator.get_mut(*bias).add_grad(base_grad);
+}
+
+fn affine_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let (_, rest) = children.split_last().unwrap();
+    let mut grads = dot_grad(allocator, grad, out, rest);
+    grads.push(Some(grad));
+    grads
+}
+
+// The gradient is routed to the first input holding the maximum
+pub fn max<T: Num>(allocator: &mut Allocator<T>, values: &[ValueId<T>]) -> ValueId<T> {
+    assert!(!values.is_empty(), "max of an empty slice");
+
+    allocator.record_op(max_forward::<T>, Op::Max, Some(max_grad::<T>), values)
+}
+
+fn argmax<T: Num>(allocator: &Allocator<T>, values: &[ValueId<T>]) -> usize {
+    let mut best = 0;
+    for (i, v) in values.iter().enumerate().skip(1) {
+        if allocator.get(*v).data > allocator.get(values[best]).data {
+            best = i;
+        }
+    }
+    best
+}
+
+fn max_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[argmax(allocator, children)]).data
+}
+
+pub(crate) fn max_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let best = argmax(allocator, children);
+    allocator.get_mut(children[best]).add_grad(base_grad);
+}
+
+fn max_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    let best = argmax(allocator, children);
+    (0..children.len())
+        .map(|i| (i == best).then_some(grad))
+        .collect()
+}
+
+// The max is subtracted before exponentiating and added back afterwards. It lives in
+// a leaf node, so the gradient of every input is exactly softmax(values).
+pub fn logsumexp<T: Num>(allocator: &mut Allocator<T>, values: &[ValueId<T>]) -> ValueId<T> {
+    assert!(!values.is_empty(), "logsumexp of an empty slice");
+
+    let max = values.iter().map(|v| allocator.get(*v).data).fold(
+        allocator.get(values[0]).data,
+        |acc, x| if x > acc { x } else { acc },
+    );
+    let shift = allocator.alloc_const(max);
+
+    let exps = values.iter().map(|v| exp(*v - shift)).collect::<Vec<_>>();
+    ln(sum(allocator, &exps)) + shift
+}
+
+#[inline(always)]
+pub fn recip<T: Num>(this: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = this.allocator().as_mut().unwrap();
+        allocator.record_op(recip_forward::<T>, Op::Recip, Some(recip_grad::<T>), [this])
+    }
+}
+
+fn recip_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    T::one() / allocator.get(children[0]).data
+}
+
+pub(crate) fn recip_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    base_val: T,
+    children: &[ValueId<T>],
+) {
+    // -1 / x^2 is the same as -(1 / x)^2
+    allocator
+        .get_mut(children[0])
+        .add_grad(-base_grad * base_val * base_val);
+}
+
+fn recip_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(-(grad * out * out))]
+}
+
+impl<T: Num> Div for ValueId<T> {
+    type Output = ValueId<T>;
+
+    #[inline(always)]
+    fn div(self, other: ValueId<T>) -> ValueId<T> {
+        assert!(self.allocator() == other.allocator());
+
+        unsafe {
+            let allocator = self.allocator().as_mut().unwrap();
+            allocator.record_op(
+                div_forward::<T>,
+                Op::Div,
+                Some(div_grad::<T>),
+                [self, other],
+            )
+        }
+    }
+}
+
+fn div_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data / allocator.get(children[1]).data
+}
+
+pub(crate) fn div_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    base_val: T,
+    children: &[ValueId<T>],
+) {
+    let b = allocator.get(children[1]).data;
+
+    allocator
+        .get_mut(children[0])
+        .add_grad(base_grad * T::one() / b);
+    allocator
+        .get_mut(children[1])
+        .add_grad(base_grad * -base_val * T::one() / b);
+}
+
+fn div_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad / children[1]), Some(-(grad * out) / children[1])]
+}
+
+// Mixed scalar/value arithmetic. The scalar is stored in a leaf node so the backward
+// can read it, but gradients are only propagated to the value operand.
+impl<T: Num> Add<T> for ValueId<T> {
+    type Output = ValueId<T>;
+
+    #[inline(always)]
+    fn add(self, other: T) -> ValueId<T> {
+        unsafe {
+            let allocator = self.allocator().as_mut().unwrap();
+            let other = allocator.alloc_const(other);
+            allocator.record_op(
+                add_scalar_forward::<T>,
+                Op::AddScalar,
+                Some(add_scalar_grad::<T>),
+                [self, other],
+            )
+        }
+    }
+}
+
+fn add_scalar_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data + allocator.get(children[1]).data
+}
+
+pub(crate) fn add_scalar_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    allocator.get_mut(children[0]).add_grad(base_grad);
+}
+
+fn add_scalar_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad), None]
+}
+
+impl<T: Num> Sub<T> for ValueId<T> {
+    type Output = ValueId<T>;
+
+    #[inline(always)]
+    fn sub(self, other: T) -> ValueId<T> {
+        self + -other
+    }
+}
+
+#[inline(always)]
+fn rsub_scalar<T: Num>(this: T, other: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = other.allocator().as_mut().unwrap();
+        let this = allocator.alloc_const(this);
+        allocator.record_op(
+            rsub_scalar_forward::<T>,
+            Op::RsubScalar,
+            Some(rsub_scalar_grad::<T>),
+            [other, this],
+        )
+    }
+}
+
+fn rsub_scalar_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    // The scalar is the second child, as in every mixed scalar op
+    allocator.get(children[1]).data - allocator.get(children[0]).data
+}
+
+pub(crate) fn rsub_scalar_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    allocator.get_mut(children[0]).add_grad(-base_grad);
+}
+
+fn rsub_scalar_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    _children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(-grad), None]
+}
+
+impl<T: Num> Mul<T> for ValueId<T> {
+    type Output = ValueId<T>;
+
+    #[inline(always)]
+    fn mul(self, other: T) -> ValueId<T> {
+        unsafe {
+            let allocator = self.allocator().as_mut().unwrap();
+            let other = allocator.alloc_const(other);
+            allocator.record_op(
+                mul_scalar_forward::<T>,
+                Op::MulScalar,
+                Some(mul_scalar_grad::<T>),
+                [self, other],
+            )
+        }
+    }
+}
+
+fn mul_scalar_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data * allocator.get(children[1]).data
+}
+
+pub(crate) fn mul_scalar_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let b = allocator.get(children[1]).data;
+    allocator.get_mut(children[0]).add_grad(base_grad * b);
+}
+
+fn mul_scalar_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad * allocator.get(children[1]).data), None]
+}
+
+impl<T: Num> Div<T> for ValueId<T> {
+    type Output = ValueId<T>;
+
+    #[inline(always)]
+    fn div(self, other: T) -> ValueId<T> {
+        unsafe {
+            let allocator = self.allocator().as_mut().unwrap();
+            let other = allocator.alloc_const(other);
+            allocator.record_op(
+                div_scalar_forward::<T>,
+                Op::DivScalar,
+                Some(div_scalar_grad::<T>),
+                [self, other],
+            )
+        }
+    }
+}
+
+fn div_scalar_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[0]).data / allocator.get(children[1]).data
+}
+
+pub(crate) fn div_scalar_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    _base_val: T,
+    children: &[ValueId<T>],
+) {
+    let b = allocator.get(children[1]).data;
+    allocator.get_mut(children[0]).add_grad(base_grad / b);
+}
+
+fn div_scalar_grad<T: Num>(
+    allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    _out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(grad / allocator.get(children[1]).data), None]
+}
+
+#[inline(always)]
+fn rdiv_scalar<T: Num>(this: T, other: ValueId<T>) -> ValueId<T> {
+    unsafe {
+        let allocator = other.allocator().as_mut().unwrap();
+        let this = allocator.alloc_const(this);
+        allocator.record_op(
+            rdiv_scalar_forward::<T>,
+            Op::RdivScalar,
+            Some(rdiv_scalar_grad::<T>),
+            [other, this],
+        )
+    }
+}
+
+fn rdiv_scalar_forward<T: Num>(allocator: &Allocator<T>, children: &[ValueId<T>]) -> T {
+    allocator.get(children[1]).data / allocator.get(children[0]).data
+}
+
+pub(crate) fn rdiv_scalar_backward<T: Num>(
+    allocator: &mut Allocator<T>,
+    base_grad: T,
+    base_val: T,
+    children: &[ValueId<T>],
+) {
+    let b = allocator.get(children[0]).data;
+    allocator
+        .get_mut(children[0])
+        .add_grad(base_grad * -base_val / b);
+}
+
+fn rdiv_scalar_grad<T: Num>(
+    _allocator: &mut Allocator<T>,
+    grad: ValueId<T>,
+    out: ValueId<T>,
+    children: &[ValueId<T>],
+) -> Vec<Option<ValueId<T>>> {
+    vec![Some(-(grad * out) / children[0]), None]
+}
+
+// Scalars on the left-hand side can't be implemented generically over `T` because of
+// the orphan rule, so they are implemented for each concrete float type instead
+macro_rules! impl_scalar_lhs_ops {
+    ($($t:ty),*) => {
+        $(
+            impl Add<ValueId<$t>> for $t {
+                type Output = ValueId<$t>;
+
+                #[inline(always)]
+                fn add(self, other: ValueId<$t>) -> ValueId<$t> {
+                    other + self
+                }
+            }
+
+            impl Sub<ValueId<$t>> for $t {
+                type Output = ValueId<$t>;
+
+                #[inline(always)]
+                fn sub(self, other: ValueId<$t>) -> ValueId<$t> {
+                    rsub_scalar(self, other)
+                }
+            }
+
+            impl Mul<ValueId<$t>> for $t {
+                type Output = ValueId<$t>;
+
+                #[inline(always)]
+                fn mul(self, other: ValueId<$t>) -> ValueId<$t> {
+                    other * self
+                }
+            }
+
+            impl Div<ValueId<$t>> for $t {
+                type Output = ValueId<$t>;
+
+                #[inline(always)]
+                fn div(self, other: ValueId<$t>) -> ValueId<$t> {
+                    rdiv_scalar(self, other)
+                }
+            }
+        )*
+    };
+}
+
+impl_scalar_lhs_ops!(f32, f64);
+
+// An optimization pass over a recorded tape, e.g. before replaying it many times:
+//   a * b + c  ->  fma(a, b, c)
+//   (a + b) + c  ->  sum(a, b, c), flattening nested sums as well
+//   a + -b  ->  sub(a, b)
+// A node is only absorbed into its consumer when nothing else on the tape uses it, and
+// then becomes a constant holding its current data, so ids of absorbed intermediates
+// shouldn't be used afterwards. Returns the number of ops removed.
+pub fn fuse<T: Num>(allocator: &mut Allocator<T>) -> usize {
+    let mut uses = vec![0; allocator.tape_len()];
+    let mut consumers = (0..allocator.tape_len())
+        .map(|i| allocator.temp_id(i))
+        .collect::<Vec<_>>();
+    consumers.extend(allocator.permanents().map(|(id, _)| id));
+    for node in consumers {
+        for child in allocator.children(node) {
+            if let Some(index) = child.tape_index() {
+                uses[index] += 1;
+            }
+        }
+    }
+
+    let is_add = |value: ValueRef<T>| value.op == Some(Op::Add);
+    let is_sum = |value: ValueRef<T>| value.op == Some(Op::Sum);
+    let is_mul = |value: ValueRef<T>| value.op == Some(Op::Mul);
+    let is_neg = |value: ValueRef<T>| value.op == Some(Op::Neg);
+    let absorbable =
+        |allocator: &Allocator<T>, child: ValueId<T>, kind: &dyn Fn(ValueRef<T>) -> bool| {
+            child
+                .tape_index()
+                .is_some_and(|index| uses[index] == 1 && kind(allocator.get(child)))
+        };
+
+    let mut fused = 0;
+    for i in 0..uses.len() {
+        let node = allocator.temp_id(i);
+        let value = allocator.get(node);
+        if !is_add(value) && !is_sum(value) {
+            continue;
+        }
+        let children = allocator.children(node);
+
+        if is_add(value) {
+            let (a, b) = (children[0], children[1]);
+            let rewritten = if absorbable(allocator, b, &is_neg) {
+                Some((b, sub_op(vec![a, allocator.children(b)[0]])))
+            } else if absorbable(allocator, a, &is_neg) {
+                Some((a, sub_op(vec![b, allocator.children(a)[0]])))
+            } else if absorbable(allocator, a, &is_mul) {
+                Some((a, fma_op([allocator.children(a), vec![b]].concat())))
+            } else if absorbable(allocator, b, &is_mul) {
+                Some((b, fma_op([allocator.children(b), vec![a]].concat())))
+            } else {
+                None
+            };
+            if let Some((absorbed, op)) = rewritten {
+                rewrite(allocator, node, op);
+                discard(allocator, absorbed);
+                fused += 1;
+                continue;
+            }
+        }
+
+        let mut flat = vec![];
+        let mut absorbed = vec![];
+        for child in children {
+            if absorbable(allocator, child, &|v| is_add(v) || is_sum(v)) {
+                flat.extend(allocator.children(child));
+                absorbed.push(child);
+            } else {
+                flat.push(child);
+            }
+        }
+        if !absorbed.is_empty() {
+            rewrite(allocator, node, sum_op(flat));
+            fused += absorbed.len();
+            for child in absorbed {
+                discard(allocator, child);
+            }
+        }
+    }
+    fused
+}
+
+// Common subexpression elimination: nodes recorded by the same op from the same children
+// are merged into the first of them, and their consumers are pointed at it instead.
+// Scalar operands live in separate constant leaves, so e.g. two `x * 2.0` aren't
+// recognized as identical. The last node, which `backward()` seeds, is always kept.
+// Returns the number of ops removed.
+pub fn eliminate_common_subexpressions<T: Num>(allocator: &mut Allocator<T>) -> usize {
+    let mut canonical = HashMap::<_, ValueId<T>>::new();
+    let mut replaced = HashMap::new();
+    let len = allocator.tape_len();
+    for i in 0..len {
+        let node = allocator.temp_id(i);
+        remap_children(allocator, node, &replaced);
+        let value = allocator.get(node);
+        let (Some(forward), Some(op)) = (value.forward, value.op) else {
+            continue;
+        };
+
+        let key = (forward as usize, op, value.previous.to_vec());
+        match canonical.get(&key) {
+            Some(original) if i + 1 < len => {
+                replaced.insert(node.raw(), original.raw());
+                discard(allocator, node);
+            }
+            Some(_) => {}
+            None => {
+                canonical.insert(key, node);
+            }
+        }
+    }
+
+    let permanents = allocator.permanents().map(|(id, _)| id).collect::<Vec<_>>();
+    for node in permanents {
+        remap_children(allocator, node, &replaced);
+    }
+    replaced.len()
+}
+
+// `replaced` maps raw ids, as stored in `Children`
+fn remap_children<T: Num>(
+    allocator: &mut Allocator<T>,
+    node: ValueId<T>,
+    replaced: &HashMap<u32, u32>,
+) {
+    let previous = allocator.get(node).previous;
+    if previous.iter().any(|child| replaced.contains_key(child)) {
+        let children = previous
+            .iter()
+            .map(|child| *replaced.get(child).unwrap_or(child))
+            .collect::<Vec<_>>();
+        allocator.set_children(node, &children);
+    }
+}
+
+// Shrinks a recorded tape before it is replayed many times, by merging duplicate nodes
+// and then fusing common patterns. Returns the number of ops removed.
+pub fn optimize<T: Num>(allocator: &mut Allocator<T>) -> usize {
+    eliminate_common_subexpressions(allocator) + fuse(allocator)
+}
+
+type FusedOp<T> = (ForwardFn<T>, Op<T>, GradFn<T>, Vec<ValueId<T>>);
+
+fn sub_op<T: Num>(children: Vec<ValueId<T>>) -> FusedOp<T> {
+    (sub_forward, Op::Sub, sub_grad, children)
+}
+
+fn fma_op<T: Num>(children: Vec<ValueId<T>>) -> FusedOp<T> {
+    (fma_forward, Op::Fma, fma_grad, children)
+}
+
+fn sum_op<T: Num>(children: Vec<ValueId<T>>) -> FusedOp<T> {
+    (sum_forward, Op::Sum, sum_grad, children)
+}
+
+// The data is left as is, since the fused op computes the same value
+fn rewrite<T: Num>(allocator: &mut Allocator<T>, node: ValueId<T>, op: FusedOp<T>) {
+    let (forward, op, grad_fn, children) = op;
+    let value = allocator.get(node);
+    let rewritten = Value {
+        data: value.data,
+        grad: value.grad,
+        requires_grad: value.requires_grad,
+        previous: children.into(),
+        op: Some(op),
+        grad_fn: Some(grad_fn),
+        forward: Some(forward),
+    };
+    allocator.set_node(node, rewritten);
+}
+
+fn discard<T: Num>(allocator: &mut Allocator<T>, node: ValueId<T>) {
+    let data = allocator.get(node).data;
+    allocator.set_node(node, Value::from(data));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addition() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(3.0);
+        let b = allocator.alloc(4.0);
+        let c = a + b;
+        assert_eq!(allocator.get(c).data, 7.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 1.0);
+        assert_eq!(allocator.get(b).grad, 1.0);
+        assert_eq!(allocator.get(c).grad, 1.0);
+    }
+
+    #[test]
+    fn test_multiplication() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(3.0);
+        let b = allocator.alloc(4.0);
+        let c = a * b;
+        assert_eq!(allocator.get(c).data, 12.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 4.0);
+        assert_eq!(allocator.get(b).grad, 3.0);
+        assert_eq!(allocator.get(c).grad, 1.0);
+    }
+
+    #[test]
     fn test_negation() {
         let mut allocator = Allocator::new();
         let a = allocator.alloc(3.0);
@@ -413,4 +1916,482 @@ mod tests {
         assert_eq!(allocator.get(c).grad, 0.0);
         assert_eq!(allocator.get(d).grad, 1.0);
     }
+
+    #[test]
+    fn test_abs() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(-3.0);
+        let b = abs(a);
+        assert_eq!(allocator.get(b).data, 3.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, -1.0);
+        assert_eq!(allocator.get(b).grad, 1.0);
+
+        let c = allocator.alloc(2.0);
+        let d = abs(c);
+        assert_eq!(allocator.get(d).data, 2.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(c).grad, 1.0);
+
+        let e = allocator.alloc(0.0);
+        let f = abs(e);
+        assert_eq!(allocator.get(f).data, 0.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(e).grad, 0.0);
+    }
+
+    #[test]
+    fn test_logsumexp() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let b = allocator.alloc(2.0);
+        let c = allocator.alloc(3.0);
+        let d = logsumexp(&mut allocator, &[a, b, c]);
+        let expected = (1.0f64.exp() + 2.0f64.exp() + 3.0f64.exp()).ln();
+        assert!((allocator.get(d).data - expected).abs() < 1e-12);
+
+        allocator.backward();
+        let total = 1.0f64.exp() + 2.0f64.exp() + 3.0f64.exp();
+        assert!((allocator.get(a).grad - 1.0f64.exp() / total).abs() < 1e-12);
+        assert!((allocator.get(b).grad - 2.0f64.exp() / total).abs() < 1e-12);
+        assert!((allocator.get(c).grad - 3.0f64.exp() / total).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_logsumexp_large_inputs() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1000.0);
+        let b = allocator.alloc(1000.0);
+        let c = logsumexp(&mut allocator, &[a, b]);
+        assert!((allocator.get(c).data - (1000.0 + 2.0f64.ln())).abs() < 1e-9);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 0.5);
+        assert_eq!(allocator.get(b).grad, 0.5);
+    }
+
+    #[test]
+    fn test_log() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(8.0);
+        let b = log2(a);
+        assert_eq!(allocator.get(b).data, 3.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 1.0 / (8.0 * 2.0f64.ln()));
+
+        let c = allocator.alloc(100.0);
+        let d = log10(c);
+        assert_eq!(allocator.get(d).data, 2.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(c).grad, 1.0 / (100.0 * 10.0f64.ln()));
+
+        let e = allocator.alloc(9.0);
+        let f = log(e, 3.0);
+        assert!((allocator.get(f).data - 2.0).abs() < 1e-12);
+
+        allocator.backward();
+        assert_eq!(allocator.get(e).grad, 1.0 / (9.0 * 3.0f64.ln()));
+    }
+
+    #[test]
+    fn test_powf() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(4.0);
+        let b = powf(a, 1.5);
+        assert_eq!(allocator.get(b).data, 8.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 3.0);
+        assert_eq!(allocator.get(b).grad, 1.0);
+    }
+
+    #[test]
+    fn test_powi() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(-2.0);
+        let b = powi(a, 3);
+        assert_eq!(allocator.get(b).data, -8.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 12.0);
+        assert_eq!(allocator.get(b).grad, 1.0);
+
+        let c = allocator.alloc(0.0);
+        let d = powi(c, 2);
+        assert_eq!(allocator.get(d).data, 0.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(c).grad, 0.0);
+    }
+
+    #[test]
+    fn test_erf() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(0.5f64);
+        let b = erf(a);
+        assert!((allocator.get(b).data - 0.5204998778130465).abs() < 1e-15);
+
+        allocator.backward();
+        assert!((allocator.get(a).grad - 0.8787825789354448).abs() < 1e-15);
+        assert_eq!(allocator.get(b).grad, 1.0);
+    }
+
+    #[test]
+    fn test_recip() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(4.0);
+        let b = recip(a);
+        assert_eq!(allocator.get(b).data, 0.25);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, -0.0625);
+        assert_eq!(allocator.get(b).grad, 1.0);
+    }
+
+    #[test]
+    fn test_scalar_operators() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(3.0);
+
+        let b = a + 2.0;
+        assert_eq!(allocator.get(b).data, 5.0);
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 1.0);
+        allocator.zero_grads();
+
+        let c = 2.0 - a;
+        assert_eq!(allocator.get(c).data, -1.0);
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, -1.0);
+        allocator.zero_grads();
+
+        let d = a - 2.0;
+        assert_eq!(allocator.get(d).data, 1.0);
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 1.0);
+        allocator.zero_grads();
+
+        let e = 2.0 * a;
+        assert_eq!(allocator.get(e).data, 6.0);
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 2.0);
+        allocator.zero_grads();
+
+        let f = a / 2.0;
+        assert_eq!(allocator.get(f).data, 1.5);
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 0.5);
+        allocator.zero_grads();
+
+        let g = 6.0 / a;
+        assert_eq!(allocator.get(g).data, 2.0);
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, -6.0 / 9.0);
+    }
+
+    #[test]
+    fn test_sum() {
+        let mut allocator = Allocator::new();
+        let values = (1..=100)
+            .map(|i| allocator.alloc(i as f64))
+            .collect::<Vec<_>>();
+        let total = sum(&mut allocator, &values);
+        assert_eq!(allocator.get(total).data, 5050.0);
+
+        allocator.backward();
+        for value in values.iter() {
+            assert_eq!(allocator.get(*value).grad, 1.0);
+        }
+
+        let empty = sum(&mut allocator, &[]);
+        assert_eq!(allocator.get(empty).data, 0.0);
+    }
+
+    #[test]
+    fn test_mean() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let b = allocator.alloc(2.0);
+        let c = allocator.alloc(6.0);
+        let d = mean(&mut allocator, &[a, b, c]);
+        assert_eq!(allocator.get(d).data, 3.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 1.0 / 3.0);
+        assert_eq!(allocator.get(b).grad, 1.0 / 3.0);
+        assert_eq!(allocator.get(c).grad, 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_dot() {
+        let mut allocator = Allocator::new();
+        let a = vec![
+            allocator.alloc(1.0),
+            allocator.alloc(2.0),
+            allocator.alloc(3.0),
+        ];
+        let b = vec![
+            allocator.alloc(4.0),
+            allocator.alloc(5.0),
+            allocator.alloc(6.0),
+        ];
+        let c = dot(&mut allocator, &a, &b);
+        assert_eq!(allocator.get(c).data, 32.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a[0]).grad, 4.0);
+        assert_eq!(allocator.get(a[1]).grad, 5.0);
+        assert_eq!(allocator.get(a[2]).grad, 6.0);
+        assert_eq!(allocator.get(b[0]).grad, 1.0);
+        assert_eq!(allocator.get(b[1]).grad, 2.0);
+        assert_eq!(allocator.get(b[2]).grad, 3.0);
+    }
+
+    #[test]
+    fn test_fma() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(3.0);
+        let b = allocator.alloc(4.0);
+        let c = allocator.alloc(5.0);
+        let d = fma(a, b, c);
+        assert_eq!(allocator.get(d).data, 17.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 4.0);
+        assert_eq!(allocator.get(b).grad, 3.0);
+        assert_eq!(allocator.get(c).grad, 1.0);
+        assert_eq!(allocator.get(d).grad, 1.0);
+    }
+
+    #[test]
+    fn test_affine() {
+        let mut allocator = Allocator::new();
+        let w = vec![allocator.alloc(1.0), allocator.alloc(2.0)];
+        let x = vec![allocator.alloc(3.0), allocator.alloc(4.0)];
+        let b = allocator.alloc(0.5);
+        let y = affine(&w, &x, b);
+        assert_eq!(allocator.get(y).data, 11.5);
+
+        allocator.backward();
+        assert_eq!(allocator.get(w[0]).grad, 3.0);
+        assert_eq!(allocator.get(w[1]).grad, 4.0);
+        assert_eq!(allocator.get(x[0]).grad, 1.0);
+        assert_eq!(allocator.get(x[1]).grad, 2.0);
+        assert_eq!(allocator.get(b).grad, 1.0);
+    }
+
+    #[test]
+    fn test_select() {
+        let mut allocator = Allocator::new();
+        let cond = allocator.alloc(1.0);
+        let a = allocator.alloc(3.0);
+        let b = allocator.alloc(4.0);
+        let c = select(cond, a, b);
+        assert_eq!(allocator.get(c).data, 3.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(cond).grad, 0.0);
+        assert_eq!(allocator.get(a).grad, 1.0);
+        assert_eq!(allocator.get(b).grad, 0.0);
+        allocator.zero_grads();
+
+        let d = -cond;
+        let e = select(d, a, b);
+        assert_eq!(allocator.get(e).data, 4.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 0.0);
+        assert_eq!(allocator.get(b).grad, 1.0);
+    }
+
+    #[test]
+    fn test_clamp() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(3.0);
+        let b = clamp(a, -1.0, 1.0);
+        assert_eq!(allocator.get(b).data, 1.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 0.0);
+
+        let c = allocator.alloc(0.5);
+        let d = clamp(c, -1.0, 1.0);
+        assert_eq!(allocator.get(d).data, 0.5);
+
+        allocator.backward();
+        assert_eq!(allocator.get(c).grad, 1.0);
+    }
+
+    #[test]
+    fn test_clamp_straight_through() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(-3.0);
+        let b = clamp_straight_through(a, -1.0, 1.0);
+        assert_eq!(allocator.get(b).data, -1.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 1.0);
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        let mut allocator = Allocator::new();
+        let a = vec![allocator.alloc(1.0), allocator.alloc(0.0)];
+        let b = vec![allocator.alloc(1.0), allocator.alloc(1.0)];
+        let c = cosine_similarity(&mut allocator, &a, &b);
+        let inv_sqrt2 = 1.0 / 2.0f64.sqrt();
+        assert!((allocator.get(c).data - inv_sqrt2).abs() < 1e-12);
+
+        allocator.backward();
+        assert!(allocator.get(a[0]).grad.abs() < 1e-12);
+        assert!((allocator.get(a[1]).grad - inv_sqrt2).abs() < 1e-12);
+        assert!((allocator.get(b[0]).grad - inv_sqrt2 / 2.0).abs() < 1e-12);
+        assert!((allocator.get(b[1]).grad + inv_sqrt2 / 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_max() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let b = allocator.alloc(5.0);
+        let c = allocator.alloc(5.0);
+        let d = max(&mut allocator, &[a, b, c]);
+        assert_eq!(allocator.get(d).data, 5.0);
+
+        allocator.backward();
+        assert_eq!(allocator.get(a).grad, 0.0);
+        assert_eq!(allocator.get(b).grad, 1.0);
+        assert_eq!(allocator.get(c).grad, 0.0);
+    }
+
+    #[test]
+    fn test_softmax() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let b = allocator.alloc(2.0);
+        let probs = softmax(&mut allocator, &[a, b]);
+        let total = 1.0f64.exp() + 2.0f64.exp();
+        assert!((allocator.get(probs[0]).data - 1.0f64.exp() / total).abs() < 1e-12);
+        assert!((allocator.get(probs[1]).data - 2.0f64.exp() / total).abs() < 1e-12);
+
+        // d p_1 / d a = -p_0 * p_1
+        allocator.backward();
+        let p0 = 1.0f64.exp() / total;
+        assert!((allocator.get(a).grad + p0 * (1.0 - p0)).abs() < 1e-12);
+        assert!((allocator.get(b).grad - p0 * (1.0 - p0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_fuse() {
+        let mut allocator = Allocator::new();
+        let w = [1.5f64, -2.0, 0.5].map(|x| allocator.alloc(x));
+        let x = [0.3, 0.7, -1.1].map(|x| allocator.alloc_t(x));
+        let b = allocator.alloc(0.25);
+        let target = allocator.alloc_t(0.4);
+
+        let z = w[0] * x[0] + w[1] * x[1] + w[2] * x[2] + b;
+        let diff = tanh(z) - target;
+        let loss = diff * diff;
+        let expected = allocator.get(loss).data;
+        allocator.backward();
+        let expected_grads = allocator.gather_grads(&w);
+        allocator.zero_grads();
+
+        let ops = allocator.stats().ops;
+        // The first two adds absorb a multiply each, and a + -b becomes one node
+        assert_eq!(fuse(&mut allocator), 3);
+        assert_eq!(allocator.stats().ops, ops - 3);
+        assert_eq!(fuse(&mut allocator), 0);
+
+        allocator.replay_forward();
+        assert!((allocator.get(loss).data - expected).abs() < 1e-12);
+        allocator.backward();
+        for (grad, expected) in allocator.gather_grads(&w).iter().zip(expected_grads) {
+            assert!((grad - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_fuse_sums() {
+        let mut allocator = Allocator::new();
+        let x = [1.0, 2.0, 3.0, 4.0].map(|x| allocator.alloc(x));
+        let total = x[0] + x[1] + (x[2] + x[3]);
+        let shared = x[0] + x[1];
+        let out = shared + shared + total;
+
+        // `shared` has two uses and stays, every other add is absorbed into `out`
+        assert_eq!(fuse(&mut allocator), 4);
+        assert_eq!(allocator.get(out).previous.len(), 6);
+        allocator.replay_forward();
+        assert_eq!(allocator.get(out).data, 16.0);
+        allocator.backward();
+        assert_eq!(allocator.gather_grads(&x), vec![3.0, 3.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_eliminate_common_subexpressions() {
+        let mut allocator = Allocator::new();
+        let w = allocator.alloc(0.5f64);
+        let x = allocator.alloc_t(2.0);
+
+        // tanh(w * x) is written out twice, as hand-written forward code might
+        let first = tanh(w * x);
+        let second = tanh(w * x);
+        let product = first * second;
+        let loss = product + first;
+        let expected = allocator.get(loss).data;
+
+        assert_eq!(eliminate_common_subexpressions(&mut allocator), 2);
+        assert!(allocator.get(product).previous[1] == first.raw());
+        assert_eq!(eliminate_common_subexpressions(&mut allocator), 0);
+
+        allocator.scatter_data(&[x], &[3.0]);
+        allocator.replay_forward();
+        let t = (1.5f64).tanh();
+        assert!((allocator.get(loss).data - (t * t + t)).abs() < 1e-12);
+        assert!(allocator.get(loss).data != expected);
+
+        // d/dw (t^2 + t) = (2t + 1)(1 - t^2) x
+        allocator.backward();
+        let grad = (2.0 * t + 1.0) * (1.0 - t * t) * 3.0;
+        assert!((allocator.get(w).grad - grad).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_optimize() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(2.0f64);
+        let b = allocator.alloc(3.0);
+        let c = allocator.alloc(4.0);
+        let out = a * b + c + a * b;
+
+        // The repeated product is merged, and the remaining mul + add chain fused
+        assert_eq!(optimize(&mut allocator), 2);
+        allocator.replay_forward();
+        assert_eq!(allocator.get(out).data, 16.0);
+        allocator.backward();
+        assert_eq!(allocator.gather_grads(&[a, b, c]), vec![6.0, 4.0, 1.0]);
+    }
+
+    #[test]
+    fn test_detach() {
+        let mut allocator = Allocator::new();
+        let w = allocator.alloc(3.0);
+        let target = detach(w * w);
+        let _ = target * w;
+        assert_eq!(allocator.get(target).data, 9.0);
+
+        // Only the direct use of w contributes: d/dw (stop(w^2) * w) = w^2
+        allocator.backward();
+        assert_eq!(allocator.get(w).grad, 9.0);
+
+        allocator.get_mut(w).set_data(2.0);
+        allocator.replay_forward();
+        assert_eq!(allocator.get(target).data, 4.0);
+    }
 }
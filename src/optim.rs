@@ -0,0 +1,681 @@
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::Num,
+};
+use std::{cmp::Ordering, collections::HashMap};
+
+pub mod lr_scheduler;
+#[cfg(feature = "half")]
+pub mod mixed;
+pub mod parallel;
+
+pub trait Optimizer<T: Num> {
+    fn step(&mut self, allocator: &mut Allocator<T>);
+    fn zero_grad(&mut self, allocator: &mut Allocator<T>);
+    fn lr(&self) -> T;
+    fn set_lr(&mut self, lr: T);
+
+    // Updates only `params`, which must be a subset of this optimizer's parameters. The
+    // state of every other parameter (e.g. momentum) is left untouched, so the cost
+    // scales with the number of parameters that actually received gradients. Optimizers
+    // without per-parameter updates fall back to a full `step`, which also moves the
+    // other parameters if they have gradients or state.
+    fn step_sparse(&mut self, allocator: &mut Allocator<T>, _params: &[ValueId<T>]) {
+        self.step(allocator);
+    }
+
+    fn zero_grad_sparse(&mut self, allocator: &mut Allocator<T>, params: &[ValueId<T>]) {
+        zero_grads(allocator, params);
+    }
+}
+
+fn zero_grads<T: Num>(allocator: &mut Allocator<T>, params: &[ValueId<T>]) {
+    for param in params {
+        allocator.get_mut(*param).zero_grad();
+    }
+}
+
+fn index_params<T: Num>(params: &[ValueId<T>]) -> HashMap<ValueId<T>, usize> {
+    params.iter().enumerate().map(|(i, p)| (*p, i)).collect()
+}
+
+// Rescales the gradients of `params` so that their global L2 norm is at most `max_norm`.
+// Returns the norm before clipping.
+pub fn clip_grad_norm<T: Num>(
+    allocator: &mut Allocator<T>,
+    params: &[ValueId<T>],
+    max_norm: T,
+) -> T {
+    let norm = allocator.grad_norm(params);
+    if norm > max_norm {
+        let scale = max_norm / norm;
+        for param in params {
+            let grad = allocator.get(*param).grad;
+            allocator.get_mut(*param).set_grad(grad * scale);
+        }
+    }
+    norm
+}
+
+// Clamps every gradient of `params` into [-limit, limit]
+pub fn clip_grad_value<T: Num>(allocator: &mut Allocator<T>, params: &[ValueId<T>], limit: T) {
+    for param in params {
+        let grad = allocator.get(*param).grad;
+        if grad > limit {
+            allocator.get_mut(*param).set_grad(limit);
+        } else if grad < -limit {
+            allocator.get_mut(*param).set_grad(-limit);
+        }
+    }
+}
+
+// Trains for up to `num_steps` steps while sweeping the learning rate exponentially
+// from `start_lr` to `end_lr`, and returns the (lr, loss) curve. The sweep stops early
+// once the loss diverges. Parameter data and the optimizer's learning rate are restored
+// afterwards, but any internal optimizer state (e.g. momentum) is not.
+pub fn lr_find<T: Num, O: Optimizer<T>>(
+    allocator: &mut Allocator<T>,
+    optimizer: &mut O,
+    params: &[ValueId<T>],
+    start_lr: T,
+    end_lr: T,
+    num_steps: usize,
+    mut loss_fn: impl FnMut(&mut Allocator<T>) -> ValueId<T>,
+) -> Vec<(T, T)> {
+    assert!(num_steps > 1, "lr_find needs at least two steps");
+    assert!(
+        start_lr > T::zero() && end_lr > start_lr,
+        "lr_find needs 0 < start_lr < end_lr"
+    );
+
+    let initial_data = params
+        .iter()
+        .map(|p| allocator.get(*p).data)
+        .collect::<Vec<_>>();
+    let initial_lr = optimizer.lr();
+    let ratio = end_lr / start_lr;
+    let divergence = T::from_u8(4).unwrap();
+
+    let mut curve = Vec::with_capacity(num_steps);
+    let mut best: Option<T> = None;
+    for i in 0..num_steps {
+        let lr = start_lr * ratio.pow(T::from_f64(i as f64 / (num_steps - 1) as f64).unwrap());
+        optimizer.set_lr(lr);
+
+        let loss = loss_fn(allocator);
+        let loss_data = allocator.get(loss).data;
+        allocator.backward();
+        optimizer.step(allocator);
+        optimizer.zero_grad(allocator);
+        allocator.clear_temps();
+
+        curve.push((lr, loss_data));
+        // NaN losses are incomparable and count as divergence as well
+        let limit = divergence * best.unwrap_or(loss_data);
+        if !matches!(
+            loss_data.partial_cmp(&limit),
+            Some(Ordering::Less | Ordering::Equal)
+        ) {
+            break;
+        }
+        if best.is_none_or(|best| loss_data < best) {
+            best = Some(loss_data);
+        }
+    }
+
+    for (param, data) in params.iter().zip(initial_data) {
+        allocator.get_mut(*param).set_data(data);
+    }
+    optimizer.set_lr(initial_lr);
+    curve
+}
+
+#[derive(Clone)]
+pub struct SGD<T: Num> {
+    pub(crate) params: Vec<ValueId<T>>,
+    pub(crate) index: HashMap<ValueId<T>, usize>,
+    pub(crate) lr: T,
+    pub(crate) momentum: T,
+    pub(crate) velocity: Vec<T>,
+}
+
+impl<T: Num> SGD<T> {
+    pub fn new(params: Vec<ValueId<T>>, lr: T) -> Self {
+        Self::with_momentum(params, lr, T::zero())
+    }
+
+    // v = momentum * v + grad, then p = p - lr * v
+    pub fn with_momentum(params: Vec<ValueId<T>>, lr: T, momentum: T) -> Self {
+        let velocity = vec![T::zero(); params.len()];
+        SGD {
+            index: index_params(&params),
+            params,
+            lr,
+            momentum,
+            velocity,
+        }
+    }
+
+    fn update(&mut self, allocator: &mut Allocator<T>, i: usize) {
+        let value = allocator.get(self.params[i]);
+        if !value.requires_grad {
+            return;
+        }
+        let velocity = &mut self.velocity[i];
+        *velocity = self.momentum * *velocity + value.grad;
+        let data = value.data - self.lr * *velocity;
+        allocator.get_mut(self.params[i]).set_data(data);
+    }
+}
+
+impl<T: Num> Optimizer<T> for SGD<T> {
+    fn step(&mut self, allocator: &mut Allocator<T>) {
+        for i in 0..self.params.len() {
+            self.update(allocator, i);
+        }
+    }
+
+    fn step_sparse(&mut self, allocator: &mut Allocator<T>, params: &[ValueId<T>]) {
+        for param in params {
+            let i = *self
+                .index
+                .get(param)
+                .expect("step_sparse got a parameter this optimizer doesn't own");
+            self.update(allocator, i);
+        }
+    }
+
+    fn zero_grad(&mut self, allocator: &mut Allocator<T>) {
+        zero_grads(allocator, &self.params);
+    }
+
+    fn lr(&self) -> T {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: T) {
+        self.lr = lr;
+    }
+}
+
+#[derive(Clone)]
+pub struct RMSProp<T: Num> {
+    pub(crate) params: Vec<ValueId<T>>,
+    pub(crate) index: HashMap<ValueId<T>, usize>,
+    pub(crate) lr: T,
+    pub(crate) alpha: T,
+    pub(crate) eps: T,
+    pub(crate) square_avg: Vec<T>,
+}
+
+impl<T: Num> RMSProp<T> {
+    // s = alpha * s + (1 - alpha) * grad^2, then p = p - lr * grad / (sqrt(s) + eps)
+    pub fn new(params: Vec<ValueId<T>>, lr: T, alpha: T, eps: T) -> Self {
+        let square_avg = vec![T::zero(); params.len()];
+        RMSProp {
+            index: index_params(&params),
+            params,
+            lr,
+            alpha,
+            eps,
+            square_avg,
+        }
+    }
+
+    fn update(&mut self, allocator: &mut Allocator<T>, i: usize) {
+        let value = allocator.get(self.params[i]);
+        if !value.requires_grad {
+            return;
+        }
+        let grad = value.grad;
+        let square_avg = &mut self.square_avg[i];
+        *square_avg = self.alpha * *square_avg + (T::one() - self.alpha) * grad * grad;
+        let data = value.data - self.lr * grad / (square_avg.sqrt() + self.eps);
+        allocator.get_mut(self.params[i]).set_data(data);
+    }
+}
+
+impl<T: Num> Optimizer<T> for RMSProp<T> {
+    fn step(&mut self, allocator: &mut Allocator<T>) {
+        for i in 0..self.params.len() {
+            self.update(allocator, i);
+        }
+    }
+
+    fn step_sparse(&mut self, allocator: &mut Allocator<T>, params: &[ValueId<T>]) {
+        for param in params {
+            let i = *self
+                .index
+                .get(param)
+                .expect("step_sparse got a parameter this optimizer doesn't own");
+            self.update(allocator, i);
+        }
+    }
+
+    fn zero_grad(&mut self, allocator: &mut Allocator<T>) {
+        zero_grads(allocator, &self.params);
+    }
+
+    fn lr(&self) -> T {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: T) {
+        self.lr = lr;
+    }
+}
+
+// Limited-memory BFGS with a backtracking (Armijo) line search. Unlike the other
+// optimizers it has to re-evaluate the loss during the line search, so instead of
+// implementing `Optimizer` it takes a closure that builds the loss on the tape.
+#[derive(Clone)]
+pub struct LBFGS<T: Num> {
+    pub(crate) params: Vec<ValueId<T>>,
+    pub(crate) lr: T,
+    pub(crate) history_size: usize,
+    pub(crate) max_line_search: usize,
+    pub(crate) s_history: Vec<Vec<T>>,
+    pub(crate) y_history: Vec<Vec<T>>,
+}
+
+impl<T: Num> LBFGS<T> {
+    pub fn new(params: Vec<ValueId<T>>, lr: T, history_size: usize) -> Self {
+        assert!(history_size > 0, "LBFGS history_size must be positive");
+        LBFGS {
+            params,
+            lr,
+            history_size,
+            max_line_search: 25,
+            s_history: vec![],
+            y_history: vec![],
+        }
+    }
+
+    // Runs one quasi-Newton iteration and returns the loss at the new parameters
+    pub fn step(
+        &mut self,
+        allocator: &mut Allocator<T>,
+        mut loss_fn: impl FnMut(&mut Allocator<T>) -> ValueId<T>,
+    ) -> T {
+        let x = allocator.gather_data(&self.params);
+        let (loss, grad) = self.evaluate(allocator, &mut loss_fn);
+
+        let mut direction = self.direction(&grad);
+        let mut slope = dot(&grad, &direction);
+        if slope >= T::zero() {
+            // Not a descent direction, so the curvature history is useless
+            self.s_history.clear();
+            self.y_history.clear();
+            direction = grad.iter().map(|g| -*g).collect();
+            slope = dot(&grad, &direction);
+        }
+        if slope == T::zero() {
+            return loss;
+        }
+
+        let c1 = T::from_f64(1e-4).unwrap();
+        let half = T::one() / (T::one() + T::one());
+        let mut t = self.lr;
+        let mut accepted = None;
+        for _ in 0..self.max_line_search {
+            let candidate = x
+                .iter()
+                .zip(direction.iter())
+                .map(|(x, d)| *x + t * *d)
+                .collect::<Vec<_>>();
+            allocator.scatter_data(&self.params, &candidate);
+            let trial = self.evaluate(allocator, &mut loss_fn);
+            if trial.0 <= loss + c1 * t * slope {
+                accepted = Some(trial);
+                break;
+            }
+            t = t * half;
+        }
+
+        let Some((new_loss, new_grad)) = accepted else {
+            // No sufficient decrease along this direction, so stay put and start over
+            allocator.scatter_data(&self.params, &x);
+            self.s_history.clear();
+            self.y_history.clear();
+            return loss;
+        };
+        let s = direction.iter().map(|d| t * *d).collect::<Vec<_>>();
+        let y = new_grad
+            .iter()
+            .zip(grad.iter())
+            .map(|(a, b)| *a - *b)
+            .collect::<Vec<_>>();
+        if dot(&s, &y) > T::zero() {
+            if self.s_history.len() == self.history_size {
+                self.s_history.remove(0);
+                self.y_history.remove(0);
+            }
+            self.s_history.push(s);
+            self.y_history.push(y);
+        }
+        new_loss
+    }
+
+    fn evaluate(
+        &self,
+        allocator: &mut Allocator<T>,
+        loss_fn: &mut impl FnMut(&mut Allocator<T>) -> ValueId<T>,
+    ) -> (T, Vec<T>) {
+        zero_grads(allocator, &self.params);
+        let loss = loss_fn(allocator);
+        let loss_data = allocator.get(loss).data;
+        allocator.backward();
+        let grad = allocator.gather_grads(&self.params);
+        allocator.clear_temps();
+        (loss_data, grad)
+    }
+
+    // Two-loop recursion computing -H * grad from the curvature history
+    fn direction(&self, grad: &[T]) -> Vec<T> {
+        let mut q = grad.to_vec();
+        let mut alphas = Vec::with_capacity(self.s_history.len());
+        for (s, y) in self.s_history.iter().zip(self.y_history.iter()).rev() {
+            let rho = T::one() / dot(y, s);
+            let alpha = rho * dot(s, &q);
+            for (q, y) in q.iter_mut().zip(y) {
+                *q = *q - alpha * *y;
+            }
+            alphas.push((rho, alpha));
+        }
+
+        if let (Some(s), Some(y)) = (self.s_history.last(), self.y_history.last()) {
+            let gamma = dot(s, y) / dot(y, y);
+            for q in q.iter_mut() {
+                *q = *q * gamma;
+            }
+        }
+
+        for ((s, y), (rho, alpha)) in self
+            .s_history
+            .iter()
+            .zip(self.y_history.iter())
+            .zip(alphas.into_iter().rev())
+        {
+            let beta = rho * dot(y, &q);
+            for (q, s) in q.iter_mut().zip(s) {
+                *q = *q + (alpha - beta) * *s;
+            }
+        }
+        q.into_iter().map(|q| -q).collect()
+    }
+}
+
+fn dot<T: Num>(a: &[T], b: &[T]) -> T {
+    a.iter().zip(b).fold(T::zero(), |acc, (a, b)| acc + *a * *b)
+}
+
+// Exponential moving average of parameter values, kept outside the allocator:
+// shadow = decay * shadow + (1 - decay) * param
+#[derive(Clone)]
+pub struct Ema<T: Num> {
+    pub(crate) params: Vec<ValueId<T>>,
+    pub(crate) shadow: Vec<T>,
+    pub(crate) decay: T,
+}
+
+impl<T: Num> Ema<T> {
+    pub fn new(allocator: &Allocator<T>, params: Vec<ValueId<T>>, decay: T) -> Self {
+        let shadow = params.iter().map(|p| allocator.get(*p).data).collect();
+        Ema {
+            params,
+            shadow,
+            decay,
+        }
+    }
+
+    pub fn update(&mut self, allocator: &Allocator<T>) {
+        for (param, shadow) in self.params.iter().zip(self.shadow.iter_mut()) {
+            let data = allocator.get(*param).data;
+            *shadow = self.decay * *shadow + (T::one() - self.decay) * data;
+        }
+    }
+
+    pub fn shadow(&self) -> &[T] {
+        &self.shadow
+    }
+
+    // Overwrites the parameters with their averaged values, e.g. before evaluation
+    pub fn copy_to(&self, allocator: &mut Allocator<T>) {
+        for (param, shadow) in self.params.iter().zip(self.shadow.iter()) {
+            allocator.get_mut(*param).set_data(*shadow);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        losses::mse,
+        nn::{Activation, MLP},
+    };
+
+    #[test]
+    fn test_sgd_step() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(3.0);
+        let b = a * a;
+        assert_eq!(allocator.get(b).data, 9.0);
+
+        let mut optimizer = SGD::new(vec![a], 0.1);
+        allocator.backward();
+        optimizer.step(&mut allocator);
+        assert_eq!(allocator.get(a).data, 2.4);
+        assert_eq!(allocator.get(a).grad, 6.0);
+
+        optimizer.zero_grad(&mut allocator);
+        assert_eq!(allocator.get(a).grad, 0.0);
+    }
+
+    #[test]
+    fn test_sgd_momentum() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let mut optimizer = SGD::with_momentum(vec![a], 0.5, 0.9);
+
+        allocator.get_mut(a).add_grad(1.0);
+        optimizer.step(&mut allocator);
+        assert_eq!(allocator.get(a).data, 0.5);
+
+        // The velocity keeps pushing in the same direction even without new gradients
+        optimizer.zero_grad(&mut allocator);
+        optimizer.step(&mut allocator);
+        assert!((allocator.get(a).data - 0.05f64).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_rmsprop_step() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let mut optimizer = RMSProp::new(vec![a], 0.01, 0.99, 0.0);
+
+        // The first step is lr / sqrt(1 - alpha) regardless of the gradient's magnitude
+        allocator.get_mut(a).add_grad(100.0);
+        optimizer.step(&mut allocator);
+        assert!((allocator.get(a).data - (1.0 - 0.1f64)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_step_sparse() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let b = allocator.alloc(1.0);
+        allocator.get_mut(a).add_grad(1.0);
+        allocator.get_mut(b).add_grad(1.0);
+        let mut optimizer = SGD::new(vec![a, b], 0.5);
+        optimizer.step_sparse(&mut allocator, &[b]);
+        assert_eq!(allocator.gather_data(&[a, b]), vec![1.0, 0.5]);
+
+        // Without a sparse update of its own, an optimizer takes a full step
+        struct Plain(SGD<f64>);
+        impl Optimizer<f64> for Plain {
+            fn step(&mut self, allocator: &mut Allocator<f64>) {
+                self.0.step(allocator);
+            }
+            fn zero_grad(&mut self, allocator: &mut Allocator<f64>) {
+                self.0.zero_grad(allocator);
+            }
+            fn lr(&self) -> f64 {
+                self.0.lr()
+            }
+            fn set_lr(&mut self, lr: f64) {
+                self.0.set_lr(lr);
+            }
+        }
+        let mut plain = Plain(optimizer);
+        plain.step_sparse(&mut allocator, &[b]);
+        assert_eq!(allocator.gather_data(&[a, b]), vec![0.5, 0.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "step_sparse got a parameter this optimizer doesn't own")]
+    fn test_step_sparse_foreign_param() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let b = allocator.alloc(1.0);
+        let mut optimizer = RMSProp::new(vec![a], 0.01, 0.99, 1e-8);
+        optimizer.step_sparse(&mut allocator, &[b]);
+    }
+
+    #[test]
+    fn test_clip_grad_norm() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(0.0);
+        let b = allocator.alloc(0.0);
+        allocator.get_mut(a).add_grad(3.0);
+        allocator.get_mut(b).add_grad(4.0);
+
+        let norm = clip_grad_norm(&mut allocator, &[a, b], 10.0);
+        assert_eq!(norm, 5.0);
+        assert_eq!(allocator.get(a).grad, 3.0);
+        assert_eq!(allocator.get(b).grad, 4.0);
+
+        let norm = clip_grad_norm(&mut allocator, &[a, b], 1.0);
+        assert_eq!(norm, 5.0);
+        assert!((allocator.get(a).grad - 0.6f64).abs() < 1e-12);
+        assert!((allocator.get(b).grad - 0.8f64).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_clip_grad_value() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(0.0);
+        let b = allocator.alloc(0.0);
+        let c = allocator.alloc(0.0);
+        allocator.get_mut(a).add_grad(3.0);
+        allocator.get_mut(b).add_grad(-4.0);
+        allocator.get_mut(c).add_grad(0.5);
+
+        clip_grad_value(&mut allocator, &[a, b, c], 1.0);
+        assert_eq!(allocator.get(a).grad, 1.0);
+        assert_eq!(allocator.get(b).grad, -1.0);
+        assert_eq!(allocator.get(c).grad, 0.5);
+    }
+
+    #[test]
+    fn test_lr_find() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(1.0);
+        let mut optimizer = SGD::new(vec![a], 0.01);
+
+        let curve = lr_find(&mut allocator, &mut optimizer, &[a], 1e-3, 10.0, 20, |_| {
+            a * a
+        });
+        assert!(!curve.is_empty() && curve.len() <= 20);
+        assert!((curve[0].0 - 1e-3f64).abs() < 1e-12);
+        assert_eq!(curve[0].1, 1.0);
+        for pair in curve.windows(2) {
+            assert!(pair[1].0 > pair[0].0);
+        }
+
+        // Learning rates above 1 make a^2 diverge, which should end the sweep early
+        assert!(curve.len() < 20);
+        assert_eq!(allocator.get(a).data, 1.0);
+        assert_eq!(optimizer.lr(), 0.01);
+    }
+
+    #[test]
+    fn test_lbfgs_quadratic() {
+        let mut allocator = Allocator::new();
+        let x = allocator.alloc(0.0);
+        let y = allocator.alloc(0.0);
+        let mut optimizer = LBFGS::new(vec![x, y], 1.0, 10);
+
+        // (x - 3)^2 + 10 * (y + 1)^2
+        let mut loss = f64::MAX;
+        for _ in 0..10 {
+            loss = optimizer.step(&mut allocator, |_| {
+                let a = x - 3.0;
+                let b = y + 1.0;
+                a * a + b * b * 10.0
+            });
+        }
+        assert!(loss < 1e-10);
+        assert!((allocator.get(x).data - 3.0).abs() < 1e-5);
+        assert!((allocator.get(y).data + 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_lbfgs_rosenbrock() {
+        let mut allocator = Allocator::new();
+        let x = allocator.alloc(-1.5f64);
+        let y = allocator.alloc(2.0);
+        let mut optimizer = LBFGS::new(vec![x, y], 1.0, 10);
+
+        // (1 - x)^2 + 100 * (y - x^2)^2, minimized at (1, 1)
+        for _ in 0..100 {
+            optimizer.step(&mut allocator, |_| {
+                let a = 1.0 - x;
+                let b = y - x * x;
+                a * a + b * b * 100.0
+            });
+        }
+        assert!((allocator.get(x).data - 1.0).abs() < 1e-4);
+        assert!((allocator.get(y).data - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_ema() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(0.0);
+        let mut ema = Ema::new(&allocator, vec![a], 0.75);
+
+        allocator.get_mut(a).set_data(4.0);
+        ema.update(&allocator);
+        assert_eq!(ema.shadow(), &[1.0]);
+
+        ema.update(&allocator);
+        assert_eq!(ema.shadow(), &[1.75]);
+
+        ema.copy_to(&mut allocator);
+        assert_eq!(allocator.get(a).data, 1.75);
+    }
+
+    #[test]
+    fn test_sgd_training() {
+        let mut allocator = Allocator::new();
+        let mlp = MLP::new(&mut allocator, &[1, 4, 1], Some(Activation::Tanh));
+        let mut optimizer = SGD::new(mlp.parameters(), 0.1);
+
+        let input = [allocator.alloc(0.5)];
+        let target = [allocator.alloc(0.25)];
+
+        let mut losses = vec![];
+        for _ in 0..50 {
+            let output = mlp.forward(&input);
+            let loss = mse(&mut allocator, &output, &target);
+            losses.push(allocator.get(loss).data);
+
+            allocator.backward();
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+        assert!(losses.last().unwrap() < losses.first().unwrap());
+    }
+}
@@ -0,0 +1,275 @@
+use super::Optimizer;
+use crate::{
+    allocator::{Allocator, ValueId},
+    operators::Num,
+};
+
+// A schedule maps the optimizer's initial learning rate and the number of scheduler
+// steps taken so far to the learning rate for the next step
+pub trait LrScheduler<T: Num> {
+    fn lr(&self, base_lr: T, step: usize) -> T;
+}
+
+// Saturates instead of wrapping, since gamma^i32::MAX has long underflowed anyway
+fn exponent(steps: usize) -> i32 {
+    i32::try_from(steps).unwrap_or(i32::MAX)
+}
+
+// Multiplies the learning rate by `gamma` every `step_size` steps
+pub struct StepLR<T: Num> {
+    pub(crate) step_size: usize,
+    pub(crate) gamma: T,
+}
+
+impl<T: Num> StepLR<T> {
+    pub fn new(step_size: usize, gamma: T) -> Self {
+        assert!(step_size > 0, "StepLR step_size must be positive");
+        StepLR { step_size, gamma }
+    }
+}
+
+impl<T: Num> LrScheduler<T> for StepLR<T> {
+    fn lr(&self, base_lr: T, step: usize) -> T {
+        base_lr * self.gamma.powi(exponent(step / self.step_size))
+    }
+}
+
+// Multiplies the learning rate by `gamma` every step
+pub struct ExponentialLR<T: Num> {
+    pub(crate) gamma: T,
+}
+
+impl<T: Num> ExponentialLR<T> {
+    pub fn new(gamma: T) -> Self {
+        ExponentialLR { gamma }
+    }
+}
+
+impl<T: Num> LrScheduler<T> for ExponentialLR<T> {
+    fn lr(&self, base_lr: T, step: usize) -> T {
+        base_lr * self.gamma.powi(exponent(step))
+    }
+}
+
+// Anneals from the base learning rate down to `min_lr` along half a cosine over
+// `t_max` steps, then stays at `min_lr`
+pub struct CosineAnnealingLR<T: Num> {
+    pub(crate) t_max: usize,
+    pub(crate) min_lr: T,
+}
+
+impl<T: Num> CosineAnnealingLR<T> {
+    pub fn new(t_max: usize, min_lr: T) -> Self {
+        assert!(t_max > 0, "CosineAnnealingLR t_max must be positive");
+        CosineAnnealingLR { t_max, min_lr }
+    }
+}
+
+impl<T: Num> LrScheduler<T> for CosineAnnealingLR<T> {
+    fn lr(&self, base_lr: T, step: usize) -> T {
+        let progress = step.min(self.t_max) as f64 / self.t_max as f64;
+        let factor = T::from_f64((1.0 + (std::f64::consts::PI * progress).cos()) / 2.0).unwrap();
+        self.min_lr + (base_lr - self.min_lr) * factor
+    }
+}
+
+// Ramps linearly from `start_factor * base_lr` up to `base_lr` over `warmup_steps`
+pub struct LinearWarmup<T: Num> {
+    pub(crate) warmup_steps: usize,
+    pub(crate) start_factor: T,
+}
+
+impl<T: Num> LinearWarmup<T> {
+    pub fn new(warmup_steps: usize, start_factor: T) -> Self {
+        assert!(
+            warmup_steps > 0,
+            "LinearWarmup warmup_steps must be positive"
+        );
+        LinearWarmup {
+            warmup_steps,
+            start_factor,
+        }
+    }
+}
+
+impl<T: Num> LrScheduler<T> for LinearWarmup<T> {
+    fn lr(&self, base_lr: T, step: usize) -> T {
+        let progress = T::from_usize(step.min(self.warmup_steps)).unwrap()
+            / T::from_usize(self.warmup_steps).unwrap();
+        base_lr * (self.start_factor + (T::one() - self.start_factor) * progress)
+    }
+}
+
+// Follows `first` for `milestone` steps and then `second`, whose step count restarts
+// at zero. Chains of more than two schedules can be built by nesting.
+pub struct Chain<A, B> {
+    pub(crate) first: A,
+    pub(crate) second: B,
+    pub(crate) milestone: usize,
+}
+
+impl<A, B> Chain<A, B> {
+    pub fn new(first: A, second: B, milestone: usize) -> Self {
+        Chain {
+            first,
+            second,
+            milestone,
+        }
+    }
+}
+
+impl<T: Num, A: LrScheduler<T>, B: LrScheduler<T>> LrScheduler<T> for Chain<A, B> {
+    fn lr(&self, base_lr: T, step: usize) -> T {
+        if step < self.milestone {
+            self.first.lr(base_lr, step)
+        } else {
+            self.second.lr(base_lr, step - self.milestone)
+        }
+    }
+}
+
+// Wraps an optimizer and updates its learning rate every time `advance` is called,
+// e.g. once per epoch or once per batch
+pub struct Scheduled<T: Num, O: Optimizer<T>, S: LrScheduler<T>> {
+    pub optimizer: O,
+    pub scheduler: S,
+    pub(crate) base_lr: T,
+    pub(crate) steps: usize,
+}
+
+impl<T: Num, O: Optimizer<T>, S: LrScheduler<T>> Scheduled<T, O, S> {
+    pub fn new(mut optimizer: O, scheduler: S) -> Self {
+        let base_lr = optimizer.lr();
+        optimizer.set_lr(scheduler.lr(base_lr, 0));
+        Scheduled {
+            optimizer,
+            scheduler,
+            base_lr,
+            steps: 0,
+        }
+    }
+
+    pub fn advance(&mut self) {
+        self.steps += 1;
+        self.optimizer
+            .set_lr(self.scheduler.lr(self.base_lr, self.steps));
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+}
+
+impl<T: Num, O: Optimizer<T>, S: LrScheduler<T>> Optimizer<T> for Scheduled<T, O, S> {
+    fn step(&mut self, allocator: &mut Allocator<T>) {
+        self.optimizer.step(allocator);
+    }
+
+    fn zero_grad(&mut self, allocator: &mut Allocator<T>) {
+        self.optimizer.zero_grad(allocator);
+    }
+
+    fn step_sparse(&mut self, allocator: &mut Allocator<T>, params: &[ValueId<T>]) {
+        self.optimizer.step_sparse(allocator, params);
+    }
+
+    fn zero_grad_sparse(&mut self, allocator: &mut Allocator<T>, params: &[ValueId<T>]) {
+        self.optimizer.zero_grad_sparse(allocator, params);
+    }
+
+    fn lr(&self) -> T {
+        self.optimizer.lr()
+    }
+
+    // Rescales the schedule to continue from `lr`, so the next `advance()` doesn't
+    // overwrite it, e.g. after `lr_find`
+    fn set_lr(&mut self, lr: T) {
+        let current = self.optimizer.lr();
+        self.base_lr = if current.is_zero() {
+            lr
+        } else {
+            self.base_lr * lr / current
+        };
+        self.optimizer.set_lr(lr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optim::SGD;
+
+    #[test]
+    fn test_step_lr() {
+        let scheduler = StepLR::new(2, 0.5);
+        assert_eq!(scheduler.lr(1.0, 0), 1.0);
+        assert_eq!(scheduler.lr(1.0, 1), 1.0);
+        assert_eq!(scheduler.lr(1.0, 2), 0.5);
+        assert_eq!(scheduler.lr(1.0, 5), 0.25);
+        assert_eq!(StepLR::new(1, 0.5).lr(1.0, usize::MAX), 0.0);
+        assert_eq!(ExponentialLR::new(0.5).lr(1.0, usize::MAX), 0.0);
+    }
+
+    #[test]
+    fn test_exponential_lr() {
+        let scheduler = ExponentialLR::new(0.5);
+        assert_eq!(scheduler.lr(1.0, 0), 1.0);
+        assert_eq!(scheduler.lr(1.0, 3), 0.125);
+    }
+
+    #[test]
+    fn test_cosine_annealing_lr() {
+        let scheduler = CosineAnnealingLR::new(10, 0.1);
+        assert_eq!(scheduler.lr(1.0, 0), 1.0);
+        assert!((scheduler.lr(1.0, 5) - 0.55f64).abs() < 1e-12);
+        assert!((scheduler.lr(1.0, 10) - 0.1f64).abs() < 1e-12);
+        assert!((scheduler.lr(1.0, 20) - 0.1f64).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_linear_warmup() {
+        let scheduler = LinearWarmup::new(4, 0.0);
+        assert_eq!(scheduler.lr(1.0, 0), 0.0);
+        assert_eq!(scheduler.lr(1.0, 2), 0.5);
+        assert_eq!(scheduler.lr(1.0, 4), 1.0);
+        assert_eq!(scheduler.lr(1.0, 8), 1.0);
+    }
+
+    #[test]
+    fn test_warmup_then_cosine() {
+        let scheduler = Chain::new(
+            LinearWarmup::new(2, 0.5),
+            CosineAnnealingLR::new(10, 0.0),
+            2,
+        );
+        assert_eq!(scheduler.lr(1.0, 0), 0.5);
+        assert_eq!(scheduler.lr(1.0, 1), 0.75);
+        assert_eq!(scheduler.lr(1.0, 2), 1.0);
+        assert!((scheduler.lr(1.0, 7) - 0.5f64).abs() < 1e-12);
+        assert!(scheduler.lr(1.0, 12).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_scheduled_optimizer() {
+        let mut allocator = Allocator::<f64>::new();
+        let a = allocator.alloc(1.0);
+        let mut optimizer = Scheduled::new(SGD::new(vec![a], 0.5), StepLR::new(1, 0.5));
+        assert_eq!(optimizer.lr(), 0.5);
+
+        allocator.get_mut(a).add_grad(1.0);
+        optimizer.step(&mut allocator);
+        assert_eq!(allocator.get(a).data, 0.5);
+
+        optimizer.advance();
+        assert_eq!(optimizer.steps(), 1);
+        assert_eq!(optimizer.lr(), 0.25);
+        optimizer.step(&mut allocator);
+        assert_eq!(allocator.get(a).data, 0.25);
+
+        // A rate set from outside carries on with the schedule
+        optimizer.set_lr(1.0);
+        assert_eq!(optimizer.lr(), 1.0);
+        optimizer.advance();
+        assert_eq!(optimizer.lr(), 0.5);
+    }
+}
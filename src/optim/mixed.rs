@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use super::{index_params, zero_grads, Optimizer};
+use crate::{
+    allocator::{Allocator, ValueId},
+    half::HalfFloat,
+};
+
+// Mixed-precision training: the model and its tape run in a 16-bit type `H`, while the
+// wrapped optimizer updates f32 master copies of the parameters. Updates too small to
+// change a parameter in `H` still accumulate in its master copy, which is rounded into
+// the model after every step.
+pub struct MixedPrecision<H: HalfFloat, O: Optimizer<f32>> {
+    pub(crate) params: Vec<ValueId<H>>,
+    pub(crate) index: HashMap<ValueId<H>, usize>,
+    // Boxed, since the master ids point at it
+    pub(crate) master: Box<Allocator<f32>>,
+    pub(crate) master_params: Vec<ValueId<f32>>,
+    pub(crate) optimizer: O,
+}
+
+impl<H: HalfFloat, O: Optimizer<f32>> MixedPrecision<H, O> {
+    // `optimizer` builds the wrapped optimizer over the master copies of `params`, e.g.
+    // `|params| SGD::new(params, 0.01)`
+    pub fn new(
+        allocator: &Allocator<H>,
+        params: Vec<ValueId<H>>,
+        optimizer: impl FnOnce(Vec<ValueId<f32>>) -> O,
+    ) -> Self {
+        let mut master = Box::new(Allocator::new());
+        let master_params = params
+            .iter()
+            .map(|param| master.alloc(allocator.get(*param).data.to_f32()))
+            .collect::<Vec<_>>();
+        MixedPrecision {
+            index: index_params(&params),
+            params,
+            master,
+            optimizer: optimizer(master_params.clone()),
+            master_params,
+        }
+    }
+
+    // The f32 values of the parameters, in the order they were given
+    pub fn master_data(&self) -> Vec<f32> {
+        self.master.gather_data(&self.master_params)
+    }
+
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn optimizer_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    // Widens the gradients of the parameters at `indices` into their master copies,
+    // dividing them by `scale`. Frozen parameters are frozen in the master allocator too.
+    // Returns whether every gradient is finite.
+    fn load_grads(&mut self, allocator: &Allocator<H>, indices: &[usize], scale: f32) -> bool {
+        let mut finite = true;
+        for &i in indices {
+            let value = allocator.get(self.params[i]);
+            let grad = value.grad.to_f32() / scale;
+            finite &= grad.is_finite();
+            let mut master = self.master.get_mut(self.master_params[i]);
+            master.set_grad(grad);
+            *master.requires_grad = value.requires_grad;
+        }
+        finite
+    }
+
+    // Steps the master copies of the parameters at `indices` on the loaded gradients and
+    // rounds them into the model
+    fn step_indices(&mut self, allocator: &mut Allocator<H>, indices: &[usize], sparse: bool) {
+        if sparse {
+            let params = indices
+                .iter()
+                .map(|i| self.master_params[*i])
+                .collect::<Vec<_>>();
+            self.optimizer.step_sparse(&mut self.master, &params);
+        } else {
+            self.optimizer.step(&mut self.master);
+        }
+        for &i in indices {
+            let data = self.master.get(self.master_params[i]).data;
+            allocator
+                .get_mut(self.params[i])
+                .set_data(H::from_f32(data).unwrap());
+        }
+    }
+}
+
+impl<H: HalfFloat, O: Optimizer<f32>> Optimizer<H> for MixedPrecision<H, O> {
+    fn step(&mut self, allocator: &mut Allocator<H>) {
+        let indices = (0..self.params.len()).collect::<Vec<_>>();
+        self.load_grads(allocator, &indices, 1.0);
+        self.step_indices(allocator, &indices, false);
+    }
+
+    fn step_sparse(&mut self, allocator: &mut Allocator<H>, params: &[ValueId<H>]) {
+        let indices = params
+            .iter()
+            .map(|p| {
+                *self
+                    .index
+                    .get(p)
+                    .expect("step_sparse got a parameter this optimizer doesn't own")
+            })
+            .collect::<Vec<_>>();
+        self.load_grads(allocator, &indices, 1.0);
+        self.step_indices(allocator, &indices, true);
+    }
+
+    fn zero_grad(&mut self, allocator: &mut Allocator<H>) {
+        zero_grads(allocator, &self.params);
+        self.optimizer.zero_grad(&mut self.master);
+    }
+
+    // Rounded to `H`, see `optimizer()` for the exact value
+    fn lr(&self) -> H {
+        H::from_f32(self.optimizer.lr()).unwrap()
+    }
+
+    fn set_lr(&mut self, lr: H) {
+        self.optimizer.set_lr(lr.to_f32());
+    }
+}
+
+// Dynamic loss scaling for `MixedPrecision`. The loss is backpropagated with the scale as
+// its seed, so that small gradients don't flush to zero in 16 bits, and the gradients are
+// divided by the scale again once widened to f32. Steps whose gradients overflowed are
+// skipped and shrink the scale, while every `growth_interval` successful steps in a row
+// grow it again.
+#[derive(Clone, Debug)]
+pub struct LossScaler {
+    pub(crate) scale: f32,
+    pub(crate) growth_factor: f32,
+    pub(crate) backoff_factor: f32,
+    pub(crate) growth_interval: usize,
+    pub(crate) good_steps: usize,
+}
+
+impl LossScaler {
+    // Doubles the scale after 2000 good steps and halves it on overflow. f16 overflows
+    // past 65504, so 2^15 is the largest power of two to start from.
+    pub fn new(initial_scale: f32) -> Self {
+        Self::with_growth(initial_scale, 2.0, 0.5, 2000)
+    }
+
+    pub fn with_growth(
+        initial_scale: f32,
+        growth_factor: f32,
+        backoff_factor: f32,
+        growth_interval: usize,
+    ) -> Self {
+        assert!(initial_scale > 0.0, "LossScaler scale must be positive");
+        assert!(
+            growth_factor > 1.0 && backoff_factor > 0.0 && backoff_factor < 1.0,
+            "LossScaler needs growth_factor > 1 and 0 < backoff_factor < 1"
+        );
+        assert!(
+            growth_interval > 0,
+            "LossScaler growth_interval must be positive"
+        );
+        LossScaler {
+            scale: initial_scale,
+            growth_factor,
+            backoff_factor,
+            growth_interval,
+            good_steps: 0,
+        }
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    // `backward_from(loss)` with the loss multiplied by the scale
+    pub fn backward<H: HalfFloat>(&self, allocator: &mut Allocator<H>, loss: ValueId<H>) {
+        allocator.backward_from_with_seed(loss, H::from_f32(self.scale).unwrap());
+    }
+
+    // Unscales the gradients and steps `optimizer`, unless a gradient is infinite or NaN.
+    // Adapts the scale either way and returns whether the step was taken. Gradients still
+    // need to be zeroed afterwards.
+    pub fn step<H: HalfFloat, O: Optimizer<f32>>(
+        &mut self,
+        allocator: &mut Allocator<H>,
+        optimizer: &mut MixedPrecision<H, O>,
+    ) -> bool {
+        let indices = (0..optimizer.params.len()).collect::<Vec<_>>();
+        if !optimizer.load_grads(allocator, &indices, self.scale) {
+            self.scale *= self.backoff_factor;
+            self.good_steps = 0;
+            return false;
+        }
+        optimizer.step_indices(allocator, &indices, false);
+        self.good_steps += 1;
+        if self.good_steps == self.growth_interval {
+            self.good_steps = 0;
+            // The seed has to stay finite in `H`
+            let grown = self.scale * self.growth_factor;
+            if H::from_f32(grown).unwrap().to_f32().is_finite() {
+                self.scale = grown;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        half::{BF16, F16},
+        nn::{Activation, MLP},
+        optim::SGD,
+    };
+
+    #[test]
+    fn test_master_weights() {
+        let mut allocator = Allocator::<F16>::new();
+        let x = allocator.alloc(F16::from_f32(1.0));
+        let y = allocator.alloc(F16::from_f32(1.0));
+        let mut plain = SGD::new(vec![x], F16::from_f32(1.0));
+        let mut mixed = MixedPrecision::new(&allocator, vec![y], |params| SGD::new(params, 1.0));
+
+        // Steps of 1e-4 are below the resolution of f16 around 1
+        for _ in 0..20 {
+            let _ = (x + y) * F16::from_f32(1e-4);
+            allocator.backward();
+            plain.step(&mut allocator);
+            mixed.step(&mut allocator);
+            plain.zero_grad(&mut allocator);
+            mixed.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+        assert_eq!(allocator.get(x).data.to_f32(), 1.0);
+        assert!((mixed.master_data()[0] - 0.998).abs() < 1e-5);
+        assert_eq!(allocator.get(y).data, F16::from_f32(0.998));
+    }
+
+    #[test]
+    fn test_loss_scaling() {
+        let mut allocator = Allocator::<F16>::new();
+        let x = allocator.alloc(F16::from_f32(1.0));
+        let mut optimizer =
+            MixedPrecision::new(&allocator, vec![x], |params| SGD::new(params, 1e6));
+        let mut scaler = LossScaler::with_growth(32768.0, 2.0, 0.5, 2);
+        let small =
+            |x: ValueId<F16>| x * F16::from_f32(1e-3) * F16::from_f32(1e-3) * F16::from_f32(1e-3);
+
+        // A gradient of 1e-9 flushes to zero in f16 without scaling
+        let loss = small(x);
+        allocator.backward_from(loss);
+        assert_eq!(allocator.get(x).grad.to_f32(), 0.0);
+        optimizer.zero_grad(&mut allocator);
+        scaler.backward(&mut allocator, loss);
+        assert!(scaler.step(&mut allocator, &mut optimizer));
+        assert!((optimizer.master_data()[0] - (1.0 - 1e-3)).abs() < 1e-4);
+        optimizer.zero_grad(&mut allocator);
+        allocator.clear_temps();
+
+        // 32768 * 100 overflows, so the step is skipped and the scale halved
+        let loss = x * F16::from_f32(100.0);
+        let before = optimizer.master_data();
+        scaler.backward(&mut allocator, loss);
+        assert!(!scaler.step(&mut allocator, &mut optimizer));
+        assert_eq!(optimizer.master_data(), before);
+        assert_eq!(scaler.scale(), 16384.0);
+        optimizer.zero_grad(&mut allocator);
+        allocator.clear_temps();
+
+        // Two good steps in a row grow it again, but not past what f16 can hold
+        for expected in [16384.0, 32768.0, 32768.0, 32768.0] {
+            let loss = small(x);
+            scaler.backward(&mut allocator, loss);
+            assert!(scaler.step(&mut allocator, &mut optimizer));
+            assert_eq!(scaler.scale(), expected);
+            optimizer.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+    }
+
+    #[test]
+    fn test_mixed_precision_training() {
+        let mut allocator = Allocator::<BF16>::new();
+        let mlp = MLP::new(&mut allocator, &[2, 4, 1], Some(Activation::Tanh));
+        let mut optimizer = MixedPrecision::new(&allocator, mlp.parameters(), |params| {
+            SGD::with_momentum(params, 0.05, 0.9)
+        });
+        let samples = [([0.0, 1.0], 0.5), ([1.0, 0.0], -0.5)];
+        let mut losses = vec![];
+        for _ in 0..100 {
+            let mut total = 0.0;
+            for (x, target) in samples {
+                let inputs = allocator.alloc_t_f32(&x);
+                let diff = mlp.forward(&inputs)[0] - BF16::from_f32(target);
+                let loss = diff * diff;
+                total += allocator.gather_data_f32(&[loss])[0];
+                allocator.backward();
+                allocator.clear_temps();
+            }
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            losses.push(total);
+        }
+        assert!(losses[99] < losses[0] * 0.1);
+        for (master, param) in optimizer
+            .master_data()
+            .iter()
+            .zip(allocator.gather_data(&mlp.parameters()))
+        {
+            assert_eq!(BF16::from_f32(*master), param);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use super::Optimizer;
+use crate::{
+    allocator::{Allocator, ValueId},
+    nn::Module,
+    operators::Num,
+};
+
+// Parameter data to load and the samples of one shard
+type Job<T, S> = (Arc<[T]>, Vec<S>);
+
+struct Worker<T: Num, S> {
+    jobs: Sender<Job<T, S>>,
+    // The summed loss and parameter gradients of a shard
+    results: Receiver<(T, Vec<T>)>,
+    handle: JoinHandle<()>,
+}
+
+// Data-parallel training on a pool of threads. Every thread builds a replica of the model
+// on an allocator of its own, and keeps it for the trainer's lifetime. Each step loads the
+// master parameters into the replicas, runs forward and backward on one shard of the batch
+// per thread, and steps the optimizer on the averaged gradients.
+pub struct ParallelTrainer<T: Num, S> {
+    workers: Vec<Worker<T, S>>,
+}
+
+impl<T: Num + Send + Sync + 'static, S: Send + 'static> ParallelTrainer<T, S> {
+    // `build` must create the model like the master model, so that the replicas list their
+    // parameters in the same order. `loss` computes the loss of one sample on a replica.
+    pub fn new<M: Module<T>>(
+        threads: usize,
+        build: impl Fn(&mut Allocator<T>) -> M + Send + Sync + 'static,
+        loss: impl Fn(&mut Allocator<T>, &M, &S) -> ValueId<T> + Send + Sync + 'static,
+    ) -> Self {
+        assert!(threads > 0, "ParallelTrainer needs at least one thread");
+        let build = Arc::new(build);
+        let loss = Arc::new(loss);
+        let workers = (0..threads)
+            .map(|_| {
+                let (jobs, job_receiver) = channel::<Job<T, S>>();
+                let (result_sender, results) = channel();
+                let (build, loss) = (build.clone(), loss.clone());
+                let handle = std::thread::spawn(move || {
+                    let mut allocator = Allocator::new();
+                    let model = build(&mut allocator);
+                    let params = model.parameters();
+                    for (data, samples) in job_receiver {
+                        allocator.scatter_data(&params, &data);
+                        allocator.zero_grads();
+                        let mut total = T::zero();
+                        for sample in &samples {
+                            let loss = loss(&mut allocator, &model, sample);
+                            total = total + allocator.get(loss).data;
+                            allocator.backward();
+                            allocator.clear_temps();
+                        }
+                        let grads = allocator.gather_grads(&params);
+                        if result_sender.send((total, grads)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                Worker {
+                    jobs,
+                    results,
+                    handle,
+                }
+            })
+            .collect();
+        ParallelTrainer { workers }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    // Takes one optimizer step on the mean loss over `batch` and returns that loss.
+    // `params` are the master model's parameters, in `parameters()` order. Their
+    // gradients are overwritten, and zeroed again after the step.
+    pub fn step(
+        &mut self,
+        allocator: &mut Allocator<T>,
+        optimizer: &mut impl Optimizer<T>,
+        params: &[ValueId<T>],
+        batch: Vec<S>,
+    ) -> T {
+        assert!(
+            !batch.is_empty(),
+            "ParallelTrainer::step got an empty batch"
+        );
+        let len = batch.len();
+        let data: Arc<[T]> = allocator.gather_data(params).into();
+        let shard_len = len.div_ceil(self.workers.len());
+        let mut batch = batch.into_iter();
+        let mut busy = 0;
+        for worker in &self.workers {
+            let shard = batch.by_ref().take(shard_len).collect::<Vec<_>>();
+            if shard.is_empty() {
+                break;
+            }
+            worker
+                .jobs
+                .send((data.clone(), shard))
+                .expect("a ParallelTrainer thread panicked");
+            busy += 1;
+        }
+
+        let mut total = T::zero();
+        let mut grads = vec![T::zero(); params.len()];
+        for worker in &self.workers[..busy] {
+            let (loss, partial) = worker
+                .results
+                .recv()
+                .expect("a ParallelTrainer thread panicked");
+            total = total + loss;
+            for (grad, partial) in grads.iter_mut().zip(partial) {
+                *grad = *grad + partial;
+            }
+        }
+
+        let scale = T::one() / T::from_usize(len).unwrap();
+        let grads = grads.into_iter().map(|g| g * scale).collect::<Vec<_>>();
+        allocator.scatter_grads(params, &grads);
+        optimizer.step(allocator);
+        optimizer.zero_grad(allocator);
+        total * scale
+    }
+}
+
+impl<T: Num, S> Drop for ParallelTrainer<T, S> {
+    fn drop(&mut self) {
+        for worker in self.workers.drain(..) {
+            // Closing the job channel ends the thread's loop
+            drop(worker.jobs);
+            let _ = worker.handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        nn::{Activation, MLP},
+        optim::SGD,
+    };
+
+    fn sample_loss(
+        allocator: &mut Allocator<f64>,
+        mlp: &MLP<f64>,
+        (x, y): &([f64; 2], f64),
+    ) -> ValueId<f64> {
+        let inputs = x.map(|x| allocator.alloc_t(x));
+        let diff = mlp.forward(&inputs)[0] - *y;
+        diff * diff
+    }
+
+    #[test]
+    fn test_parallel_trainer_matches_serial() {
+        let build = |allocator: &mut Allocator<f64>| {
+            MLP::new(allocator, &[2, 4, 1], Some(Activation::Tanh))
+        };
+        let mut allocator = Allocator::new();
+        let mlp = build(&mut allocator);
+        let params = mlp.parameters();
+        let batch = (0..10)
+            .map(|i| {
+                let x = i as f64 / 10.0;
+                ([x, 1.0 - x], (3.0 * x).sin())
+            })
+            .collect::<Vec<_>>();
+        let initial = allocator.snapshot();
+
+        let mut trainer = ParallelTrainer::new(3, build, sample_loss);
+        assert_eq!(trainer.threads(), 3);
+        let mut optimizer = SGD::new(params.clone(), 0.1);
+        let losses = (0..3)
+            .map(|_| trainer.step(&mut allocator, &mut optimizer, &params, batch.clone()))
+            .collect::<Vec<_>>();
+        let trained = allocator.gather_data(&params);
+
+        allocator.restore(&initial);
+        let mut optimizer = SGD::new(params.clone(), 0.1);
+        for expected in losses {
+            let sample_losses = batch
+                .iter()
+                .map(|sample| sample_loss(&mut allocator, &mlp, sample))
+                .collect::<Vec<_>>();
+            let loss = crate::operators::mean(&mut allocator, &sample_losses);
+            assert!((allocator.get(loss).data - expected).abs() < 1e-12);
+            allocator.backward();
+            optimizer.step(&mut allocator);
+            optimizer.zero_grad(&mut allocator);
+            allocator.clear_temps();
+        }
+        for (a, b) in allocator.gather_data(&params).iter().zip(trained) {
+            assert!((a - b).abs() < 1e-12);
+        }
+    }
+}